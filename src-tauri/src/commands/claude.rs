@@ -966,7 +966,7 @@ fn append_permission_args(
                 args.push("--mcp-config".to_string());
                 args.push(config_path.to_string());
                 args.push("--permission-prompt-tool".to_string());
-                args.push("mcp__opcode__permission_prompt".to_string());
+                args.push(mcp_server_identity().permission_prompt_tool());
             }
         }
         "plan" => {
@@ -984,7 +984,7 @@ fn append_permission_args(
                 args.push("--mcp-config".to_string());
                 args.push(config_path.to_string());
                 args.push("--permission-prompt-tool".to_string());
-                args.push("mcp__opcode__permission_prompt".to_string());
+                args.push(mcp_server_identity().permission_prompt_tool());
             }
         }
         _ => {
@@ -994,13 +994,15 @@ fn append_permission_args(
                 args.push("--mcp-config".to_string());
                 args.push(config_path.to_string());
                 args.push("--permission-prompt-tool".to_string());
-                args.push("mcp__opcode__permission_prompt".to_string());
+                args.push(mcp_server_identity().permission_prompt_tool());
             }
         }
     }
 }
 
-/// Respond to a permission prompt from the frontend.
+/// Respond to a permission prompt from the frontend. With `remember`, the
+/// answer is reused for identical requests later in the session. A `modify`
+/// answer (or an allow) can name a `transform` to apply to the input.
 #[tauri::command]
 pub async fn respond_permission_prompt(
     app: AppHandle,
@@ -1008,6 +1010,8 @@ pub async fn respond_permission_prompt(
     prompt_id: String,
     behavior: String,
     input: Option<serde_json::Value>,
+    remember: Option<bool>,
+    transform: Option<String>,
 ) -> Result<(), String> {
     log::info!(
         "Responding to permission prompt '{}' for session '{}': {}",
@@ -1017,7 +1021,7 @@ pub async fn respond_permission_prompt(
     );
     let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
 
-    let response = if behavior == "allow" {
+    let response = if behavior == "allow" || behavior == crate::permission_prompt::MODIFY_BEHAVIOR {
         crate::permission_prompt::PermissionResponse {
             behavior,
             updated_input: input,
@@ -1027,25 +1031,537 @@ pub async fn respond_permission_prompt(
         crate::permission_prompt::PermissionResponse {
             behavior,
             updated_input: None,
-            message: Some("Denied by user".to_string()),
+            message: Some(crate::permission_prompt::USER_DENY_MESSAGE.to_string()),
         }
     };
 
-    crate::permission_prompt::resolve_prompt(
+    crate::permission_prompt::resolve_prompt_with(
         &session_id,
         &prompt_id,
         response,
+        transform.as_deref(),
+        remember.unwrap_or(false),
         &registry,
     )
     .await
+    .map_err(|e| e.to_string())
+}
+
+/// Allow a permission prompt, running the tool with `input` or, without it,
+/// with the input the prompt showed.
+#[tauri::command]
+pub async fn allow_permission_prompt(
+    app: AppHandle,
+    session_id: String,
+    prompt_id: String,
+    input: Option<serde_json::Value>,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::allow_prompt(&session_id, &prompt_id, input, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Deny a permission prompt, telling Claude why with `message`.
+#[tauri::command]
+pub async fn deny_permission_prompt(
+    app: AppHandle,
+    session_id: String,
+    prompt_id: String,
+    message: Option<String>,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::deny_prompt(&session_id, &prompt_id, message.as_deref(), &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Turn inspect mode on or off for a session: every request for a tool that
+/// isn't blocked is allowed as sent and only shown and logged, with nothing
+/// to click.
+#[tauri::command]
+pub async fn set_permission_inspect_mode(
+    app: AppHandle,
+    session_id: String,
+    enabled: bool,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::set_inspect_mode(&session_id, enabled, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Change how long a running session's new permission prompts wait for an
+/// answer. `0` waits forever.
+#[tauri::command]
+pub async fn set_permission_prompt_timeout(
+    app: AppHandle,
+    session_id: String,
+    timeout_ms: u64,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::set_timeout(
+        &session_id,
+        std::time::Duration::from_millis(timeout_ms),
+        &registry,
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Give a running session's new prompts for one tool their own timeout, or
+/// go back to the session's with `None`. `0` waits forever.
+#[tauri::command]
+pub async fn set_permission_tool_timeout(
+    app: AppHandle,
+    session_id: String,
+    tool_name: String,
+    timeout_ms: Option<u64>,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::set_tool_timeout(
+        &session_id,
+        &tool_name,
+        timeout_ms.map(std::time::Duration::from_millis),
+        &registry,
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Limit how many permission requests a running session may send per
+/// window (10 seconds unless `window_ms` is given), or lift the limit with
+/// `None`. Requests over it are denied without a prompt.
+#[tauri::command]
+pub async fn set_permission_rate_limit(
+    app: AppHandle,
+    session_id: String,
+    max_requests: Option<u32>,
+    window_ms: Option<u64>,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    let rate_limit = max_requests.map(|max| {
+        crate::permission_prompt::RateLimit::per_window(
+            max,
+            window_ms.map(std::time::Duration::from_millis),
+        )
+    });
+    crate::permission_prompt::set_rate_limit(&session_id, rate_limit, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Load a policy file (JSON or `.toml`) whose rules answer a running
+/// session's requests before the shared rules, or drop it with `None`.
+/// Returns the number of policy rules in effect.
+#[tauri::command]
+pub async fn set_permission_policy(
+    app: AppHandle,
+    session_id: String,
+    path: Option<String>,
+) -> Result<usize, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::set_policy(&session_id, path.map(std::path::PathBuf::from), &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Re-read a session's policy file after editing it. The old rules stay in
+/// effect if the file no longer loads.
+#[tauri::command]
+pub async fn reload_permission_policy(app: AppHandle, session_id: String) -> Result<usize, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::reload_policy(&session_id, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Permission prompts still waiting for an answer in a session, soonest
+/// deadline first.
+#[tauri::command]
+pub async fn list_pending_permission_prompts(
+    app: AppHandle,
+    session_id: String,
+) -> Result<Vec<crate::permission_prompt::PendingPromptInfo>, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    Ok(crate::permission_prompt::list_pending(&session_id, &registry).await)
+}
+
+/// Allow/deny/timeout counts for a session's permission decisions.
+#[tauri::command]
+pub async fn get_permission_metrics(
+    app: AppHandle,
+    session_id: String,
+) -> Result<crate::permission_prompt::PermissionMetrics, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::get_metrics(&session_id, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Snapshot of every running permission server, for the diagnostics view.
+#[tauri::command]
+pub async fn get_permission_servers_snapshot(
+    app: AppHandle,
+) -> Result<Vec<crate::permission_prompt::ServerSnapshot>, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    Ok(crate::permission_prompt::snapshot_registry(&registry).await)
+}
+
+/// Running permission servers and the prompts they're waiting on, for the
+/// status-bar badge.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermissionActivity {
+    pub sessions: usize,
+    pub waiting: usize,
+}
+
+/// Counts for the permission status badge, cheap enough to poll.
+#[tauri::command]
+pub async fn get_permission_activity(app: AppHandle) -> Result<PermissionActivity, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    Ok(PermissionActivity {
+        sessions: crate::permission_prompt::active_server_count(&registry).await,
+        waiting: crate::permission_prompt::total_pending(&registry).await,
+    })
+}
+
+/// Answer a question Claude asked through the `ask_user` MCP tool; `None`
+/// dismisses it.
+#[tauri::command]
+pub async fn respond_user_input(
+    app: AppHandle,
+    session_id: String,
+    request_id: String,
+    answer: Option<String>,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::resolve_user_input(&session_id, &request_id, answer, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Stop a session's permission server, giving prompts already showing up to
+/// `grace_ms` to be answered first. New requests are denied meanwhile.
+#[tauri::command]
+pub async fn stop_permission_server_graceful(
+    app: AppHandle,
+    session_id: String,
+    grace_ms: u64,
+) -> Result<crate::permission_prompt::StopResult, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    Ok(crate::permission_prompt::stop_server_graceful(
+        &session_id,
+        &registry,
+        std::time::Duration::from_millis(grace_ms),
+    )
+    .await)
+}
+
+/// Emit the session's pending permission prompts again, e.g. after the
+/// window reloaded. Returns how many were replayed.
+#[tauri::command]
+pub async fn replay_permission_prompts(
+    app: AppHandle,
+    session_id: String,
+) -> Result<usize, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::replay_pending(&session_id, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Tear down one pending permission prompt (e.g. its tab was closed); the
+/// request is denied as cancelled.
+#[tauri::command]
+pub async fn cancel_permission_prompt(
+    app: AppHandle,
+    session_id: String,
+    prompt_id: String,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::cancel_prompt(&session_id, &prompt_id, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Postpone a permission prompt for `delay_ms` without answering it; it is
+/// shown again afterwards while Claude keeps waiting.
+#[tauri::command]
+pub async fn defer_permission_prompt(
+    app: AppHandle,
+    session_id: String,
+    prompt_id: String,
+    delay_ms: u64,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::defer_prompt(
+        &session_id,
+        &prompt_id,
+        std::time::Duration::from_millis(delay_ms),
+        &registry,
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Deny every permission prompt a session is waiting on (e.g. on Stop)
+/// without shutting its server down. Returns how many were denied.
+#[tauri::command]
+pub async fn deny_all_permission_prompts(
+    app: AppHandle,
+    session_id: String,
+    message: Option<String>,
+) -> Result<usize, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    let message = message.unwrap_or_else(|| "Stopped by user".to_string());
+    Ok(crate::permission_prompt::deny_all_pending(&session_id, &registry, message).await)
+}
+
+/// Move the permission audit log to `path`, or turn it off with `None`.
+#[tauri::command]
+pub async fn set_permission_audit_log_path(
+    app: AppHandle,
+    path: Option<String>,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::set_audit_log_path(&registry, path.map(PathBuf::from));
+    Ok(())
+}
+
+/// Mask secrets matching `pattern` (a regex) in permission prompt events and
+/// the audit log, replacing any pattern already called `name`.
+#[tauri::command]
+pub async fn add_permission_secret_pattern(
+    app: AppHandle,
+    name: String,
+    pattern: String,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::add_secret_pattern(&registry, &name, &pattern)
+        .map_err(|e| e.to_string())
+}
+
+/// Also show OS notifications for new permission prompts while the main
+/// window isn't focused, or stop showing them.
+#[tauri::command]
+pub async fn set_permission_os_notifications(app: AppHandle, enabled: bool) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::set_os_notifications(&registry, enabled);
+    Ok(())
+}
+
+/// Let a tool through without a permission prompt for the rest of a session.
+#[tauri::command]
+pub async fn add_permission_allowed_tool(
+    app: AppHandle,
+    session_id: String,
+    tool_name: String,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::add_allowed_tool(&session_id, &tool_name, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Resume prompting for a tool previously added with
+/// `add_permission_allowed_tool`. Returns whether it was allowlisted.
+#[tauri::command]
+pub async fn remove_permission_allowed_tool(
+    app: AppHandle,
+    session_id: String,
+    tool_name: String,
+) -> Result<bool, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::remove_allowed_tool(&session_id, &tool_name, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Deny a tool without a permission prompt for the rest of a session. Takes
+/// precedence over `add_permission_allowed_tool`.
+#[tauri::command]
+pub async fn block_permission_tool(
+    app: AppHandle,
+    session_id: String,
+    tool_name: String,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::block_tool(&session_id, &tool_name, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Lift a block set by `block_permission_tool`. Returns whether the tool was
+/// blocked.
+#[tauri::command]
+pub async fn unblock_permission_tool(
+    app: AppHandle,
+    session_id: String,
+    tool_name: String,
+) -> Result<bool, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::unblock_tool(&session_id, &tool_name, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Finalize a high-risk deny staged by `respond_permission_prompt` (see the
+/// `permission-deny-confirm` event).
+#[tauri::command]
+pub async fn confirm_permission_deny(
+    app: AppHandle,
+    session_id: String,
+    prompt_id: String,
+) -> Result<(), String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::confirm_deny(&session_id, &prompt_id, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Token for answering a session's prompts through the permission server's
+/// `/resolve` endpoint, e.g. from a CLI or approver bot.
+#[tauri::command]
+pub async fn get_permission_controller_token(
+    app: AppHandle,
+    session_id: String,
+) -> Result<String, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::controller_token(&session_id, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Report the Node.js used for permission prompts and whether its version
+/// is supported.
+#[tauri::command]
+pub async fn get_permission_node_status() -> Result<crate::permission_prompt::NodeStatus, String> {
+    crate::permission_prompt::node_status(require_min_node_version()).map_err(|e| e.to_string())
+}
+
+/// Whether an outdated Node.js stops the permission server from starting.
+/// On by default, since the MCP script fails to parse on it; set
+/// `OPCODE_REQUIRE_NODE_VERSION=0` to only warn.
+fn require_min_node_version() -> bool {
+    !matches!(
+        std::env::var("OPCODE_REQUIRE_NODE_VERSION").as_deref(),
+        Ok("0") | Ok("false")
+    )
+}
+
+/// JavaScript runtime forced through `OPCODE_JS_RUNTIME` (`node`, `bun` or
+/// `deno`); unset probes them in that order.
+fn forced_js_runtime() -> Result<Option<crate::permission_prompt::RuntimeKind>, String> {
+    match std::env::var("OPCODE_JS_RUNTIME") {
+        Ok(name) if !name.is_empty() => crate::permission_prompt::RuntimeKind::from_name(&name)
+            .map(Some)
+            .ok_or_else(|| format!("Unknown OPCODE_JS_RUNTIME '{}'", name)),
+        _ => Ok(None),
+    }
+}
+
+/// Name and tool description of the permission MCP server, for running
+/// several instances side by side or white-labeled builds. Set through
+/// `OPCODE_MCP_SERVER_NAME` and `OPCODE_MCP_TOOL_DESCRIPTION`; defaults to
+/// `opcode`.
+fn mcp_server_identity() -> crate::permission_prompt::ServerIdentity {
+    crate::permission_prompt::ServerIdentity::from_env(|key| std::env::var(key).ok())
+}
+
+/// The executable Claude Code should run as the permission MCP server when
+/// `OPCODE_MCP_BRIDGE=native` opts in to the built-in bridge (opcode itself,
+/// see `permission_prompt::bridge`). `None` keeps the Node.js script, which
+/// stays the default and the fallback if opcode's own path is unknown.
+fn native_mcp_bridge() -> Option<std::path::PathBuf> {
+    if std::env::var("OPCODE_MCP_BRIDGE").as_deref() != Ok("native") {
+        return None;
+    }
+    std::env::current_exe()
+        .map_err(|e| log::warn!("Falling back to the Node.js MCP script: {}", e))
+        .ok()
+}
+
+/// Answer a batched permission prompt with one response per invocation in
+/// its `permission-prompt-batch` event, in the same order.
+#[tauri::command]
+pub async fn respond_permission_batch(
+    app: AppHandle,
+    session_id: String,
+    prompt_id: String,
+    responses: Vec<crate::permission_prompt::PermissionResponse>,
+) -> Result<(), String> {
+    log::info!(
+        "Responding to batch permission prompt '{}' for session '{}' ({} responses)",
+        prompt_id,
+        session_id,
+        responses.len()
+    );
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::resolve_batch(&session_id, &prompt_id, responses, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Fetch the full event of a pending prompt whose emitted copy was trimmed
+/// to fit the event size limit.
+#[tauri::command]
+pub async fn get_permission_prompt_event(
+    app: AppHandle,
+    session_id: String,
+    prompt_id: String,
+) -> Result<crate::permission_prompt::PermissionPromptEvent, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::get_prompt_event(&session_id, &prompt_id, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Fetch the real input of a pending prompt whose event only carried a
+/// preview (`input_truncated`).
+#[tauri::command]
+pub async fn get_full_permission_input(
+    app: AppHandle,
+    session_id: String,
+    prompt_id: String,
+) -> Result<serde_json::Value, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::get_full_input(&session_id, &prompt_id, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Simulate a permission prompt for UI development (debug builds, or with
+/// `OPCODE_ENABLE_TEST_PROMPTS=1`). Returns the prompt ID.
+#[tauri::command]
+pub async fn inject_test_permission_prompt(
+    app: AppHandle,
+    session_id: String,
+    tool_name: String,
+    input: serde_json::Value,
+) -> Result<String, String> {
+    let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
+    crate::permission_prompt::inject_test_prompt(&session_id, &tool_name, input, &registry)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Set the minimum level of permission-subsystem logs echoed to the frontend
+/// as `permission-log` events ("off", "error", "warn", "info", "debug", "trace").
+#[tauri::command]
+pub async fn set_permission_log_echo(level: String) -> Result<(), String> {
+    let level = level
+        .parse::<log::LevelFilter>()
+        .map_err(|_| format!("Invalid log level '{}'", level))?;
+    crate::permission_prompt::log_echo::set_echo_level(level);
+    Ok(())
 }
 
 /// Holds cleanup info for the permission MCP server so `spawn_claude_process`
 /// can re-key and clean up after the process exits.
 struct PermissionCleanup {
     placeholder_id: String,
-    config_path: std::path::PathBuf,
-    script_path: std::path::PathBuf,
+    files: crate::permission_prompt::McpFiles,
 }
 
 /// Start a permission MCP server for the session.
@@ -1055,6 +1571,7 @@ struct PermissionCleanup {
 async fn maybe_start_permission_server(
     app: &AppHandle,
     permission_mode: Option<&str>,
+    project_path: &str,
 ) -> Result<Option<(String, PermissionCleanup)>, String> {
     match permission_mode {
         // Plan mode is read-only with no interactive prompts
@@ -1062,33 +1579,98 @@ async fn maybe_start_permission_server(
         // All other modes (including bypassPermissions and default/None) need
         // the MCP server so AskUserQuestion can route through it.
         _ => {
-            let node_path = crate::permission_prompt::find_node()?;
+            // Opt in to HTTPS; the native bridge only speaks plain HTTP
+            let tls = matches!(
+                std::env::var("OPCODE_PERMISSION_TLS").as_deref(),
+                Ok("1") | Ok("true")
+            );
+            let native_bridge = if tls { None } else { native_mcp_bridge() };
+            let (runtime, node_path) = match native_bridge {
+                Some(_) => Default::default(),
+                None => {
+                    let (runtime, path) =
+                        crate::permission_prompt::find_runtime(forced_js_runtime()?)?;
+                    if runtime == crate::permission_prompt::RuntimeKind::Node {
+                        crate::permission_prompt::node_status(require_min_node_version())?;
+                    }
+                    (runtime, path)
+                }
+            };
             let registry = app.state::<crate::permission_prompt::PermissionServerRegistry>();
 
             let placeholder = format!("pending-{}", uuid::Uuid::new_v4());
-            let port =
-                crate::permission_prompt::start_server(app.clone(), &placeholder, &registry)
-                    .await?;
-
-            let (config_path, script_path) =
-                crate::permission_prompt::generate_mcp_files(port, &placeholder, &node_path)?;
-
-            // Store paths so cleanup works
-            crate::permission_prompt::set_mcp_paths(
+            // Opt in to a Unix socket instead of a TCP port
+            let transport = match std::env::var("OPCODE_PERMISSION_TRANSPORT").as_deref() {
+                Ok("abstract") => crate::permission_prompt::ServerTransport::AbstractSocket,
+                Ok("unix") => crate::permission_prompt::ServerTransport::UnixSocket,
+                _ => crate::permission_prompt::ServerTransport::Tcp,
+            };
+            let config = crate::permission_prompt::PermissionServerConfig {
+                cwd: Some(std::path::PathBuf::from(project_path)),
+                transport,
+                policy_path: std::env::var_os("OPCODE_PERMISSION_POLICY")
+                    .map(std::path::PathBuf::from),
+                max_body_bytes: std::env::var("OPCODE_PERMISSION_MAX_BODY_BYTES")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+                preferred_port: std::env::var("OPCODE_PERMISSION_PORT")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+                tls,
+                // Let everything through, only showing it (onboarding/debugging)
+                inspect_mode: matches!(
+                    std::env::var("OPCODE_PERMISSION_INSPECT").as_deref(),
+                    Ok("1") | Ok("true")
+                ),
+                mcp_server: mcp_server_identity(),
+                // Requests per 10 seconds; unlimited when unset
+                rate_limit: std::env::var("OPCODE_PERMISSION_RATE_LIMIT")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .map(|max| crate::permission_prompt::RateLimit::per_window(max, None)),
+                ..Default::default()
+            };
+            let port = crate::permission_prompt::start_server(
+                app.clone(),
                 &placeholder,
-                config_path.clone(),
-                script_path.clone(),
+                config,
                 &registry,
             )
-            .await;
+            .await?;
+
+            let files = crate::permission_prompt::mcp_file_options(&placeholder, &registry)
+                .await
+                .and_then(|options| {
+                    let mcp_options = crate::permission_prompt::McpFileOptions {
+                        native_bridge,
+                        runtime,
+                        ..options
+                    };
+                    crate::permission_prompt::generate_mcp_files(
+                        port,
+                        &placeholder,
+                        &node_path,
+                        &mcp_options,
+                    )
+                });
+            let files = match files {
+                Ok(files) => files,
+                Err(e) => {
+                    // Nothing will clean up a server without MCP files
+                    crate::permission_prompt::stop_server(&placeholder, &registry).await;
+                    return Err(e.into());
+                }
+            };
+
+            // Store paths so cleanup works
+            crate::permission_prompt::set_mcp_files(&placeholder, files.clone(), &registry).await;
 
-            let config_str = config_path.to_string_lossy().to_string();
+            let config_str = files.config_path.to_string_lossy().to_string();
             Ok(Some((
                 config_str,
                 PermissionCleanup {
                     placeholder_id: placeholder,
-                    config_path,
-                    script_path,
+                    files,
                 },
             )))
         }
@@ -1112,7 +1694,8 @@ pub async fn execute_claude_code(
 
     let claude_path = find_claude_binary(&app)?;
 
-    let perm_info = maybe_start_permission_server(&app, permission_mode.as_deref()).await?;
+    let perm_info =
+        maybe_start_permission_server(&app, permission_mode.as_deref(), &project_path).await?;
     let mcp_config_str = perm_info.as_ref().map(|(s, _)| s.as_str());
 
     let mut args = vec![
@@ -1148,7 +1731,8 @@ pub async fn continue_claude_code(
 
     let claude_path = find_claude_binary(&app)?;
 
-    let perm_info = maybe_start_permission_server(&app, permission_mode.as_deref()).await?;
+    let perm_info =
+        maybe_start_permission_server(&app, permission_mode.as_deref(), &project_path).await?;
     let mcp_config_str = perm_info.as_ref().map(|(s, _)| s.as_str());
 
     let mut args = vec![
@@ -1187,7 +1771,8 @@ pub async fn resume_claude_code(
 
     let claude_path = find_claude_binary(&app)?;
 
-    let perm_info = maybe_start_permission_server(&app, permission_mode.as_deref()).await?;
+    let perm_info =
+        maybe_start_permission_server(&app, permission_mode.as_deref(), &project_path).await?;
     let mcp_config_str = perm_info.as_ref().map(|(s, _)| s.as_str());
 
     let mut args = vec![
@@ -1457,11 +2042,13 @@ async fn spawn_claude_process(
                             let placeholder = perm_placeholder_clone.lock().unwrap().take();
                             if let Some(placeholder) = placeholder {
                                 let perm_reg = app_handle.state::<crate::permission_prompt::PermissionServerRegistry>();
-                                crate::permission_prompt::rekey_server(
+                                if let Err(e) = crate::permission_prompt::rekey_server(
                                     &placeholder,
                                     claude_session_id,
                                     &perm_reg,
-                                ).await;
+                                ).await {
+                                    log::warn!("Failed to re-key permission server: {}", e);
+                                }
                             }
 
                             // Now register with ProcessRegistry using Claude's session ID
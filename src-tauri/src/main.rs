@@ -19,17 +19,28 @@ use commands::agents::{
     stream_session_output, update_agent, AgentDb,
 };
 use commands::claude::{
-    cancel_claude_execution, check_auto_checkpoint, check_claude_version, cleanup_old_checkpoints,
-    clear_checkpoint_manager, continue_claude_code, create_checkpoint, create_project,
+    add_permission_allowed_tool, add_permission_secret_pattern, allow_permission_prompt,
+    block_permission_tool, cancel_claude_execution, cancel_permission_prompt,
+    check_auto_checkpoint, check_claude_version, cleanup_old_checkpoints, clear_checkpoint_manager,
+    confirm_permission_deny, continue_claude_code, create_checkpoint, create_project,
+    defer_permission_prompt, deny_all_permission_prompts, deny_permission_prompt,
     execute_claude_code, find_claude_md_files, fork_from_checkpoint, get_checkpoint_diff,
     get_checkpoint_settings, get_checkpoint_state_stats, get_claude_session_output,
-    get_claude_settings, get_home_directory, get_hooks_config, get_project_sessions,
-    get_recently_modified_files, get_session_timeline, get_system_prompt, list_checkpoints,
-    list_directory_contents, list_projects, list_running_claude_sessions, load_session_history,
-    open_new_session, read_claude_md_file, respond_permission_prompt, restore_checkpoint,
-    resume_claude_code, save_claude_md_file, save_claude_settings, save_system_prompt,
-    search_files, track_checkpoint_message, track_session_messages, update_checkpoint_settings,
-    update_hooks_config, validate_hook_command, ClaudeProcessState,
+    get_claude_settings, get_full_permission_input, get_home_directory, get_hooks_config,
+    get_permission_activity, get_permission_controller_token, get_permission_metrics,
+    get_permission_node_status, get_permission_prompt_event, get_permission_servers_snapshot,
+    get_project_sessions, get_recently_modified_files, get_session_timeline, get_system_prompt,
+    inject_test_permission_prompt, list_checkpoints, list_directory_contents,
+    list_pending_permission_prompts, list_projects, list_running_claude_sessions,
+    load_session_history, open_new_session, read_claude_md_file, reload_permission_policy,
+    remove_permission_allowed_tool, replay_permission_prompts, respond_permission_batch,
+    respond_permission_prompt, respond_user_input, restore_checkpoint, resume_claude_code,
+    save_claude_md_file, save_claude_settings, save_system_prompt, search_files,
+    set_permission_audit_log_path, set_permission_inspect_mode, set_permission_log_echo,
+    set_permission_os_notifications, set_permission_policy, set_permission_prompt_timeout,
+    set_permission_rate_limit, set_permission_tool_timeout, stop_permission_server_graceful,
+    track_checkpoint_message, track_session_messages, unblock_permission_tool,
+    update_checkpoint_settings, update_hooks_config, validate_hook_command, ClaudeProcessState,
 };
 use commands::mcp::{
     mcp_add, mcp_add_from_claude_desktop, mcp_add_json, mcp_get, mcp_get_server_status, mcp_list,
@@ -54,12 +65,18 @@ use window_vibrancy::{apply_vibrancy, NSVisualEffectMaterial};
 
 
 fn main() {
-    // Initialize logger
-    env_logger::init();
+    // Claude Code runs this binary as the permission MCP server
+    if std::env::args().nth(1).as_deref() == Some(permission_prompt::bridge::BRIDGE_FLAG) {
+        std::process::exit(permission_prompt::bridge::run());
+    }
+
+    // Initialize logger (wrapped so permission logs can be echoed to the UI)
+    permission_prompt::log_echo::init_logger();
 
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_notification::init())
         .setup(|app| {
             // Initialize agents database
             let conn = init_database(&app.handle()).expect("Failed to initialize agents database");
@@ -150,7 +167,26 @@ fn main() {
             app.manage(ClaudeProcessState::default());
 
             // Initialize permission prompt server registry
-            app.manage(permission_prompt::PermissionServerRegistry::default());
+            let permission_emitter = std::sync::Arc::new(app.handle().clone());
+            let permission_registry =
+                permission_prompt::PermissionServerRegistry::new(permission_emitter.clone());
+            permission_prompt::spawn_idle_reaper(
+                permission_registry.clone(),
+                permission_prompt::DEFAULT_IDLE_TIMEOUT,
+            );
+            // Reclaim MCP temp files left behind by a crashed run
+            let orphan_registry = permission_registry.clone();
+            tauri::async_runtime::spawn(async move {
+                permission_prompt::cleanup_orphaned_temp_files(&orphan_registry).await;
+            });
+            if let Ok(app_data_dir) = app.path().app_data_dir() {
+                permission_prompt::set_audit_log_path(
+                    &permission_registry,
+                    Some(app_data_dir.join(permission_prompt::audit::AUDIT_LOG_FILE_NAME)),
+                );
+            }
+            app.manage(permission_registry);
+            permission_prompt::log_echo::attach_emitter(permission_emitter);
 
             // Apply window vibrancy with rounded corners on macOS
             #[cfg(target_os = "macos")]
@@ -209,6 +245,39 @@ fn main() {
             resume_claude_code,
             cancel_claude_execution,
             respond_permission_prompt,
+            allow_permission_prompt,
+            deny_permission_prompt,
+            respond_permission_batch,
+            confirm_permission_deny,
+            cancel_permission_prompt,
+            defer_permission_prompt,
+            deny_all_permission_prompts,
+            get_permission_prompt_event,
+            get_full_permission_input,
+            list_pending_permission_prompts,
+            replay_permission_prompts,
+            get_permission_servers_snapshot,
+            get_permission_activity,
+            get_permission_metrics,
+            stop_permission_server_graceful,
+            respond_user_input,
+            get_permission_node_status,
+            get_permission_controller_token,
+            inject_test_permission_prompt,
+            set_permission_log_echo,
+            set_permission_audit_log_path,
+            add_permission_secret_pattern,
+            set_permission_os_notifications,
+            set_permission_prompt_timeout,
+            set_permission_tool_timeout,
+            set_permission_rate_limit,
+            set_permission_inspect_mode,
+            set_permission_policy,
+            reload_permission_policy,
+            add_permission_allowed_tool,
+            remove_permission_allowed_tool,
+            block_permission_tool,
+            unblock_permission_tool,
             list_running_claude_sessions,
             get_claude_session_output,
             list_directory_contents,
@@ -296,6 +365,18 @@ fn main() {
             get_proxy_settings,
             save_proxy_settings,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            // Don't leave permission servers' ports and temp files behind
+            if let tauri::RunEvent::Exit = event {
+                let registry = app.try_state::<permission_prompt::PermissionServerRegistry>();
+                if let Some(registry) = registry {
+                    tauri::async_runtime::block_on(permission_prompt::stop_all_servers(
+                        &registry,
+                        permission_prompt::SHUTDOWN_TIMEOUT,
+                    ));
+                }
+            }
+        });
 }
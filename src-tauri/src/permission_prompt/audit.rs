@@ -0,0 +1,148 @@
+//! The permission audit log: every decision appended to a JSONL file.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Mutex, RwLock};
+
+use super::decisions::DecisionRecord;
+
+/// File name of the audit log inside the app data dir.
+pub const AUDIT_LOG_FILE_NAME: &str = "permission-audit.jsonl";
+
+/// Append-only JSONL record of every permission decision, one
+/// `DecisionRecord` per line. Lines are written by a background thread so a
+/// slow or failing disk never holds up a response; failures are only logged.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    /// Where entries go; `None` disables the log.
+    path: RwLock<Option<PathBuf>>,
+    /// Queue to the writer thread, started on the first entry.
+    writer: Mutex<Option<Sender<(PathBuf, String)>>>,
+}
+
+impl AuditLog {
+    pub fn path(&self) -> Option<PathBuf> {
+        self.path.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_path(&self, path: Option<PathBuf>) {
+        *self.path.write().unwrap_or_else(|e| e.into_inner()) = path;
+    }
+
+    /// Queue a decision for the log. No-op while the log is disabled.
+    pub fn append(&self, record: &DecisionRecord) {
+        let Some(path) = self.path() else {
+            return;
+        };
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("Failed to serialize audit entry {}: {}", record.seq, e);
+                return;
+            }
+        };
+
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        if writer.is_none() {
+            *writer = spawn_writer();
+        }
+        let sent = writer
+            .as_ref()
+            .is_some_and(|tx| tx.send((path, line)).is_ok());
+        if !sent {
+            // Writer thread is gone; try a fresh one next time
+            *writer = None;
+            log::warn!(
+                "Permission audit writer unavailable; dropped entry {}",
+                record.seq
+            );
+        }
+    }
+}
+
+fn spawn_writer() -> Option<Sender<(PathBuf, String)>> {
+    let (tx, rx) = mpsc::channel::<(PathBuf, String)>();
+    let spawned = std::thread::Builder::new()
+        .name("permission-audit".to_string())
+        .spawn(move || {
+            for (path, line) in rx {
+                if let Err(e) = append_line(&path, &line) {
+                    log::warn!("Failed to write audit log {}: {}", path.display(), e);
+                }
+            }
+        });
+    match spawned {
+        Ok(_) => Some(tx),
+        Err(e) => {
+            log::warn!("Failed to start permission audit writer: {}", e);
+            None
+        }
+    }
+}
+
+fn append_line(path: &Path, line: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decisions::{last_sequence_in_log, DecisionLog, DecisionSource};
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn wait_for_lines(path: &Path, count: usize) -> Vec<String> {
+        let start = Instant::now();
+        loop {
+            let lines: Vec<String> = std::fs::read_to_string(path)
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_string)
+                .collect();
+            if lines.len() >= count || start.elapsed() > Duration::from_secs(2) {
+                return lines;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_decisions_appended_in_order() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("nested").join(AUDIT_LOG_FILE_NAME);
+        let audit = AuditLog::default();
+        let decisions = DecisionLog::default();
+        let input = serde_json::json!({ "command": "ls" });
+
+        // Disabled until a path is set
+        audit.append(&decisions.record("s", "p0", "Bash", &input, "allow", DecisionSource::User));
+        audit.set_path(Some(path.clone()));
+        for (i, source) in [DecisionSource::User, DecisionSource::Timeout]
+            .into_iter()
+            .enumerate()
+        {
+            let mut record =
+                decisions.record("s", &format!("p{}", i + 1), "Bash", &input, "deny", source);
+            record.message = Some("no".to_string());
+            audit.append(&record);
+        }
+
+        let lines = wait_for_lines(&path, 2);
+        assert_eq!(lines.len(), 2);
+        let records: Vec<DecisionRecord> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records[0].prompt_id, "p1");
+        assert_eq!(records[1].source, DecisionSource::Timeout);
+        assert_eq!(records[1].message.as_deref(), Some("no"));
+        assert_eq!(last_sequence_in_log(&path), 3);
+    }
+}
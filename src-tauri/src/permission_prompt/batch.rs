@@ -0,0 +1,655 @@
+//! Batched permission requests: several tool calls asking for permission in
+//! one MCP call, shown together as a single prompt and answered in order.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use super::decisions::{DecisionRecord, DecisionSource};
+use super::tool_lists::{allowed_decision, blocked_decision};
+use super::{
+    abandon_unreachable, auto_decision, check_response, count_tool_prompt, deny_with,
+    emit_session_event, expire_pending, log_prompt_step, longest_timeout, policy_decision,
+    queue_while_paused, request_cwd, restore_real_input, take_pending, timeout_response,
+    tool_timeout, wait_for_response, HttpState, PendingPrompt, PendingReply, PermissionError,
+    PermissionRequest, PermissionResponse, PermissionServerRegistry, PromptContext,
+    DECISION_LIMIT_MESSAGE, RATE_LIMITED_MESSAGE, SERVER_CLOSING_MESSAGE,
+};
+
+/// Denial for a batched invocation that somehow got no answer.
+const UNDECIDED_MESSAGE: &str = "Permission request was not decided";
+
+/// One tool call inside a batched permission request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolInvocation {
+    pub tool_use_id: String,
+    pub tool_name: String,
+    pub input: serde_json::Value,
+}
+
+/// Several tool calls asking for permission in a single MCP call. Answered
+/// with one `PermissionResponse` per invocation, in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermissionBatchRequest {
+    pub batch: Vec<ToolInvocation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<PromptContext>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub agent_path: Vec<String>,
+    /// Directory the tools run in, as the MCP script reports it
+    /// (`OPCODE_CWD`). The session's cwd is used when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+}
+
+impl PermissionBatchRequest {
+    /// The single-request view of one invocation, for rule evaluation.
+    fn request_for(&self, invocation: &ToolInvocation) -> PermissionRequest {
+        PermissionRequest {
+            tool_use_id: invocation.tool_use_id.clone(),
+            tool_name: invocation.tool_name.clone(),
+            input: invocation.input.clone(),
+            context: self.context.clone(),
+            agent_path: self.agent_path.clone(),
+            cwd: self.cwd.clone(),
+        }
+    }
+}
+
+/// Payload emitted as `permission-prompt-batch`. Lists only the invocations
+/// that need an answer; the frontend resolves them in this order with
+/// `resolve_batch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermissionBatchEvent {
+    pub prompt_id: String,
+    pub session_id: String,
+    pub invocations: Vec<ToolInvocation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<PromptContext>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub agent_path: Vec<String>,
+    /// Directory the tools would run in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// How long the prompt waits before it times out, in milliseconds.
+    /// Unset when it waits forever.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Handle a batched request. Invocations answered by the block list, the
+/// allowlist or rules keep that answer; the rest are shown together as one
+/// `permission-prompt-batch` event and resolved in order by `resolve_batch`.
+///
+/// Batches skip part of the single-request pipeline: remembered answers
+/// aren't replayed, the event transform doesn't run, large inputs aren't
+/// truncated or previewed, and no `permission-prompt-notify` is queued.
+pub(super) async fn handle_permission_batch(
+    state: HttpState,
+    req: PermissionBatchRequest,
+) -> Vec<PermissionResponse> {
+    state.note_request().await;
+
+    // Each invocation costs what a single request does, so batching can't
+    // get around the session's rate limit. Those turned away aren't
+    // decisions, same as on the single path
+    let now = Instant::now();
+    let admitted: Vec<bool> = req
+        .batch
+        .iter()
+        .map(|inv| {
+            let admitted = state.rate_limiter.try_acquire(now);
+            if !admitted {
+                state.metrics.count_rate_limited();
+                log::debug!("Rate limited a batched '{}' request", inv.tool_name);
+            }
+            admitted
+        })
+        .collect();
+
+    let prompt_id = Uuid::new_v4().to_string();
+    let session_id = state.session_id.lock().await.clone();
+    let limited = state.decision_limit_reached(&session_id);
+    let closing = state.closing.load(Ordering::Relaxed);
+    let inspecting = state.inspect_mode.load(Ordering::Relaxed);
+    let mut decided: Vec<Option<(PermissionResponse, DecisionSource)>> = req
+        .batch
+        .iter()
+        .zip(&admitted)
+        .map(|(inv, admitted)| {
+            if !admitted {
+                return None;
+            }
+            if let Some(blocked) = blocked_decision(&state, &inv.tool_name) {
+                return Some(blocked);
+            }
+            if closing {
+                return Some((deny_with(SERVER_CLOSING_MESSAGE), DecisionSource::Cancelled));
+            }
+            if inspecting {
+                let resp = PermissionResponse {
+                    behavior: "allow".to_string(),
+                    updated_input: Some(inv.input.clone()),
+                    message: None,
+                };
+                return Some((resp, DecisionSource::Inspect));
+            }
+            if limited {
+                return Some((deny_with(DECISION_LIMIT_MESSAGE), DecisionSource::Limit));
+            }
+            let single = req.request_for(inv);
+            if let Some(allowed) = allowed_decision(&state, &single) {
+                return Some(allowed);
+            }
+            let cwd = request_cwd(&single, &state.cwd);
+            policy_decision(&state.registry, &state.policy, &session_id, &single, &cwd)
+                .map(|resp| (resp, DecisionSource::Rule))
+                .or_else(|| {
+                    auto_decision(
+                        &state.registry,
+                        state.emitter.as_ref(),
+                        &session_id,
+                        &single,
+                        &cwd,
+                    )
+                })
+        })
+        .collect();
+    let mut undecided: Vec<usize> = (0..decided.len())
+        .filter(|i| admitted[*i] && decided[*i].is_none())
+        .collect();
+
+    // The batch shows as one prompt, so it holds one slot under
+    // `max_concurrent` until it's answered
+    let slot = match undecided.is_empty() {
+        true => Ok(None),
+        false => state.prompt_slot().await,
+    };
+    let _slot = match slot {
+        Ok(slot) => slot,
+        Err(resp) => {
+            for i in undecided.drain(..) {
+                decided[i] = Some((resp.clone(), DecisionSource::Overflow));
+            }
+            None
+        }
+    };
+
+    if !undecided.is_empty() {
+        for i in &undecided {
+            count_tool_prompt(&state.tool_prompts, &req.batch[*i].tool_name);
+        }
+        let (tx, rx) = oneshot::channel::<Vec<PermissionResponse>>();
+        let reply = PendingReply::Batch {
+            tx,
+            len: undecided.len(),
+        };
+        let tool_names: Vec<&str> = undecided
+            .iter()
+            .map(|i| req.batch[*i].tool_name.as_str())
+            .collect();
+        // The whole batch waits as long as its most patient tool
+        let timeout = longest_timeout(
+            tool_names
+                .iter()
+                .map(|tool| tool_timeout(&state.prompt_timeout, &state.tool_timeouts, tool)),
+        );
+        let prompt = PendingPrompt::new(reply, &tool_names.join(", "), timeout);
+        let deadline = prompt.deadline.clone();
+        let session_id = state.session_id.lock().await.clone();
+        state
+            .registry
+            .update_pending(&state.pending, state.emitter.as_ref(), &session_id, |p| {
+                p.insert(prompt_id.clone(), prompt)
+            })
+            .await;
+        log_prompt_step(
+            log::Level::Info,
+            &session_id,
+            &prompt_id,
+            "created",
+            format_args!("batch tools={}", tool_names.join(",")),
+        );
+
+        let mut paused_rx = state.registry.paused.subscribe();
+        queue_while_paused(&session_id, &prompt_id, &mut paused_rx, &deadline).await;
+
+        let session_id = state.session_id.lock().await.clone();
+        let redacted: Vec<Option<serde_json::Value>> = undecided
+            .iter()
+            .map(|i| state.registry.redact_input(&req.batch[*i].input))
+            .collect();
+        let invocations = undecided
+            .iter()
+            .zip(&redacted)
+            .map(|(i, redacted)| ToolInvocation {
+                input: redacted
+                    .clone()
+                    .unwrap_or_else(|| req.batch[*i].input.clone()),
+                ..req.batch[*i].clone()
+            })
+            .collect();
+        let event = PermissionBatchEvent {
+            prompt_id: prompt_id.clone(),
+            session_id: session_id.clone(),
+            invocations,
+            context: req.context.clone(),
+            agent_path: req.agent_path.clone(),
+            cwd: Some(
+                request_cwd(&req.request_for(&req.batch[undecided[0]]), &state.cwd)
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            timeout_ms: timeout.map(|t| t.as_millis() as u64),
+        };
+        let emitted = emit_session_event(
+            state.emitter.as_ref(),
+            "permission-prompt-batch",
+            &session_id,
+            &event,
+            state.registry.emit_generic(),
+        );
+
+        let unreachable = if emitted {
+            None
+        } else {
+            abandon_unreachable(&state, &prompt_id)
+                .await
+                .map(|(resp, source)| (vec![resp; undecided.len()], source))
+        };
+        let answer = match unreachable {
+            Some(answer) => Some(answer),
+            None => wait_for_response(rx, deadline, paused_rx)
+                .await
+                .map(|responses| (responses, DecisionSource::User)),
+        };
+        match answer {
+            Some((responses, source)) => {
+                for ((i, mut resp), redacted) in undecided.iter().zip(responses).zip(&redacted) {
+                    restore_real_input(&mut resp, &[(redacted.as_ref(), &req.batch[*i].input)]);
+                    decided[*i] = Some((resp, source));
+                }
+            }
+            None => {
+                let (source, message) = expire_pending(&state, &prompt_id).await;
+                for i in &undecided {
+                    let input = &req.batch[*i].input;
+                    let resp = match source {
+                        DecisionSource::Timeout => {
+                            timeout_response(state.timeout_behavior, message, input, input)
+                        }
+                        _ => deny_with(message),
+                    };
+                    decided[*i] = Some((resp, source));
+                }
+            }
+        }
+    }
+
+    let session_id = state.session_id.lock().await.clone();
+    req.batch
+        .iter()
+        .zip(decided)
+        .zip(admitted)
+        .map(|((inv, decision), admitted)| {
+            let (resp, source) = match (admitted, decision) {
+                (false, _) => return deny_with(RATE_LIMITED_MESSAGE),
+                (true, Some(decided)) => decided,
+                // Every admitted invocation is answered above, but a gap
+                // must not turn into an allow
+                (true, None) => {
+                    log::error!(
+                        "Batched '{}' request in prompt {} was left undecided",
+                        inv.tool_name,
+                        prompt_id
+                    );
+                    return deny_with(UNDECIDED_MESSAGE);
+                }
+            };
+            state.record_decision(DecisionRecord {
+                message: resp.message.clone(),
+                ..DecisionRecord::new(
+                    &session_id,
+                    &prompt_id,
+                    &inv.tool_name,
+                    &inv.input,
+                    &resp.behavior,
+                    source,
+                )
+            });
+            resp
+        })
+        .collect()
+}
+
+/// Resolve a pending batch prompt with one response per invocation listed in
+/// its `permission-prompt-batch` event, in the same order.
+pub async fn resolve_batch(
+    session_id: &str,
+    prompt_id: &str,
+    mut responses: Vec<PermissionResponse>,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    for response in &mut responses {
+        check_response(response)?;
+    }
+    let count = responses.len();
+    match take_pending(
+        session_id,
+        prompt_id,
+        registry,
+        |reply| matches!(reply, PendingReply::Batch { len, .. } if *len == count),
+    )
+    .await?
+    .reply
+    {
+        PendingReply::Batch { tx, .. } => tx
+            .send(responses)
+            .map_err(|_| PermissionError::ReceiverDropped),
+        PendingReply::Single(_) => unreachable!("checked by take_pending"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::super::{
+        add_allowed_tool, get_metrics, handle_permission_prompt, handle_permission_route,
+        recent_decisions, resolve_prompt, set_scope_rules, OverflowBehavior, PermissionPayload,
+        PermissionRule, PermissionServerConfig, RateLimit, RuleAction, RuleScope,
+        TOO_MANY_PENDING_MESSAGE,
+    };
+    use super::*;
+    use axum::{extract::State as AxumState, Json};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_batch_request_round_trips_mixed_decisions() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+
+        // Without a `batch` field the payload is still a single request
+        let single: PermissionPayload = serde_json::from_value(serde_json::json!({
+            "tool_use_id": "toolu_1", "tool_name": "Read", "input": { "file_path": "a" }
+        }))
+        .unwrap();
+        assert!(matches!(single, PermissionPayload::Single(_)));
+
+        let payload: PermissionPayload = serde_json::from_value(serde_json::json!({
+            "batch": [
+                { "tool_use_id": "toolu_1", "tool_name": "Read", "input": { "file_path": "a" } },
+                { "tool_use_id": "toolu_2", "tool_name": "Bash", "input": { "command": "rm -rf /" } }
+            ]
+        }))
+        .unwrap();
+        let state = test_http_state(&registry, "session-1").await;
+        let token = state.auth_token.clone();
+        let handler = tokio::spawn(handle_permission_route(
+            AxumState(state),
+            bearer_headers(&token),
+            Json(payload),
+        ));
+
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || {
+            emitter_for_wait
+                .names()
+                .contains(&"permission-prompt-batch:session-1".to_string())
+        })
+        .await;
+        let event = emitter
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| name == "permission-prompt-batch:session-1")
+            .map(|(_, payload)| payload.clone())
+            .unwrap();
+        assert_eq!(event["invocations"].as_array().unwrap().len(), 2);
+        let prompt_id = event["prompt_id"].as_str().unwrap().to_string();
+
+        // Wrong shapes are rejected and leave the batch pending
+        assert!(resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .is_err());
+        assert!(
+            resolve_batch("session-1", &prompt_id, vec![allow()], &registry)
+                .await
+                .is_err()
+        );
+
+        resolve_batch(
+            "session-1",
+            &prompt_id,
+            vec![allow(), deny_with("Not that one")],
+            &registry,
+        )
+        .await
+        .unwrap();
+
+        let reply = serde_json::to_value(handler.await.unwrap().unwrap().0).unwrap();
+        assert_eq!(reply[0]["behavior"], "allow");
+        assert_eq!(reply[1]["behavior"], "deny");
+        assert_eq!(reply[1]["message"], "Not that one");
+
+        let tools: Vec<String> = recent_decisions(Some("session-1"), &registry)
+            .into_iter()
+            .map(|d| d.tool_name)
+            .collect();
+        assert_eq!(tools, vec!["Read", "Bash"]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_invocations_count_against_the_rate_limit() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                rate_limit: Some(RateLimit::per_window(2, Some(Duration::from_secs(3600)))),
+                ..Default::default()
+            },
+        )
+        .await;
+        add_allowed_tool("session-1", "Read", &registry)
+            .await
+            .unwrap();
+
+        let invocation = |id: &str| ToolInvocation {
+            tool_use_id: id.to_string(),
+            tool_name: "Read".to_string(),
+            input: serde_json::json!({ "file_path": "a" }),
+        };
+        let state = test_http_state(&registry, "session-1").await;
+        let replies = handle_permission_batch(
+            state,
+            PermissionBatchRequest {
+                batch: vec![
+                    invocation("toolu_1"),
+                    invocation("toolu_2"),
+                    invocation("toolu_3"),
+                ],
+                context: None,
+                agent_path: Vec::new(),
+                cwd: None,
+            },
+        )
+        .await;
+
+        let behaviors: Vec<(&str, Option<&str>)> = replies
+            .iter()
+            .map(|r| (r.behavior.as_str(), r.message.as_deref()))
+            .collect();
+        assert_eq!(
+            behaviors,
+            vec![
+                ("allow", None),
+                ("allow", None),
+                ("deny", Some(RATE_LIMITED_MESSAGE)),
+            ]
+        );
+        // Only the admitted invocations are decisions
+        let metrics = get_metrics("session-1", &registry).await.unwrap();
+        assert_eq!(metrics.auto_allowed, 2);
+        assert_eq!(metrics.rate_limited, 1);
+        assert!(emitter.prompts().is_empty());
+        assert_eq!(registry.decisions.recent(Some("session-1")).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_batch_prompt_takes_a_concurrency_slot() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                max_concurrent: Some(1),
+                overflow_behavior: OverflowBehavior::Deny,
+                ..Default::default()
+            },
+        )
+        .await;
+        let state = test_http_state(&registry, "session-1").await;
+        let batch = tokio::spawn(handle_permission_batch(
+            state.clone(),
+            PermissionBatchRequest {
+                batch: vec![ToolInvocation {
+                    tool_use_id: "toolu_1".to_string(),
+                    tool_name: "Bash".to_string(),
+                    input: serde_json::json!({ "command": "ls" }),
+                }],
+                context: None,
+                agent_path: Vec::new(),
+                cwd: None,
+            },
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || {
+            emitter_for_wait
+                .names()
+                .contains(&"permission-prompt-batch:session-1".to_string())
+        })
+        .await;
+
+        // The batch holds the only slot, so a single prompt can't show
+        let resp = handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(test_request(
+                "Bash",
+                serde_json::json!({ "command": "pwd" }),
+            )),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.message.as_deref(), Some(TOO_MANY_PENDING_MESSAGE));
+
+        // ...and neither can a second batch
+        let replies = handle_permission_batch(
+            state,
+            PermissionBatchRequest {
+                batch: vec![ToolInvocation {
+                    tool_use_id: "toolu_2".to_string(),
+                    tool_name: "Bash".to_string(),
+                    input: serde_json::json!({ "command": "pwd" }),
+                }],
+                context: None,
+                agent_path: Vec::new(),
+                cwd: None,
+            },
+        )
+        .await;
+        assert_eq!(
+            replies[0].message.as_deref(),
+            Some(TOO_MANY_PENDING_MESSAGE)
+        );
+        assert_eq!(
+            recent_decisions(Some("session-1"), &registry)[0].source,
+            DecisionSource::Overflow
+        );
+
+        let prompt_id = emitter
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| name == "permission-prompt-batch:session-1")
+            .map(|(_, payload)| payload["prompt_id"].as_str().unwrap().to_string())
+            .unwrap();
+        resolve_batch("session-1", &prompt_id, vec![allow()], &registry)
+            .await
+            .unwrap();
+        assert_eq!(batch.await.unwrap()[0].behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_batch_cwd_reaches_event_and_rules() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_scope_rules(
+            &registry,
+            RuleScope::Project,
+            vec![PermissionRule {
+                tool: "Read".to_string(),
+                field: Some("file_path".to_string()),
+                pattern: Some("/elsewhere/*".to_string()),
+                agent_path_prefix: None,
+                condition: None,
+                action: RuleAction::Allow,
+                message: None,
+                origin: None,
+            }],
+        );
+
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_batch(
+            state,
+            PermissionBatchRequest {
+                batch: vec![
+                    ToolInvocation {
+                        tool_use_id: "toolu_1".to_string(),
+                        tool_name: "Read".to_string(),
+                        input: serde_json::json!({ "file_path": "a.txt" }),
+                    },
+                    ToolInvocation {
+                        tool_use_id: "toolu_2".to_string(),
+                        tool_name: "Bash".to_string(),
+                        input: serde_json::json!({ "command": "ls" }),
+                    },
+                ],
+                context: None,
+                agent_path: Vec::new(),
+                cwd: Some("/elsewhere".to_string()),
+            },
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || {
+            emitter_for_wait
+                .names()
+                .contains(&"permission-prompt-batch:session-1".to_string())
+        })
+        .await;
+        let event = emitter
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| name == "permission-prompt-batch:session-1")
+            .map(|(_, payload)| payload.clone())
+            .unwrap();
+        // The Read resolved against the batch's cwd, so the rule answered it
+        assert_eq!(event["cwd"], "/elsewhere");
+        assert_eq!(event["invocations"].as_array().unwrap().len(), 1);
+        assert_eq!(event["invocations"][0]["tool_name"], "Bash");
+
+        let prompt_id = event["prompt_id"].as_str().unwrap().to_string();
+        resolve_batch("session-1", &prompt_id, vec![allow()], &registry)
+            .await
+            .unwrap();
+        let replies = handler.await.unwrap();
+        assert_eq!(replies[0].behavior, "allow");
+        assert_eq!(replies[1].behavior, "allow");
+    }
+}
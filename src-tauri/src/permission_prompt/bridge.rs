@@ -0,0 +1,691 @@
+//! Rust port of the Node.js MCP script, so permission prompts work on
+//! machines without Node. Claude Code spawns the opcode binary itself with
+//! `BRIDGE_FLAG`; it speaks newline-delimited JSON-RPC on stdin/stdout and
+//! forwards the calls of each tool in `tools::mcp_tools` to the session's
+//! permission server, configured through the same env vars the script reads.
+
+use super::tools::{McpTool, ServerIdentity};
+use serde_json::{json, Value};
+use std::io::{BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Hidden command-line flag that runs the binary as the MCP bridge.
+pub const BRIDGE_FLAG: &str = "--mcp-permission-bridge";
+
+/// Pauses between connection attempts. Claude Code can start the bridge
+/// before the server is listening, so a refused connection is retried (3
+/// attempts over ~500ms) before the request is denied.
+const CONNECT_RETRY_DELAYS: [Duration; 2] =
+    [Duration::from_millis(100), Duration::from_millis(400)];
+
+/// Where the permission server listens, from the MCP config env.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ServerAddress {
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    /// Linux abstract socket name, without the leading NUL.
+    AbstractSocket(String),
+    /// Path of a Unix socket file.
+    UnixSocket(std::path::PathBuf),
+}
+
+#[derive(Debug)]
+struct BridgeConfig {
+    address: ServerAddress,
+    auth_token: Option<String>,
+    /// How long to wait for the server's answer before denying; `None`
+    /// waits forever.
+    timeout: Option<Duration>,
+    identity: ServerIdentity,
+}
+
+impl BridgeConfig {
+    fn from_env(env: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let non_empty = |key: &str| env(key).filter(|v| !v.is_empty());
+        let address = match (
+            non_empty("PERMISSION_SERVER_SOCKET_PATH"),
+            non_empty("PERMISSION_SERVER_ABSTRACT_SOCKET"),
+            non_empty("PERMISSION_SERVER_PORT"),
+        ) {
+            (Some(path), _, _) => ServerAddress::UnixSocket(path.into()),
+            (None, Some(name), _) => ServerAddress::AbstractSocket(name),
+            (None, None, Some(port)) => ServerAddress::Tcp {
+                host: non_empty("PERMISSION_SERVER_HOST")
+                    .unwrap_or_else(|| "127.0.0.1".to_string()),
+                port: port
+                    .parse()
+                    .map_err(|_| format!("Invalid PERMISSION_SERVER_PORT '{}'", port))?,
+            },
+            (None, None, None) => return Err("PERMISSION_SERVER_PORT not set".to_string()),
+        };
+        if non_empty("PERMISSION_SERVER_CERT_SHA256").is_some() {
+            return Err("HTTPS permission servers need the Node.js MCP script".to_string());
+        }
+        Ok(Self {
+            address,
+            auth_token: non_empty("PERMISSION_AUTH_TOKEN"),
+            timeout: non_empty("PERMISSION_CLIENT_TIMEOUT_MS")
+                .and_then(|ms| ms.trim().parse().ok())
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            identity: ServerIdentity::from_env(&env),
+        })
+    }
+}
+
+/// Run the bridge until stdin closes. Returns the process exit code.
+pub fn run() -> i32 {
+    let config = match BridgeConfig::from_env(|key| std::env::var(key).ok()) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let stdout = Arc::new(Mutex::new(std::io::stdout()));
+
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg: Value = match serde_json::from_str(&line) {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("Failed to parse JSON-RPC message: {}", e);
+                continue;
+            }
+        };
+
+        // A tool call waits on the user, so it must not hold up other messages
+        let is_tool_call = msg.get("method").and_then(Value::as_str) == Some("tools/call");
+        let config = config.clone();
+        let stdout = stdout.clone();
+        let respond = move || {
+            let reply = handle_message(&config.identity, &msg, |path, request| {
+                post_to_server(&config, path, request)
+            });
+            if let Some(reply) = reply {
+                write_line(&stdout, &reply);
+            }
+        };
+        if is_tool_call {
+            std::thread::spawn(respond);
+        } else {
+            respond();
+        }
+    }
+    0
+}
+
+fn write_line(stdout: &Mutex<std::io::Stdout>, reply: &Value) {
+    let mut out = stdout.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = writeln!(out, "{}", reply).and_then(|_| out.flush());
+}
+
+fn response(id: &Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error_response(id: &Value, code: i64, message: String) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+/// The reply to one JSON-RPC message, or `None` for notifications.
+/// `post` sends a tool's request to its route on the server.
+fn handle_message(
+    identity: &ServerIdentity,
+    msg: &Value,
+    post: impl FnOnce(&str, &Value) -> Result<Value, String>,
+) -> Option<Value> {
+    let id = msg.get("id").cloned();
+    let method = msg.get("method").and_then(Value::as_str).unwrap_or("");
+    let params = msg.get("params");
+
+    match method {
+        "initialize" => Some(response(
+            id.as_ref()?,
+            json!({
+                "protocolVersion": "2025-11-25",
+                "capabilities": { "tools": {} },
+                "serverInfo": identity.server_info(),
+            }),
+        )),
+        "notifications/initialized" => None,
+        "tools/list" => {
+            let tools: Vec<Value> = identity.tools().iter().map(McpTool::definition).collect();
+            Some(response(id.as_ref()?, json!({ "tools": tools })))
+        }
+        "tools/call" => {
+            let id = id?;
+            let tool_name = params.and_then(|p| p.get("name")).and_then(Value::as_str);
+            let Some(tool) = tool_name.and_then(|name| identity.find_tool(name)) else {
+                return Some(error_response(
+                    &id,
+                    -32601,
+                    format!("Unknown tool: {}", tool_name.unwrap_or("undefined")),
+                ));
+            };
+            let empty = json!({});
+            let args = params
+                .and_then(|p| p.get("arguments"))
+                .filter(|a| a.is_object())
+                .unwrap_or(&empty);
+            let request = build_request(&tool, args, |key| std::env::var(key).ok());
+            let result = post(tool.path, &request).unwrap_or_else(|e| {
+                eprintln!("{} request failed: {}", tool.name, e);
+                // On error, answer with the tool's fallback (a deny for permissions)
+                tool.fallback
+            });
+            Some(response(
+                &id,
+                json!({ "content": [{ "type": "text", "text": result.to_string() }] }),
+            ))
+        }
+        _ => id.map(|id| error_response(&id, -32601, format!("Method not found: {}", method))),
+    }
+}
+
+/// JavaScript truthiness, to match the script's `a || b` fallbacks.
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(_) | Value::Object(_) => true,
+    }
+}
+
+/// The body posted for a call to `tool`: each of its fields from the
+/// arguments (or its default), with the same context, agent-path and
+/// working-directory fallbacks as the script.
+fn build_request(tool: &McpTool, args: &Value, env: impl Fn(&str) -> Option<String>) -> Value {
+    let mut request = json!({});
+    if let Some(defaults) = tool.defaults.as_object() {
+        for (key, default) in defaults {
+            let value = args.get(key).filter(|v| truthy(v)).unwrap_or(default);
+            request[key] = value.clone();
+        }
+    }
+    request["agent_path"] = json!(build_agent_path(args, &env));
+    if let Some(cwd) = env("OPCODE_CWD").filter(|cwd| !cwd.is_empty()) {
+        request["cwd"] = json!(cwd);
+    }
+    if let Some(context) = build_context(args, &env) {
+        request["context"] = context;
+    }
+    request
+}
+
+fn build_context(args: &Value, env: &impl Fn(&str) -> Option<String>) -> Option<Value> {
+    let src = args.get("context").filter(|c| truthy(c));
+    let field = |key: &str| {
+        src.and_then(|c| c.get(key))
+            .or_else(|| args.get(key))
+            .cloned()
+    };
+
+    let message_id = field("message_id")
+        .filter(truthy)
+        .map(|v| match v {
+            Value::String(s) => s,
+            other => other.to_string(),
+        })
+        .or_else(|| env("OPCODE_MESSAGE_ID").filter(|v| !v.is_empty()));
+    let turn = match field("turn").filter(|v| !v.is_null()) {
+        Some(Value::String(s)) => s.trim().parse::<u64>().ok(),
+        Some(v) => v.as_u64(),
+        None => env("OPCODE_TURN").and_then(|v| v.trim().parse::<u64>().ok()),
+    };
+
+    let mut context = serde_json::Map::new();
+    if let Some(message_id) = message_id {
+        context.insert("message_id".to_string(), json!(message_id));
+    }
+    if let Some(turn) = turn {
+        context.insert("turn".to_string(), json!(turn));
+    }
+    (!context.is_empty()).then_some(Value::Object(context))
+}
+
+/// Agent hierarchy (outermost first), from the arguments or a
+/// comma-separated `OPCODE_AGENT_PATH`.
+fn build_agent_path(args: &Value, env: &impl Fn(&str) -> Option<String>) -> Vec<String> {
+    let src = args
+        .get("agent_path")
+        .filter(|v| !v.is_null())
+        .or_else(|| args.get("context").and_then(|c| c.get("agent_path")))
+        .filter(|v| !v.is_null())
+        .cloned()
+        .or_else(|| env("OPCODE_AGENT_PATH").map(Value::String));
+    match src {
+        Some(Value::Array(items)) => items
+            .into_iter()
+            .filter(truthy)
+            .map(|v| match v {
+                Value::String(s) => s,
+                other => other.to_string(),
+            })
+            .collect(),
+        Some(Value::String(s)) => s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// POST a request to one of the permission server's routes and parse its
+/// JSON answer.
+fn post_to_server(config: &BridgeConfig, path: &str, request: &Value) -> Result<Value, String> {
+    let payload = request.to_string();
+    let mut head = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        path,
+        payload.len()
+    );
+    if let Some(token) = &config.auth_token {
+        head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    let raw = format!("{}\r\n{}", head, payload);
+
+    let reply = match &config.address {
+        ServerAddress::Tcp { host, port } => {
+            let mut stream =
+                connect_with_retry(|| std::net::TcpStream::connect((host.as_str(), *port)))
+                    .map_err(|e| e.to_string())?;
+            stream
+                .set_read_timeout(config.timeout)
+                .map_err(|e| e.to_string())?;
+            exchange(&mut stream, &raw)
+        }
+        #[cfg(target_os = "linux")]
+        ServerAddress::AbstractSocket(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::{SocketAddr, UnixStream};
+            let addr = SocketAddr::from_abstract_name(name).map_err(|e| e.to_string())?;
+            let mut stream = connect_with_retry(|| UnixStream::connect_addr(&addr))
+                .map_err(|e| e.to_string())?;
+            stream
+                .set_read_timeout(config.timeout)
+                .map_err(|e| e.to_string())?;
+            exchange(&mut stream, &raw)
+        }
+        #[cfg(not(target_os = "linux"))]
+        ServerAddress::AbstractSocket(_) => {
+            return Err("Abstract sockets are only supported on Linux".to_string())
+        }
+        #[cfg(unix)]
+        ServerAddress::UnixSocket(path) => {
+            let mut stream = connect_with_retry(|| std::os::unix::net::UnixStream::connect(path))
+                .map_err(|e| e.to_string())?;
+            stream
+                .set_read_timeout(config.timeout)
+                .map_err(|e| e.to_string())?;
+            exchange(&mut stream, &raw)
+        }
+        #[cfg(not(unix))]
+        ServerAddress::UnixSocket(_) => {
+            return Err("Unix sockets aren't supported on this platform".to_string())
+        }
+    }
+    .map_err(|e| e.to_string())?;
+
+    let (status, body) = parse_http_response(&reply)?;
+    if status == 401 {
+        return Err("Permission server rejected the auth token".to_string());
+    }
+    serde_json::from_slice(body).map_err(|_| "Invalid JSON from permission server".to_string())
+}
+
+/// Run `connect`, retrying after each of `CONNECT_RETRY_DELAYS` while the
+/// error shows the server isn't there yet. Only connecting is retried, so a
+/// request is never sent twice.
+fn connect_with_retry<S>(mut connect: impl FnMut() -> std::io::Result<S>) -> std::io::Result<S> {
+    for delay in CONNECT_RETRY_DELAYS {
+        match connect() {
+            Err(e) if server_not_ready(&e) => std::thread::sleep(delay),
+            result => return result,
+        }
+    }
+    connect()
+}
+
+fn server_not_ready(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::NotFound
+            | std::io::ErrorKind::AddrNotAvailable
+    )
+}
+
+fn exchange(stream: &mut (impl Read + Write), raw: &str) -> std::io::Result<Vec<u8>> {
+    stream.write_all(raw.as_bytes())?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    Ok(reply)
+}
+
+/// Status code and body of a `Connection: close` HTTP/1.1 response.
+fn parse_http_response(reply: &[u8]) -> Result<(u16, &[u8]), String> {
+    let split = reply
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("Malformed response from permission server")?;
+    let head = String::from_utf8_lossy(&reply[..split]);
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or("Malformed status line from permission server")?;
+
+    let body = &reply[split + 4..];
+    let length = head.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("content-length")
+            .then(|| value.trim().parse::<usize>().ok())?
+    });
+    Ok((
+        status,
+        &body[..length.unwrap_or(body.len()).min(body.len())],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permission_prompt::tools;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+
+        let config = BridgeConfig::from_env(env(&[
+            ("PERMISSION_SERVER_PORT", "4312"),
+            ("PERMISSION_AUTH_TOKEN", "tok"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.address,
+            ServerAddress::Tcp {
+                host: "127.0.0.1".to_string(),
+                port: 4312
+            }
+        );
+        assert_eq!(config.auth_token.as_deref(), Some("tok"));
+        assert_eq!(config.timeout, None);
+
+        let config = BridgeConfig::from_env(env(&[
+            ("PERMISSION_SERVER_PORT", "4312"),
+            ("PERMISSION_SERVER_ABSTRACT_SOCKET", "opcode-perm"),
+            ("PERMISSION_CLIENT_TIMEOUT_MS", "330000"),
+        ]))
+        .unwrap();
+        assert_eq!(config.timeout, Some(Duration::from_secs(330)));
+        assert_eq!(
+            config.address,
+            ServerAddress::AbstractSocket("opcode-perm".to_string())
+        );
+
+        let config = BridgeConfig::from_env(env(&[
+            (
+                "PERMISSION_SERVER_SOCKET_PATH",
+                "/tmp/opcode-sock-x/permission.sock",
+            ),
+            ("PERMISSION_SERVER_ABSTRACT_SOCKET", "opcode-perm"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.address,
+            ServerAddress::UnixSocket("/tmp/opcode-sock-x/permission.sock".into())
+        );
+
+        assert!(BridgeConfig::from_env(no_env).is_err());
+        assert!(BridgeConfig::from_env(env(&[("PERMISSION_SERVER_PORT", "x")])).is_err());
+        assert!(BridgeConfig::from_env(env(&[
+            ("PERMISSION_SERVER_PORT", "4000"),
+            ("PERMISSION_SERVER_CERT_SHA256", "AB:CD"),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_protocol_messages() {
+        let unused =
+            |_: &str, _: &Value| -> Result<Value, String> { panic!("no request expected") };
+
+        let init = handle_message(
+            &ServerIdentity::default(),
+            &json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize" }),
+            unused,
+        )
+        .unwrap();
+        assert_eq!(init["id"], 1);
+        assert_eq!(
+            init["result"]["serverInfo"]["name"],
+            "opcode-permission-prompt"
+        );
+
+        let list = handle_message(
+            &ServerIdentity::default(),
+            &json!({ "id": 2, "method": "tools/list" }),
+            unused,
+        )
+        .unwrap();
+        assert_eq!(list["result"]["tools"][0]["name"], "permission_prompt");
+        assert_eq!(list["result"]["tools"][1]["name"], "ask_user");
+        assert!(list["result"]["tools"][1].get("path").is_none());
+
+        assert!(handle_message(
+            &ServerIdentity::default(),
+            &json!({ "method": "notifications/initialized" }),
+            unused
+        )
+        .is_none());
+        assert!(handle_message(
+            &ServerIdentity::default(),
+            &json!({ "method": "notifications/other" }),
+            unused
+        )
+        .is_none());
+
+        let unknown = handle_message(
+            &ServerIdentity::default(),
+            &json!({ "id": 3, "method": "resources/list" }),
+            unused,
+        )
+        .unwrap();
+        assert_eq!(unknown["error"]["code"], -32601);
+
+        let wrong_tool = handle_message(
+            &ServerIdentity::default(),
+            &json!({ "id": 4, "method": "tools/call", "params": { "name": "other" } }),
+            unused,
+        )
+        .unwrap();
+        assert_eq!(wrong_tool["error"]["message"], "Unknown tool: other");
+
+        let renamed = ServerIdentity {
+            name: "acme".to_string(),
+            description: Some("Ask Acme first.".to_string()),
+        };
+        let init = handle_message(
+            &renamed,
+            &json!({ "id": 5, "method": "initialize" }),
+            unused,
+        )
+        .unwrap();
+        assert_eq!(
+            init["result"]["serverInfo"]["name"],
+            "acme-permission-prompt"
+        );
+        let list = handle_message(
+            &renamed,
+            &json!({ "id": 6, "method": "tools/list" }),
+            unused,
+        )
+        .unwrap();
+        assert_eq!(list["result"]["tools"][0]["description"], "Ask Acme first.");
+    }
+
+    #[test]
+    fn test_tool_call_forwards_and_denies_on_error() {
+        let msg = json!({
+            "id": 7,
+            "method": "tools/call",
+            "params": {
+                "name": "permission_prompt",
+                "arguments": { "tool_use_id": "tu1", "tool_name": "Bash", "input": { "command": "ls" } },
+            },
+        });
+
+        let reply = handle_message(&ServerIdentity::default(), &msg, |path, request| {
+            assert_eq!(path, "/permission-prompt");
+            assert_eq!(request["tool_name"], "Bash");
+            assert_eq!(request["input"]["command"], "ls");
+            Ok(json!({ "behavior": "allow", "updatedInput": { "command": "ls" } }))
+        })
+        .unwrap();
+        let text = reply["result"]["content"][0]["text"].as_str().unwrap();
+        let answer: Value = serde_json::from_str(text).unwrap();
+        assert_eq!(answer["behavior"], "allow");
+
+        let reply = handle_message(&ServerIdentity::default(), &msg, |_, _| {
+            Err("connection refused".to_string())
+        })
+        .unwrap();
+        let text = reply["result"]["content"][0]["text"].as_str().unwrap();
+        let answer: Value = serde_json::from_str(text).unwrap();
+        assert_eq!(answer["behavior"], "deny");
+        assert_eq!(answer["message"], "Permission server unavailable");
+    }
+
+    #[test]
+    fn test_ask_user_call_posts_to_its_route() {
+        let msg = json!({
+            "id": 8,
+            "method": "tools/call",
+            "params": { "name": "ask_user", "arguments": { "question": "Which branch?" } },
+        });
+        let reply = handle_message(&ServerIdentity::default(), &msg, |path, request| {
+            assert_eq!(path, "/user-input");
+            assert_eq!(request["question"], "Which branch?");
+            assert!(request.get("tool_name").is_none());
+            Ok(json!({ "answer": "main" }))
+        })
+        .unwrap();
+        let text = reply["result"]["content"][0]["text"].as_str().unwrap();
+        assert_eq!(text, r#"{"answer":"main"}"#);
+
+        let reply = handle_message(&ServerIdentity::default(), &msg, |_, _| {
+            Err("connection refused".to_string())
+        })
+        .unwrap();
+        let text = reply["result"]["content"][0]["text"].as_str().unwrap();
+        let answer: Value = serde_json::from_str(text).unwrap();
+        assert!(answer.get("answer").is_none());
+    }
+
+    #[test]
+    fn test_request_context_and_agent_path() {
+        let permission = ServerIdentity::default()
+            .find_tool(tools::PERMISSION_PROMPT_TOOL)
+            .unwrap();
+        let request = build_request(&permission, &json!({}), no_env);
+        assert_eq!(request["tool_use_id"], "");
+        assert_eq!(request["tool_name"], "unknown");
+        assert_eq!(request["input"], json!({}));
+        assert!(request.get("context").is_none());
+        assert_eq!(request["agent_path"], json!([]));
+
+        let args = json!({
+            "tool_name": "Read",
+            "turn": "3",
+            "context": { "message_id": "msg_1", "agent_path": ["main", "", "research"] },
+        });
+        let request = build_request(&permission, &args, no_env);
+        assert_eq!(
+            request["context"],
+            json!({ "message_id": "msg_1", "turn": 3 })
+        );
+        assert_eq!(request["agent_path"], json!(["main", "research"]));
+
+        let env = |key: &str| match key {
+            "OPCODE_MESSAGE_ID" => Some("msg_env".to_string()),
+            "OPCODE_AGENT_PATH" => Some(" main , sub ,".to_string()),
+            "OPCODE_CWD" => Some("/work/project".to_string()),
+            _ => None,
+        };
+        let request = build_request(&permission, &json!({ "turn": -1 }), env);
+        assert_eq!(request["context"], json!({ "message_id": "msg_env" }));
+        assert_eq!(request["agent_path"], json!(["main", "sub"]));
+        assert_eq!(request["cwd"], "/work/project");
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let reply = b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 20\r\n\r\n{\"behavior\":\"allow\"}trailing";
+        let (status, body) = parse_http_response(reply).unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"{\"behavior\":\"allow\"}");
+
+        let (status, body) = parse_http_response(b"HTTP/1.1 401 Unauthorized\r\n\r\n").unwrap();
+        assert_eq!(status, 401);
+        assert!(body.is_empty());
+
+        assert!(parse_http_response(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_connect_retried_only_while_server_not_ready() {
+        use std::io::{Error, ErrorKind};
+
+        let mut attempts = 0;
+        let connected = connect_with_retry(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(Error::from(ErrorKind::ConnectionRefused))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(connected.unwrap(), 3);
+
+        let mut attempts = 0;
+        let refused = connect_with_retry(|| -> std::io::Result<()> {
+            attempts += 1;
+            Err(Error::from(ErrorKind::ConnectionRefused))
+        });
+        assert!(refused.is_err());
+        assert_eq!(attempts, CONNECT_RETRY_DELAYS.len() + 1);
+
+        let mut attempts = 0;
+        let denied = connect_with_retry(|| -> std::io::Result<()> {
+            attempts += 1;
+            Err(Error::from(ErrorKind::PermissionDenied))
+        });
+        assert!(denied.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,214 @@
+//! Endpoints for external controllers (a CLI, an approver bot): `GET
+//! /pending` lists a session's prompts and `POST /resolve` answers one the
+//! way the UI would. Both need the session's controller token, as does
+//! `GET /ws`, which resolves through here too.
+
+use axum::{
+    extract::State as AxumState,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    bearer_matches, pending_infos, resolve_prompt_with, HttpState, PendingPromptInfo,
+    PermissionError, PermissionResponse, PermissionServerRegistry, MODIFY_BEHAVIOR,
+    QUARANTINE_BEHAVIOR,
+};
+
+/// Body of `POST /resolve`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolveRequest {
+    pub prompt_id: String,
+    pub behavior: String,
+    #[serde(default, alias = "updatedInput")]
+    pub updated_input: Option<serde_json::Value>,
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Remember the answer for identical requests later in the session.
+    #[serde(default)]
+    pub remember: bool,
+    /// Named input transform to apply; see `MODIFY_BEHAVIOR`.
+    #[serde(default)]
+    pub transform: Option<String>,
+}
+
+/// Answer a single prompt for an external controller (CLI, approver bot)
+/// exactly as the UI would through `resolve_prompt`.
+pub(super) async fn handle_resolve(
+    AxumState(state): AxumState<HttpState>,
+    headers: HeaderMap,
+    Json(req): Json<ResolveRequest>,
+) -> StatusCode {
+    if !bearer_matches(&headers, &state.controller_token) {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let prompt_id = req.prompt_id.clone();
+    match resolve_from_controller(&state, req).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(PermissionError::InvalidUpdatedInput(kind)) => {
+            log::warn!(
+                "Controller sent {} as the updated input for '{}'",
+                kind,
+                prompt_id
+            );
+            StatusCode::BAD_REQUEST
+        }
+        Err(e @ PermissionError::Invalid(_)) => {
+            log::warn!("Controller could not resolve '{}': {}", prompt_id, e);
+            StatusCode::BAD_REQUEST
+        }
+        Err(e @ PermissionError::AlreadyResolved(..)) => {
+            log::warn!("Controller could not resolve '{}': {}", prompt_id, e);
+            StatusCode::CONFLICT
+        }
+        Err(e) => {
+            log::warn!("Controller could not resolve '{}': {}", prompt_id, e);
+            StatusCode::NOT_FOUND
+        }
+    }
+}
+
+/// Behaviors a controller may answer with.
+const CONTROLLER_BEHAVIORS: [&str; 4] = ["allow", "deny", QUARANTINE_BEHAVIOR, MODIFY_BEHAVIOR];
+
+/// Refuse a controller's answer with a behavior it may not use.
+fn check_controller_behavior(behavior: &str) -> Result<(), PermissionError> {
+    if CONTROLLER_BEHAVIORS.contains(&behavior) {
+        return Ok(());
+    }
+    Err(PermissionError::Invalid(format!(
+        "Unknown behavior '{}'",
+        behavior
+    )))
+}
+
+/// Resolve a prompt of the state's session for a controller, through
+/// `POST /resolve` or `GET /ws`.
+pub(super) async fn resolve_from_controller(
+    state: &HttpState,
+    req: ResolveRequest,
+) -> Result<(), PermissionError> {
+    check_controller_behavior(&req.behavior)?;
+    let session_id = state.session_id.lock().await.clone();
+    let response = PermissionResponse {
+        behavior: req.behavior,
+        updated_input: req.updated_input,
+        message: req.message,
+    };
+    resolve_prompt_with(
+        &session_id,
+        &req.prompt_id,
+        response,
+        req.transform.as_deref(),
+        req.remember,
+        &state.registry,
+    )
+    .await
+}
+
+/// List the session's pending prompts, soonest deadline first.
+pub(super) async fn handle_pending(
+    AxumState(state): AxumState<HttpState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<PendingPromptInfo>>, StatusCode> {
+    if !bearer_matches(&headers, &state.controller_token) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let pending = state.pending.lock().await;
+    Ok(Json(pending_infos(&pending, &state.registry)))
+}
+
+/// The token an external controller must present to the session's
+/// `/resolve` and `/pending` endpoints.
+pub async fn controller_token(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<String, PermissionError> {
+    registry
+        .servers
+        .lock()
+        .await
+        .get(session_id)
+        .map(|entry| entry.controller_token.clone())
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::*;
+    use super::super::{handle_permission_prompt, PermissionServerRegistry};
+    use super::*;
+    use axum::http::HeaderMap;
+
+    #[tokio::test]
+    async fn test_controller_resolves_prompt_only_with_token() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let token = controller_token("session-1", &registry).await.unwrap();
+
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let resolve = ResolveRequest {
+            prompt_id: prompt_id.clone(),
+            behavior: "allow".to_string(),
+            updated_input: None,
+            message: None,
+            remember: false,
+            transform: None,
+        };
+
+        // Without the token (or with the wrong one) nothing is listed or resolved
+        let state = test_http_state(&registry, "session-1").await;
+        assert_eq!(
+            handle_pending(AxumState(state.clone()), HeaderMap::new())
+                .await
+                .unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        for headers in [
+            HeaderMap::new(),
+            bearer_headers("not-the-token"),
+            bearer_headers(&token[..token.len() - 1]),
+        ] {
+            let status =
+                handle_resolve(AxumState(state.clone()), headers, Json(resolve.clone())).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let wrong_behavior = ResolveRequest {
+            behavior: "Allow".to_string(),
+            ..resolve.clone()
+        };
+        let status = handle_resolve(
+            AxumState(state.clone()),
+            bearer_headers(&token),
+            Json(wrong_behavior),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(!handler.is_finished());
+
+        let pending = handle_pending(AxumState(state.clone()), bearer_headers(&token))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].prompt_id, prompt_id);
+
+        let status = handle_resolve(AxumState(state), bearer_headers(&token), Json(resolve)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(handler.await.unwrap().unwrap().0.behavior, "allow");
+    }
+}
@@ -0,0 +1,308 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Decisions kept in memory across all sessions; older ones are dropped first.
+const MAX_RECENT_DECISIONS: usize = 500;
+
+/// How a prompt was resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecisionSource {
+    /// Someone answered the prompt in the UI.
+    User,
+    /// A permission rule matched and answered automatically.
+    Rule,
+    /// No rule matched and the scope default answered without prompting.
+    Default,
+    /// Nobody answered before the deadline.
+    Timeout,
+    /// The server stopped while the prompt was still pending.
+    Cancelled,
+    /// The session had used up its decision limit.
+    Limit,
+    /// The tool is on the session's allowlist.
+    Allowlist,
+    /// The tool is on the session's block list.
+    Blocklist,
+    /// The user had asked to remember their answer to this exact request.
+    Remembered,
+    /// Too many prompts were already showing for the session.
+    Overflow,
+    /// The session was in inspect mode, which allows everything.
+    Inspect,
+}
+
+/// One resolved prompt. `seq` is unique and strictly increasing across the
+/// whole app (and across restarts once seeded from the audit log), so a gap
+/// in a persisted log means a record went missing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecisionRecord {
+    pub seq: u64,
+    pub session_id: String,
+    /// Unique per decision. Allowlisted, blocked and other requests answered
+    /// without asking still get one, though no prompt was shown for them.
+    pub prompt_id: String,
+    pub tool_name: String,
+    /// The input as Claude Code sent it.
+    pub input: serde_json::Value,
+    /// The input after the tool's auto-edit, when one was applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_edited_input: Option<serde_json::Value>,
+    pub behavior: String,
+    /// The message sent back with the answer, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub source: DecisionSource,
+    pub timestamp_ms: i64,
+    /// Set for prompts made by `inject_test_prompt`; no agent asked for them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub test: bool,
+}
+
+impl DecisionRecord {
+    /// A decision ready for `DecisionLog::record_entry`, which fills in the
+    /// sequence number and timestamp.
+    pub fn new(
+        session_id: &str,
+        prompt_id: &str,
+        tool_name: &str,
+        input: &serde_json::Value,
+        behavior: &str,
+        source: DecisionSource,
+    ) -> Self {
+        Self {
+            seq: 0,
+            session_id: session_id.to_string(),
+            prompt_id: prompt_id.to_string(),
+            tool_name: tool_name.to_string(),
+            input: input.clone(),
+            auto_edited_input: None,
+            behavior: behavior.to_string(),
+            message: None,
+            source,
+            timestamp_ms: 0,
+            test: false,
+        }
+    }
+}
+
+/// Registry-wide decision sequence plus a bounded buffer of recent decisions.
+#[derive(Debug, Default)]
+pub struct DecisionLog {
+    seq: AtomicU64,
+    recent: Mutex<VecDeque<DecisionRecord>>,
+}
+
+impl DecisionLog {
+    /// Make sure future sequence numbers start after `last`. Never moves the
+    /// counter backwards.
+    pub fn seed(&self, last: u64) {
+        self.seq.fetch_max(last, Ordering::SeqCst);
+    }
+
+    /// Stamp the next sequence number on a decision and remember it.
+    pub fn record(
+        &self,
+        session_id: &str,
+        prompt_id: &str,
+        tool_name: &str,
+        input: &serde_json::Value,
+        behavior: &str,
+        source: DecisionSource,
+    ) -> DecisionRecord {
+        self.record_entry(DecisionRecord::new(
+            session_id, prompt_id, tool_name, input, behavior, source,
+        ))
+    }
+
+    /// Like `record`, for a prebuilt record carrying optional details.
+    pub fn record_entry(&self, mut record: DecisionRecord) -> DecisionRecord {
+        record.seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        record.timestamp_ms = chrono::Utc::now().timestamp_millis();
+
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() >= MAX_RECENT_DECISIONS {
+            recent.pop_front();
+        }
+        recent.push_back(record.clone());
+        record
+    }
+
+    /// Recent decisions, oldest first, optionally limited to one session.
+    pub fn recent(&self, session_id: Option<&str>) -> Vec<DecisionRecord> {
+        self.recent
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|r| session_id.is_none_or(|sid| r.session_id == sid))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Running decision counts for one session, bumped as each decision is
+/// recorded. Unlike the recent-decisions buffer these never roll over.
+#[derive(Debug, Default)]
+pub struct DecisionCounters {
+    user_allowed: AtomicU64,
+    user_denied: AtomicU64,
+    auto_allowed: AtomicU64,
+    auto_denied: AtomicU64,
+    timed_out: AtomicU64,
+    cancelled: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+/// Snapshot of a session's `DecisionCounters`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionMetrics {
+    /// Prompts the user allowed, quarantined allows included.
+    pub user_allowed: u64,
+    pub user_denied: u64,
+    /// Requests allowed without asking: rules, scope defaults, the
+    /// allowlist and remembered answers.
+    pub auto_allowed: u64,
+    /// Requests denied without asking: rules, scope defaults, the block
+    /// list and the decision and concurrency limits.
+    pub auto_denied: u64,
+    /// Prompts nobody answered in time, however the timeout resolved them.
+    pub timed_out: u64,
+    /// Prompts still pending when the server stopped.
+    pub cancelled: u64,
+    /// Requests turned away by the rate limit. They aren't decisions, so
+    /// no other count includes them.
+    #[serde(default)]
+    pub rate_limited: u64,
+}
+
+impl DecisionCounters {
+    /// Count one decision. `behavior` is as logged, so `quarantine` counts
+    /// as an allow.
+    pub fn count(&self, source: DecisionSource, behavior: &str) {
+        let allowed = behavior == "allow" || behavior == "quarantine";
+        let counter = match source {
+            DecisionSource::Timeout => &self.timed_out,
+            DecisionSource::Cancelled => &self.cancelled,
+            DecisionSource::User if allowed => &self.user_allowed,
+            DecisionSource::User => &self.user_denied,
+            _ if allowed => &self.auto_allowed,
+            _ => &self.auto_denied,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> PermissionMetrics {
+        PermissionMetrics {
+            user_allowed: self.user_allowed.load(Ordering::Relaxed),
+            user_denied: self.user_denied.load(Ordering::Relaxed),
+            auto_allowed: self.auto_allowed.load(Ordering::Relaxed),
+            auto_denied: self.auto_denied.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Highest `seq` found in a JSONL decision log, or 0 if the file is missing
+/// or has no sequenced entries. Unparseable lines are skipped.
+pub fn last_sequence_in_log(path: &Path) -> u64 {
+    let file = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(_) => return 0,
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+        .filter_map(|entry| entry.get("seq").and_then(|s| s.as_u64()))
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequential_decisions_strictly_increase() {
+        let log = DecisionLog::default();
+        let input = serde_json::json!({});
+        let seqs: Vec<u64> = (0..5)
+            .map(|i| {
+                log.record(
+                    "s",
+                    &format!("p{}", i),
+                    "Bash",
+                    &input,
+                    "allow",
+                    DecisionSource::User,
+                )
+                .seq
+            })
+            .collect();
+        assert!(seqs.windows(2).all(|w| w[1] > w[0]), "{:?}", seqs);
+
+        // Seeding from an older log never rewinds the counter
+        log.seed(2);
+        assert_eq!(
+            log.record("s", "p5", "Bash", &input, "deny", DecisionSource::Timeout)
+                .seq,
+            6
+        );
+    }
+
+    #[test]
+    fn test_last_sequence_read_from_log() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        assert_eq!(last_sequence_in_log(&path), 0);
+
+        std::fs::write(&path, "{\"seq\":7}\nnot json\n{\"seq\":41}\n{\"seq\":12}\n").unwrap();
+        let log = DecisionLog::default();
+        log.seed(last_sequence_in_log(&path));
+        let input = serde_json::json!({});
+        assert_eq!(
+            log.record("s", "p", "Read", &input, "allow", DecisionSource::User)
+                .seq,
+            42
+        );
+    }
+
+    #[test]
+    fn test_counters_split_user_and_automatic_decisions() {
+        let counters = DecisionCounters::default();
+        counters.count(DecisionSource::User, "allow");
+        counters.count(DecisionSource::User, "quarantine");
+        counters.count(DecisionSource::User, "deny");
+        counters.count(DecisionSource::Rule, "allow");
+        counters.count(DecisionSource::Allowlist, "allow");
+        counters.count(DecisionSource::Remembered, "deny");
+        counters.count(DecisionSource::Blocklist, "deny");
+        counters.count(DecisionSource::Timeout, "allow");
+        counters.count(DecisionSource::Timeout, "deny");
+        counters.count(DecisionSource::Cancelled, "deny");
+        counters.count_rate_limited();
+
+        assert_eq!(
+            counters.snapshot(),
+            PermissionMetrics {
+                user_allowed: 2,
+                user_denied: 1,
+                auto_allowed: 2,
+                auto_denied: 2,
+                timed_out: 2,
+                cancelled: 1,
+                rate_limited: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use super::decisions::DecisionSource;
+
+/// Errors from the permission server's public API. `Display` keeps the
+/// wording the frontend has always shown.
+#[derive(Debug, thiserror::Error)]
+pub enum PermissionError {
+    #[error("Failed to bind permission server: {0}")]
+    BindFailed(String),
+    #[error("No permission server for session '{0}'")]
+    SessionNotFound(String),
+    #[error("No pending prompt '{0}'")]
+    PromptNotFound(String),
+    /// The prompt was already resolved differently, by a timeout, a rule or
+    /// an earlier answer, so this answer can't take effect.
+    #[error("Prompt '{0}' was already resolved ({1:?})")]
+    AlreadyResolved(String, DecisionSource),
+    /// The request waiting on the prompt has already gone away.
+    #[error("Receiver already dropped")]
+    ReceiverDropped,
+    /// An answer's `updated_input` isn't a JSON object, so Claude Code
+    /// couldn't run the tool with it.
+    #[error("Updated input must be a JSON object, got {0}")]
+    InvalidUpdatedInput(&'static str),
+    /// An answer's `behavior` isn't one the server knows, e.g. `Allow`.
+    /// Behaviors are matched exactly.
+    #[error("Unknown behavior '{0}'; expected allow, deny, modify or quarantine")]
+    InvalidBehavior(String),
+    #[error("Node.js is required for permission prompt support but was not found on PATH")]
+    NodeNotFound,
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    /// The call can't be carried out as asked (wrong reply shape, rejected
+    /// MCP env var, Node.js too old, ...).
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// Tauri commands report errors as strings; this lets them use `?`.
+impl From<PermissionError> for String {
+    fn from(e: PermissionError) -> Self {
+        e.to_string()
+    }
+}
@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// What kind of access a request asks for, for color-coding prompts and
+/// picking default policies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskCategory {
+    ReadOnly,
+    FileWrite,
+    Execute,
+    Network,
+    #[default]
+    Unknown,
+}
+
+/// Claude Code's built-in tools and what they do.
+const TOOL_CATEGORIES: &[(&str, RiskCategory)] = &[
+    ("Read", RiskCategory::ReadOnly),
+    ("LS", RiskCategory::ReadOnly),
+    ("Glob", RiskCategory::ReadOnly),
+    ("Grep", RiskCategory::ReadOnly),
+    ("NotebookRead", RiskCategory::ReadOnly),
+    ("BashOutput", RiskCategory::ReadOnly),
+    ("Write", RiskCategory::FileWrite),
+    ("Edit", RiskCategory::FileWrite),
+    ("MultiEdit", RiskCategory::FileWrite),
+    ("NotebookEdit", RiskCategory::FileWrite),
+    ("Bash", RiskCategory::Execute),
+    ("KillShell", RiskCategory::Execute),
+    ("KillBash", RiskCategory::Execute),
+    ("WebFetch", RiskCategory::Network),
+    ("WebSearch", RiskCategory::Network),
+];
+
+/// Input fields that give away what an unfamiliar tool (e.g. from an MCP
+/// server) does, strongest first.
+const INPUT_CATEGORIES: &[(&str, RiskCategory)] = &[
+    ("command", RiskCategory::Execute),
+    ("url", RiskCategory::Network),
+    ("content", RiskCategory::FileWrite),
+    ("new_string", RiskCategory::FileWrite),
+    ("edits", RiskCategory::FileWrite),
+    ("new_source", RiskCategory::FileWrite),
+];
+
+/// Per-tool explanation templates. `{target}` is replaced with the request's
+/// target summary; tools whose target can't be determined get no explanation.
+const EXPLANATIONS: &[(&str, &str)] = &[
+    ("Read", "read the file {target}"),
+    ("Write", "create or overwrite the file {target}"),
+    ("Edit", "edit the file {target}"),
+    ("MultiEdit", "make several edits to the file {target}"),
+    ("NotebookEdit", "edit the notebook {target}"),
+    ("Bash", "run the shell command `{target}`"),
+    ("LS", "list the contents of {target}"),
+    ("Glob", "search for files matching {target}"),
+    ("Grep", "search file contents for {target}"),
+    ("WebFetch", "fetch the web page {target}"),
+    ("WebSearch", "search the web for \"{target}\""),
+];
+
+/// Input field holding each tool's primary target, in lookup order.
+const TARGET_FIELDS: &[&str] = &[
+    "file_path",
+    "notebook_path",
+    "command",
+    "path",
+    "pattern",
+    "url",
+    "query",
+];
+
+/// Classify a request by its tool name, or for tools we don't know by the
+/// fields in its input. Reading is only assumed for known read-only tools.
+pub fn classify_tool(tool_name: &str, input: &Value) -> RiskCategory {
+    if let Some((_, category)) = TOOL_CATEGORIES.iter().find(|(tool, _)| *tool == tool_name) {
+        return *category;
+    }
+    INPUT_CATEGORIES
+        .iter()
+        .find(|(field, _)| input.get(*field).is_some_and(|v| !v.is_null()))
+        .map(|(_, category)| *category)
+        .unwrap_or_default()
+}
+
+/// Substrings of a shell command that make it worth a closer look, with the
+/// reason shown to the user.
+const BASH_RISKS: &[(&str, &str)] = &[
+    ("rm -rf", "deletes files and directories recursively"),
+    ("rm -r", "deletes files and directories recursively"),
+    ("sudo ", "runs with elevated privileges"),
+    ("git push --force", "overwrites remote git history"),
+    ("git push -f", "overwrites remote git history"),
+    ("git reset --hard", "discards uncommitted changes"),
+    ("| sh", "pipes content straight into a shell"),
+    ("| bash", "pipes content straight into a shell"),
+    ("chmod -R", "changes permissions recursively"),
+];
+
+/// The single most relevant value in a tool input (file path, command, URL,
+/// ...), if there is one.
+pub fn target_summary(input: &Value) -> Option<String> {
+    TARGET_FIELDS
+        .iter()
+        .filter_map(|field| input.get(*field).and_then(|v| v.as_str()))
+        .find(|v| !v.trim().is_empty())
+        .map(|v| v.trim().to_string())
+}
+
+/// Reasons a request deserves extra care. Empty for ordinary requests.
+pub fn risk_reasons(tool_name: &str, input: &Value) -> Vec<&'static str> {
+    let mut reasons = Vec::new();
+    if tool_name == "Bash" {
+        if let Some(command) = input.get("command").and_then(|v| v.as_str()) {
+            for (needle, reason) in BASH_RISKS {
+                if command.contains(needle) && !reasons.contains(reason) {
+                    reasons.push(*reason);
+                }
+            }
+        }
+    }
+    reasons
+}
+
+/// Describe a request in one sentence, e.g. "This will read the file
+/// src/main.rs." Returns `None` for tools without an explanation template.
+pub fn explain_request(tool_name: &str, input: &Value) -> Option<String> {
+    let template = EXPLANATIONS
+        .iter()
+        .find(|(tool, _)| *tool == tool_name)
+        .map(|(_, template)| *template)?;
+    let target = target_summary(input)?;
+
+    let mut sentence = format!("This will {}", template.replace("{target}", &target));
+    let reasons = risk_reasons(tool_name, input);
+    if !reasons.is_empty() {
+        sentence.push_str(", which ");
+        sentence.push_str(&reasons.join(" and "));
+    }
+    sentence.push('.');
+    Some(sentence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_explains_read_and_edit() {
+        assert_eq!(
+            explain_request("Read", &json!({ "file_path": "src/main.rs" })).as_deref(),
+            Some("This will read the file src/main.rs.")
+        );
+        assert_eq!(
+            explain_request(
+                "Edit",
+                &json!({ "file_path": "src/lib.rs", "old_string": "a", "new_string": "b" })
+            )
+            .as_deref(),
+            Some("This will edit the file src/lib.rs.")
+        );
+    }
+
+    #[test]
+    fn test_explains_bash_with_risk_reasons() {
+        assert_eq!(
+            explain_request("Bash", &json!({ "command": "cargo build" })).as_deref(),
+            Some("This will run the shell command `cargo build`.")
+        );
+        assert_eq!(
+            explain_request("Bash", &json!({ "command": "rm -rf build" })).as_deref(),
+            Some(
+                "This will run the shell command `rm -rf build`, which deletes files and \
+                 directories recursively."
+            )
+        );
+    }
+
+    #[test]
+    fn test_unknown_tool_or_missing_target_has_no_explanation() {
+        assert_eq!(
+            explain_request("mcp__custom__tool", &json!({ "path": "x" })),
+            None
+        );
+        assert_eq!(explain_request("Read", &json!({})), None);
+    }
+
+    #[test]
+    fn test_classifies_builtin_tools() {
+        let cases = [
+            (
+                "Read",
+                json!({ "file_path": "a.rs" }),
+                RiskCategory::ReadOnly,
+            ),
+            ("LS", json!({ "path": "." }), RiskCategory::ReadOnly),
+            (
+                "Glob",
+                json!({ "pattern": "**/*.rs" }),
+                RiskCategory::ReadOnly,
+            ),
+            (
+                "Grep",
+                json!({ "pattern": "fn main" }),
+                RiskCategory::ReadOnly,
+            ),
+            (
+                "Write",
+                json!({ "file_path": "a", "content": "x" }),
+                RiskCategory::FileWrite,
+            ),
+            (
+                "Edit",
+                json!({ "file_path": "a", "new_string": "x" }),
+                RiskCategory::FileWrite,
+            ),
+            (
+                "MultiEdit",
+                json!({ "file_path": "a", "edits": [] }),
+                RiskCategory::FileWrite,
+            ),
+            (
+                "NotebookEdit",
+                json!({ "notebook_path": "a.ipynb" }),
+                RiskCategory::FileWrite,
+            ),
+            ("Bash", json!({ "command": "ls" }), RiskCategory::Execute),
+            (
+                "WebFetch",
+                json!({ "url": "https://example.com" }),
+                RiskCategory::Network,
+            ),
+            (
+                "WebSearch",
+                json!({ "query": "rust" }),
+                RiskCategory::Network,
+            ),
+        ];
+        for (tool, input, expected) in cases {
+            assert_eq!(classify_tool(tool, &input), expected, "{}", tool);
+        }
+        // The tool name wins over misleading input
+        assert_eq!(
+            classify_tool("Read", &json!({ "file_path": "a", "command": "rm" })),
+            RiskCategory::ReadOnly
+        );
+    }
+
+    #[test]
+    fn test_classifies_unknown_tools_by_input() {
+        let classify = |input| classify_tool("mcp__custom__tool", &input);
+        assert_eq!(
+            classify(json!({ "command": "make" })),
+            RiskCategory::Execute
+        );
+        assert_eq!(
+            classify(json!({ "url": "https://x", "content": "y" })),
+            RiskCategory::Network
+        );
+        assert_eq!(
+            classify(json!({ "path": "a", "content": "y" })),
+            RiskCategory::FileWrite
+        );
+        assert_eq!(classify(json!({ "path": "a" })), RiskCategory::Unknown);
+        assert_eq!(classify(json!({ "command": null })), RiskCategory::Unknown);
+        assert_eq!(classify(json!("not an object")), RiskCategory::Unknown);
+        assert_eq!(
+            serde_json::to_value(RiskCategory::FileWrite).unwrap(),
+            json!("file_write")
+        );
+    }
+}
@@ -0,0 +1,641 @@
+//! Taking servers down: stopping one session's server at once or after its
+//! pending prompts drain, stopping them all on app exit, and reaping servers
+//! left idle. A stopped server's pending prompts are denied and its MCP files
+//! removed.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use super::{
+    cleanup_temp_files, emit_session_event, remove_socket_file, PermissionServerEntry,
+    PermissionServerRegistry,
+};
+
+/// Stop and clean up the permission server for a session.
+pub async fn stop_server(session_id: &str, registry: &PermissionServerRegistry) {
+    let mut servers = registry.servers.lock().await;
+    if let Some(entry) = servers.remove(session_id) {
+        tear_down(entry, registry).await;
+    }
+}
+
+/// How long `stop_all_servers` waits on app exit for servers to finish.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Stop every session's server, e.g. when the app exits: each is torn down
+/// like `stop_server`, then their shutdowns are awaited for up to `timeout`
+/// in all. Servers still running then are aborted. Returns how many were
+/// stopped.
+pub async fn stop_all_servers(registry: &PermissionServerRegistry, timeout: Duration) -> usize {
+    let entries: Vec<PermissionServerEntry> = registry
+        .servers
+        .lock()
+        .await
+        .drain()
+        .map(|(_, entry)| entry)
+        .collect();
+    let stopped = entries.len();
+    let mut tasks = Vec::new();
+    for entry in entries {
+        tasks.extend(tear_down(entry, registry).await);
+    }
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    for mut task in tasks {
+        if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+            log::warn!(
+                "Permission server didn't shut down in {:?}; aborting it",
+                timeout
+            );
+            task.abort();
+        }
+    }
+    if stopped > 0 {
+        log::info!("Stopped {} permission server(s)", stopped);
+    }
+    stopped
+}
+
+/// Shut down a server already taken out of the registry: signal it, deny
+/// whatever is pending and remove its files. Returns its serving task.
+async fn tear_down(
+    mut entry: PermissionServerEntry,
+    registry: &PermissionServerRegistry,
+) -> Option<tokio::task::JoinHandle<()>> {
+    emit_session_summary(&entry, registry).await;
+
+    // Signal shutdown
+    let _ = entry.shutdown_tx.send(true);
+
+    // Drop all pending senders → auto-deny any waiting requests
+    let current_id = entry.session_id.lock().await.clone();
+    registry
+        .update_pending(&entry.pending, entry.emitter.as_ref(), &current_id, |p| {
+            p.clear()
+        })
+        .await;
+    entry.pending_inputs.lock().await.clear();
+
+    // Clean up temp files
+    cleanup_temp_files(&entry.mcp_files);
+    if let Some(path) = &entry.socket_path {
+        remove_socket_file(path);
+    }
+
+    emit_session_event(
+        entry.emitter.as_ref(),
+        "permission-server-stopped",
+        &current_id,
+        &ServerLifecycleEvent {
+            session_id: current_id.clone(),
+            port: entry.port,
+        },
+        registry.emit_generic(),
+    );
+    log::info!(
+        "Permission server for session '{}' stopped and cleaned up",
+        current_id
+    );
+    entry.server_task.take()
+}
+
+/// Emitted once as `permission-session-summary` when a session's server
+/// stops. Counts come from the session's own metrics, so they cover the
+/// whole session however many decisions other sessions have made.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionSummaryEvent {
+    pub session_id: String,
+    pub total_decisions: usize,
+    /// Includes quarantined allows.
+    pub allowed: usize,
+    /// Denies other than timeouts.
+    pub denied: usize,
+    pub timed_out: usize,
+    /// Every tool the user was prompted for.
+    pub tools: BTreeSet<String>,
+    /// Prompts shown to the user per tool.
+    pub tool_prompts: BTreeMap<String, u64>,
+}
+
+async fn emit_session_summary(entry: &PermissionServerEntry, registry: &PermissionServerRegistry) {
+    let session_id = entry.session_id.lock().await.clone();
+    let tool_prompts: BTreeMap<String, u64> = entry
+        .tool_prompts
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(tool, count)| (tool.clone(), *count))
+        .collect();
+    let metrics = entry.metrics.snapshot();
+    let allowed = (metrics.user_allowed + metrics.auto_allowed) as usize;
+    // Prompts cancelled by a stop were denied too
+    let denied = (metrics.user_denied + metrics.auto_denied + metrics.cancelled) as usize;
+    let timed_out = metrics.timed_out as usize;
+    let summary = SessionSummaryEvent {
+        session_id: session_id.clone(),
+        total_decisions: allowed + denied + timed_out,
+        allowed,
+        denied,
+        timed_out,
+        tools: tool_prompts.keys().cloned().collect(),
+        tool_prompts,
+    };
+    emit_session_event(
+        entry.emitter.as_ref(),
+        "permission-session-summary",
+        &session_id,
+        &summary,
+        registry.emit_generic(),
+    );
+}
+
+/// How often a draining stop re-checks pending prompts and activity.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Outcome of `stop_server_draining` and `stop_server_graceful`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopResult {
+    /// The server was stopped and cleaned up.
+    Stopped,
+    /// A new request arrived during the drain, so the server was kept alive.
+    Aborted,
+    /// No server was registered for the session.
+    NotFound,
+}
+
+/// Stop a session's server once its pending prompts have been answered,
+/// waiting at most `grace` before stopping anyway. If a new request reaches
+/// the server while draining, the stop is treated as erroneous and aborted.
+pub async fn stop_server_draining(
+    session_id: &str,
+    grace: Duration,
+    registry: &PermissionServerRegistry,
+) -> StopResult {
+    drain_server(session_id, grace, DrainPolicy::AbortOnRequest, registry).await
+}
+
+/// Deny message for requests arriving while a server is stopping gracefully.
+pub const SERVER_CLOSING_MESSAGE: &str = "Session is shutting down";
+
+/// Stop a session's server without cutting off a decision in progress: new
+/// requests are denied right away, prompts already showing may still be
+/// answered for up to `grace`, and whatever is left then is denied as
+/// `stop_server` would.
+pub async fn stop_server_graceful(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+    grace: Duration,
+) -> StopResult {
+    drain_server(session_id, grace, DrainPolicy::DenyNewRequests, registry).await
+}
+
+/// What a draining stop does with requests that arrive while it waits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DrainPolicy {
+    /// The agent is still working, so the stop was premature: keep the
+    /// server and let the request through.
+    AbortOnRequest,
+    /// Deny them with `SERVER_CLOSING_MESSAGE` and stop regardless.
+    DenyNewRequests,
+}
+
+/// Stop a session's server once its pending prompts and input requests have
+/// been answered, or when `grace` runs out; `stop_server` denies whatever is
+/// left then.
+async fn drain_server(
+    session_id: &str,
+    grace: Duration,
+    policy: DrainPolicy,
+    registry: &PermissionServerRegistry,
+) -> StopResult {
+    let (pending, pending_inputs, last_activity) = {
+        let servers = registry.servers.lock().await;
+        let Some(entry) = servers.get(session_id) else {
+            return StopResult::NotFound;
+        };
+        if policy == DrainPolicy::DenyNewRequests {
+            entry.closing.store(true, Ordering::Relaxed);
+        }
+        (
+            entry.pending.clone(),
+            entry.pending_inputs.clone(),
+            entry.last_activity.clone(),
+        )
+    };
+    log::info!(
+        "Draining permission server for session '{}'; waiting up to {:?} for pending prompts",
+        session_id,
+        grace
+    );
+
+    let drain_started = Instant::now();
+    let deadline = drain_started + grace;
+    loop {
+        if policy == DrainPolicy::AbortOnRequest && *last_activity.lock().await > drain_started {
+            log::info!(
+                "New permission request during drain of session '{}'; keeping server alive",
+                session_id
+            );
+            return StopResult::Aborted;
+        }
+        let drained = pending.lock().await.is_empty() && pending_inputs.lock().await.is_empty();
+        if drained || Instant::now() >= deadline {
+            break;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    stop_server(session_id, registry).await;
+    StopResult::Stopped
+}
+
+/// Servers idle this long are stopped by the reaper unless configured
+/// otherwise.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How often the reaper looks for idle servers.
+const REAPER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Emitted as `permission-server-started` once a server is listening and
+/// registered, so the session can be handed to Claude Code, and as
+/// `permission-server-stopped` when `stop_server` has torn it down.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerLifecycleEvent {
+    pub session_id: String,
+    pub port: u16,
+}
+
+/// Emitted as `permission-server-reaped` when an idle server is stopped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerReapedEvent {
+    pub session_id: String,
+    pub idle_ms: u64,
+}
+
+/// Stop every server with no pending prompts and no request for at least
+/// `idle`, e.g. after an agent exited without a clean shutdown. Returns the
+/// reaped sessions.
+pub async fn reap_idle_servers(registry: &PermissionServerRegistry, idle: Duration) -> Vec<String> {
+    let mut idle_sessions = Vec::new();
+    {
+        let servers = registry.servers.lock().await;
+        for (session_id, entry) in servers.iter() {
+            let idle_for = entry.last_activity.lock().await.elapsed();
+            if idle_for >= idle && entry.pending.lock().await.is_empty() {
+                idle_sessions.push((session_id.clone(), entry.emitter.clone(), idle_for));
+            }
+        }
+    }
+
+    let mut reaped = Vec::new();
+    for (session_id, emitter, idle_for) in idle_sessions {
+        log::info!(
+            "Reaping permission server for session '{}' after {:?} idle",
+            session_id,
+            idle_for
+        );
+        stop_server(&session_id, registry).await;
+        emit_session_event(
+            emitter.as_ref(),
+            "permission-server-reaped",
+            &session_id,
+            &ServerReapedEvent {
+                session_id: session_id.clone(),
+                idle_ms: idle_for.as_millis() as u64,
+            },
+            registry.emit_generic(),
+        );
+        reaped.push(session_id);
+    }
+    reaped
+}
+
+/// Run `reap_idle_servers` in the background for the life of the app.
+pub fn spawn_idle_reaper(registry: PermissionServerRegistry, idle: Duration) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(REAPER_INTERVAL.min(idle));
+        loop {
+            interval.tick().await;
+            reap_idle_servers(&registry, idle).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decisions::{DecisionRecord, DecisionSource};
+    use super::super::testing::*;
+    use super::super::{
+        active_server_count, count_tool_prompt, generate_mcp_files, handle_permission_prompt,
+        handle_user_input, inject_test_prompt, mcp_file_options, recent_decisions, resolve_prompt,
+        resolve_user_input, set_mcp_files, start_server_with, PermissionResponse,
+        PermissionServerConfig, UserInputRequest,
+    };
+    use super::*;
+    use axum::{extract::State as AxumState, Json};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_request_during_drain_aborts_stop() {
+        let registry = PermissionServerRegistry::default();
+        insert_test_entry(&registry, "session-1").await;
+        let prompt_id = inject_test_prompt("session-1", "Bash", serde_json::json!({}), &registry)
+            .await
+            .unwrap();
+
+        // Simulate the HTTP handler receiving a request mid-drain
+        let last_activity = registry.servers.lock().await["session-1"]
+            .last_activity
+            .clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            *last_activity.lock().await = Instant::now();
+        });
+
+        let result = stop_server_draining("session-1", Duration::from_secs(5), &registry).await;
+        assert_eq!(result, StopResult::Aborted);
+        assert!(registry.servers.lock().await.contains_key("session-1"));
+
+        // Once the pending prompt is answered and nothing new arrives, the stop goes through
+        let deny = PermissionResponse {
+            behavior: "deny".to_string(),
+            updated_input: None,
+            message: None,
+        };
+        resolve_prompt("session-1", &prompt_id, deny, &registry)
+            .await
+            .unwrap();
+        let result = stop_server_draining("session-1", Duration::from_secs(5), &registry).await;
+        assert_eq!(result, StopResult::Stopped);
+        assert!(!registry.servers.lock().await.contains_key("session-1"));
+    }
+
+    #[tokio::test]
+    async fn test_idle_server_reaped_while_active_survives() {
+        let registry = PermissionServerRegistry::default();
+        let idle_emitter = insert_test_entry(&registry, "idle").await;
+        insert_test_entry(&registry, "active").await;
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        // A request keeps this one alive
+        test_http_state(&registry, "active")
+            .await
+            .note_request()
+            .await;
+
+        let reaped = reap_idle_servers(&registry, Duration::from_millis(50)).await;
+        assert_eq!(reaped, vec!["idle".to_string()]);
+        let servers = registry.servers.lock().await;
+        assert!(!servers.contains_key("idle"));
+        assert!(servers.contains_key("active"));
+        assert!(idle_emitter
+            .names()
+            .contains(&"permission-server-reaped:idle".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_stop_emits_session_summary() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        insert_test_entry(&registry, "other").await;
+        {
+            let servers = registry.servers.lock().await;
+            count_tool_prompt(&servers["session-1"].tool_prompts, "Bash");
+            count_tool_prompt(&servers["session-1"].tool_prompts, "Bash");
+            count_tool_prompt(&servers["session-1"].tool_prompts, "Write");
+            count_tool_prompt(&servers["other"].tool_prompts, "Glob");
+        }
+        let state = test_http_state(&registry, "session-1").await;
+        let other = test_http_state(&registry, "other").await;
+        let input = serde_json::json!({});
+        let decisions = [
+            ("Read", "allow", DecisionSource::Rule),
+            ("Bash", "allow", DecisionSource::User),
+            ("Bash", "deny", DecisionSource::Timeout),
+            ("Write", "deny", DecisionSource::User),
+        ];
+        for (i, (tool, behavior, source)) in decisions.into_iter().enumerate() {
+            state.record_decision(DecisionRecord::new(
+                "session-1",
+                &format!("p{}", i),
+                tool,
+                &input,
+                behavior,
+                source,
+            ));
+        }
+        other.record_decision(DecisionRecord::new(
+            "other",
+            "p9",
+            "Glob",
+            &input,
+            "allow",
+            DecisionSource::User,
+        ));
+
+        stop_server("session-1", &registry).await;
+
+        let summary: SessionSummaryEvent = emitter
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| name == "permission-session-summary:session-1")
+            .map(|(_, payload)| serde_json::from_value(payload.clone()).unwrap())
+            .unwrap();
+        assert_eq!(
+            summary,
+            SessionSummaryEvent {
+                session_id: "session-1".to_string(),
+                total_decisions: 4,
+                allowed: 2,
+                denied: 1,
+                timed_out: 1,
+                tools: ["Bash", "Write"].into_iter().map(String::from).collect(),
+                tool_prompts: BTreeMap::from([("Bash".to_string(), 2), ("Write".to_string(), 1)]),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_summary_counts_decisions_beyond_recent_buffer() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        insert_test_entry(&registry, "other").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let other = test_http_state(&registry, "other").await;
+        let input = serde_json::json!({});
+        for i in 0..300 {
+            let behavior = if i % 3 == 0 { "deny" } else { "allow" };
+            state.record_decision(DecisionRecord::new(
+                "session-1",
+                &format!("p{}", i),
+                "Bash",
+                &input,
+                behavior,
+                DecisionSource::User,
+            ));
+        }
+        // Pushes most of session-1's decisions out of the shared buffer
+        for i in 0..400 {
+            other.record_decision(DecisionRecord::new(
+                "other",
+                &format!("o{}", i),
+                "Read",
+                &input,
+                "allow",
+                DecisionSource::Rule,
+            ));
+        }
+        assert!(recent_decisions(Some("session-1"), &registry).len() < 300);
+
+        stop_server("session-1", &registry).await;
+
+        let summary: SessionSummaryEvent = emitter
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| name == "permission-session-summary:session-1")
+            .map(|(_, payload)| serde_json::from_value(payload.clone()).unwrap())
+            .unwrap();
+        assert_eq!(summary.total_decisions, 300);
+        assert_eq!(summary.allowed, 200);
+        assert_eq!(summary.denied, 100);
+        assert_eq!(summary.timed_out, 0);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_stop_lets_shown_prompt_finish() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        assert_eq!(
+            stop_server_graceful("missing", &registry, Duration::ZERO).await,
+            StopResult::NotFound
+        );
+
+        let shown = tokio::spawn(handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let registry_for_stop = registry.clone();
+        let stop = tokio::spawn(async move {
+            stop_server_graceful("session-1", &registry_for_stop, Duration::from_secs(5)).await
+        });
+        let closing = registry.servers.lock().await["session-1"].closing.clone();
+        wait_until(move || closing.load(Ordering::Relaxed)).await;
+
+        // New requests are turned away without a prompt
+        let Json(resp) = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "a" }),
+            )),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.message.as_deref(), Some(SERVER_CLOSING_MESSAGE));
+        assert_eq!(emitter.prompts().len(), 1);
+
+        // The prompt already showing can still be answered
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(shown.await.unwrap().unwrap().0.behavior, "allow");
+        assert_eq!(stop.await.unwrap(), StopResult::Stopped);
+        assert!(registry.servers.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_pending_user_input() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+
+        let asked = tokio::spawn(handle_user_input(
+            AxumState(state.clone()),
+            bearer_headers(&state.auth_token),
+            Json(UserInputRequest {
+                question: "Which branch?".to_string(),
+                context: None,
+                agent_path: Vec::new(),
+            }),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || {
+            emitter_for_wait
+                .names()
+                .contains(&"user-input-request:session-1".to_string())
+        })
+        .await;
+        let request_id = emitter.events.lock().unwrap()[0].1["request_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let registry_for_stop = registry.clone();
+        let stop = tokio::spawn(async move {
+            stop_server_draining("session-1", Duration::from_secs(5), &registry_for_stop).await
+        });
+        tokio::time::sleep(DRAIN_POLL_INTERVAL * 3).await;
+        assert!(registry.servers.lock().await.contains_key("session-1"));
+
+        resolve_user_input("session-1", &request_id, None, &registry)
+            .await
+            .unwrap();
+        assert!(asked.await.unwrap().is_ok());
+        assert_eq!(stop.await.unwrap(), StopResult::Stopped);
+        assert!(registry.servers.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stop_all_servers_tears_down_every_session() {
+        let registry = PermissionServerRegistry::default();
+        let mut addrs = Vec::new();
+        for session_id in ["session-1", "session-2"] {
+            let port = start_server_with(
+                Arc::new(RecordingEmitter::default()),
+                session_id,
+                PermissionServerConfig::default(),
+                &registry,
+            )
+            .await
+            .unwrap();
+            let options = mcp_file_options(session_id, &registry).await.unwrap();
+            let files = generate_mcp_files(port, session_id, &test_node_path(), &options).unwrap();
+            set_mcp_files(session_id, files.clone(), &registry).await;
+            let host = registry.servers.lock().await[session_id].host;
+            addrs.push((std::net::SocketAddr::new(host, port), files));
+        }
+
+        // A prompt left showing is denied rather than holding up the exit
+        let emitter = insert_test_entry(&registry, "session-3").await;
+        let state = test_http_state(&registry, "session-3").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        emitter.nth_prompt_id(0).await;
+
+        assert_eq!(stop_all_servers(&registry, Duration::from_secs(5)).await, 3);
+        assert_eq!(active_server_count(&registry).await, 0);
+        assert_eq!(handler.await.unwrap().unwrap().0.behavior, "deny");
+        for (addr, files) in addrs {
+            assert!(!files.dir.exists());
+            assert!(std::net::TcpStream::connect(addr).is_err());
+        }
+        assert_eq!(stop_all_servers(&registry, Duration::from_secs(5)).await, 0);
+    }
+}
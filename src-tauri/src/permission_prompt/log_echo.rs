@@ -0,0 +1,225 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use super::PermissionEmitter;
+
+/// How often buffered lines are flushed to the frontend.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+/// Lines kept between flushes; older lines are dropped first.
+const MAX_BUFFERED_LINES: usize = 500;
+
+static LOG_ECHO: OnceLock<&'static PermissionLogEcho> = OnceLock::new();
+
+thread_local! {
+    /// Set while flushing so anything logged by the emit itself is not echoed
+    /// back into the buffer.
+    static IN_ECHO: Cell<bool> = const { Cell::new(false) };
+}
+
+/// One forwarded log record, emitted in batches as `permission-log`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PermissionLogLine {
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    pub timestamp_ms: i64,
+}
+
+/// Logger wrapper that passes every record to the inner logger and, when an
+/// echo level is set, also buffers permission-subsystem records for the
+/// frontend's live log viewer.
+pub struct PermissionLogEcho {
+    inner: Box<dyn Log>,
+    inner_filter: LevelFilter,
+    /// `LevelFilter` stored as usize; `LevelFilter::Off` (0) disables echoing.
+    level: AtomicUsize,
+    buffer: Mutex<Vec<PermissionLogLine>>,
+}
+
+impl PermissionLogEcho {
+    fn new(inner: Box<dyn Log>, inner_filter: LevelFilter) -> Self {
+        Self {
+            inner,
+            inner_filter,
+            level: AtomicUsize::new(LevelFilter::Off as usize),
+            buffer: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn echo_level(&self) -> LevelFilter {
+        match self.level.load(Ordering::Relaxed) {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    fn should_echo(&self, record: &Record) -> bool {
+        is_permission_target(record.target())
+            && record.level() <= self.echo_level()
+            && !IN_ECHO.with(|f| f.get())
+    }
+
+    fn take_lines(&self) -> Vec<PermissionLogLine> {
+        std::mem::take(&mut *self.buffer.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Emit everything buffered so far as a single `permission-log` event.
+    fn flush_to(&self, emitter: &dyn PermissionEmitter) {
+        let lines = self.take_lines();
+        if lines.is_empty() {
+            return;
+        }
+        let payload = match serde_json::to_value(&lines) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        IN_ECHO.with(|f| f.set(true));
+        let _ = emitter.emit_json("permission-log", payload);
+        IN_ECHO.with(|f| f.set(false));
+    }
+}
+
+impl Log for PermissionLogEcho {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+            || (is_permission_target(metadata.target()) && metadata.level() <= self.echo_level())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+
+        if self.should_echo(record) {
+            let line = PermissionLogLine {
+                level: record.level().to_string(),
+                target: record.target().to_string(),
+                message: record.args().to_string(),
+                timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            };
+            let mut buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+            if buffer.len() >= MAX_BUFFERED_LINES {
+                buffer.remove(0);
+            }
+            buffer.push(line);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Records from this module tree, whichever crate name it is compiled under
+/// (`opcode::permission_prompt::...` or `opcode_lib::permission_prompt::...`).
+fn is_permission_target(target: &str) -> bool {
+    target.split("::").nth(1) == Some("permission_prompt")
+}
+
+/// Install the process logger: `env_logger` as usual, wrapped so permission
+/// records can be echoed to the frontend. Replaces `env_logger::init()`.
+pub fn init_logger() {
+    let inner = env_logger::Builder::from_default_env().build();
+    let inner_filter = inner.filter();
+    let echo: &'static PermissionLogEcho = Box::leak(Box::new(PermissionLogEcho::new(
+        Box::new(inner),
+        inner_filter,
+    )));
+
+    if log::set_logger(echo).is_ok() {
+        log::set_max_level(inner_filter);
+        let _ = LOG_ECHO.set(echo);
+
+        if let Ok(level) = std::env::var("OPCODE_PERMISSION_LOG_ECHO") {
+            if let Ok(level) = level.parse::<LevelFilter>() {
+                set_echo_level(level);
+            }
+        }
+    }
+}
+
+/// Set the minimum level echoed to the frontend. `LevelFilter::Off` (the
+/// default) disables echoing entirely.
+pub fn set_echo_level(level: LevelFilter) {
+    if let Some(echo) = LOG_ECHO.get() {
+        echo.level.store(level as usize, Ordering::Relaxed);
+        log::set_max_level(echo.inner_filter.max(level));
+        if level == LevelFilter::Off {
+            echo.take_lines();
+        }
+    }
+}
+
+/// Start the background task that flushes buffered lines to the frontend.
+/// Does nothing if `init_logger` was not used to install the logger.
+pub fn attach_emitter(emitter: Arc<dyn PermissionEmitter>) {
+    let echo = match LOG_ECHO.get() {
+        Some(echo) => *echo,
+        None => return,
+    };
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            echo.flush_to(emitter.as_ref());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::RecordingEmitter;
+    use super::*;
+
+    struct NullLog;
+
+    impl Log for NullLog {
+        fn enabled(&self, _: &Metadata) -> bool {
+            false
+        }
+        fn log(&self, _: &Record) {}
+        fn flush(&self) {}
+    }
+
+    fn log_line(echo: &PermissionLogEcho, target: &str) {
+        echo.log(
+            &Record::builder()
+                .target(target)
+                .level(log::Level::Info)
+                .args(format_args!("prompt created"))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_log_lines_echoed_only_when_enabled() {
+        let echo = PermissionLogEcho::new(Box::new(NullLog), LevelFilter::Error);
+        let emitter = RecordingEmitter::default();
+
+        // Disabled by default: nothing is buffered or emitted
+        log_line(&echo, "opcode_lib::permission_prompt");
+        echo.flush_to(&emitter);
+        assert!(emitter.events.lock().unwrap().is_empty());
+
+        echo.level
+            .store(LevelFilter::Info as usize, Ordering::Relaxed);
+        log_line(&echo, "opcode_lib::permission_prompt");
+        log_line(&echo, "opcode::permission_prompt::rules");
+        // Records from other modules are never echoed
+        log_line(&echo, "opcode_lib::commands::claude");
+        echo.flush_to(&emitter);
+
+        let batches = emitter.events.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].1.as_array().unwrap().len(), 2);
+        assert_eq!(batches[0].1[0]["message"], "prompt created");
+    }
+}
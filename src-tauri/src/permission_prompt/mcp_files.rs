@@ -0,0 +1,1562 @@
+//! What Claude Code is pointed at to reach a permission server: the
+//! `--mcp-config` file, the Node.js MCP script it names, and the JavaScript
+//! runtime that runs it. Also cleans the files up again, including ones left
+//! behind by a crash.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{
+    bridge, longest_session_timeout, tools, PermissionError, PermissionServerRegistry,
+    ServerIdentity,
+};
+
+/// Claude Code's `--mcp-config` file: `{ "mcpServers": { "<name>": {...} } }`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct McpConfig {
+    #[serde(rename = "mcpServers")]
+    pub mcp_servers: HashMap<String, McpServer>,
+}
+
+/// A stdio MCP server entry: the command Claude Code spawns and its env.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct McpServer {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+/// Env vars the generated config always sets; callers can't override them.
+const RESERVED_MCP_ENV: &[&str] = &[
+    "PERMISSION_SERVER_PORT",
+    "PERMISSION_SERVER_HOST",
+    "PERMISSION_SERVER_ABSTRACT_SOCKET",
+    "PERMISSION_SERVER_SOCKET_PATH",
+    "PERMISSION_AUTH_TOKEN",
+    "PERMISSION_CLIENT_TIMEOUT_MS",
+    "OPCODE_SESSION_ID",
+    "OPCODE_CWD",
+    "PERMISSION_SERVER_CERT_SHA256",
+    tools::SERVER_NAME_ENV,
+    tools::TOOL_DESCRIPTION_ENV,
+];
+
+/// How much longer the MCP client waits than the server's prompt timeout, so
+/// the server's own timeout answers first when everything is working.
+pub const CLIENT_TIMEOUT_MARGIN: Duration = Duration::from_secs(30);
+
+/// Caller-supplied extras for `generate_mcp_files`.
+#[derive(Debug, Clone, Default)]
+pub struct McpFileOptions {
+    /// Extra env vars for the MCP process. Values arrive as JSON from the
+    /// frontend and must be plain strings.
+    pub extra_env: BTreeMap<String, serde_json::Value>,
+    /// Extra arguments passed to node before the script path. Only used
+    /// when `runtime` is Node.js.
+    pub node_args: Vec<String>,
+    /// JavaScript runtime `node_path` points at (see `find_runtime`).
+    pub runtime: RuntimeKind,
+    /// Abstract socket name (without the leading NUL) the script connects
+    /// to instead of the TCP port.
+    pub abstract_socket: Option<String>,
+    /// Unix socket file the script connects to instead of the TCP port.
+    pub socket_path: Option<PathBuf>,
+    /// Loopback address the script connects to; it assumes `127.0.0.1`
+    /// when unset.
+    pub host: Option<String>,
+    /// Token the script sends as `Authorization: Bearer` (see
+    /// `PermissionServerEntry::auth_token`).
+    pub auth_token: Option<String>,
+    /// Human-readable name (e.g. the project) put into the temp file names
+    /// together with a timestamp, to find them in a cluttered temp dir.
+    pub label: Option<String>,
+    /// Executable to run as the MCP server with `bridge::BRIDGE_FLAG`
+    /// instead of node and the script (usually opcode itself). `node_path`
+    /// and `node_args` are ignored and no script is written when set.
+    pub native_bridge: Option<PathBuf>,
+    /// How long the MCP client waits for the server's answer before denying
+    /// (`PERMISSION_CLIENT_TIMEOUT_MS`). `None` waits forever.
+    pub client_timeout: Option<Duration>,
+    /// The session's working directory, sent along with each request
+    /// (`OPCODE_CWD`).
+    pub cwd: Option<PathBuf>,
+    /// Fingerprint of the server's certificate when it speaks HTTPS
+    /// (`PERMISSION_SERVER_CERT_SHA256`); the script connects only if the
+    /// server presents exactly this certificate.
+    pub tls_fingerprint: Option<String>,
+    /// The `mcpServers` key and how the server describes itself. Defaults
+    /// to `opcode`; Claude Code's `--permission-prompt-tool` must match.
+    pub mcp_server: ServerIdentity,
+}
+
+/// Build the MCP config pointing Claude Code at our permission-prompt script.
+pub(super) fn build_mcp_config(
+    port: u16,
+    session_id: &str,
+    node_path: &str,
+    script_path: &Path,
+    options: &McpFileOptions,
+) -> Result<McpConfig, PermissionError> {
+    options
+        .mcp_server
+        .validate()
+        .map_err(PermissionError::Invalid)?;
+    let mut env = BTreeMap::new();
+    for (key, value) in &options.extra_env {
+        if key.is_empty() || key.contains('=') || key.contains('\0') {
+            return Err(PermissionError::Invalid(format!(
+                "Invalid MCP env var name '{}'",
+                key
+            )));
+        }
+        if RESERVED_MCP_ENV.contains(&key.as_str()) {
+            return Err(PermissionError::Invalid(format!(
+                "MCP env var '{}' is reserved",
+                key
+            )));
+        }
+        let value = value.as_str().ok_or_else(|| {
+            PermissionError::Invalid(format!(
+                "MCP env var '{}' must be a string, got {}",
+                key, value
+            ))
+        })?;
+        env.insert(key.clone(), value.to_string());
+    }
+    env.insert("PERMISSION_SERVER_PORT".to_string(), port.to_string());
+    if let Some(host) = &options.host {
+        env.insert("PERMISSION_SERVER_HOST".to_string(), host.clone());
+    }
+    env.insert("OPCODE_SESSION_ID".to_string(), session_id.to_string());
+    if let Some(name) = &options.abstract_socket {
+        // Env values can't hold the NUL prefix; the script adds it back
+        env.insert(
+            "PERMISSION_SERVER_ABSTRACT_SOCKET".to_string(),
+            name.clone(),
+        );
+    }
+    if let Some(path) = &options.socket_path {
+        env.insert(
+            "PERMISSION_SERVER_SOCKET_PATH".to_string(),
+            path.to_string_lossy().to_string(),
+        );
+    }
+    if let Some(token) = &options.auth_token {
+        env.insert("PERMISSION_AUTH_TOKEN".to_string(), token.clone());
+    }
+    if let Some(timeout) = options.client_timeout {
+        env.insert(
+            "PERMISSION_CLIENT_TIMEOUT_MS".to_string(),
+            timeout.as_millis().to_string(),
+        );
+    }
+    if let Some(cwd) = &options.cwd {
+        env.insert("OPCODE_CWD".to_string(), cwd.to_string_lossy().to_string());
+    }
+    if let Some(fingerprint) = &options.tls_fingerprint {
+        env.insert(
+            "PERMISSION_SERVER_CERT_SHA256".to_string(),
+            fingerprint.clone(),
+        );
+    }
+    if options.native_bridge.is_some() {
+        // The script has the identity written in; the bridge reads it here
+        for (key, value) in options.mcp_server.env() {
+            env.insert(key.to_string(), value);
+        }
+    }
+
+    let (command, args) = match &options.native_bridge {
+        Some(exe) => (
+            exe.to_string_lossy().to_string(),
+            vec![bridge::BRIDGE_FLAG.to_string()],
+        ),
+        None => {
+            let mut args = match options.runtime {
+                RuntimeKind::Node => options.node_args.clone(),
+                RuntimeKind::Bun => Vec::new(),
+                RuntimeKind::Deno => {
+                    if options.abstract_socket.is_some() || options.socket_path.is_some() {
+                        return Err(PermissionError::Invalid(
+                            "Deno can't connect to a Unix socket".to_string(),
+                        ));
+                    }
+                    deno_args(options.host.as_deref())
+                }
+            };
+            args.push(script_path.to_string_lossy().to_string());
+            (node_path.to_string(), args)
+        }
+    };
+
+    let mut mcp_servers = HashMap::new();
+    mcp_servers.insert(
+        options.mcp_server.name.clone(),
+        McpServer { command, args, env },
+    );
+    Ok(McpConfig { mcp_servers })
+}
+
+/// `deno run` flags giving the script what it uses and nothing more: network
+/// access to the server's host and reading its env. `--no-prompt` makes a
+/// missing permission fail rather than wait on stdin, which is the MCP pipe.
+fn deno_args(host: Option<&str>) -> Vec<String> {
+    let host = match host.unwrap_or("127.0.0.1") {
+        host if host.contains(':') => format!("[{}]", host),
+        host => host.to_string(),
+    };
+    vec![
+        "run".to_string(),
+        "--no-prompt".to_string(),
+        format!("--allow-net={}", host),
+        "--allow-env".to_string(),
+    ]
+}
+
+/// MCP file options describing how to reach a session's running server.
+pub async fn mcp_file_options(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<McpFileOptions, PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    Ok(McpFileOptions {
+        abstract_socket: entry.abstract_socket.clone(),
+        socket_path: entry.socket_path.clone(),
+        host: Some(entry.host.to_string()),
+        auth_token: Some(entry.auth_token.clone()),
+        client_timeout: longest_session_timeout(entry).map(|t| t + CLIENT_TIMEOUT_MARGIN),
+        cwd: Some(entry.cwd.to_path_buf()),
+        tls_fingerprint: entry.tls.as_ref().map(|tls| tls.fingerprint.clone()),
+        mcp_server: (*entry.mcp_server).clone(),
+        ..Default::default()
+    })
+}
+
+/// Longest label kept in MCP file names.
+const MAX_FILE_LABEL_CHARS: usize = 40;
+
+/// `(config, script)` file names. Without a usable label this is the plain
+/// `opcode-mcp-{session}` scheme.
+fn mcp_file_names(session_id: &str, label: Option<&str>) -> (String, String) {
+    let stem = match label.map(sanitize_file_label).filter(|l| !l.is_empty()) {
+        Some(label) => format!(
+            "{}-{}-{}",
+            label,
+            chrono::Local::now().format("%Y%m%d-%H%M%S"),
+            session_id
+        ),
+        None => session_id.to_string(),
+    };
+    (
+        format!("opcode-mcp-{}.json", stem),
+        format!("opcode-mcp-server-{}.js", stem),
+    )
+}
+
+/// Reduce a label to ASCII letters, digits, `-` and `_`. Everything else
+/// (path separators and dots included) becomes `_`, runs collapse, and the
+/// result is trimmed and capped.
+fn sanitize_file_label(label: &str) -> String {
+    let mut out = String::new();
+    for c in label.chars() {
+        let c = if c.is_ascii_alphanumeric() || c == '-' {
+            c
+        } else {
+            '_'
+        };
+        if c == '_' && out.ends_with('_') {
+            continue;
+        }
+        out.push(c);
+    }
+    out.trim_matches(['_', '-'])
+        .chars()
+        .take(MAX_FILE_LABEL_CHARS)
+        .collect()
+}
+
+/// The temp files written for one session. Both live in `dir`, a private
+/// directory created for the session alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct McpFiles {
+    pub dir: PathBuf,
+    pub config_path: PathBuf,
+    /// Empty when the config uses the native bridge, which needs no script.
+    pub script_path: PathBuf,
+}
+
+/// Prefix of the per-session directories in the system temp dir.
+const MCP_DIR_PREFIX: &str = "opcode-mcp-";
+
+/// Write the Node.js MCP stdio server script and its config JSON into a
+/// fresh directory with an unpredictable name, so other local users can't
+/// guess, read or swap the files. On Unix the directory is `0700` and the
+/// files `0600`.
+///
+/// `node_path` (a bare name is looked up on `PATH`) must be an executable
+/// file. It and the script are written to the config as canonical paths,
+/// each a whole `command`/`args` entry: the launcher quotes them itself, so
+/// spaces and non-ASCII names need no escaping of ours.
+pub fn generate_mcp_files(
+    port: u16,
+    session_id: &str,
+    node_path: &str,
+    options: &McpFileOptions,
+) -> Result<McpFiles, PermissionError> {
+    let node_path = match options.native_bridge {
+        Some(_) => node_path.to_string(),
+        None => resolve_node_path(node_path)?.to_string_lossy().to_string(),
+    };
+
+    // Removed again on any error below, until `keep`
+    let dir = tempfile::Builder::new().prefix(MCP_DIR_PREFIX).tempdir()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))?;
+    }
+    let dir_path = launch_path(dir.path())?;
+    let (config_name, script_name) = mcp_file_names(session_id, options.label.as_deref());
+    let script_path = match options.native_bridge {
+        Some(_) => PathBuf::new(),
+        // Deno only runs `require` in files it knows are CommonJS
+        None => dir_path
+            .join(script_name)
+            .with_extension(options.runtime.script_extension()),
+    };
+    let config_path = dir_path.join(config_name);
+
+    let config = build_mcp_config(port, session_id, &node_path, &script_path, options)?;
+    let config_json = serde_json::to_string_pretty(&config)
+        .map_err(|e| PermissionError::Invalid(format!("Failed to serialize MCP config: {}", e)))?;
+
+    // --- Node.js MCP stdio server ---
+    if options.native_bridge.is_none() {
+        write_private_file(
+            &script_path,
+            &options.mcp_server.render_script(&script_template()),
+        )?;
+    }
+
+    // --- MCP config JSON ---
+    write_private_file(&config_path, &config_json)?;
+
+    let _ = dir.keep();
+    Ok(McpFiles {
+        dir: dir_path,
+        config_path,
+        script_path,
+    })
+}
+
+/// The Node.js executable at `node_path`, or found on `PATH` for a bare
+/// name, as a canonical path. Fails before anything is written if it's
+/// missing or not executable, rather than when Claude Code tries to start it.
+fn resolve_node_path(node_path: &str) -> Result<PathBuf, PermissionError> {
+    let found = which::which(node_path).map_err(|_| {
+        PermissionError::Invalid(format!(
+            "Node.js at '{}' is missing or not executable",
+            node_path
+        ))
+    })?;
+    Ok(launch_path(&found)?)
+}
+
+/// `path` with symlinks, `..` and Windows 8.3 short names (`PROGRA~1`)
+/// resolved, as launchers get it in the MCP config.
+fn launch_path(path: &Path) -> std::io::Result<PathBuf> {
+    Ok(strip_verbatim_prefix(std::fs::canonicalize(path)?))
+}
+
+/// Undo the `\\?\` prefix Windows' canonicalize adds, which Node.js and
+/// most launchers can't handle: `\\?\C:\x` becomes `C:\x` and
+/// `\\?\UNC\host\share` becomes `\\host\share`. Other paths are returned
+/// unchanged.
+fn strip_verbatim_prefix(path: PathBuf) -> PathBuf {
+    let Some(text) = path.to_str() else {
+        return path;
+    };
+    if let Some(rest) = text.strip_prefix(r"\\?\UNC\") {
+        return PathBuf::from(format!(r"\\{}", rest));
+    }
+    match text.strip_prefix(r"\\?\") {
+        Some(rest) if rest.as_bytes().get(1) == Some(&b':') => PathBuf::from(rest),
+        _ => path,
+    }
+}
+
+/// Write a file only the current user can read and write. The contents go
+/// to a new `.tmp` sibling first and are renamed into place, so Claude Code
+/// never sees a half-written file even if we crash mid-write.
+fn write_private_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut options = std::fs::OpenOptions::new();
+    // Never write through a file someone else put there
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options.open(&tmp_path).and_then(|mut file| {
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    });
+    let result = written.and_then(|_| std::fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Node.js path found by the last successful `find_node`.
+static NODE_PATH: std::sync::Mutex<Option<String>> = std::sync::Mutex::new(None);
+
+/// `node --version` output for the Node.js at a path, so it runs once rather
+/// than for every session. `None` inside when the version couldn't be read.
+type CachedNodeVersion = Option<(String, Option<String>)>;
+static NODE_VERSION: std::sync::Mutex<CachedNodeVersion> = std::sync::Mutex::new(None);
+
+/// Locate node / node.exe on the system PATH. A hit is remembered for later
+/// sessions until `clear_node_cache`; a miss is retried every time, so Node
+/// installed mid-run is picked up.
+pub fn find_node() -> Result<String, PermissionError> {
+    let mut cached = NODE_PATH.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(path) = cached.as_ref() {
+        return Ok(path.clone());
+    }
+    let path = which::which("node")
+        .map(|p| p.to_string_lossy().to_string())
+        .map_err(|_| PermissionError::NodeNotFound)?;
+    *cached = Some(path.clone());
+    Ok(path)
+}
+
+/// JavaScript runtime that runs the MCP script. The script only needs Node's
+/// `http`, `tls` and `readline`, which Bun and Deno both provide.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeKind {
+    #[default]
+    Node,
+    Bun,
+    Deno,
+}
+
+impl RuntimeKind {
+    /// Probe order for `find_runtime`.
+    pub const ALL: [RuntimeKind; 3] = [RuntimeKind::Node, RuntimeKind::Bun, RuntimeKind::Deno];
+
+    /// Executable name looked up on PATH.
+    pub fn binary(self) -> &'static str {
+        match self {
+            RuntimeKind::Node => "node",
+            RuntimeKind::Bun => "bun",
+            RuntimeKind::Deno => "deno",
+        }
+    }
+
+    /// Parse a runtime by its binary name, e.g. from an env var.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.binary().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// Extension of the script file. Deno treats `.js` as an ES module, so it
+    /// gets `.cjs`.
+    fn script_extension(self) -> &'static str {
+        match self {
+            RuntimeKind::Deno => "cjs",
+            RuntimeKind::Node | RuntimeKind::Bun => "js",
+        }
+    }
+}
+
+/// Find a runtime for the MCP script: `force` if given, else the first of
+/// node, bun and deno on PATH. Node's path is cached as in `find_node`.
+pub fn find_runtime(force: Option<RuntimeKind>) -> Result<(RuntimeKind, String), PermissionError> {
+    let candidates = match force {
+        Some(kind) => vec![kind],
+        None => RuntimeKind::ALL.to_vec(),
+    };
+    for kind in candidates {
+        let found = match kind {
+            RuntimeKind::Node => find_node().ok(),
+            _ => which::which(kind.binary())
+                .ok()
+                .map(|p| p.to_string_lossy().to_string()),
+        };
+        if let Some(path) = found {
+            return Ok((kind, path));
+        }
+    }
+    match force {
+        Some(kind) if kind != RuntimeKind::Node => Err(PermissionError::Invalid(format!(
+            "{} was requested for permission prompts but was not found on PATH",
+            kind.binary()
+        ))),
+        _ => Err(PermissionError::NodeNotFound),
+    }
+}
+
+/// Forget the remembered Node.js path and version, e.g. after Node was
+/// reinstalled or upgraded.
+pub fn clear_node_cache() {
+    *NODE_PATH.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    *NODE_VERSION.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Oldest Node.js the MCP script is supported on. The script waits between
+/// connection retries with `timers/promises`, which 16 is the first LTS
+/// release to ship as stable.
+pub const MIN_NODE_VERSION: (u64, u64, u64) = (16, 0, 0);
+
+/// The Node.js used for the MCP script, with a warning when its version is
+/// below `MIN_NODE_VERSION` or couldn't be determined.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeStatus {
+    pub path: String,
+    pub version: Option<String>,
+    pub warning: Option<String>,
+}
+
+/// Parse `node --version` output such as `v18.19.0`. Missing minor/patch
+/// parts count as 0 and pre-release or build suffixes are ignored.
+pub fn parse_node_version(raw: &str) -> Option<(u64, u64, u64)> {
+    let raw = raw.trim();
+    let raw = raw
+        .strip_prefix('v')
+        .or_else(|| raw.strip_prefix('V'))
+        .unwrap_or(raw);
+    let core = raw.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|p| p.trim().parse::<u64>());
+    let major = parts.next()?.ok()?;
+    let minor = match parts.next() {
+        Some(p) => p.ok()?,
+        None => 0,
+    };
+    let patch = match parts.next() {
+        Some(p) => p.ok()?,
+        None => 0,
+    };
+    Some((major, minor, patch))
+}
+
+/// Compare a reported version against `MIN_NODE_VERSION`. Below the minimum
+/// is a warning unless `require_min` is set, in which case it's an error.
+pub fn check_node_version(
+    path: &str,
+    version: Option<&str>,
+    require_min: bool,
+) -> Result<NodeStatus, PermissionError> {
+    let (major, minor, patch) = MIN_NODE_VERSION;
+    let version = version.map(|v| v.trim().to_string());
+    let warning = match version.as_deref().map(|v| (v, parse_node_version(v))) {
+        Some((_, Some(found))) if found >= MIN_NODE_VERSION => None,
+        Some((raw, Some(_))) => {
+            let msg = format!(
+                "Node.js {} is older than the minimum {}.{}.{} needed for permission prompts; \
+                 please upgrade to Node.js {} or newer",
+                raw, major, minor, patch, major
+            );
+            if require_min {
+                return Err(PermissionError::Invalid(msg));
+            }
+            Some(msg)
+        }
+        Some((raw, None)) => Some(format!("Could not parse Node.js version '{}'", raw)),
+        None => Some("Could not determine the Node.js version".to_string()),
+    };
+    Ok(NodeStatus {
+        path: path.to_string(),
+        version,
+        warning,
+    })
+}
+
+/// Locate node and check its version. See `check_node_version`.
+pub fn node_status(require_min: bool) -> Result<NodeStatus, PermissionError> {
+    let path = find_node()?;
+    let version = node_version(&path);
+    let status = check_node_version(&path, version.as_deref(), require_min)?;
+    if let Some(warning) = &status.warning {
+        log::warn!("{}", warning);
+    }
+    Ok(status)
+}
+
+/// The version the Node.js at `path` reports, asked once per path until
+/// `clear_node_cache`.
+fn node_version(path: &str) -> Option<String> {
+    let mut cached = NODE_VERSION.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((cached_path, version)) = cached.as_ref() {
+        if cached_path == path {
+            return version.clone();
+        }
+    }
+    let version = std::process::Command::new(path)
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).to_string());
+    *cached = Some((path.to_string(), version.clone()));
+    version
+}
+
+/// Best-effort removal of a session's temp files and their directory.
+pub fn cleanup_temp_files(files: &McpFiles) {
+    for path in cleanup_targets(files) {
+        if path == files.dir {
+            let _ = std::fs::remove_dir_all(path);
+        } else {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// What `cleanup_temp_files` removes: the files, then their directory.
+/// Paths not set yet are skipped; abstract sockets have no file to remove.
+fn cleanup_targets(files: &McpFiles) -> Vec<PathBuf> {
+    [&files.config_path, &files.script_path, &files.dir]
+        .into_iter()
+        .filter(|path| !path.as_os_str().is_empty())
+        .cloned()
+        .collect()
+}
+
+/// Temp entries younger than this are left alone by the orphan sweep, in
+/// case another opcode instance is starting a session with them right now.
+const ORPHAN_MIN_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Remove MCP temp files left in the system temp dir by runs that crashed
+/// before `cleanup_temp_files` could run. Only our own names are touched:
+/// `opcode-mcp-*` directories holding nothing but MCP files, and loose MCP
+/// files from versions that wrote them straight into the temp dir. Anything
+/// belonging to a registered session is kept. Returns how many files and
+/// directories were reclaimed.
+pub async fn cleanup_orphaned_temp_files(registry: &PermissionServerRegistry) -> usize {
+    let owned: HashSet<PathBuf> = registry
+        .servers
+        .lock()
+        .await
+        .values()
+        .flat_map(|entry| cleanup_targets(&entry.mcp_files))
+        .collect();
+    // Session directories are recorded canonicalized, so list them that way
+    let temp_dir = std::env::temp_dir();
+    let temp_dir = launch_path(&temp_dir).unwrap_or(temp_dir);
+    let reclaimed = cleanup_orphans_in(&temp_dir, &owned, ORPHAN_MIN_AGE);
+    if reclaimed > 0 {
+        log::info!("Reclaimed {} orphaned MCP temp file(s)", reclaimed);
+    }
+    reclaimed
+}
+
+/// Whether a file name is one `generate_mcp_files` writes, including the
+/// `.tmp` sibling of an interrupted write.
+fn is_mcp_file_name(name: &str) -> bool {
+    let name = name.strip_suffix(".tmp").unwrap_or(name);
+    name.starts_with(MCP_DIR_PREFIX)
+        && (name.ends_with(".json") || name.ends_with(".js") || name.ends_with(".cjs"))
+}
+
+fn cleanup_orphans_in(dir: &Path, owned: &HashSet<PathBuf>, min_age: Duration) -> usize {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut reclaimed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !name.starts_with(MCP_DIR_PREFIX) || owned.contains(&path) {
+            continue;
+        }
+        let Ok(meta) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        let old_enough = min_age.is_zero()
+            || meta
+                .modified()
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age >= min_age);
+        if !old_enough {
+            continue;
+        }
+
+        let removed = if meta.is_dir() {
+            holds_only_mcp_files(&path) && std::fs::remove_dir_all(&path).is_ok()
+        } else {
+            meta.is_file() && is_mcp_file_name(name) && std::fs::remove_file(&path).is_ok()
+        };
+        if removed {
+            reclaimed += 1;
+        }
+    }
+    reclaimed
+}
+
+/// Whether every entry of `dir` is a plain MCP file, i.e. the directory is
+/// one of ours. An unreadable directory counts as not ours.
+fn holds_only_mcp_files(dir: &Path) -> bool {
+    let Ok(mut entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    entries.all(|entry| {
+        entry.is_ok_and(|entry| {
+            entry.file_type().is_ok_and(|t| t.is_file())
+                && entry.file_name().to_str().is_some_and(is_mcp_file_name)
+        })
+    })
+}
+
+/// The files stopping a session's server would delete, without deleting
+/// anything. Empty for unknown sessions.
+pub async fn cleanup_preview(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Vec<PathBuf> {
+    registry
+        .servers
+        .lock()
+        .await
+        .get(session_id)
+        .map(|entry| cleanup_targets(&entry.mcp_files))
+        .unwrap_or_default()
+}
+
+/// Record a session's temp files in its registry entry so cleanup works.
+pub async fn set_mcp_files(session_id: &str, files: McpFiles, registry: &PermissionServerRegistry) {
+    let mut servers = registry.servers.lock().await;
+    if let Some(entry) = servers.get_mut(session_id) {
+        entry.mcp_files = files;
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Embedded MCP script template
+// ---------------------------------------------------------------------------
+
+/// Env var naming a file to use instead of `MCP_SCRIPT_TEMPLATE`, to work on
+/// the script without rebuilding. Its placeholders are filled in the same way.
+pub const SCRIPT_OVERRIDE_ENV: &str = "OPCODE_MCP_SCRIPT_OVERRIDE";
+
+/// The script template `generate_mcp_files` renders, read fresh each time.
+fn script_template() -> std::borrow::Cow<'static, str> {
+    let path = std::env::var_os(SCRIPT_OVERRIDE_ENV)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from);
+    script_template_from(path.as_deref())
+}
+
+/// The contents of `override_path` if it's readable, else the embedded
+/// template. Logs which one is used.
+fn script_template_from(override_path: Option<&Path>) -> std::borrow::Cow<'static, str> {
+    let Some(path) = override_path else {
+        log::debug!("Using the embedded MCP script");
+        return MCP_SCRIPT_TEMPLATE.into();
+    };
+    match std::fs::read_to_string(path) {
+        Ok(script) => {
+            log::info!("Using the MCP script override at {}", path.display());
+            script.into()
+        }
+        Err(e) => {
+            log::warn!(
+                "Can't read the MCP script override at {}, using the embedded script: {}",
+                path.display(),
+                e
+            );
+            MCP_SCRIPT_TEMPLATE.into()
+        }
+    }
+}
+
+const MCP_SCRIPT_TEMPLATE: &str = r#"#!/usr/bin/env node
+"use strict";
+
+const http = require("http");
+const readline = require("readline");
+const tls = require("tls");
+const { setTimeout: sleep } = require("timers/promises");
+
+const PORT = process.env.PERMISSION_SERVER_PORT;
+const HOST = process.env.PERMISSION_SERVER_HOST || "127.0.0.1";
+const ABSTRACT_SOCKET = process.env.PERMISSION_SERVER_ABSTRACT_SOCKET || "";
+const SOCKET_PATH = process.env.PERMISSION_SERVER_SOCKET_PATH || "";
+const SESSION_ID = process.env.OPCODE_SESSION_ID || "";
+const AUTH_TOKEN = process.env.PERMISSION_AUTH_TOKEN || "";
+// How long to wait for the server's answer before denying; unset or 0 waits
+// forever. Set a little past the server's own prompt timeout.
+const CLIENT_TIMEOUT_MS = Number(process.env.PERMISSION_CLIENT_TIMEOUT_MS) || 0;
+// Set when the server speaks HTTPS: the SHA-256 fingerprint of its
+// self-signed certificate, the only one accepted.
+const CERT_SHA256 = process.env.PERMISSION_SERVER_CERT_SHA256 || "";
+
+if (!PORT && !ABSTRACT_SOCKET && !SOCKET_PATH) {
+  process.stderr.write("PERMISSION_SERVER_PORT not set\n");
+  process.exit(1);
+}
+
+// Socket files are connected to by path, and Linux abstract sockets are
+// addressed with a leading NUL byte. "localhost"
+// may resolve to ::1 while the server listens on 127.0.0.1 (or the other way
+// round), so retries go through each loopback address in turn.
+function serverAddresses() {
+  if (SOCKET_PATH) return [{ socketPath: SOCKET_PATH }];
+  if (ABSTRACT_SOCKET) return [{ socketPath: "\0" + ABSTRACT_SOCKET }];
+  const hosts = HOST === "localhost" ? ["localhost", "127.0.0.1", "::1"] : [HOST];
+  return hosts.map((hostname) => ({ hostname, port: Number(PORT) }));
+}
+const SERVER_ADDRESSES = serverAddresses();
+
+// Claude Code can start this script before the server is listening. A
+// connection that failed with one of these never reached the server, so it
+// is retried after each delay (3 attempts over ~500ms) before giving up.
+const RETRYABLE_CODES = new Set([
+  "ECONNREFUSED",
+  "ENOENT",
+  "EADDRNOTAVAIL",
+  "EHOSTUNREACH",
+  "ENETUNREACH",
+]);
+const RETRY_DELAYS_MS = [100, 400];
+
+// Tools offered to Claude, filled in by OpCode when the script is written:
+// [{ name, description, inputSchema, path, defaults, fallback }]
+const TOOLS = /*__OPCODE_MCP_TOOLS__*/[];
+// Name and version reported on initialize, filled in the same way
+const SERVER_INFO = /*__OPCODE_MCP_SERVER_INFO__*/{};
+
+// ---------- JSON-RPC helpers (newline-delimited JSON) ----------
+
+function sendResponse(id, result) {
+  const body = JSON.stringify({ jsonrpc: "2.0", id, result });
+  process.stdout.write(body + "\n");
+}
+
+function sendError(id, code, message) {
+  const body = JSON.stringify({
+    jsonrpc: "2.0",
+    id,
+    error: { code, message },
+  });
+  process.stdout.write(body + "\n");
+}
+
+// ---------- HTTP POST to OpCode permission server ----------
+
+// The body posted for a tool call: each of the tool's fields from the
+// arguments (or its default when missing), plus context, agent path and the
+// working directory.
+function buildRequest(tool, args) {
+  const request = {};
+  for (const [key, fallback] of Object.entries(tool.defaults)) {
+    request[key] = args[key] || fallback;
+  }
+  request.context = buildContext(args);
+  request.agent_path = buildAgentPath(args);
+  request.cwd = process.env.OPCODE_CWD || undefined;
+  return request;
+}
+
+// Optional transcript context. Claude Code may pass it alongside the tool
+// arguments; otherwise fall back to whatever the environment provides.
+function buildContext(args) {
+  const src = args.context || {};
+  const messageId = src.message_id || args.message_id || process.env.OPCODE_MESSAGE_ID;
+  const rawTurn = src.turn ?? args.turn ?? process.env.OPCODE_TURN;
+  const turn = rawTurn === undefined || rawTurn === "" ? undefined : Number(rawTurn);
+
+  const context = {};
+  if (messageId) context.message_id = String(messageId);
+  if (Number.isInteger(turn) && turn >= 0) context.turn = turn;
+  return Object.keys(context).length > 0 ? context : undefined;
+}
+
+// Agent hierarchy (outermost first), e.g. ["main", "research-subagent"].
+// Taken from the tool arguments when present, else OPCODE_AGENT_PATH
+// (comma-separated).
+function buildAgentPath(args) {
+  const src = args.agent_path ?? args.context?.agent_path ?? process.env.OPCODE_AGENT_PATH;
+  if (Array.isArray(src)) return src.map(String).filter(Boolean);
+  if (typeof src === "string") return src.split(",").map((s) => s.trim()).filter(Boolean);
+  return [];
+}
+
+async function postToServer(path, request) {
+  for (let attempt = 0; ; attempt++) {
+    const address = SERVER_ADDRESSES[attempt % SERVER_ADDRESSES.length];
+    try {
+      return await postOnce(address, path, request);
+    } catch (err) {
+      if (!RETRYABLE_CODES.has(err.code) || attempt >= RETRY_DELAYS_MS.length) throw err;
+      await sleep(RETRY_DELAYS_MS[attempt]);
+    }
+  }
+}
+
+// TLS connection for HTTPS servers. The self-signed certificate can't be
+// checked against a CA, so it's checked against the pinned fingerprint
+// before the request is sent.
+function pinnedConnection(options, callback) {
+  const socket = tls.connect({ ...options, rejectUnauthorized: false });
+  socket.once("secureConnect", () => {
+    if (socket.getPeerCertificate().fingerprint256 === CERT_SHA256) {
+      callback(null, socket);
+      return;
+    }
+    socket.destroy();
+    callback(new Error("Permission server certificate does not match the pinned fingerprint"));
+  });
+  socket.once("error", (err) => callback(err));
+}
+
+function postOnce(address, path, request) {
+  return new Promise((resolve, reject) => {
+    const payload = JSON.stringify(request);
+    const req = http.request(
+      {
+        ...address,
+        path,
+        method: "POST",
+        headers: {
+          "Content-Type": "application/json",
+          "Content-Length": Buffer.byteLength(payload),
+          ...(AUTH_TOKEN ? { Authorization: "Bearer " + AUTH_TOKEN } : {}),
+        },
+        ...(CERT_SHA256 ? { createConnection: pinnedConnection } : {}),
+      },
+      (res) => {
+        if (res.statusCode === 401) {
+          res.resume();
+          reject(new Error("Permission server rejected the auth token"));
+          return;
+        }
+        let data = "";
+        res.on("data", (chunk) => (data += chunk));
+        res.on("end", () => {
+          try {
+            resolve(JSON.parse(data));
+          } catch (e) {
+            reject(new Error("Invalid JSON from permission server"));
+          }
+        });
+      }
+    );
+    req.on("error", reject);
+    if (CLIENT_TIMEOUT_MS > 0) {
+      req.setTimeout(CLIENT_TIMEOUT_MS, () => {
+        req.destroy(new Error("Permission server did not answer in time"));
+      });
+    }
+    req.write(payload);
+    req.end();
+  });
+}
+
+// ---------- Handle incoming JSON-RPC messages ----------
+
+async function handleMessage(msg) {
+  const { id, method, params } = msg;
+
+  switch (method) {
+    case "initialize":
+      sendResponse(id, {
+        protocolVersion: "2025-11-25",
+        capabilities: { tools: {} },
+        serverInfo: SERVER_INFO,
+      });
+      break;
+
+    case "notifications/initialized":
+      // No response needed for notifications
+      break;
+
+    case "tools/list":
+      sendResponse(id, {
+        tools: TOOLS.map(({ name, description, inputSchema }) => ({
+          name,
+          description,
+          inputSchema,
+        })),
+      });
+      break;
+
+    case "tools/call": {
+      const toolName = params?.name;
+      const tool = TOOLS.find((t) => t.name === toolName);
+      if (!tool) {
+        sendError(id, -32601, "Unknown tool: " + toolName);
+        return;
+      }
+
+      const args = params?.arguments || {};
+      let result;
+      try {
+        result = await postToServer(tool.path, buildRequest(tool, args));
+      } catch (err) {
+        // On error, answer with the tool's fallback (a deny for permissions)
+        result = tool.fallback;
+      }
+      sendResponse(id, {
+        content: [{ type: "text", text: JSON.stringify(result) }],
+      });
+      break;
+    }
+
+    default:
+      if (id !== undefined) {
+        sendError(id, -32601, "Method not found: " + method);
+      }
+      break;
+  }
+}
+
+// ---------- Stdin reader (newline-delimited JSON) ----------
+
+const rl = readline.createInterface({ input: process.stdin, terminal: false });
+
+rl.on("line", (line) => {
+  if (!line.trim()) return;
+  try {
+    const msg = JSON.parse(line);
+    handleMessage(msg).catch((err) => {
+      process.stderr.write("Error handling message: " + err.message + "\n");
+    });
+  } catch (e) {
+    process.stderr.write("Failed to parse JSON-RPC message: " + e.message + "\n");
+  }
+});
+
+rl.on("close", () => {
+  process.exit(0);
+});
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::super::stop_server;
+    use super::super::testing::*;
+    use super::*;
+
+    #[test]
+    fn test_mcp_config_round_trips_with_expected_keys() {
+        let config = build_mcp_config(
+            4321,
+            "session-1",
+            "/usr/bin/node",
+            Path::new("/tmp/opcode-mcp-server-session-1.js"),
+            &McpFileOptions::default(),
+        )
+        .unwrap();
+
+        let json = serde_json::to_value(&config).unwrap();
+        let server = &json["mcpServers"]["opcode"];
+        assert_eq!(server["command"], "/usr/bin/node");
+        assert_eq!(server["args"][0], "/tmp/opcode-mcp-server-session-1.js");
+        assert_eq!(server["env"]["PERMISSION_SERVER_PORT"], "4321");
+        assert_eq!(server["env"]["OPCODE_SESSION_ID"], "session-1");
+        assert!(server["env"].get("PERMISSION_AUTH_TOKEN").is_none());
+        assert!(server["env"].get("PERMISSION_CLIENT_TIMEOUT_MS").is_none());
+
+        let text = serde_json::to_string_pretty(&config).unwrap();
+        let parsed: McpConfig = serde_json::from_str(&text).unwrap();
+        assert_eq!(parsed, config);
+    }
+
+    #[test]
+    fn test_non_string_env_value_is_an_error() {
+        let script = Path::new("/tmp/opcode-mcp-server-session-1.js");
+        let mut options = McpFileOptions::default();
+        options.extra_env.insert(
+            "NODE_OPTIONS".to_string(),
+            serde_json::json!("--no-warnings"),
+        );
+        let config = build_mcp_config(1, "session-1", "node", script, &options).unwrap();
+        assert_eq!(
+            config.mcp_servers["opcode"].env["NODE_OPTIONS"],
+            "--no-warnings"
+        );
+
+        options
+            .extra_env
+            .insert("RETRIES".to_string(), serde_json::json!(3));
+        let err =
+            generate_mcp_files(1, "session-bad-env", &test_node_path(), &options).unwrap_err();
+        assert!(
+            err.to_string().contains("RETRIES"),
+            "unexpected error: {}",
+            err
+        );
+        // The session's directory went away with the error
+        let leftover = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(Result::ok)
+            .any(|dir| dir.path().join("opcode-mcp-session-bad-env.json").exists());
+        assert!(!leftover);
+
+        let mut options = McpFileOptions::default();
+        options.extra_env.insert(
+            "OPCODE_SESSION_ID".to_string(),
+            serde_json::json!("spoofed"),
+        );
+        assert!(build_mcp_config(1, "session-1", "node", script, &options).is_err());
+    }
+
+    #[test]
+    fn test_node_version_against_minimum() {
+        assert_eq!(parse_node_version("v18.19.0\n"), Some((18, 19, 0)));
+        assert_eq!(parse_node_version("v20.0.0-nightly2023"), Some((20, 0, 0)));
+        assert_eq!(parse_node_version("16"), Some((16, 0, 0)));
+        assert_eq!(parse_node_version("vx.1"), None);
+
+        // Below: a warning, or an error when the minimum is required
+        let below = check_node_version("node", Some("v14.21.3"), false).unwrap();
+        let warning = below.warning.unwrap();
+        assert!(warning.contains("older than the minimum 16.0.0"));
+        assert!(warning.contains("Node.js 16 or newer"));
+        for version in ["v12.22.12", "v14.21.3", "v15.14.0"] {
+            assert!(check_node_version("node", Some(version), true).is_err());
+        }
+
+        // At and above: no warning even when required
+        for version in ["v16.0.0", "v18.19.0", "v22.3.1"] {
+            let status = check_node_version("node", Some(version), true).unwrap();
+            assert_eq!(status.warning, None, "{}", version);
+            assert_eq!(status.version.as_deref(), Some(version));
+        }
+
+        // Unknown versions warn but never fail
+        let unknown = check_node_version("node", None, true).unwrap();
+        assert!(unknown.warning.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_preview_matches_removed_files() {
+        let registry = PermissionServerRegistry::default();
+        insert_test_entry(&registry, "session-1").await;
+        assert!(cleanup_preview("session-1", &registry).await.is_empty());
+
+        let files = generate_mcp_files(
+            1,
+            "session-1",
+            &test_node_path(),
+            &McpFileOptions::default(),
+        )
+        .unwrap();
+        set_mcp_files("session-1", files.clone(), &registry).await;
+
+        let preview = cleanup_preview("session-1", &registry).await;
+        assert_eq!(
+            preview,
+            vec![files.config_path, files.script_path, files.dir]
+        );
+        // Previewing deletes nothing
+        assert!(preview.iter().all(|path| path.exists()));
+
+        stop_server("session-1", &registry).await;
+        assert!(preview.iter().all(|path| !path.exists()));
+        assert!(cleanup_preview("session-1", &registry).await.is_empty());
+    }
+
+    #[test]
+    fn test_mcp_file_label_is_sanitized() {
+        assert_eq!(
+            mcp_file_names("abc", None),
+            (
+                "opcode-mcp-abc.json".to_string(),
+                "opcode-mcp-server-abc.js".to_string()
+            )
+        );
+        // Nothing usable left: back to the default scheme
+        assert_eq!(
+            mcp_file_names("abc", Some("/../")),
+            mcp_file_names("abc", None)
+        );
+
+        let (config, script) = mcp_file_names("abc", Some("../my proj\\x:*?<>|"));
+        for name in [&config, &script] {
+            assert!(
+                name.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)),
+                "{}",
+                name
+            );
+            assert!(!name.contains(".."), "{}", name);
+            assert!(name.contains("my_proj_x-"), "{}", name);
+            assert!(name.ends_with("-abc.json") || name.ends_with("-abc.js"));
+        }
+        assert_eq!(
+            std::env::temp_dir().join(&script).parent(),
+            Some(std::env::temp_dir().as_path())
+        );
+    }
+
+    #[test]
+    fn test_native_bridge_config_needs_no_script() {
+        let options = McpFileOptions {
+            native_bridge: Some(PathBuf::from("/opt/opcode/opcode")),
+            node_args: vec!["--no-warnings".to_string()],
+            ..Default::default()
+        };
+        let files = generate_mcp_files(4312, "session-native", "", &options).unwrap();
+        let McpFiles {
+            config_path,
+            script_path,
+            ..
+        } = files.clone();
+        assert!(script_path.as_os_str().is_empty());
+        assert_eq!(
+            cleanup_targets(&files),
+            vec![config_path.clone(), files.dir.clone()]
+        );
+
+        let config: McpConfig =
+            serde_json::from_str(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+        let server = &config.mcp_servers["opcode"];
+        assert_eq!(server.command, "/opt/opcode/opcode");
+        assert_eq!(server.args, vec![bridge::BRIDGE_FLAG.to_string()]);
+        assert_eq!(server.env["PERMISSION_SERVER_PORT"], "4312");
+        cleanup_temp_files(&files);
+        assert!(!files.dir.exists());
+    }
+
+    #[test]
+    fn test_found_node_path_is_cached_until_cleared() {
+        *NODE_PATH.lock().unwrap() = Some("/opt/node/bin/node".to_string());
+        assert_eq!(find_node().unwrap(), "/opt/node/bin/node");
+
+        *NODE_VERSION.lock().unwrap() = Some(("/opt/node/bin/node".to_string(), None));
+        assert_eq!(node_version("/opt/node/bin/node"), None);
+        let status = node_status(true).unwrap();
+        assert_eq!(status.version, None);
+
+        *NODE_VERSION.lock().unwrap() = Some((
+            "/opt/node/bin/node".to_string(),
+            Some("v12.0.0".to_string()),
+        ));
+        assert!(node_status(true).is_err());
+
+        clear_node_cache();
+        assert!(NODE_PATH.lock().unwrap().is_none());
+        assert!(NODE_VERSION.lock().unwrap().is_none());
+        // Only a successful lookup is remembered
+        match find_node() {
+            Ok(path) => assert_eq!(NODE_PATH.lock().unwrap().as_deref(), Some(path.as_str())),
+            Err(_) => assert!(NODE_PATH.lock().unwrap().is_none()),
+        }
+    }
+
+    #[test]
+    fn test_mcp_files_are_private_and_unpredictable() {
+        let options = McpFileOptions::default();
+        let first = generate_mcp_files(1, "session-1", &test_node_path(), &options).unwrap();
+        let second = generate_mcp_files(1, "session-1", &test_node_path(), &options).unwrap();
+        assert_ne!(first.dir, second.dir);
+        assert_eq!(first.config_path.parent(), Some(first.dir.as_path()));
+        assert_eq!(first.script_path.parent(), Some(first.dir.as_path()));
+        assert!(first
+            .dir
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(MCP_DIR_PREFIX));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&first.dir), 0o700);
+            assert_eq!(mode(&first.config_path), 0o600);
+            assert_eq!(mode(&first.script_path), 0o600);
+        }
+
+        // Written through temp siblings that were renamed into place
+        let mut names: Vec<_> = std::fs::read_dir(&first.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        names.sort();
+        let mut expected = vec![first.config_path.clone(), first.script_path.clone()];
+        expected.sort();
+        assert_eq!(names, expected);
+        let script = std::fs::read_to_string(&first.script_path).unwrap();
+        assert!(script.contains("permission_prompt") && script.contains("ask_user"));
+        assert!(!script.contains(tools::TOOLS_PLACEHOLDER));
+
+        for files in [first, second] {
+            cleanup_temp_files(&files);
+            assert!(!files.dir.exists());
+        }
+    }
+
+    #[test]
+    fn test_cleanup_orphans_only_removes_our_files() {
+        let base = tempfile::tempdir().unwrap();
+        let make_dir = |name: &str, files: &[&str]| {
+            let dir = base.path().join(name);
+            std::fs::create_dir(&dir).unwrap();
+            for file in files {
+                std::fs::write(dir.join(file), "{}").unwrap();
+            }
+            dir
+        };
+        let orphan = make_dir(
+            "opcode-mcp-a1b2c3",
+            &["opcode-mcp-s1.json", "opcode-mcp-server-s1.js"],
+        );
+        let interrupted = make_dir("opcode-mcp-d4e5f6", &["opcode-mcp-s2.json.tmp"]);
+        let foreign = make_dir("opcode-mcp-notes", &["opcode-mcp-s3.json", "todo.txt"]);
+        let owned = make_dir("opcode-mcp-g7h8i9", &["opcode-mcp-s4.json"]);
+        let legacy = base.path().join("opcode-mcp-server-s5.js");
+        let unrelated = base.path().join("opcode-mcp-readme.md");
+        let other = base.path().join("other-tool.json");
+        for file in [&legacy, &unrelated, &other] {
+            std::fs::write(file, "x").unwrap();
+        }
+
+        // Nothing is old enough yet
+        let owned_paths = HashSet::from([owned.clone()]);
+        assert_eq!(
+            cleanup_orphans_in(base.path(), &owned_paths, ORPHAN_MIN_AGE),
+            0
+        );
+        assert!(orphan.exists());
+
+        assert_eq!(
+            cleanup_orphans_in(base.path(), &owned_paths, Duration::ZERO),
+            3
+        );
+        assert!(!orphan.exists());
+        assert!(!interrupted.exists());
+        assert!(!legacy.exists());
+        assert!(foreign.join("todo.txt").exists());
+        assert!(owned.join("opcode-mcp-s4.json").exists());
+        assert!(unrelated.exists());
+        assert!(other.exists());
+    }
+
+    #[test]
+    fn test_tls_fingerprint_reaches_mcp_env() {
+        let options = McpFileOptions {
+            tls_fingerprint: Some("AB:CD".to_string()),
+            ..Default::default()
+        };
+        let config = build_mcp_config(1, "session-1", "node", Path::new("s.js"), &options).unwrap();
+        assert_eq!(
+            config.mcp_servers["opcode"].env["PERMISSION_SERVER_CERT_SHA256"],
+            "AB:CD"
+        );
+
+        let spoofed = McpFileOptions {
+            extra_env: BTreeMap::from([(
+                "PERMISSION_SERVER_CERT_SHA256".to_string(),
+                serde_json::json!("00:00"),
+            )]),
+            ..Default::default()
+        };
+        assert!(build_mcp_config(1, "session-1", "node", Path::new("s.js"), &spoofed).is_err());
+    }
+
+    #[test]
+    fn test_custom_mcp_server_name() {
+        let identity = ServerIdentity {
+            name: "acme".to_string(),
+            description: Some("Ask Acme first.".to_string()),
+        };
+        let options = McpFileOptions {
+            mcp_server: identity.clone(),
+            ..Default::default()
+        };
+        let files = generate_mcp_files(1, "session-acme", &test_node_path(), &options).unwrap();
+        let config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&files.config_path).unwrap()).unwrap();
+        assert!(config["mcpServers"]["acme"].is_object());
+        assert!(config["mcpServers"].get("opcode").is_none());
+        let script = std::fs::read_to_string(&files.script_path).unwrap();
+        assert!(script.contains(r#"const SERVER_INFO = {"name":"acme-permission-prompt""#));
+        assert!(script.contains("Ask Acme first."));
+        cleanup_temp_files(&files);
+
+        // Only the bridge needs the identity in its env
+        let bridged = McpFileOptions {
+            native_bridge: Some(PathBuf::from("/opt/opcode")),
+            ..options.clone()
+        };
+        let config = build_mcp_config(1, "session-acme", "", Path::new(""), &bridged).unwrap();
+        assert_eq!(
+            config.mcp_servers["acme"].env[tools::SERVER_NAME_ENV],
+            "acme"
+        );
+        let config =
+            build_mcp_config(1, "session-acme", "node", Path::new("s.js"), &options).unwrap();
+        assert!(!config.mcp_servers["acme"]
+            .env
+            .contains_key(tools::SERVER_NAME_ENV));
+
+        let invalid = McpFileOptions {
+            mcp_server: ServerIdentity {
+                name: "my server".to_string(),
+                description: None,
+            },
+            ..Default::default()
+        };
+        assert!(build_mcp_config(1, "session-acme", "node", Path::new("s.js"), &invalid).is_err());
+    }
+
+    #[test]
+    fn test_mcp_paths_with_spaces_and_unicode() {
+        let root = tempfile::tempdir().unwrap();
+        let bin = root.path().join("Program Files").join("nödé ✓");
+        std::fs::create_dir_all(&bin).unwrap();
+        let node = bin.join(if cfg!(windows) { "node.exe" } else { "node" });
+        std::fs::write(&node, "").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&node, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let options = McpFileOptions::default();
+        let files = generate_mcp_files(1, "session-1", &node.to_string_lossy(), &options).unwrap();
+        let config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&files.config_path).unwrap()).unwrap();
+        let server = &config["mcpServers"]["opcode"];
+        // Written as-is, one entry each, for the launcher to quote
+        let command = server["command"].as_str().unwrap();
+        assert_eq!(Path::new(command), launch_path(&node).unwrap());
+        assert!(command.contains("Program Files") && command.contains("nödé ✓"));
+        let args = server["args"].as_array().unwrap();
+        assert_eq!(args.len(), 1);
+        assert_eq!(Path::new(args[0].as_str().unwrap()), files.script_path);
+        assert!(files.script_path.is_absolute() && files.script_path.exists());
+        cleanup_temp_files(&files);
+
+        let missing = bin.join("missing-node");
+        assert!(generate_mcp_files(1, "session-1", &missing.to_string_lossy(), &options).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&node, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(generate_mcp_files(1, "session-1", &node.to_string_lossy(), &options).is_err());
+        }
+    }
+
+    #[test]
+    fn test_strip_verbatim_prefix() {
+        let strip = |path: &str| strip_verbatim_prefix(PathBuf::from(path));
+        assert_eq!(
+            strip(r"\\?\C:\Program Files\x"),
+            PathBuf::from(r"C:\Program Files\x")
+        );
+        assert_eq!(
+            strip(r"\\?\UNC\host\share\x"),
+            PathBuf::from(r"\\host\share\x")
+        );
+        assert_eq!(
+            strip(r"\\?\Volume{abc}\x"),
+            PathBuf::from(r"\\?\Volume{abc}\x")
+        );
+        assert_eq!(strip("/tmp/nödé dir"), PathBuf::from("/tmp/nödé dir"));
+    }
+
+    #[test]
+    fn test_runtime_command_lines() {
+        let script = Path::new("/tmp/opcode-mcp-server-s1.cjs");
+        let options = McpFileOptions {
+            runtime: RuntimeKind::Deno,
+            node_args: vec!["--no-warnings".to_string()],
+            ..Default::default()
+        };
+        let config = build_mcp_config(1, "s1", "/usr/bin/deno", script, &options).unwrap();
+        assert_eq!(
+            config.mcp_servers["opcode"].args,
+            [
+                "run",
+                "--no-prompt",
+                "--allow-net=127.0.0.1",
+                "--allow-env",
+                "/tmp/opcode-mcp-server-s1.cjs"
+            ]
+        );
+        let ipv6 = McpFileOptions {
+            host: Some("::1".to_string()),
+            ..options.clone()
+        };
+        let config = build_mcp_config(1, "s1", "deno", script, &ipv6).unwrap();
+        assert_eq!(config.mcp_servers["opcode"].args[2], "--allow-net=[::1]");
+        let abstract_socket = McpFileOptions {
+            abstract_socket: Some("opcode-s1".to_string()),
+            ..options.clone()
+        };
+        assert!(build_mcp_config(1, "s1", "deno", script, &abstract_socket).is_err());
+
+        let bun = McpFileOptions {
+            runtime: RuntimeKind::Bun,
+            ..options.clone()
+        };
+        let config = build_mcp_config(1, "s1", "/usr/bin/bun", script, &bun).unwrap();
+        assert_eq!(config.mcp_servers["opcode"].command, "/usr/bin/bun");
+        assert_eq!(
+            config.mcp_servers["opcode"].args,
+            ["/tmp/opcode-mcp-server-s1.cjs"]
+        );
+
+        let files = generate_mcp_files(1, "s1", &test_node_path(), &options).unwrap();
+        assert_eq!(files.script_path.extension().unwrap(), "cjs");
+        assert!(is_mcp_file_name(
+            &files.script_path.file_name().unwrap().to_string_lossy()
+        ));
+        cleanup_temp_files(&files);
+    }
+
+    #[test]
+    fn test_runtime_kind_names() {
+        assert_eq!(RuntimeKind::from_name("bun"), Some(RuntimeKind::Bun));
+        assert_eq!(RuntimeKind::from_name(" Deno "), Some(RuntimeKind::Deno));
+        assert_eq!(RuntimeKind::from_name("python"), None);
+        assert_eq!(
+            serde_json::to_value(RuntimeKind::Node).unwrap(),
+            serde_json::json!("node")
+        );
+        if let Ok(path) = find_node() {
+            assert_eq!(
+                find_runtime(Some(RuntimeKind::Node)).unwrap(),
+                (RuntimeKind::Node, path)
+            );
+        }
+    }
+
+    #[test]
+    fn test_script_override_replaces_the_embedded_template() {
+        assert_eq!(script_template_from(None), MCP_SCRIPT_TEMPLATE);
+
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.js");
+        assert_eq!(script_template_from(Some(&missing)), MCP_SCRIPT_TEMPLATE);
+
+        let path = dir.path().join("bridge.js");
+        std::fs::write(&path, "// patched\n").unwrap();
+        let script = script_template_from(Some(&path));
+        assert_eq!(script, "// patched\n");
+        // Edits show up on the next read, without a rebuild
+        std::fs::write(&path, "// patched again\n").unwrap();
+        assert_eq!(script_template_from(Some(&path)), "// patched again\n");
+    }
+}
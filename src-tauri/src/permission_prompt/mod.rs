@@ -0,0 +1,8092 @@
+use axum::{
+    extract::{DefaultBodyLimit, State as AxumState},
+    http::{header, HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::{broadcast, oneshot, watch, Mutex, OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+use audit::AuditLog;
+use batch::handle_permission_batch;
+pub use batch::{resolve_batch, PermissionBatchEvent, PermissionBatchRequest, ToolInvocation};
+pub use controller::{controller_token, ResolveRequest};
+use controller::{handle_pending, handle_resolve};
+pub use decisions::PermissionMetrics;
+use decisions::{DecisionCounters, DecisionLog, DecisionRecord, DecisionSource};
+pub use error::PermissionError;
+pub use explain::RiskCategory;
+pub use lifecycle::{
+    reap_idle_servers, spawn_idle_reaper, stop_all_servers, stop_server, stop_server_draining,
+    stop_server_graceful, ServerLifecycleEvent, ServerReapedEvent, SessionSummaryEvent, StopResult,
+    DEFAULT_IDLE_TIMEOUT, SERVER_CLOSING_MESSAGE, SHUTDOWN_TIMEOUT,
+};
+pub use mcp_files::{
+    check_node_version, cleanup_orphaned_temp_files, cleanup_preview, cleanup_temp_files,
+    clear_node_cache, find_node, find_runtime, generate_mcp_files, mcp_file_options, node_status,
+    parse_node_version, set_mcp_files, McpConfig, McpFileOptions, McpFiles, McpServer, NodeStatus,
+    RuntimeKind, CLIENT_TIMEOUT_MARGIN, MIN_NODE_VERSION, SCRIPT_OVERRIDE_ENV,
+};
+use rules::{
+    ConflictPolicy, DefaultOutcome, PermissionPolicy, PermissionRule, PriorAllow, RuleAction,
+    RuleEngineState, RuleEvaluation, RuleScope, RuleSet, RuleTarget, ScopeDefault,
+};
+pub use tool_lists::{
+    add_allowed_tool, allow_command, block_tool, disallow_command, remove_allowed_tool,
+    unblock_tool,
+};
+use tool_lists::{allowed_decision, blocked_decision};
+pub use tools::ServerIdentity;
+pub use transforms::InputTransform;
+
+pub mod audit;
+pub mod batch;
+pub mod bridge;
+pub mod controller;
+pub mod decisions;
+pub mod error;
+pub mod explain;
+pub mod lifecycle;
+pub mod log_echo;
+pub mod mcp_files;
+pub mod redact;
+pub mod rules;
+pub mod tls;
+pub mod tool_lists;
+pub mod tools;
+pub mod transforms;
+pub mod ws;
+
+#[cfg(test)]
+mod testing;
+
+// ---------------------------------------------------------------------------
+// Data structures
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermissionRequest {
+    pub tool_use_id: String,
+    pub tool_name: String,
+    pub input: serde_json::Value,
+    /// Transcript context forwarded by the MCP script, when available.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<PromptContext>,
+    /// Agent hierarchy the request came from, outermost first
+    /// (e.g. `["main", "research-subagent"]`). Empty when unknown.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub agent_path: Vec<String>,
+    /// Directory the tool runs in, as the MCP script reports it
+    /// (`OPCODE_CWD`). The session's cwd is used when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+}
+
+/// Body of a `/permission-prompt` call. A `batch` field selects the batched
+/// form; anything else is a single request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PermissionPayload {
+    Batch(PermissionBatchRequest),
+    Single(PermissionRequest),
+}
+
+/// Reply matching the shape of the request: an object for a single request,
+/// an array for a batch.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum PermissionReply {
+    Single(PermissionResponse),
+    Batch(Vec<PermissionResponse>),
+}
+
+/// Body of a `/user-input` call: a free-text question from the `ask_user`
+/// MCP tool.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserInputRequest {
+    pub question: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<PromptContext>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub agent_path: Vec<String>,
+}
+
+/// Emitted as `user-input-request` when Claude asks the user a question.
+/// Answered with `resolve_user_input`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserInputRequestEvent {
+    pub request_id: String,
+    pub session_id: String,
+    pub question: String,
+    /// Unix time in milliseconds when the question was asked.
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<PromptContext>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub agent_path: Vec<String>,
+}
+
+/// Reply to an `ask_user` call. No `answer` means the user dismissed the
+/// question (or it timed out), with `message` saying why.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserInputResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl UserInputResponse {
+    fn unanswered(message: &str) -> Self {
+        Self {
+            answer: None,
+            message: Some(message.to_string()),
+        }
+    }
+}
+
+/// Conversation context a prompt arose from, so the UI can link it back to
+/// the transcript. Every field is optional; the MCP script only forwards what
+/// Claude Code (or its environment) actually provides.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PromptContext {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub turn: Option<u64>,
+}
+
+/// Response sent back to the MCP script. Claude Code expects either:
+///   `{ "behavior": "allow", "updatedInput": {...} }`
+///   `{ "behavior": "deny",  "message": "..." }`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermissionResponse {
+    pub behavior: String,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "updatedInput")]
+    pub updated_input: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Payload emitted to the frontend via Tauri event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermissionPromptEvent {
+    pub prompt_id: String,
+    pub session_id: String,
+    /// Claude Code's ID for the tool call, matching the `tool_use` block in
+    /// the transcript. Empty for test prompts.
+    #[serde(default)]
+    pub tool_use_id: String,
+    pub tool_name: String,
+    /// When the request arrived, in Unix milliseconds.
+    #[serde(default)]
+    pub created_at: u64,
+    pub input: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<PromptContext>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub agent_path: Vec<String>,
+    /// Set when the tool's auto-edit changed the input: the edited input the
+    /// dialog should offer by default (same as `input`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suggested_input: Option<serde_json::Value>,
+    /// The input as Claude Code sent it, so the user can revert an auto-edit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_input: Option<serde_json::Value>,
+    /// The request's main target (file path, command, URL, ...).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    /// Plain-language sentence describing the request, for tools we know.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<String>,
+    /// What kind of access the request asks for.
+    #[serde(default)]
+    pub risk_category: RiskCategory,
+    /// Set for prompts created by `inject_test_prompt` rather than Claude Code.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub test: bool,
+    /// Set when the session is in inspect mode: the request was already
+    /// allowed and the event is only for show, with nothing to answer.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub inspect: bool,
+    /// Set when fields were dropped to fit the registry's event size limit;
+    /// the full event is available from `get_prompt_event`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Set when `input` is a shortened preview because the real input was
+    /// over the registry's input size limit. `get_full_input` returns the
+    /// real one, and an allow without edits sends it to Claude Code.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub input_truncated: bool,
+    /// Serialized size of the real input, in bytes, when `input_truncated`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_bytes: Option<usize>,
+    /// Directory the tool would run in, e.g. to show "Bash in ~/project".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// How long the prompt waits before it times out, in milliseconds, for
+    /// a countdown. Unset when it waits forever.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Default cap on a serialized prompt event, in bytes.
+pub const DEFAULT_MAX_EVENT_BYTES: usize = 256 * 1024;
+
+/// Longest summary kept when even the essentials exceed the size limit.
+const TRIMMED_SUMMARY_CHARS: usize = 200;
+
+/// Default cap on a serialized tool input in an emitted event, in bytes.
+pub const DEFAULT_MAX_INPUT_BYTES: usize = 64 * 1024;
+
+/// Longest string kept in an input preview.
+const INPUT_PREVIEW_CHARS: usize = 1000;
+
+/// Preview of a tool input whose serialized size is over `max_bytes`: every
+/// long string is cut short, or if that isn't enough the input is dropped
+/// (`Null`). `None` if the input fits; `max_bytes` 0 is unlimited.
+fn input_preview(input: &serde_json::Value, max_bytes: usize) -> Option<serde_json::Value> {
+    fn shorten(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(text) => {
+                if let Some((end, _)) = text.char_indices().nth(INPUT_PREVIEW_CHARS) {
+                    text.truncate(end);
+                    text.push('…');
+                }
+            }
+            serde_json::Value::Array(items) => items.iter_mut().for_each(shorten),
+            serde_json::Value::Object(fields) => fields.values_mut().for_each(shorten),
+            _ => {}
+        }
+    }
+    let size = |value: &serde_json::Value| serde_json::to_vec(value).map_or(0, |b| b.len());
+
+    if max_bytes == 0 || size(input) <= max_bytes {
+        return None;
+    }
+    let mut preview = input.clone();
+    shorten(&mut preview);
+    if size(&preview) > max_bytes {
+        preview = serde_json::Value::Null;
+    }
+    Some(preview)
+}
+
+impl PermissionPromptEvent {
+    /// Swap an input over `max_bytes` (and the auto-edit copies) for a
+    /// preview, flagging it `input_truncated`. Returns the event unchanged if
+    /// the input fits.
+    fn with_input_limit(mut self, max_bytes: usize) -> Self {
+        let Some(preview) = input_preview(&self.input, max_bytes) else {
+            return self;
+        };
+        self.input_bytes = serde_json::to_vec(&self.input).ok().map(|b| b.len());
+        self.input = preview;
+        self.input_truncated = true;
+        for copy in [&mut self.suggested_input, &mut self.original_input]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(preview) = input_preview(copy, max_bytes) {
+                *copy = preview;
+            }
+        }
+        self
+    }
+
+    /// Shrink the event to at most `max_bytes` when serialized, dropping the
+    /// least essential fields first: explanation, agent path, context, the
+    /// auto-edit copies, then input. The ID, tool name and summary are always
+    /// kept (the summary is shortened as a last resort). Returns the event
+    /// unchanged if it fits.
+    fn trimmed_to(mut self, max_bytes: usize) -> Self {
+        fn fits(event: &PermissionPromptEvent, max_bytes: usize) -> bool {
+            serde_json::to_vec(event)
+                .map(|bytes| bytes.len() <= max_bytes)
+                .unwrap_or(false)
+        }
+        let drops: [fn(&mut PermissionPromptEvent); 6] = [
+            |e| e.explanation = None,
+            |e| e.agent_path.clear(),
+            |e| e.context = None,
+            |e| e.original_input = None,
+            |e| e.suggested_input = None,
+            |e| e.input = serde_json::Value::Null,
+        ];
+
+        for trim in drops {
+            if fits(&self, max_bytes) {
+                return self;
+            }
+            trim(&mut self);
+            self.truncated = true;
+        }
+        if !fits(&self, max_bytes) {
+            if let Some(summary) = &mut self.summary {
+                if let Some((end, _)) = summary.char_indices().nth(TRIMMED_SUMMARY_CHARS) {
+                    summary.truncate(end);
+                    summary.push('…');
+                }
+            }
+        }
+        self
+    }
+}
+
+/// Per-server options passed to `start_server`.
+#[derive(Debug, Clone, Default)]
+pub struct PermissionServerConfig {
+    /// Working directory of the session. Relative paths in tool inputs are
+    /// resolved against it; falls back to the process cwd when unset.
+    pub cwd: Option<PathBuf>,
+    /// How long a prompt waits for an answer before it is denied. Defaults to
+    /// `DEFAULT_PROMPT_TIMEOUT`; `Duration::ZERO` waits forever.
+    pub prompt_timeout: Option<Duration>,
+    /// Timeouts for particular tools, by tool name, overriding
+    /// `prompt_timeout` (e.g. longer for `Bash` builds than for `Read`).
+    /// `Duration::ZERO` waits forever.
+    pub tool_timeouts: HashMap<String, Duration>,
+    /// What an unanswered prompt turns into when it times out.
+    pub timeout_behavior: TimeoutBehavior,
+    /// How the MCP script reaches the server.
+    pub transport: ServerTransport,
+    /// How long after start a session may go without any request before it
+    /// is flagged as a likely MCP handshake failure. Defaults to
+    /// `DEFAULT_HANDSHAKE_GRACE`.
+    pub handshake_grace: Option<Duration>,
+    /// Most decisions the session may make before every further request is
+    /// denied. `None` (the default) is unlimited.
+    pub max_decisions: Option<u64>,
+    /// Most prompts the session shows at once. `None` (the default) and 0
+    /// are unlimited.
+    pub max_concurrent: Option<usize>,
+    /// What a prompt does when `max_concurrent` are already showing.
+    pub overflow_behavior: OverflowBehavior,
+    /// How fast the session may send permission requests; faster ones are
+    /// denied unseen. `None` (the default) is unlimited.
+    pub rate_limit: Option<RateLimit>,
+    /// Policy file whose rules answer this session's requests before the
+    /// shared rules (see `rules::load_policy`).
+    pub policy_path: Option<PathBuf>,
+    /// Largest request body the server reads, in bytes; bigger ones get
+    /// `413 Payload Too Large`. Defaults to `DEFAULT_MAX_BODY_BYTES`.
+    pub max_body_bytes: Option<usize>,
+    /// TCP port to listen on, for firewalls that only allow specific ports.
+    /// A random port is used when unset or when this one is taken.
+    pub preferred_port: Option<u16>,
+    /// Serve HTTPS with a self-signed certificate the MCP script pins (see
+    /// `tls`). TCP only; plain HTTP when unset.
+    pub tls: bool,
+    /// Start in inspect mode (see `set_inspect_mode`).
+    pub inspect_mode: bool,
+    /// Name and description the session's MCP server goes by.
+    pub mcp_server: ServerIdentity,
+}
+
+/// Grace period used when the server config doesn't set one.
+pub const DEFAULT_HANDSHAKE_GRACE: Duration = Duration::from_secs(120);
+
+/// Request body cap used when the server config doesn't set one.
+pub const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// How a prompt that nobody answered in time is resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeoutBehavior {
+    #[default]
+    Deny,
+    /// Allow with the input as shown in the dialog (after any auto-edit).
+    Allow,
+    /// Allow with the input exactly as Claude Code sent it.
+    AllowWithOriginalInput,
+}
+
+/// What a new prompt does while the session is at its `max_concurrent`
+/// limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowBehavior {
+    /// Wait for a slot, then prompt.
+    #[default]
+    Queue,
+    /// Deny right away with `TOO_MANY_PENDING_MESSAGE`.
+    Deny,
+}
+
+/// Deny message for prompts refused under `OverflowBehavior::Deny`.
+pub const TOO_MANY_PENDING_MESSAGE: &str = "Too many pending permission requests";
+
+/// Deny message for prompts whose event couldn't be delivered to the
+/// frontend at all.
+pub const FRONTEND_UNREACHABLE_MESSAGE: &str = "Frontend unreachable";
+
+/// Listener the permission server binds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ServerTransport {
+    /// A TCP port on loopback: `preferred_port` if set and free, else a
+    /// random one.
+    #[default]
+    Tcp,
+    /// A Linux abstract-namespace Unix socket. There is no socket file, so
+    /// nothing needs cleaning up and nothing on disk can be hijacked. Only
+    /// Linux has abstract sockets; other platforms fall back to TCP.
+    AbstractSocket,
+    /// A Unix domain socket file, readable only by us, in a fresh private
+    /// temp directory, for machines where no TCP port may be opened. It is
+    /// unlinked when the server stops. Windows falls back to TCP.
+    UnixSocket,
+}
+
+/// Prompt timeout used when the server config doesn't set one.
+pub const DEFAULT_PROMPT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Where the answer to a pending prompt goes.
+pub enum PendingReply {
+    /// The source says who answered: the user, or a rule when pending
+    /// prompts are re-evaluated.
+    Single(oneshot::Sender<(PermissionResponse, DecisionSource)>),
+    /// A batch expecting exactly `len` responses.
+    Batch {
+        tx: oneshot::Sender<Vec<PermissionResponse>>,
+        len: usize,
+    },
+}
+
+/// A prompt waiting for an answer.
+pub struct PendingPrompt {
+    pub reply: PendingReply,
+    /// Tool asking for permission; a batch lists all of its tools.
+    pub tool_name: String,
+    pub created_at: Instant,
+    /// When the prompt times out. Shared with the waiting handler, which
+    /// picks up changes immediately, so the deadline can be moved while the
+    /// prompt is showing.
+    pub deadline: Arc<watch::Sender<Option<Instant>>>,
+    /// The full prompt event as emitted (before size trimming).
+    pub event: Option<PermissionPromptEvent>,
+    /// A deny waiting for `confirm_deny`, and when the chance to confirm it
+    /// runs out.
+    pub staged_deny: Option<(PermissionResponse, Instant)>,
+    /// Set when the user asks to remember the answer. Shared with the
+    /// waiting handler, which stores the answer once it has the real input
+    /// back in place of any redacted copy or preview.
+    pub remember: Arc<AtomicBool>,
+}
+
+impl PendingPrompt {
+    /// `timeout: None` means the prompt never times out.
+    fn new(reply: PendingReply, tool_name: &str, timeout: Option<Duration>) -> Self {
+        Self {
+            reply,
+            tool_name: tool_name.to_string(),
+            created_at: Instant::now(),
+            deadline: Arc::new(watch::channel(timeout.map(|t| Instant::now() + t)).0),
+            event: None,
+            staged_deny: None,
+            remember: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn extend(&self, extra: Duration) {
+        push_back_deadline(&self.deadline, extra);
+    }
+}
+
+/// Move a deadline later, if there is one.
+fn push_back_deadline(deadline: &watch::Sender<Option<Instant>>, by: Duration) {
+    deadline.send_modify(|deadline| {
+        if let Some(deadline) = deadline {
+            *deadline += by;
+        }
+    });
+}
+
+/// Prompt timeout for a configured duration, where zero means none.
+fn timeout_from(duration: Duration) -> Option<Duration> {
+    (!duration.is_zero()).then_some(duration)
+}
+
+/// The longest of some prompt timeouts, where `None` (waiting forever)
+/// outlasts any duration.
+fn longest_timeout(timeouts: impl IntoIterator<Item = Option<Duration>>) -> Option<Duration> {
+    timeouts
+        .into_iter()
+        .try_fold(Duration::ZERO, |longest, timeout| {
+            timeout.map(|t| longest.max(t))
+        })
+}
+
+pub type PendingPrompts = Arc<Mutex<HashMap<String, PendingPrompt>>>;
+
+/// How many resolved prompt IDs a session remembers, and for how long.
+const RESOLVED_PROMPTS_MAX: usize = 256;
+const RESOLVED_PROMPT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Prompts a session resolved lately, by what and with which behavior,
+/// oldest first, so the same answer arriving again (e.g. a double click) is
+/// a no-op rather than a "No pending prompt" error. Bounded by
+/// `RESOLVED_PROMPTS_MAX` and `RESOLVED_PROMPT_TTL`.
+#[derive(Debug, Default)]
+pub struct ResolvedPrompts {
+    ids: std::sync::Mutex<std::collections::VecDeque<ResolvedPrompt>>,
+}
+
+/// A prompt ID, how it was resolved, the behavior it got and when.
+type ResolvedPrompt = (String, DecisionSource, String, Instant);
+
+impl ResolvedPrompts {
+    fn insert(&self, prompt_id: &str, source: DecisionSource, behavior: &str) {
+        if prompt_id.is_empty() {
+            return;
+        }
+        let mut ids = self.ids.lock().unwrap_or_else(|e| e.into_inner());
+        Self::expire(&mut ids);
+        if ids.len() >= RESOLVED_PROMPTS_MAX {
+            ids.pop_front();
+        }
+        ids.push_back((
+            prompt_id.to_string(),
+            source,
+            behavior.to_string(),
+            Instant::now(),
+        ));
+    }
+
+    /// How `prompt_id` was resolved and with which behavior, if it was
+    /// lately.
+    fn outcome(&self, prompt_id: &str) -> Option<(DecisionSource, String)> {
+        let mut ids = self.ids.lock().unwrap_or_else(|e| e.into_inner());
+        Self::expire(&mut ids);
+        ids.iter()
+            .rev()
+            .find(|(id, _, _, _)| id == prompt_id)
+            .map(|(_, source, behavior, _)| (*source, behavior.clone()))
+    }
+
+    fn expire(ids: &mut std::collections::VecDeque<ResolvedPrompt>) {
+        while ids
+            .front()
+            .is_some_and(|(_, _, _, at)| at.elapsed() >= RESOLVED_PROMPT_TTL)
+        {
+            ids.pop_front();
+        }
+    }
+}
+
+/// Prompts showing, by the `tool_use_id` they ask about, with the prompt ID
+/// and a channel carrying the final answer. A retried request attaches to
+/// the prompt already showing instead of opening a second one.
+pub type InFlightPrompts =
+    Arc<std::sync::Mutex<HashMap<String, (String, watch::Receiver<Option<PermissionResponse>>)>>>;
+
+/// Holds a `tool_use_id` in `InFlightPrompts` while its prompt's handler
+/// runs. Dropped (answered or not), it releases the ID.
+struct InFlightClaim {
+    prompts: InFlightPrompts,
+    tool_use_id: String,
+    prompt_id: String,
+    answer: watch::Sender<Option<PermissionResponse>>,
+}
+
+impl InFlightClaim {
+    /// Claim `tool_use_id` for `prompt_id`, or get the ID and answer channel
+    /// of the prompt already holding it. Requests without an ID are never
+    /// shared.
+    fn claim(
+        prompts: &InFlightPrompts,
+        tool_use_id: &str,
+        prompt_id: &str,
+    ) -> Result<Option<Self>, (String, watch::Receiver<Option<PermissionResponse>>)> {
+        if tool_use_id.is_empty() {
+            return Ok(None);
+        }
+        let mut claimed = prompts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(holder) = claimed.get(tool_use_id) {
+            return Err(holder.clone());
+        }
+        let (answer, rx) = watch::channel(None);
+        claimed.insert(tool_use_id.to_string(), (prompt_id.to_string(), rx));
+        Ok(Some(Self {
+            prompts: prompts.clone(),
+            tool_use_id: tool_use_id.to_string(),
+            prompt_id: prompt_id.to_string(),
+            answer,
+        }))
+    }
+
+    /// Hand the final answer to every request attached to this prompt.
+    fn finish(&self, resp: &PermissionResponse) {
+        self.answer.send_replace(Some(resp.clone()));
+    }
+}
+
+impl Drop for InFlightClaim {
+    fn drop(&mut self) {
+        let mut claimed = self.prompts.lock().unwrap_or_else(|e| e.into_inner());
+        if claimed
+            .get(&self.tool_use_id)
+            .is_some_and(|(prompt_id, _)| *prompt_id == self.prompt_id)
+        {
+            claimed.remove(&self.tool_use_id);
+        }
+    }
+}
+
+/// Questions from `ask_user` waiting on the user, keyed by request ID.
+pub type PendingInputs = Arc<Mutex<HashMap<String, oneshot::Sender<UserInputResponse>>>>;
+
+/// A session's loaded policy, if it has one.
+pub type SessionPolicy = Arc<std::sync::RwLock<Option<PermissionPolicy>>>;
+
+/// Emitted as `permission-deny-confirm` when denying a high-risk prompt needs
+/// a second confirmation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DenyConfirmEvent {
+    pub session_id: String,
+    pub prompt_id: String,
+    /// Why the prompt counts as high-risk.
+    pub reasons: Vec<String>,
+    /// How long `confirm_deny` is accepted before the prompt reverts to
+    /// plain pending.
+    pub window_ms: u64,
+}
+
+/// Emitted as `permission-quarantined` when a prompt is answered with the
+/// quarantine behavior, for a wrapper that enforces the sandbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedEvent {
+    pub session_id: String,
+    pub prompt_id: String,
+    pub tool_name: String,
+}
+
+/// Emitted as `permission-deny-reverted` when a staged deny wasn't confirmed
+/// in time and the prompt is waiting for an answer again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DenyRevertedEvent {
+    pub session_id: String,
+    pub prompt_id: String,
+}
+
+/// Emitted as `permission-cancelled` when `cancel_prompt` tears down a
+/// single prompt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptCancelledEvent {
+    pub session_id: String,
+    pub prompt_id: String,
+}
+
+/// Emitted as `permission-deferred` when `defer_prompt` puts a prompt aside;
+/// it comes back as a new `permission-prompt` event after `delay_ms`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptDeferredEvent {
+    pub session_id: String,
+    pub prompt_id: String,
+    pub delay_ms: u64,
+}
+
+/// Emitted as `permission-timeout` when a prompt expires unanswered, so the
+/// UI can drop it. For a batch `tool_name` lists its tools.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptTimeoutEvent {
+    pub session_id: String,
+    pub prompt_id: String,
+    pub tool_name: String,
+}
+
+/// Emitted as `permission-pending-changed` when a session's pending count
+/// moves between zero and non-zero.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingChangedEvent {
+    pub session_id: String,
+    pub count: usize,
+}
+
+/// Emitted as `permission-prompt-notify` for a burst of new prompts, for
+/// OS-level notifications. Prompt dialogs use `permission-prompt` instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptNotifyEvent {
+    pub session_id: String,
+    pub count: usize,
+    pub message: String,
+    /// The burst's latest prompt, to focus its session when the
+    /// notification is clicked.
+    pub prompt_id: String,
+    pub tool_name: String,
+}
+
+impl PromptNotifyEvent {
+    /// Text of the OS notification: how many prompts wait, for which tool
+    /// and session.
+    pub fn body(&self) -> String {
+        format!(
+            "{} ({} in session {})",
+            self.message, self.tool_name, self.session_id
+        )
+    }
+}
+
+/// One running permission HTTP server bound to a session.
+pub struct PermissionServerEntry {
+    pub port: u16,
+    pub pending: PendingPrompts,
+    pub shutdown_tx: watch::Sender<bool>,
+    /// The task serving HTTP, finished once a shutdown has drained its
+    /// connections. `None` for entries registered without a server.
+    pub server_task: Option<tokio::task::JoinHandle<()>>,
+    /// Temp files handed to Claude Code, removed when the server stops.
+    pub mcp_files: McpFiles,
+    /// Shared with the axum HttpState — updating this updates the session ID
+    /// used in Tauri events emitted by the HTTP handler.
+    pub session_id: Arc<Mutex<String>>,
+    /// Event sink shared with the HTTP handler, so registry-level helpers can
+    /// emit events for this session too. Also feeds `feed`.
+    pub emitter: Arc<dyn PermissionEmitter>,
+    /// The session's events for `GET /ws` clients.
+    pub feed: ws::EventFeed,
+    /// Bumped by the HTTP handler on every incoming request.
+    pub last_activity: Arc<Mutex<Instant>>,
+    /// Base directory for resolving relative paths in tool inputs.
+    pub cwd: Arc<PathBuf>,
+    /// How long new prompts wait for an answer; `None` waits forever.
+    /// Shared with the HTTP handler so `set_timeout` applies live.
+    pub prompt_timeout: Arc<std::sync::RwLock<Option<Duration>>>,
+    /// Per-tool overrides of `prompt_timeout`, changed by `set_tool_timeout`.
+    pub tool_timeouts: ToolTimeouts,
+    /// What an unanswered prompt turns into when it times out.
+    pub timeout_behavior: TimeoutBehavior,
+    /// Name of the abstract socket the server listens on (without the
+    /// leading NUL), when bound that way instead of to `port`.
+    pub abstract_socket: Option<String>,
+    /// Path of the Unix socket file the server listens on, when bound that
+    /// way instead of to `port`. Removed with its directory by `stop_server`.
+    pub socket_path: Option<PathBuf>,
+    /// Certificate and key when the server speaks HTTPS.
+    pub tls: Option<Arc<tls::ServerTls>>,
+    /// Name and description the session's MCP server goes by.
+    pub mcp_server: Arc<ServerIdentity>,
+    /// Loopback address the TCP listener bound to: IPv4 unless that failed
+    /// and `::1` was used instead.
+    pub host: IpAddr,
+    /// How many prompts each tool has raised this session.
+    pub tool_prompts: ToolPromptCounts,
+    /// Tools allowed without a prompt for the rest of the session.
+    pub allowed_tools: Arc<std::sync::Mutex<HashSet<String>>>,
+    /// Tools denied without a prompt; wins over `allowed_tools`.
+    pub blocked_tools: Arc<std::sync::Mutex<HashSet<String>>>,
+    /// Answers the user asked to remember, keyed by `request_key`.
+    pub remembered: RememberedDecisions,
+    /// Whether the MCP script has ever reached this server.
+    pub handshake: Arc<HandshakeState>,
+    /// Decisions made so far against the session's limit.
+    pub decision_budget: Arc<DecisionBudget>,
+    /// Allow/deny/timeout counts for `get_metrics`.
+    pub metrics: Arc<DecisionCounters>,
+    /// Turns away requests over the session's rate limit.
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Set by `stop_server_graceful`: prompts already showing may still be
+    /// answered, new requests are denied.
+    pub closing: Arc<AtomicBool>,
+    /// Allow every request as sent, only showing and logging it.
+    pub inspect_mode: Arc<AtomicBool>,
+    /// Unanswered `ask_user` questions.
+    pub pending_inputs: PendingInputs,
+    /// Prompts showing, by `tool_use_id`, so retries share them.
+    pub in_flight: InFlightPrompts,
+    /// Prompts resolved lately, so a repeated answer isn't an error.
+    pub resolved: Arc<ResolvedPrompts>,
+    /// One permit per prompt showing at once, when `max_concurrent` is set.
+    pub prompt_slots: Option<Arc<Semaphore>>,
+    pub overflow_behavior: OverflowBehavior,
+    /// The session's policy file rules, swapped in place on reload.
+    pub policy: SessionPolicy,
+    /// Bearer token for the `/resolve` and `/pending` controller endpoints.
+    /// Never handed to the MCP script, so it can't approve its own requests.
+    pub controller_token: String,
+    /// Bearer token the MCP script must send to `/permission-prompt`, so
+    /// other local processes can't spoof or observe prompts.
+    pub auth_token: String,
+}
+
+/// Server-side view of the MCP handshake. Claude Code only calls the
+/// permission tool once the MCP server is connected, so a session that never
+/// sends a request probably has a broken MCP setup.
+#[derive(Debug, Default)]
+pub struct HandshakeState {
+    pub received_request: AtomicBool,
+    /// Set when the grace period ran out without a request; cleared by the
+    /// first request after that.
+    pub suspect: AtomicBool,
+}
+
+/// Circuit breaker on the number of decisions one session can make, against
+/// agents stuck in a loop.
+#[derive(Debug, Default)]
+pub struct DecisionBudget {
+    /// 0 means unlimited.
+    pub max: AtomicU64,
+    /// Every resolution counts: user, rule, default, timeout and limit.
+    pub used: AtomicU64,
+    /// Set once `permission-limit-reached` has been emitted.
+    reported: AtomicBool,
+}
+
+impl DecisionBudget {
+    fn exhausted(&self) -> bool {
+        let max = self.max.load(Ordering::Relaxed);
+        max > 0 && self.used.load(Ordering::Relaxed) >= max
+    }
+}
+
+/// Returned to Claude for requests past the session's decision limit.
+const DECISION_LIMIT_MESSAGE: &str = "Session decision limit reached";
+
+/// Returned to Claude for requests over the session's rate limit.
+pub const RATE_LIMITED_MESSAGE: &str = "Rate limited";
+
+/// Window `RateLimit::per_window` uses when the caller gives none.
+pub const DEFAULT_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+
+/// At most `max_requests` permission requests per `window`, as a token
+/// bucket: a burst of `max_requests` is let through at once, then requests
+/// are let through as fast as the bucket refills.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub max_requests: u32,
+    pub window: Duration,
+}
+
+impl RateLimit {
+    pub fn per_window(max_requests: u32, window: Option<Duration>) -> Self {
+        Self {
+            max_requests,
+            window: window.unwrap_or(DEFAULT_RATE_LIMIT_WINDOW),
+        }
+    }
+}
+
+/// A session's token bucket for its `RateLimit`, changed live by
+/// `set_rate_limit`.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    bucket: std::sync::Mutex<Option<TokenBucket>>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter for `limit`; one allowing no requests, or with no window,
+    /// is unlimited.
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        let limiter = Self::default();
+        limiter.set(limit);
+        limiter
+    }
+
+    /// Replace the limit, starting from a full bucket.
+    pub fn set(&self, limit: Option<RateLimit>) {
+        let bucket = limit
+            .filter(|limit| limit.max_requests > 0 && !limit.window.is_zero())
+            .map(|limit| TokenBucket {
+                limit,
+                tokens: f64::from(limit.max_requests),
+                refilled_at: Instant::now(),
+            });
+        *self.bucket.lock().unwrap_or_else(|e| e.into_inner()) = bucket;
+    }
+
+    pub fn limit(&self) -> Option<RateLimit> {
+        self.bucket
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|bucket| bucket.limit)
+    }
+
+    /// Take a token for one request at `now`. False when the bucket is
+    /// empty and the request is over the limit.
+    fn try_acquire(&self, now: Instant) -> bool {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(bucket) = bucket.as_mut() else {
+            return true;
+        };
+        let capacity = f64::from(bucket.limit.max_requests);
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        let refill = elapsed.as_secs_f64() / bucket.limit.window.as_secs_f64() * capacity;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// Emitted as `permission-rule-conflict` when rules with different actions
+/// match one request, naming the rule that won and the ones it overrode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleConflictEvent {
+    pub session_id: String,
+    pub tool_name: String,
+    pub policy: ConflictPolicy,
+    pub winner: PermissionRule,
+    pub overridden: Vec<PermissionRule>,
+}
+
+/// Emitted once as `permission-limit-reached` when a session hits its
+/// decision limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LimitReachedEvent {
+    pub session_id: String,
+    pub max_decisions: u64,
+    pub message: String,
+}
+
+pub type ToolPromptCounts = Arc<std::sync::Mutex<HashMap<String, u64>>>;
+
+/// Per-tool prompt timeouts by tool name; `None` waits forever.
+pub type ToolTimeouts = Arc<std::sync::RwLock<HashMap<String, Option<Duration>>>>;
+
+pub type RememberedDecisions = Arc<std::sync::Mutex<HashMap<String, PermissionResponse>>>;
+
+/// Cache key for a request: the tool name plus its input as JSON with object
+/// keys sorted, so two requests differing only in key order share a key. The
+/// whole text is the key, so different requests can't collide.
+fn request_key(tool_name: &str, input: &serde_json::Value) -> String {
+    format!(
+        "{}:{}",
+        serde_json::Value::from(tool_name),
+        canonical_json(input)
+    )
+}
+
+fn canonical_json(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let fields: Vec<String> = keys
+                .into_iter()
+                .map(|key| {
+                    format!(
+                        "{}:{}",
+                        serde_json::Value::from(key.as_str()),
+                        canonical_json(&map[key])
+                    )
+                })
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        serde_json::Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        scalar => scalar.to_string(),
+    }
+}
+
+/// Count a prompt shown for `tool_name`.
+fn count_tool_prompt(counts: &ToolPromptCounts, tool_name: &str) {
+    *counts
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(tool_name.to_string())
+        .or_insert(0) += 1;
+}
+
+impl PermissionServerEntry {
+    /// Build an entry with fresh shared state. Temp-file paths are filled in
+    /// later by `set_mcp_files`.
+    fn new(
+        port: u16,
+        session_id: &str,
+        shutdown_tx: watch::Sender<bool>,
+        emitter: Arc<dyn PermissionEmitter>,
+        config: &PermissionServerConfig,
+    ) -> Self {
+        let feed = broadcast::channel(ws::FEED_CAPACITY).0;
+        Self {
+            port,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            shutdown_tx,
+            server_task: None,
+            mcp_files: McpFiles::default(),
+            session_id: Arc::new(Mutex::new(session_id.to_string())),
+            emitter: Arc::new(ws::FeedEmitter::new(emitter, feed.clone())),
+            feed,
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            cwd: Arc::new(session_cwd(session_id, config)),
+            prompt_timeout: Arc::new(std::sync::RwLock::new(timeout_from(
+                config.prompt_timeout.unwrap_or(DEFAULT_PROMPT_TIMEOUT),
+            ))),
+            tool_timeouts: Arc::new(std::sync::RwLock::new(
+                config
+                    .tool_timeouts
+                    .iter()
+                    .map(|(tool, timeout)| (tool.clone(), timeout_from(*timeout)))
+                    .collect(),
+            )),
+            timeout_behavior: config.timeout_behavior,
+            abstract_socket: None,
+            socket_path: None,
+            tls: None,
+            mcp_server: Arc::new(config.mcp_server.clone()),
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            tool_prompts: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            allowed_tools: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            blocked_tools: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            remembered: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            handshake: Arc::new(HandshakeState::default()),
+            decision_budget: Arc::new(DecisionBudget {
+                max: AtomicU64::new(config.max_decisions.unwrap_or(0)),
+                ..Default::default()
+            }),
+            metrics: Arc::new(DecisionCounters::default()),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limit)),
+            closing: Arc::new(AtomicBool::new(false)),
+            inspect_mode: Arc::new(AtomicBool::new(config.inspect_mode)),
+            pending_inputs: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            resolved: Arc::new(ResolvedPrompts::default()),
+            prompt_slots: config
+                .max_concurrent
+                .filter(|n| *n > 0)
+                .map(|n| Arc::new(Semaphore::new(n))),
+            overflow_behavior: config.overflow_behavior,
+            policy: Arc::new(std::sync::RwLock::new(None)),
+            controller_token: Uuid::new_v4().to_string(),
+            auth_token: Uuid::new_v4().to_string(),
+        }
+    }
+}
+
+/// The configured working directory, or the process cwd if none was given.
+fn session_cwd(session_id: &str, config: &PermissionServerConfig) -> PathBuf {
+    if let Some(cwd) = &config.cwd {
+        return cwd.clone();
+    }
+    log::warn!(
+        "No working directory configured for permission session '{}'; using the process cwd",
+        session_id
+    );
+    std::env::current_dir().unwrap_or_default()
+}
+
+/// Global registry managed as Tauri state.
+#[derive(Clone)]
+pub struct PermissionServerRegistry {
+    pub servers: Arc<Mutex<HashMap<String, PermissionServerEntry>>>,
+    /// Whether events are also broadcast under their generic (unscoped) name
+    /// in addition to the `name:{session_id}` variant. UIs that subscribe per
+    /// session can turn this off to avoid cross-session noise.
+    pub emit_generic_events: Arc<AtomicBool>,
+    /// Optional host hook applied to every prompt event right before emit.
+    pub event_transform: Arc<std::sync::RwLock<Option<EventTransform>>>,
+    /// App-wide event sink for events that aren't tied to one session.
+    pub app_emitter: Option<Arc<dyn PermissionEmitter>>,
+    /// Global maintenance pause. While set, new prompts are queued instead of
+    /// emitted and pending prompts' timeouts are suspended.
+    pub paused: Arc<watch::Sender<bool>>,
+    /// Sequence counter and recent history for every resolved prompt.
+    pub decisions: Arc<DecisionLog>,
+    /// Durable JSONL copy of every decision. Off until a path is set.
+    pub audit: Arc<AuditLog>,
+    /// Auto-approval rules for every scope.
+    pub rules: Arc<std::sync::RwLock<RuleEngineState>>,
+    /// Cap on serialized prompt events in bytes; 0 means unlimited. Larger
+    /// events are trimmed and flagged `truncated`.
+    pub max_event_bytes: Arc<AtomicUsize>,
+    /// Cap on a prompt event's serialized input in bytes; 0 means unlimited.
+    /// Larger inputs are emitted as a preview flagged `input_truncated`.
+    pub max_input_bytes: Arc<AtomicUsize>,
+    /// Per-tool auto-edits applied before prompting. Empty (off) by default.
+    pub auto_edits: Arc<std::sync::RwLock<HashMap<String, AutoEdit>>>,
+    /// Named transforms a `modify` answer can apply to a prompt's input.
+    /// Starts with the built-ins from `transforms::builtin_transforms`.
+    pub input_transforms: Arc<std::sync::RwLock<HashMap<String, InputTransform>>>,
+    /// Secrets masked in prompt events and the audit log. Starts with
+    /// `redact::builtin_patterns`.
+    pub secret_patterns: Arc<std::sync::RwLock<Vec<redact::SecretPattern>>>,
+    /// When set, denying a high-risk prompt must be confirmed with
+    /// `confirm_deny` within this window. Off by default.
+    pub deny_confirm_window: Arc<std::sync::RwLock<Option<Duration>>>,
+    /// Prompts arriving within this window of a session's first unsent
+    /// notification share one `permission-prompt-notify` event. Zero
+    /// notifies for every prompt.
+    pub notify_window: Arc<std::sync::RwLock<Duration>>,
+    /// Prompts counted towards each session's pending notification.
+    notify_bursts: Arc<std::sync::Mutex<HashMap<String, PromptNotifyEvent>>>,
+    /// Also show each `permission-prompt-notify` as an OS notification
+    /// while the main window isn't focused. Off by default.
+    pub os_notifications: Arc<AtomicBool>,
+}
+
+/// Default coalescing window for prompt notifications.
+pub const DEFAULT_NOTIFY_WINDOW: Duration = Duration::from_millis(500);
+
+/// Rewrites a prompt event before it is emitted (e.g. to add org metadata or
+/// drop fields). It runs inline in the request path, so it must be fast and
+/// infallible.
+pub type EventTransform = Arc<dyn Fn(PermissionPromptEvent) -> PermissionPromptEvent + Send + Sync>;
+
+impl Default for PermissionServerRegistry {
+    fn default() -> Self {
+        Self {
+            servers: Arc::new(Mutex::new(HashMap::new())),
+            emit_generic_events: Arc::new(AtomicBool::new(true)),
+            event_transform: Arc::new(std::sync::RwLock::new(None)),
+            app_emitter: None,
+            paused: Arc::new(watch::channel(false).0),
+            decisions: Arc::new(DecisionLog::default()),
+            audit: Arc::new(AuditLog::default()),
+            rules: Arc::new(std::sync::RwLock::new(RuleEngineState::default())),
+            max_event_bytes: Arc::new(AtomicUsize::new(DEFAULT_MAX_EVENT_BYTES)),
+            max_input_bytes: Arc::new(AtomicUsize::new(DEFAULT_MAX_INPUT_BYTES)),
+            auto_edits: Arc::new(std::sync::RwLock::new(HashMap::new())),
+            input_transforms: Arc::new(std::sync::RwLock::new(transforms::builtin_transforms())),
+            secret_patterns: Arc::new(std::sync::RwLock::new(redact::builtin_patterns())),
+            deny_confirm_window: Arc::new(std::sync::RwLock::new(None)),
+            notify_window: Arc::new(std::sync::RwLock::new(DEFAULT_NOTIFY_WINDOW)),
+            notify_bursts: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            os_notifications: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl PermissionServerRegistry {
+    /// Registry whose app-wide events (e.g. the global pause banner) go to
+    /// `emitter`.
+    pub fn new(emitter: Arc<dyn PermissionEmitter>) -> Self {
+        Self {
+            app_emitter: Some(emitter),
+            ..Self::default()
+        }
+    }
+
+    /// Emit an event that isn't scoped to a session.
+    fn emit_app_event<T: Serialize>(&self, name: &str, payload: &T) {
+        if let (Some(emitter), Ok(payload)) = (&self.app_emitter, serde_json::to_value(payload)) {
+            if let Err(e) = emitter.emit_json(name, payload) {
+                log::warn!("Failed to emit '{}': {}", name, e);
+            }
+        }
+    }
+
+    fn emit_generic(&self) -> bool {
+        self.emit_generic_events.load(Ordering::Relaxed)
+    }
+
+    /// Add a decision to the recent history and the audit log, without
+    /// touching any session's counters.
+    fn log_decision(&self, record: DecisionRecord) {
+        // The in-memory copy keeps the real input for rules to match; the one
+        // written to disk doesn't
+        let mut record = self.decisions.record_entry(record);
+        if let Some(input) = self.redact_input(&record.input) {
+            record.input = input;
+        }
+        if let Some(edited) = record
+            .auto_edited_input
+            .as_ref()
+            .and_then(|edited| self.redact_input(edited))
+        {
+            record.auto_edited_input = Some(edited);
+        }
+        self.audit.append(&record);
+    }
+
+    /// Change a session's pending prompts through `f`, emitting
+    /// `permission-pending-changed` if the count crossed zero. The event is
+    /// sent under the pending lock so transitions are reported in order.
+    async fn update_pending<R>(
+        &self,
+        pending: &PendingPrompts,
+        emitter: &dyn PermissionEmitter,
+        session_id: &str,
+        f: impl FnOnce(&mut HashMap<String, PendingPrompt>) -> R,
+    ) -> R {
+        let mut pending = pending.lock().await;
+        let before = pending.len();
+        let result = f(&mut pending);
+        let count = pending.len();
+        if (before == 0) != (count == 0) {
+            let event = PendingChangedEvent {
+                session_id: session_id.to_string(),
+                count,
+            };
+            emit_session_event(
+                emitter,
+                "permission-pending-changed",
+                session_id,
+                &event,
+                self.emit_generic(),
+            );
+        }
+        result
+    }
+
+    /// The tool's auto-edited input, if it has an auto-edit that changes it.
+    fn auto_edited_input(
+        &self,
+        tool_name: &str,
+        input: &serde_json::Value,
+    ) -> Option<serde_json::Value> {
+        self.auto_edits
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(tool_name)
+            .map(|edit| edit.apply(input))
+            .filter(|edited| edited != input)
+    }
+
+    /// `input` with its secrets masked, for events and the audit log, or
+    /// `None` if it has none.
+    fn redact_input(&self, input: &serde_json::Value) -> Option<serde_json::Value> {
+        let patterns = self
+            .secret_patterns
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        redact::redact_value(input, &patterns)
+    }
+
+    /// A prompt event fit to leave the server: its inputs and the text
+    /// describing them with secrets masked. The stored copy keeps the real
+    /// input for remembering, transforms and rules.
+    fn redact_event(&self, mut event: PermissionPromptEvent) -> PermissionPromptEvent {
+        let patterns = self
+            .secret_patterns
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        for input in [
+            Some(&mut event.input),
+            event.suggested_input.as_mut(),
+            event.original_input.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Some(redacted) = redact::redact_value(input, &patterns) {
+                *input = redacted;
+            }
+        }
+        for text in [event.summary.as_mut(), event.explanation.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(redacted) = redact::redact_str(text, &patterns) {
+                *text = redacted;
+            }
+        }
+        event
+    }
+
+    /// Run the event transform (if any) over a new prompt event.
+    fn transform_prompt_event(&self, event: PermissionPromptEvent) -> PermissionPromptEvent {
+        let transform = self
+            .event_transform
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        match transform {
+            Some(transform) => transform(event),
+            None => event,
+        }
+    }
+
+    /// Emit a prompt event, trimmed to the configured size limit, and queue
+    /// its notification. Returns whether the frontend could be reached.
+    fn emit_prompt_event(
+        &self,
+        emitter: &Arc<dyn PermissionEmitter>,
+        event: &PermissionPromptEvent,
+    ) -> bool {
+        let emitted = self.send_prompt_event(emitter.as_ref(), event);
+        self.notify_prompt(emitter, event);
+        emitted
+    }
+
+    /// Emit a prompt event, trimmed to the configured size limits. Returns
+    /// whether the frontend could be reached.
+    fn send_prompt_event(
+        &self,
+        emitter: &dyn PermissionEmitter,
+        event: &PermissionPromptEvent,
+    ) -> bool {
+        let max_bytes = self.max_event_bytes.load(Ordering::Relaxed);
+        let event = self
+            .redact_event(event.clone())
+            .with_input_limit(self.max_input_bytes.load(Ordering::Relaxed));
+        let event = if max_bytes == 0 {
+            event
+        } else {
+            event.trimmed_to(max_bytes)
+        };
+        if event.truncated {
+            log_prompt_step(
+                log::Level::Info,
+                &event.session_id,
+                &event.prompt_id,
+                "trimmed",
+                format_args!("limit={}", max_bytes),
+            );
+        }
+        let emitted = emit_session_event(
+            emitter,
+            "permission-prompt",
+            &event.session_id,
+            &event,
+            self.emit_generic(),
+        );
+        let (level, step) = if emitted {
+            (log::Level::Debug, "emitted")
+        } else {
+            (log::Level::Warn, "emit_failed")
+        };
+        log_prompt_step(
+            level,
+            &event.session_id,
+            &event.prompt_id,
+            step,
+            format_args!("tool={}", event.tool_name),
+        );
+        emitted
+    }
+
+    /// Count a prompt towards the session's next `permission-prompt-notify`
+    /// event. The first prompt of a burst opens the window; the notification
+    /// goes out when it closes, covering every prompt seen meanwhile.
+    fn notify_prompt(&self, emitter: &Arc<dyn PermissionEmitter>, prompt: &PermissionPromptEvent) {
+        let session_id = &prompt.session_id;
+        let notification = PromptNotifyEvent {
+            session_id: session_id.clone(),
+            count: 1,
+            message: String::new(),
+            prompt_id: prompt.prompt_id.clone(),
+            tool_name: prompt.tool_name.clone(),
+        };
+        let window = *self.notify_window.read().unwrap_or_else(|e| e.into_inner());
+        if window.is_zero() {
+            self.emit_notification(emitter.as_ref(), notification);
+            return;
+        }
+
+        {
+            let mut bursts = self.notify_bursts.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(burst) = bursts.get_mut(session_id) {
+                burst.count += 1;
+                burst.prompt_id = notification.prompt_id;
+                burst.tool_name = notification.tool_name;
+                return;
+            }
+            bursts.insert(session_id.clone(), notification);
+        }
+
+        let registry = self.clone();
+        let emitter = emitter.clone();
+        let session_id = session_id.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            let burst = registry
+                .notify_bursts
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&session_id);
+            if let Some(burst) = burst {
+                registry.emit_notification(emitter.as_ref(), burst);
+            }
+        });
+    }
+
+    fn emit_notification(&self, emitter: &dyn PermissionEmitter, mut event: PromptNotifyEvent) {
+        event.message = if event.count == 1 {
+            "1 permission request pending".to_string()
+        } else {
+            format!("{} permission requests pending", event.count)
+        };
+        emit_session_event(
+            emitter,
+            "permission-prompt-notify",
+            &event.session_id,
+            &event,
+            self.emit_generic(),
+        );
+        if self.os_notifications.load(Ordering::Relaxed) && emitter.notify_os(&event) {
+            log_prompt_step(
+                log::Level::Debug,
+                &event.session_id,
+                &event.prompt_id,
+                "os_notified",
+                format_args!("count={}", event.count),
+            );
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Event emission
+// ---------------------------------------------------------------------------
+
+/// Destination for permission events. The Tauri `AppHandle` is the real
+/// implementation; tests substitute an in-memory recorder.
+pub trait PermissionEmitter: Send + Sync {
+    fn emit_json(&self, event: &str, payload: serde_json::Value) -> Result<(), String>;
+
+    /// Show `notification` as an OS notification if the user may not be
+    /// looking at the app. Returns whether one was shown; emitters without
+    /// a desktop show none.
+    fn notify_os(&self, _notification: &PromptNotifyEvent) -> bool {
+        false
+    }
+}
+
+impl PermissionEmitter for AppHandle {
+    fn emit_json(&self, event: &str, payload: serde_json::Value) -> Result<(), String> {
+        self.emit(event, payload).map_err(|e| e.to_string())
+    }
+
+    /// Only while the main window isn't focused; the dialog is in plain
+    /// sight otherwise. The prompt ID rides along in the notification's
+    /// extra data.
+    fn notify_os(&self, notification: &PromptNotifyEvent) -> bool {
+        use tauri_plugin_notification::NotificationExt;
+        let focused = self
+            .get_webview_window("main")
+            .and_then(|window| window.is_focused().ok())
+            .unwrap_or(false);
+        if focused {
+            return false;
+        }
+        let shown = self
+            .notification()
+            .builder()
+            .title("Permission needed")
+            .body(notification.body())
+            .extra("session_id", &notification.session_id)
+            .extra("prompt_id", &notification.prompt_id)
+            .show();
+        match shown {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Failed to show permission notification: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Emit `{name}:{session_id}` and, unless disabled, the generic `{name}`.
+/// Failures are logged; returns whether either event went out.
+fn emit_session_event<T: Serialize>(
+    emitter: &dyn PermissionEmitter,
+    name: &str,
+    session_id: &str,
+    payload: &T,
+    emit_generic: bool,
+) -> bool {
+    let payload = match serde_json::to_value(payload) {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("Failed to serialize '{}' event payload: {}", name, e);
+            return false;
+        }
+    };
+
+    let scoped = format!("{}:{}", name, session_id);
+    let mut emitted = match emitter.emit_json(&scoped, payload.clone()) {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("Failed to emit '{}': {}", scoped, e);
+            false
+        }
+    };
+    if emit_generic {
+        match emitter.emit_json(name, payload) {
+            Ok(()) => emitted = true,
+            Err(e) => log::warn!(
+                "Failed to emit '{}' for session '{}': {}",
+                name,
+                session_id,
+                e
+            ),
+        }
+    }
+    emitted
+}
+
+/// Log one step of a prompt's life with its IDs as `key=value` fields, so
+/// filtering on `prompt=<id>` reconstructs a single decision from creation
+/// through emit to its answer or timeout.
+fn log_prompt_step(
+    level: log::Level,
+    session_id: &str,
+    prompt_id: &str,
+    step: &str,
+    detail: std::fmt::Arguments,
+) {
+    log::log!(
+        level,
+        "prompt={} session={} step={} {}",
+        prompt_id,
+        session_id,
+        step,
+        detail
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Shared state handed into each axum handler
+// ---------------------------------------------------------------------------
+
+#[derive(Clone)]
+struct HttpState {
+    registry: PermissionServerRegistry,
+    emitter: Arc<dyn PermissionEmitter>,
+    session_id: Arc<Mutex<String>>,
+    pending: PendingPrompts,
+    last_activity: Arc<Mutex<Instant>>,
+    cwd: Arc<PathBuf>,
+    mcp_server: Arc<ServerIdentity>,
+    prompt_timeout: Arc<std::sync::RwLock<Option<Duration>>>,
+    tool_timeouts: ToolTimeouts,
+    timeout_behavior: TimeoutBehavior,
+    tool_prompts: ToolPromptCounts,
+    allowed_tools: Arc<std::sync::Mutex<HashSet<String>>>,
+    blocked_tools: Arc<std::sync::Mutex<HashSet<String>>>,
+    remembered: RememberedDecisions,
+    handshake: Arc<HandshakeState>,
+    decision_budget: Arc<DecisionBudget>,
+    metrics: Arc<DecisionCounters>,
+    rate_limiter: Arc<RateLimiter>,
+    closing: Arc<AtomicBool>,
+    inspect_mode: Arc<AtomicBool>,
+    pending_inputs: PendingInputs,
+    in_flight: InFlightPrompts,
+    resolved: Arc<ResolvedPrompts>,
+    prompt_slots: Option<Arc<Semaphore>>,
+    overflow_behavior: OverflowBehavior,
+    policy: SessionPolicy,
+    controller_token: String,
+    auth_token: String,
+    feed: ws::EventFeed,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl HttpState {
+    /// Share the entry's state with the handler.
+    fn new(entry: &PermissionServerEntry, registry: &PermissionServerRegistry) -> Self {
+        Self {
+            registry: registry.clone(),
+            emitter: entry.emitter.clone(),
+            session_id: entry.session_id.clone(),
+            pending: entry.pending.clone(),
+            last_activity: entry.last_activity.clone(),
+            cwd: entry.cwd.clone(),
+            mcp_server: entry.mcp_server.clone(),
+            prompt_timeout: entry.prompt_timeout.clone(),
+            tool_timeouts: entry.tool_timeouts.clone(),
+            timeout_behavior: entry.timeout_behavior,
+            tool_prompts: entry.tool_prompts.clone(),
+            allowed_tools: entry.allowed_tools.clone(),
+            blocked_tools: entry.blocked_tools.clone(),
+            remembered: entry.remembered.clone(),
+            handshake: entry.handshake.clone(),
+            decision_budget: entry.decision_budget.clone(),
+            metrics: entry.metrics.clone(),
+            rate_limiter: entry.rate_limiter.clone(),
+            closing: entry.closing.clone(),
+            inspect_mode: entry.inspect_mode.clone(),
+            pending_inputs: entry.pending_inputs.clone(),
+            in_flight: entry.in_flight.clone(),
+            resolved: entry.resolved.clone(),
+            prompt_slots: entry.prompt_slots.clone(),
+            overflow_behavior: entry.overflow_behavior,
+            policy: entry.policy.clone(),
+            controller_token: entry.controller_token.clone(),
+            auth_token: entry.auth_token.clone(),
+            feed: entry.feed.clone(),
+            shutdown_rx: entry.shutdown_tx.subscribe(),
+        }
+    }
+
+    /// A slot for a new prompt under the session's `max_concurrent`: waits
+    /// for one to free up, or denies when the overflow behavior says so.
+    /// `None` when the session has no limit.
+    async fn prompt_slot(&self) -> Result<Option<OwnedSemaphorePermit>, PermissionResponse> {
+        let Some(slots) = &self.prompt_slots else {
+            return Ok(None);
+        };
+        let permit = match self.overflow_behavior {
+            // The semaphore is never closed, so this only fails if it were
+            OverflowBehavior::Queue => slots.clone().acquire_owned().await.ok(),
+            OverflowBehavior::Deny => Some(
+                slots
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| deny_with(TOO_MANY_PENDING_MESSAGE))?,
+            ),
+        };
+        Ok(permit)
+    }
+
+    /// Record a resolution, count it in the session's metrics and against
+    /// its limit.
+    fn record_decision(&self, record: DecisionRecord) {
+        self.resolved
+            .insert(&record.prompt_id, record.source, &record.behavior);
+        self.decision_budget.used.fetch_add(1, Ordering::Relaxed);
+        self.metrics.count(record.source, &record.behavior);
+        self.registry.log_decision(record);
+    }
+
+    /// Whether the session used up its decision limit. The first time it
+    /// has, emits `permission-limit-reached`.
+    fn decision_limit_reached(&self, session_id: &str) -> bool {
+        let budget = &self.decision_budget;
+        if !budget.exhausted() {
+            return false;
+        }
+        if !budget.reported.swap(true, Ordering::Relaxed) {
+            let max_decisions = budget.max.load(Ordering::Relaxed);
+            log::warn!(
+                "Permission session '{}' reached its limit of {} decisions; denying further requests",
+                session_id,
+                max_decisions
+            );
+            let event = LimitReachedEvent {
+                session_id: session_id.to_string(),
+                max_decisions,
+                message: "This session hit its permission decision limit. Review what the agent \
+                          is doing before letting it continue."
+                    .to_string(),
+            };
+            emit_session_event(
+                self.emitter.as_ref(),
+                "permission-limit-reached",
+                session_id,
+                &event,
+                self.registry.emit_generic(),
+            );
+        }
+        true
+    }
+
+    /// Record that the MCP script reached us: bumps the activity time and
+    /// clears a suspected handshake failure.
+    async fn note_request(&self) {
+        // Lets a draining `stop_server_draining` notice the session is still alive
+        *self.last_activity.lock().await = Instant::now();
+
+        self.handshake
+            .received_request
+            .store(true, Ordering::Relaxed);
+        if self.handshake.suspect.swap(false, Ordering::Relaxed) {
+            let session_id = self.session_id.lock().await.clone();
+            log::info!(
+                "Permission session '{}' received its first request after being flagged",
+                session_id
+            );
+            emit_session_event(
+                self.emitter.as_ref(),
+                "permission-handshake-ok",
+                &session_id,
+                &serde_json::json!({ "session_id": session_id }),
+                self.registry.emit_generic(),
+            );
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// HTTP server
+// ---------------------------------------------------------------------------
+
+/// A bound listener for the permission server.
+enum BoundListener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(target_os = "linux")]
+    Abstract(tokio::net::UnixListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl BoundListener {
+    /// The IP a TCP listener is bound to.
+    fn host(&self) -> Option<IpAddr> {
+        match self {
+            BoundListener::Tcp(listener) => listener.local_addr().ok().map(|a| a.ip()),
+            #[cfg(target_os = "linux")]
+            BoundListener::Abstract(_) => None,
+            #[cfg(unix)]
+            BoundListener::Unix(..) => None,
+        }
+    }
+
+    /// The socket file a Unix listener is bound to.
+    fn socket_path(&self) -> Option<PathBuf> {
+        match self {
+            #[cfg(unix)]
+            BoundListener::Unix(_, path) => Some(path.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Loopback addresses tried in order. Some locked-down machines filter
+/// `127.0.0.1` but let `::1` through.
+const LOOPBACK_ADDRS: &[&str] = &["127.0.0.1:0", "[::1]:0"];
+
+/// Bind a random port on the first of `addrs` that works.
+async fn bind_loopback(addrs: &[&str]) -> Result<tokio::net::TcpListener, PermissionError> {
+    let mut last_err = String::from("no loopback address to try");
+    for addr in addrs {
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) => {
+                log::warn!("Failed to bind permission server on {}: {}", addr, e);
+                last_err = e.to_string();
+            }
+        }
+    }
+    Err(PermissionError::BindFailed(last_err))
+}
+
+/// Bind a Linux abstract-namespace socket. `name` excludes the leading NUL.
+#[cfg(target_os = "linux")]
+fn bind_abstract_socket(name: &str) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+    let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+    listener.set_nonblocking(true)?;
+    tokio::net::UnixListener::from_std(listener)
+}
+
+/// Prefix of the private directories holding Unix socket files.
+#[cfg(unix)]
+const SOCKET_DIR_PREFIX: &str = "opcode-sock-";
+
+/// File name of the socket inside its directory.
+#[cfg(unix)]
+const SOCKET_FILE_NAME: &str = "permission.sock";
+
+/// Bind a Unix socket file in a fresh 0700 directory in the system temp
+/// dir, restricted to 0600. The directory keeps other users out from the
+/// start; the file mode is set too in case the directory is loosened.
+#[cfg(unix)]
+fn bind_unix_socket() -> std::io::Result<(tokio::net::UnixListener, PathBuf)> {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempfile::Builder::new()
+        .prefix(SOCKET_DIR_PREFIX)
+        .tempdir()?;
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))?;
+    let path = dir.path().join(SOCKET_FILE_NAME);
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    let _ = dir.keep();
+    Ok((listener, path))
+}
+
+/// Best-effort removal of a socket file bound by `bind_unix_socket` and its
+/// directory.
+fn remove_socket_file(path: &Path) {
+    let _ = std::fs::remove_file(path);
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::remove_dir(dir);
+    }
+}
+
+/// Bind the listener for `transport`, falling back to TCP when a socket
+/// isn't available. TCP uses `preferred_port` if it's free and a
+/// random port otherwise. Returns the listener, its TCP port (0 for a
+/// socket) and the abstract socket name if one was bound; a socket file's
+/// path is on the listener.
+async fn bind_listener(
+    session_id: &str,
+    transport: ServerTransport,
+    preferred_port: Option<u16>,
+) -> Result<(BoundListener, u16, Option<String>), PermissionError> {
+    if transport == ServerTransport::AbstractSocket {
+        #[cfg(target_os = "linux")]
+        {
+            let name = format!("opcode-permission-{}", Uuid::new_v4());
+            match bind_abstract_socket(&name) {
+                Ok(listener) => {
+                    log::info!(
+                        "Permission prompt server for session '{}' listening on abstract socket '@{}'",
+                        session_id,
+                        name
+                    );
+                    return Ok((BoundListener::Abstract(listener), 0, Some(name)));
+                }
+                Err(e) => log::warn!(
+                    "Failed to bind abstract socket for session '{}', using TCP: {}",
+                    session_id,
+                    e
+                ),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        log::warn!(
+            "Abstract sockets are Linux-only; using TCP for session '{}'",
+            session_id
+        );
+    }
+    if transport == ServerTransport::UnixSocket {
+        #[cfg(unix)]
+        {
+            match bind_unix_socket() {
+                Ok((listener, path)) => {
+                    log::info!(
+                        "Permission prompt server for session '{}' listening on {}",
+                        session_id,
+                        path.display()
+                    );
+                    return Ok((BoundListener::Unix(listener, path), 0, None));
+                }
+                Err(e) => log::warn!(
+                    "Failed to bind Unix socket for session '{}', using TCP: {}",
+                    session_id,
+                    e
+                ),
+            }
+        }
+        #[cfg(not(unix))]
+        log::warn!(
+            "Unix sockets aren't supported here; using TCP for session '{}'",
+            session_id
+        );
+    }
+
+    let preferred = match preferred_port.filter(|port| *port != 0) {
+        Some(port) => {
+            let addrs = [format!("127.0.0.1:{}", port), format!("[::1]:{}", port)];
+            let addrs: Vec<&str> = addrs.iter().map(String::as_str).collect();
+            match bind_loopback(&addrs).await {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    log::warn!(
+                        "Preferred port {} unavailable for session '{}', using a random port: {}",
+                        port,
+                        session_id,
+                        e
+                    );
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    // Otherwise bind to random port on loopback
+    let listener = match preferred {
+        Some(listener) => listener,
+        None => bind_loopback(LOOPBACK_ADDRS).await?,
+    };
+
+    let addr = listener.local_addr()?;
+
+    let port = addr.port();
+    log::info!(
+        "Permission prompt server for session '{}' listening on {}",
+        session_id,
+        addr
+    );
+    Ok((BoundListener::Tcp(listener), port, None))
+}
+
+/// Resolves once the shutdown signal is sent (or its sender dropped).
+async fn shutdown_signal(mut shutdown_rx: watch::Receiver<bool>) {
+    loop {
+        if *shutdown_rx.borrow() {
+            break;
+        }
+        if shutdown_rx.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Flag the session with a `permission-handshake-suspect` event if no
+/// request arrives within `grace` of the server starting.
+fn spawn_handshake_watchdog(
+    entry: &PermissionServerEntry,
+    registry: &PermissionServerRegistry,
+    grace: Duration,
+) {
+    let handshake = entry.handshake.clone();
+    let session = entry.session_id.clone();
+    let emitter = entry.emitter.clone();
+    let shutdown_rx = entry.shutdown_tx.subscribe();
+    let emit_generic = registry.emit_generic();
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        if *shutdown_rx.borrow() || handshake.received_request.load(Ordering::Relaxed) {
+            return;
+        }
+        handshake.suspect.store(true, Ordering::Relaxed);
+        let session_id = session.lock().await.clone();
+        log::warn!(
+            "Permission session '{}' got no request within {:?}; the MCP handshake may have failed",
+            session_id,
+            grace
+        );
+        emit_session_event(
+            emitter.as_ref(),
+            "permission-handshake-suspect",
+            &session_id,
+            &serde_json::json!({ "session_id": session_id, "grace_ms": grace.as_millis() as u64 }),
+            emit_generic,
+        );
+    });
+}
+
+/// How often each server sweeps its pending map for stale prompts.
+const PENDING_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long past its deadline a prompt may stay pending before the sweeper
+/// drops it. Live handlers remove their prompt right at the deadline; this
+/// only keeps the sweeper from racing them.
+const PENDING_SWEEP_GRACE: Duration = Duration::from_secs(5);
+
+/// Drop prompts still pending well past their deadline (`created_at` plus
+/// the timeout, as moved by extensions and pauses). That only happens when
+/// the waiting handler is gone, e.g. its client disconnected; dropping the
+/// entry closes the reply channel. Nothing is swept while prompting is
+/// paused, since queued deadlines only move on resume. Returns the removed
+/// prompt IDs.
+async fn sweep_stale_pending(
+    registry: &PermissionServerRegistry,
+    pending: &PendingPrompts,
+    emitter: &dyn PermissionEmitter,
+    session_id: &str,
+    grace: Duration,
+) -> Vec<String> {
+    if *registry.paused.borrow() {
+        return Vec::new();
+    }
+    let now = Instant::now();
+    let removed = registry
+        .update_pending(pending, emitter, session_id, |p| {
+            let stale: Vec<String> = p
+                .iter()
+                .filter(|(_, prompt)| (*prompt.deadline.borrow()).is_some_and(|d| now >= d + grace))
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &stale {
+                p.remove(id);
+            }
+            stale
+        })
+        .await;
+    for prompt_id in &removed {
+        log_prompt_step(
+            log::Level::Warn,
+            session_id,
+            prompt_id,
+            "dropped_stale",
+            format_args!("past its deadline with no handler waiting"),
+        );
+    }
+    removed
+}
+
+/// Run `sweep_stale_pending` every `interval` until the server shuts down.
+fn spawn_pending_sweeper(
+    entry: &PermissionServerEntry,
+    registry: &PermissionServerRegistry,
+    interval: Duration,
+) {
+    let registry = registry.clone();
+    let pending = entry.pending.clone();
+    let session = entry.session_id.clone();
+    let emitter = entry.emitter.clone();
+    let shutdown = shutdown_signal(entry.shutdown_tx.subscribe());
+    tokio::spawn(async move {
+        tokio::pin!(shutdown);
+        let mut ticks = tokio::time::interval(interval);
+        // The first tick fires immediately; nothing can be stale yet
+        ticks.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = &mut shutdown => break,
+            }
+            let session_id = session.lock().await.clone();
+            sweep_stale_pending(
+                &registry,
+                &pending,
+                emitter.as_ref(),
+                &session_id,
+                PENDING_SWEEP_GRACE,
+            )
+            .await;
+        }
+    });
+}
+
+/// Start an HTTP server for a session. Returns the TCP port it listens on,
+/// or 0 when it is bound to an abstract socket instead (see
+/// `mcp_file_options`).
+pub async fn start_server(
+    app: AppHandle,
+    session_id: &str,
+    config: PermissionServerConfig,
+    registry: &PermissionServerRegistry,
+) -> Result<u16, PermissionError> {
+    start_server_with(Arc::new(app), session_id, config, registry).await
+}
+
+/// `start_server` with the session's events going to `emitter` rather than
+/// the app's windows, e.g. to drive a real server from tests.
+pub async fn start_server_with(
+    emitter: Arc<dyn PermissionEmitter>,
+    session_id: &str,
+    config: PermissionServerConfig,
+    registry: &PermissionServerRegistry,
+) -> Result<u16, PermissionError> {
+    let policy = config
+        .policy_path
+        .as_deref()
+        .map(rules::load_policy)
+        .transpose()
+        .map_err(PermissionError::Invalid)?;
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let (listener, port, abstract_socket) =
+        bind_listener(session_id, config.transport, config.preferred_port).await?;
+    let tls = match (&listener, config.tls) {
+        (BoundListener::Tcp(_), true) => Some(Arc::new(tls::ServerTls::generate()?)),
+        (_, true) => {
+            if let Some(path) = listener.socket_path() {
+                remove_socket_file(&path);
+            }
+            return Err(PermissionError::Invalid(
+                "TLS is only supported for TCP permission servers".to_string(),
+            ));
+        }
+        (_, false) => None,
+    };
+    let tls_config = match &tls {
+        Some(tls) => Some(tls.rustls_config().await?),
+        None => None,
+    };
+
+    let mut entry = PermissionServerEntry::new(port, session_id, shutdown_tx, emitter, &config);
+    entry.abstract_socket = abstract_socket;
+    entry.socket_path = listener.socket_path();
+    entry.tls = tls;
+    *entry.policy.write().unwrap_or_else(|e| e.into_inner()) = policy;
+    if let Some(host) = listener.host() {
+        entry.host = host;
+    }
+    let state = HttpState::new(&entry, registry);
+    spawn_handshake_watchdog(
+        &entry,
+        registry,
+        config.handshake_grace.unwrap_or(DEFAULT_HANDSHAKE_GRACE),
+    );
+    spawn_pending_sweeper(&entry, registry, PENDING_SWEEP_INTERVAL);
+
+    let router = build_router(
+        state,
+        config.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES),
+    );
+
+    // Spawn the server with graceful shutdown
+    entry.server_task = Some(tokio::spawn(async move {
+        let shutdown = shutdown_signal(shutdown_rx);
+        match listener {
+            BoundListener::Tcp(listener) => match tls_config {
+                Some(tls_config) => serve_tls(listener, router, tls_config, shutdown).await,
+                None => axum::serve(listener, router)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+                    .ok(),
+            },
+            #[cfg(target_os = "linux")]
+            BoundListener::Abstract(listener) => axum::serve(listener, router)
+                .with_graceful_shutdown(shutdown)
+                .await
+                .ok(),
+            #[cfg(unix)]
+            BoundListener::Unix(listener, _) => axum::serve(listener, router)
+                .with_graceful_shutdown(shutdown)
+                .await
+                .ok(),
+        };
+        log::info!("Permission prompt server on port {} shut down", port);
+    }));
+
+    // Register in the global map (temp files are recorded after generate_mcp_files)
+    let emitter = entry.emitter.clone();
+    {
+        let mut servers = registry.servers.lock().await;
+        servers.insert(session_id.to_string(), entry);
+    }
+    emit_session_event(
+        emitter.as_ref(),
+        "permission-server-started",
+        session_id,
+        &ServerLifecycleEvent {
+            session_id: session_id.to_string(),
+            port,
+        },
+        registry.emit_generic(),
+    );
+
+    Ok(port)
+}
+
+/// Serve `router` over HTTPS until `shutdown` resolves, then let requests in
+/// progress finish, as `axum::serve`'s graceful shutdown does.
+async fn serve_tls(
+    listener: tokio::net::TcpListener,
+    router: Router,
+    config: axum_server::tls_rustls::RustlsConfig,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> Option<()> {
+    let listener = listener.into_std().ok()?;
+    let handle = axum_server::Handle::new();
+    let on_shutdown = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        on_shutdown.graceful_shutdown(None);
+    });
+    axum_server::tls_rustls::from_tcp_rustls(listener, config)
+        .handle(handle)
+        .serve(router.into_make_service())
+        .await
+        .ok()
+}
+
+/// The server's routes. Bodies over `max_body_bytes` are refused before any
+/// handler buffers them.
+fn build_router(state: HttpState, max_body_bytes: usize) -> Router {
+    Router::new()
+        .route("/permission-prompt", post(handle_permission_route))
+        .route("/user-input", post(handle_user_input))
+        .route("/resolve", post(handle_resolve))
+        .route("/pending", get(handle_pending))
+        .route("/ws", get(ws::handle_ws))
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .with_state(state)
+}
+
+/// The axum route handler. Rejects requests without the session's auth
+/// token, then dispatches single and batched requests.
+async fn handle_permission_route(
+    state: AxumState<HttpState>,
+    headers: HeaderMap,
+    Json(payload): Json<PermissionPayload>,
+) -> Result<Json<PermissionReply>, StatusCode> {
+    if !bearer_matches(&headers, &state.auth_token) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    match payload {
+        PermissionPayload::Single(req) => {
+            let Json(resp) = handle_permission_prompt(state, Json(req)).await?;
+            Ok(Json(PermissionReply::Single(resp)))
+        }
+        PermissionPayload::Batch(req) => {
+            let AxumState(state) = state;
+            Ok(Json(PermissionReply::Batch(
+                handle_permission_batch(state, req).await,
+            )))
+        }
+    }
+}
+
+/// Event emitted (session-scoped, plus the generic name unless disabled) for
+/// each `ask_user` question.
+pub const USER_INPUT_EVENT: &str = "user-input-request";
+
+/// Reply message when an `ask_user` question gets no answer in time.
+pub const USER_INPUT_TIMEOUT_MESSAGE: &str = "The user did not answer in time";
+
+/// Receives an `ask_user` question from the MCP script, emits
+/// `user-input-request`, then waits for `resolve_user_input` like a
+/// permission prompt waits for its answer. Uses the session's prompt timeout.
+async fn handle_user_input(
+    AxumState(state): AxumState<HttpState>,
+    headers: HeaderMap,
+    Json(req): Json<UserInputRequest>,
+) -> Result<Json<UserInputResponse>, StatusCode> {
+    if !bearer_matches(&headers, &state.auth_token) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    state.note_request().await;
+    if state.closing.load(Ordering::Relaxed) {
+        return Ok(Json(UserInputResponse::unanswered(SERVER_CLOSING_MESSAGE)));
+    }
+
+    let request_id = Uuid::new_v4().to_string();
+    let (tx, rx) = oneshot::channel();
+    state
+        .pending_inputs
+        .lock()
+        .await
+        .insert(request_id.clone(), tx);
+
+    let session_id = state.session_id.lock().await.clone();
+    let event = UserInputRequestEvent {
+        request_id: request_id.clone(),
+        session_id: session_id.clone(),
+        question: req.question,
+        created_at: unix_millis(),
+        context: req.context,
+        agent_path: req.agent_path,
+    };
+    emit_session_event(
+        state.emitter.as_ref(),
+        USER_INPUT_EVENT,
+        &session_id,
+        &event,
+        state.registry.emit_generic(),
+    );
+
+    // A dropped sender means the server stopped before anyone answered
+    let answer = match read_timeout(&state.prompt_timeout) {
+        Some(timeout) => tokio::time::timeout(timeout, rx)
+            .await
+            .unwrap_or_else(|_| Ok(UserInputResponse::unanswered(USER_INPUT_TIMEOUT_MESSAGE))),
+        None => rx.await,
+    };
+    state.pending_inputs.lock().await.remove(&request_id);
+    Ok(Json(answer.unwrap_or_else(|_| {
+        UserInputResponse::unanswered(SERVER_CLOSING_MESSAGE)
+    })))
+}
+
+/// Receives a permission request from the MCP script, emits a Tauri event,
+/// then waits for the frontend to respond.
+async fn handle_permission_prompt(
+    AxumState(state): AxumState<HttpState>,
+    Json(req): Json<PermissionRequest>,
+) -> Result<Json<PermissionResponse>, StatusCode> {
+    state.note_request().await;
+
+    // A flood is turned away before it can fill the pending map or the UI.
+    // It isn't a decision: nothing is emitted, logged or counted against
+    // the decision limit
+    if !state.rate_limiter.try_acquire(Instant::now()) {
+        state.metrics.count_rate_limited();
+        log::debug!(
+            "Rate limited a '{}' request for session '{}'",
+            req.tool_name,
+            state.session_id.lock().await
+        );
+        return Ok(Json(deny_with(RATE_LIMITED_MESSAGE)));
+    }
+
+    // A blocked tool is denied whatever else is set, a tool also on the
+    // allowlist or inspect mode included
+    let session_id = state.session_id.lock().await.clone();
+    if let Some((resp, source)) = blocked_decision(&state, &req.tool_name) {
+        record_unprompted(&state, &session_id, &req, &resp, source);
+        return Ok(Json(resp));
+    }
+
+    // A graceful stop lets prompts already showing finish but takes no more
+    if state.closing.load(Ordering::Relaxed) {
+        let resp = deny_with(SERVER_CLOSING_MESSAGE);
+        record_unprompted(&state, &session_id, &req, &resp, DecisionSource::Cancelled);
+        return Ok(Json(resp));
+    }
+
+    if state.inspect_mode.load(Ordering::Relaxed) {
+        let prompt_id = Uuid::new_v4().to_string();
+        return Ok(Json(inspect_request(&state, &req, &prompt_id).await));
+    }
+
+    // Past the limit nothing is allowed, not even listed tools
+    if state.decision_limit_reached(&session_id) {
+        let resp = deny_with(DECISION_LIMIT_MESSAGE);
+        record_unprompted(&state, &session_id, &req, &resp, DecisionSource::Limit);
+        return Ok(Json(resp));
+    }
+
+    // Allowlisted tools skip the prompt entirely, before a prompt id is
+    // allocated or anything goes into `pending`
+    if let Some((resp, source)) = allowed_decision(&state, &req) {
+        record_unprompted(&state, &session_id, &req, &resp, source);
+        return Ok(Json(resp));
+    }
+
+    let prompt_id = Uuid::new_v4().to_string();
+
+    // The session's policy, then the shared rules and the scope default, may
+    // answer without asking anyone
+    let cwd = request_cwd(&req, &state.cwd);
+    let decided = policy_decision(&state.registry, &state.policy, &session_id, &req, &cwd)
+        .map(|resp| (resp, DecisionSource::Rule))
+        .or_else(|| {
+            auto_decision(
+                &state.registry,
+                state.emitter.as_ref(),
+                &session_id,
+                &req,
+                &cwd,
+            )
+        });
+    if let Some((resp, source)) = decided {
+        state.record_decision(DecisionRecord {
+            message: resp.message.clone(),
+            ..DecisionRecord::new(
+                &session_id,
+                &prompt_id,
+                &req.tool_name,
+                &req.input,
+                &resp.behavior,
+                source,
+            )
+        });
+        return Ok(Json(resp));
+    }
+
+    // The user may have asked to remember the answer to this exact request
+    let remembered = state
+        .remembered
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&request_key(&req.tool_name, &req.input))
+        .cloned();
+    if let Some(resp) = remembered {
+        state.record_decision(DecisionRecord {
+            message: resp.message.clone(),
+            ..DecisionRecord::new(
+                &session_id,
+                &prompt_id,
+                &req.tool_name,
+                &req.input,
+                &resp.behavior,
+                DecisionSource::Remembered,
+            )
+        });
+        return Ok(Json(resp));
+    }
+
+    // A retry of a request already showing gets that prompt's answer rather
+    // than a second prompt. If the first handler gave up unanswered, the
+    // retry takes its place and the first prompt goes away.
+    let in_flight = loop {
+        match InFlightClaim::claim(&state.in_flight, &req.tool_use_id, &prompt_id) {
+            Ok(claim) => break claim,
+            Err((held_by, mut answer)) => {
+                if let Ok(resp) = answer.wait_for(Option::is_some).await {
+                    if let Some(resp) = resp.as_ref() {
+                        return Ok(Json(resp.clone()));
+                    }
+                }
+                drop_abandoned_prompt(&state, &held_by).await;
+            }
+        }
+    };
+
+    // Held until the prompt is answered, so only `max_concurrent` show at once
+    let _slot = match state.prompt_slot().await {
+        Ok(slot) => slot,
+        Err(resp) => {
+            state.record_decision(DecisionRecord {
+                message: resp.message.clone(),
+                ..DecisionRecord::new(
+                    &session_id,
+                    &prompt_id,
+                    &req.tool_name,
+                    &req.input,
+                    &resp.behavior,
+                    DecisionSource::Overflow,
+                )
+            });
+            return Ok(Json(resp));
+        }
+    };
+
+    count_tool_prompt(&state.tool_prompts, &req.tool_name);
+    let (tx, rx) = oneshot::channel::<(PermissionResponse, DecisionSource)>();
+
+    // Store the sender so `resolve_prompt` can complete the request later
+    let timeout = tool_timeout(&state.prompt_timeout, &state.tool_timeouts, &req.tool_name);
+    let prompt = PendingPrompt::new(PendingReply::Single(tx), &req.tool_name, timeout);
+    let deadline = prompt.deadline.clone();
+    let remember = prompt.remember.clone();
+    let session_id = state.session_id.lock().await.clone();
+    state
+        .registry
+        .update_pending(&state.pending, state.emitter.as_ref(), &session_id, |p| {
+            p.insert(prompt_id.clone(), prompt)
+        })
+        .await;
+    log_prompt_step(
+        log::Level::Info,
+        &session_id,
+        &prompt_id,
+        "created",
+        format_args!("tool={} tool_use_id={}", req.tool_name, req.tool_use_id),
+    );
+
+    let mut paused_rx = state.registry.paused.subscribe();
+    queue_while_paused(&session_id, &prompt_id, &mut paused_rx, &deadline).await;
+
+    let session_id = state.session_id.lock().await.clone();
+
+    // The dialog shows the auto-edited input (if any) and offers it as the
+    // default; the original stays available to revert to and for audit.
+    let auto_edited = state.registry.auto_edited_input(&req.tool_name, &req.input);
+    let shown_input = auto_edited.clone().unwrap_or_else(|| req.input.clone());
+    let event = PermissionPromptEvent {
+        suggested_input: auto_edited.clone(),
+        original_input: auto_edited.as_ref().map(|_| req.input.clone()),
+        timeout_ms: timeout.map(|t| t.as_millis() as u64),
+        ..request_event(&prompt_id, &session_id, &req, &shown_input, &cwd)
+    };
+
+    // Keep the full event for `get_prompt_event`, then emit the
+    // session-scoped event (plus the generic one unless disabled)
+    let event = state.registry.transform_prompt_event(event);
+    if let Some(prompt) = state.pending.lock().await.get_mut(&prompt_id) {
+        prompt.event = Some(event.clone());
+    }
+    let emitted = state.registry.emit_prompt_event(&state.emitter, &event);
+    // What the dialog got instead of the input, if not the input itself: a
+    // copy with secrets masked, then a preview if it was too big
+    let redacted = state.registry.redact_input(&shown_input);
+    let redacted_original = auto_edited
+        .as_ref()
+        .and_then(|_| state.registry.redact_input(&req.input));
+    let preview = input_preview(
+        redacted.as_ref().unwrap_or(&shown_input),
+        state.registry.max_input_bytes.load(Ordering::Relaxed),
+    );
+
+    // Wait for the frontend to respond (timeout → auto-deny). If no event got
+    // through nobody will ever answer, so deny now instead.
+    let unreachable = if emitted {
+        None
+    } else {
+        abandon_unreachable(&state, &prompt_id).await
+    };
+    let answer = match unreachable {
+        Some(answer) => Some(answer),
+        None => wait_for_response(rx, deadline, paused_rx).await,
+    };
+    let (mut resp, source) = match answer {
+        Some(answer) => answer,
+        None => {
+            let (source, message) = expire_pending(&state, &prompt_id).await;
+            let resp = match source {
+                DecisionSource::Timeout => {
+                    timeout_response(state.timeout_behavior, message, &shown_input, &req.input)
+                }
+                _ => deny_with(message),
+            };
+            (resp, source)
+        }
+    };
+
+    // The dialog may only have had a redacted copy or a preview of the
+    // input; an allow that didn't edit it gets the real input back
+    let stood_in = redacted.is_some() || preview.is_some();
+    if resp.behavior == "allow" && resp.updated_input.is_none() && stood_in {
+        resp.updated_input = Some(shown_input.clone());
+    }
+    let stand_ins = [
+        (redacted.as_ref(), &shown_input),
+        (preview.as_ref(), &shown_input),
+        (redacted_original.as_ref(), &req.input),
+    ];
+    restore_real_input(&mut resp, &stand_ins);
+
+    // Remembered as Claude Code gets it, so a replay carries the real input
+    if source == DecisionSource::User && remember.load(Ordering::Relaxed) {
+        state
+            .remembered
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(request_key(&req.tool_name, &req.input), resp.clone());
+    }
+
+    // Claude Code only sees an allow; the log keeps the quarantine intent
+    let behavior = if is_quarantined(&resp) {
+        QUARANTINE_BEHAVIOR
+    } else {
+        resp.behavior.as_str()
+    };
+    let mut record = DecisionRecord::new(
+        &session_id,
+        &prompt_id,
+        &req.tool_name,
+        &req.input,
+        behavior,
+        source,
+    );
+    record.auto_edited_input = auto_edited;
+    record.message = resp.message.clone();
+    log_prompt_step(
+        log::Level::Info,
+        &session_id,
+        &prompt_id,
+        "resolved",
+        format_args!("behavior={} source={:?}", behavior, source),
+    );
+    state.record_decision(record);
+    if let Some(claim) = &in_flight {
+        claim.finish(&resp);
+    }
+    Ok(Json(resp))
+}
+
+/// The prompt event for a request from Claude Code, showing `shown_input`.
+fn request_event(
+    prompt_id: &str,
+    session_id: &str,
+    req: &PermissionRequest,
+    shown_input: &serde_json::Value,
+    cwd: &Path,
+) -> PermissionPromptEvent {
+    PermissionPromptEvent {
+        prompt_id: prompt_id.to_string(),
+        session_id: session_id.to_string(),
+        tool_use_id: req.tool_use_id.clone(),
+        tool_name: req.tool_name.clone(),
+        created_at: unix_millis(),
+        summary: explain::target_summary(shown_input),
+        explanation: explain::explain_request(&req.tool_name, shown_input),
+        risk_category: explain::classify_tool(&req.tool_name, shown_input),
+        input: shown_input.clone(),
+        suggested_input: None,
+        original_input: None,
+        context: req.context.clone(),
+        agent_path: req.agent_path.clone(),
+        test: false,
+        inspect: false,
+        truncated: false,
+        input_truncated: false,
+        input_bytes: None,
+        cwd: Some(cwd.to_string_lossy().to_string()),
+        timeout_ms: None,
+    }
+}
+
+/// Swap a stand-in for an input (a redacted copy or a preview) that an allow
+/// sent back unedited for the real input it stands for.
+fn restore_real_input(
+    resp: &mut PermissionResponse,
+    stand_ins: &[(Option<&serde_json::Value>, &serde_json::Value)],
+) {
+    if resp.behavior != "allow" {
+        return;
+    }
+    let Some(input) = &resp.updated_input else {
+        return;
+    };
+    let real = stand_ins
+        .iter()
+        .find(|(stand_in, _)| *stand_in == Some(input))
+        .map(|(_, real)| (*real).clone());
+    if let Some(real) = real {
+        resp.updated_input = Some(real);
+    }
+}
+
+/// Record a decision made before any prompt was considered. It has no
+/// prompt id, so it gets one of its own that only the log uses.
+fn record_unprompted(
+    state: &HttpState,
+    session_id: &str,
+    req: &PermissionRequest,
+    resp: &PermissionResponse,
+    source: DecisionSource,
+) {
+    state.record_decision(DecisionRecord {
+        message: resp.message.clone(),
+        ..DecisionRecord::new(
+            session_id,
+            &Uuid::new_v4().to_string(),
+            &req.tool_name,
+            &req.input,
+            &resp.behavior,
+            source,
+        )
+    });
+}
+
+/// Let a request through in inspect mode: allowed with its input as sent,
+/// shown to the UI as an `inspect` event and logged with source `inspect`.
+async fn inspect_request(
+    state: &HttpState,
+    req: &PermissionRequest,
+    prompt_id: &str,
+) -> PermissionResponse {
+    let session_id = state.session_id.lock().await.clone();
+    let cwd = request_cwd(req, &state.cwd);
+    let event = PermissionPromptEvent {
+        inspect: true,
+        ..request_event(prompt_id, &session_id, req, &req.input, &cwd)
+    };
+    let event = state.registry.transform_prompt_event(event);
+    state
+        .registry
+        .send_prompt_event(state.emitter.as_ref(), &event);
+
+    let resp = PermissionResponse {
+        behavior: "allow".to_string(),
+        updated_input: Some(req.input.clone()),
+        message: None,
+    };
+    log_prompt_step(
+        log::Level::Info,
+        &session_id,
+        prompt_id,
+        "inspected",
+        format_args!("tool={}", req.tool_name),
+    );
+    state.record_decision(DecisionRecord::new(
+        &session_id,
+        prompt_id,
+        &req.tool_name,
+        &req.input,
+        &resp.behavior,
+        DecisionSource::Inspect,
+    ));
+    resp
+}
+
+/// Turn a session's inspect mode on or off. While on, every request for a
+/// tool not on the session's block list is allowed with its input as sent,
+/// without asking; the UI still gets each
+/// one as a prompt event marked `inspect`, and the decision log and audit
+/// log record it with source `inspect`. Requests already waiting for an
+/// answer are unaffected.
+pub async fn set_inspect_mode(
+    session_id: &str,
+    enabled: bool,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    entry.inspect_mode.store(enabled, Ordering::Relaxed);
+    log::info!(
+        "Inspect mode {} for session '{}'",
+        if enabled { "on" } else { "off" },
+        session_id
+    );
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Controller endpoints (headless approvals)
+// ---------------------------------------------------------------------------
+
+/// One entry of `GET /pending` and `list_pending`. Batch prompts have no
+/// stored event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingPromptInfo {
+    pub prompt_id: String,
+    pub tool_name: String,
+    /// When the prompt was registered, in Unix milliseconds.
+    pub created_at_ms: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<PermissionPromptEvent>,
+}
+
+/// Whether the request carries `Authorization: Bearer {token}`.
+fn bearer_matches(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+}
+
+/// Byte equality that takes as long wherever the first difference is, so
+/// timing can't reveal how much of a guessed token was right. Only the
+/// length, which is fixed for our tokens, shows.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Describe pending prompts, soonest deadline first.
+fn pending_infos(
+    pending: &HashMap<String, PendingPrompt>,
+    registry: &PermissionServerRegistry,
+) -> Vec<PendingPromptInfo> {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let mut prompts: Vec<(Option<Instant>, PendingPromptInfo)> = pending
+        .iter()
+        .map(|(prompt_id, prompt)| {
+            let info = PendingPromptInfo {
+                prompt_id: prompt_id.clone(),
+                tool_name: prompt.tool_name.clone(),
+                created_at_ms: now_ms - prompt.created_at.elapsed().as_millis() as i64,
+                event: prompt.event.clone().map(|e| registry.redact_event(e)),
+            };
+            (*prompt.deadline.borrow(), info)
+        })
+        .collect();
+    // Prompts without a deadline go last
+    prompts.sort_by_key(|(deadline, _)| (deadline.is_none(), *deadline));
+    prompts.into_iter().map(|(_, info)| info).collect()
+}
+
+/// Hold a freshly registered prompt while the registry is globally paused.
+/// It stays resolvable but is only shown once prompting resumes, and the
+/// time spent queued doesn't count against its deadline.
+async fn queue_while_paused(
+    session_id: &str,
+    prompt_id: &str,
+    paused_rx: &mut watch::Receiver<bool>,
+    deadline: &watch::Sender<Option<Instant>>,
+) {
+    if *paused_rx.borrow() {
+        log_prompt_step(
+            log::Level::Info,
+            session_id,
+            prompt_id,
+            "queued",
+            format_args!("prompting is paused"),
+        );
+        let paused_at = Instant::now();
+        let _ = paused_rx.wait_for(|paused| !*paused).await;
+        push_back_deadline(deadline, paused_at.elapsed());
+    }
+}
+
+/// Drop a prompt whose event reached no listener. Returns the deny to answer
+/// with, or `None` if the prompt was already resolved some other way and its
+/// answer is waiting on the channel.
+async fn abandon_unreachable(
+    state: &HttpState,
+    prompt_id: &str,
+) -> Option<(PermissionResponse, DecisionSource)> {
+    let session_id = state.session_id.lock().await.clone();
+    state
+        .registry
+        .update_pending(&state.pending, state.emitter.as_ref(), &session_id, |p| {
+            p.remove(prompt_id)
+        })
+        .await?;
+    log_prompt_step(
+        log::Level::Warn,
+        &session_id,
+        prompt_id,
+        "unreachable",
+        format_args!("no listener got the event"),
+    );
+    Some((
+        deny_with(FRONTEND_UNREACHABLE_MESSAGE),
+        DecisionSource::Cancelled,
+    ))
+}
+
+/// Drop a prompt whose handler went away unanswered (its caller hung up)
+/// when a retry takes over, and tell the UI it's gone with
+/// `permission-cancelled`. Nothing happens if it's no longer pending.
+async fn drop_abandoned_prompt(state: &HttpState, prompt_id: &str) {
+    let session_id = state.session_id.lock().await.clone();
+    let dropped = state
+        .registry
+        .update_pending(&state.pending, state.emitter.as_ref(), &session_id, |p| {
+            p.remove(prompt_id)
+        })
+        .await;
+    if dropped.is_none() {
+        return;
+    }
+    let event = PromptCancelledEvent {
+        session_id: session_id.clone(),
+        prompt_id: prompt_id.to_string(),
+    };
+    emit_session_event(
+        state.emitter.as_ref(),
+        "permission-cancelled",
+        &session_id,
+        &event,
+        state.registry.emit_generic(),
+    );
+    log_prompt_step(
+        log::Level::Info,
+        &session_id,
+        prompt_id,
+        "cancelled",
+        format_args!("taken over by a retry"),
+    );
+}
+
+/// Clean up a prompt that ended without an answer. Still pending means it
+/// timed out; otherwise `stop_server` dropped the sender. Either way the
+/// caller denies with the returned message.
+async fn expire_pending(state: &HttpState, prompt_id: &str) -> (DecisionSource, &'static str) {
+    let session_id = state.session_id.lock().await.clone();
+    let expired = state
+        .registry
+        .update_pending(&state.pending, state.emitter.as_ref(), &session_id, |p| {
+            p.remove(prompt_id)
+        })
+        .await;
+    match expired {
+        Some(prompt) => {
+            log_prompt_step(
+                log::Level::Info,
+                &session_id,
+                prompt_id,
+                "timed_out",
+                format_args!("after={:?}", prompt.created_at.elapsed()),
+            );
+            let event = PromptTimeoutEvent {
+                session_id: session_id.clone(),
+                prompt_id: prompt_id.to_string(),
+                tool_name: prompt.tool_name,
+            };
+            emit_session_event(
+                state.emitter.as_ref(),
+                "permission-timeout",
+                &session_id,
+                &event,
+                state.registry.emit_generic(),
+            );
+            (DecisionSource::Timeout, "Permission prompt timed out")
+        }
+        None => (DecisionSource::Cancelled, "Permission prompt was cancelled"),
+    }
+}
+
+/// The answer for a timed-out prompt under the session's timeout behavior.
+/// The message says which policy fired.
+fn timeout_response(
+    behavior: TimeoutBehavior,
+    deny_message: &str,
+    shown_input: &serde_json::Value,
+    original_input: &serde_json::Value,
+) -> PermissionResponse {
+    let (input, message) = match behavior {
+        TimeoutBehavior::Deny => return deny_with(deny_message),
+        TimeoutBehavior::Allow => (shown_input, "Auto-allowed on timeout"),
+        TimeoutBehavior::AllowWithOriginalInput => (
+            original_input,
+            "Auto-allowed on timeout with the original input",
+        ),
+    };
+    PermissionResponse {
+        behavior: "allow".to_string(),
+        updated_input: Some(input.clone()),
+        message: Some(message.to_string()),
+    }
+}
+
+/// Answer a request from the rule engine when a rule matches or the effective
+/// scope default isn't `Prompt`.
+fn auto_decision(
+    registry: &PermissionServerRegistry,
+    emitter: &dyn PermissionEmitter,
+    session_id: &str,
+    req: &PermissionRequest,
+    cwd: &Path,
+) -> Option<(PermissionResponse, DecisionSource)> {
+    if let Some(resp) = rule_decision(registry, emitter, session_id, req, cwd) {
+        return Some((resp, DecisionSource::Rule));
+    }
+
+    let fallback = registry
+        .rules
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .default_outcome();
+    let resp = match fallback.outcome {
+        DefaultOutcome::Prompt => return None,
+        DefaultOutcome::AllowAll => allow_unchanged(&req.input),
+        DefaultOutcome::DenyAll => deny_with(
+            fallback
+                .message
+                .as_deref()
+                .unwrap_or("No permission rule allows this request"),
+        ),
+    };
+    Some((resp, DecisionSource::Default))
+}
+
+/// The answer of the first matching rule, if any. Rules see the input with
+/// relative paths resolved; an allow passes the original input back
+/// unchanged. Conditional rules also see the session's earlier manual
+/// allows, resolved the same way.
+fn rule_decision(
+    registry: &PermissionServerRegistry,
+    emitter: &dyn PermissionEmitter,
+    session_id: &str,
+    req: &PermissionRequest,
+    cwd: &Path,
+) -> Option<PermissionResponse> {
+    let (rule_set, policy, allowlisted) = {
+        let engine = registry.rules.read().unwrap_or_else(|e| e.into_inner());
+        let allowlisted = req.tool_name == "Bash"
+            && req
+                .input
+                .get("command")
+                .and_then(|c| c.as_str())
+                .is_some_and(|c| engine.command_allowlist.contains(c));
+        (engine.merged(), engine.conflict_policy, allowlisted)
+    };
+    let prior_allows = if rule_set.has_conditional_rules() {
+        prior_user_allows(registry, session_id, cwd)
+    } else {
+        Vec::new()
+    };
+    let resolved = resolve_input_paths(&req.input, cwd);
+    let target = RuleTarget {
+        agent_path: &req.agent_path,
+        prior_allows: &prior_allows,
+        ..RuleTarget::new(&req.tool_name, &resolved)
+    };
+
+    // Pattern rules come first so a deny still beats the allowlist
+    let RuleEvaluation {
+        winner: rule,
+        overridden,
+    } = match rule_set.evaluate_with_conflicts(&target, policy) {
+        Some(evaluation) => evaluation,
+        None if allowlisted => {
+            log::info!(
+                "Allowlisted command hash allowed a Bash request in session '{}'",
+                session_id
+            );
+            return Some(allow_unchanged(&req.input));
+        }
+        None => return None,
+    };
+    if !overridden.is_empty() {
+        log::warn!(
+            "Conflicting permission rules for a '{}' request in session '{}'; '{}' ({:?}) won under {:?}",
+            req.tool_name,
+            session_id,
+            rule.tool,
+            rule.action,
+            policy
+        );
+        let event = RuleConflictEvent {
+            session_id: session_id.to_string(),
+            tool_name: req.tool_name.clone(),
+            policy,
+            winner: rule.clone(),
+            overridden: overridden.into_iter().cloned().collect(),
+        };
+        emit_session_event(
+            emitter,
+            "permission-rule-conflict",
+            session_id,
+            &event,
+            registry.emit_generic(),
+        );
+    }
+    if rule.is_conditional() {
+        log::info!(
+            "Conditional rule for '{}' ({:?}) answered a '{}' request in session '{}'",
+            rule.tool,
+            rule.condition,
+            req.tool_name,
+            session_id
+        );
+    }
+    Some(rule_response(rule, &req.input))
+}
+
+/// What a matching rule answers. An allow passes `input` back unchanged.
+fn rule_response(rule: &PermissionRule, input: &serde_json::Value) -> PermissionResponse {
+    match rule.action {
+        RuleAction::Allow => allow_unchanged(input),
+        RuleAction::Deny => deny_with(
+            rule.message
+                .as_deref()
+                .unwrap_or("Denied by permission rule"),
+        ),
+    }
+}
+
+/// The answer of the session's policy, if one of its rules matches. Rules
+/// see the same resolved input and prior allows as in `rule_decision`.
+fn policy_decision(
+    registry: &PermissionServerRegistry,
+    policy: &SessionPolicy,
+    session_id: &str,
+    req: &PermissionRequest,
+    cwd: &Path,
+) -> Option<PermissionResponse> {
+    let policy = policy.read().unwrap_or_else(|e| e.into_inner());
+    let policy = policy.as_ref()?;
+    let prior_allows = if policy.has_conditional_rules() {
+        prior_user_allows(registry, session_id, cwd)
+    } else {
+        Vec::new()
+    };
+    let resolved = resolve_input_paths(&req.input, cwd);
+    let target = RuleTarget {
+        agent_path: &req.agent_path,
+        prior_allows: &prior_allows,
+        ..RuleTarget::new(&req.tool_name, &resolved)
+    };
+    let rule = policy.evaluate(&target)?;
+    log::info!(
+        "Policy rule for '{}' ({:?}) answered a '{}' request in session '{}'",
+        rule.tool,
+        rule.action,
+        req.tool_name,
+        session_id
+    );
+    Some(rule_response(rule, &req.input))
+}
+
+/// Requests the user manually allowed in this session, from the recent
+/// decisions buffer.
+fn prior_user_allows(
+    registry: &PermissionServerRegistry,
+    session_id: &str,
+    cwd: &Path,
+) -> Vec<PriorAllow> {
+    registry
+        .decisions
+        .recent(Some(session_id))
+        .into_iter()
+        .filter(|d| d.source == DecisionSource::User && d.behavior == "allow")
+        .map(|d| PriorAllow {
+            input: resolve_input_paths(&d.input, cwd),
+            tool_name: d.tool_name,
+        })
+        .collect()
+}
+
+fn allow_unchanged(input: &serde_json::Value) -> PermissionResponse {
+    PermissionResponse {
+        behavior: "allow".to_string(),
+        updated_input: Some(input.clone()),
+        message: None,
+    }
+}
+
+fn deny_with(message: &str) -> PermissionResponse {
+    PermissionResponse {
+        behavior: "deny".to_string(),
+        updated_input: None,
+        message: Some(message.to_string()),
+    }
+}
+
+/// Wait for a prompt's response until its deadline. The deadline may be
+/// moved while waiting, and time spent while the registry is globally paused
+/// pushes it back. Without a deadline it waits until answered. Returns
+/// `None` on timeout or if the sender was dropped.
+async fn wait_for_response<T>(
+    mut rx: oneshot::Receiver<T>,
+    deadline: Arc<watch::Sender<Option<Instant>>>,
+    mut paused_rx: watch::Receiver<bool>,
+) -> Option<T> {
+    let mut deadline_rx = deadline.subscribe();
+    loop {
+        if *paused_rx.borrow_and_update() {
+            let paused_at = Instant::now();
+            tokio::select! {
+                resp = &mut rx => return resp.ok(),
+                changed = paused_rx.changed() => {
+                    push_back_deadline(&deadline, paused_at.elapsed());
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+
+        let until = *deadline_rx.borrow_and_update();
+        let expired = async {
+            match until {
+                Some(until) => tokio::time::sleep_until(until.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            resp = &mut rx => return resp.ok(),
+            _ = expired => return None,
+            changed = paused_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            // We hold the sender, so this only fires on a real change
+            _ = deadline_rx.changed() => {}
+        }
+    }
+
+    // The registry is gone; fall back to waiting out the current deadline.
+    let until = *deadline.borrow();
+    match until {
+        Some(until) => tokio::time::timeout_at(until.into(), rx).await.ok()?.ok(),
+        None => rx.await.ok(),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Input helpers
+// ---------------------------------------------------------------------------
+
+/// Input fields that hold filesystem paths in Claude Code's built-in tools.
+const PATH_FIELDS: &[&str] = &["file_path", "path", "notebook_path"];
+
+/// A per-tool edit applied to incoming input before prompting, e.g. always
+/// appending `--dry-run` to a command. The edited input becomes the dialog's
+/// default; the user can still revert to the original.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AutoEdit {
+    /// Top-level fields to set, replacing any existing value.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, serde_json::Value>,
+    /// Text appended to top-level string fields, unless they already end
+    /// with it.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub append: BTreeMap<String, String>,
+}
+
+impl AutoEdit {
+    /// Apply the edit to a copy of `input`. Non-object inputs are returned
+    /// unchanged.
+    pub fn apply(&self, input: &serde_json::Value) -> serde_json::Value {
+        let mut edited = input.clone();
+        if let Some(obj) = edited.as_object_mut() {
+            for (field, value) in &self.set {
+                obj.insert(field.clone(), value.clone());
+            }
+            for (field, suffix) in &self.append {
+                if let Some(serde_json::Value::String(text)) = obj.get_mut(field) {
+                    if !text.ends_with(suffix.as_str()) {
+                        text.push_str(suffix);
+                    }
+                }
+            }
+        }
+        edited
+    }
+}
+
+/// The directory a request's relative paths refer to: the one the MCP script
+/// reported (itself relative to the session's, if relative), else the
+/// session's.
+fn request_cwd(req: &PermissionRequest, session_cwd: &Path) -> PathBuf {
+    match req.cwd.as_deref().filter(|cwd| !cwd.is_empty()) {
+        Some(cwd) => session_cwd.join(cwd),
+        None => session_cwd.to_path_buf(),
+    }
+}
+
+/// Return a copy of `input` with relative path fields made absolute against
+/// `cwd`, so classification and rule matching see the real target.
+pub fn resolve_input_paths(input: &serde_json::Value, cwd: &Path) -> serde_json::Value {
+    let mut resolved = input.clone();
+    if let Some(obj) = resolved.as_object_mut() {
+        for field in PATH_FIELDS {
+            if let Some(serde_json::Value::String(p)) = obj.get_mut(*field) {
+                if !p.is_empty() && Path::new(p.as_str()).is_relative() {
+                    *p = cwd.join(p.as_str()).to_string_lossy().to_string();
+                }
+            }
+        }
+    }
+    resolved
+}
+
+// ---------------------------------------------------------------------------
+// Lifecycle helpers
+// ---------------------------------------------------------------------------
+
+/// Re-key a server entry from a placeholder ID to the real session ID.
+/// Also updates the shared session_id Arc so the HTTP handler emits
+/// events with the correct session ID.
+pub async fn rekey_server(
+    old_id: &str,
+    new_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let mut servers = registry.servers.lock().await;
+    let entry = servers
+        .remove(old_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(old_id.to_string()))?;
+    // Update the shared session_id so the axum HTTP handler will emit
+    // Tauri events with the real session ID (not the placeholder).
+    {
+        let mut sid = entry.session_id.lock().await;
+        *sid = new_id.to_string();
+    }
+    servers.insert(new_id.to_string(), entry);
+    log::info!(
+        "Re-keyed permission server from '{}' to '{}'",
+        old_id,
+        new_id
+    );
+    Ok(())
+}
+
+/// Load a policy file for a running session, or with `None` drop its
+/// policy. A file that fails to load leaves the current policy in place.
+/// Returns the number of rules now in effect.
+pub async fn set_policy(
+    session_id: &str,
+    path: Option<PathBuf>,
+    registry: &PermissionServerRegistry,
+) -> Result<usize, PermissionError> {
+    let policy = path
+        .as_deref()
+        .map(rules::load_policy)
+        .transpose()
+        .map_err(PermissionError::Invalid)?;
+    let count = policy.as_ref().map_or(0, |p| p.rules.len());
+
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    *entry.policy.write().unwrap_or_else(|e| e.into_inner()) = policy;
+    log::info!(
+        "Permission session '{}' now has {} policy rules",
+        session_id,
+        count
+    );
+    Ok(count)
+}
+
+/// Re-read a session's policy file after it was edited. See `set_policy`.
+pub async fn reload_policy(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<usize, PermissionError> {
+    let source = {
+        let servers = registry.servers.lock().await;
+        let entry = servers
+            .get(session_id)
+            .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+        let policy = entry.policy.read().unwrap_or_else(|e| e.into_inner());
+        policy.as_ref().and_then(|p| p.source.clone())
+    };
+    let source = source.ok_or_else(|| {
+        PermissionError::Invalid(format!(
+            "Session '{}' has no policy file to reload",
+            session_id
+        ))
+    })?;
+    set_policy(session_id, Some(source), registry).await
+}
+
+/// Enable or disable the generic (unscoped) variant of permission events.
+pub fn set_emit_generic_events(registry: &PermissionServerRegistry, enabled: bool) {
+    registry
+        .emit_generic_events
+        .store(enabled, Ordering::Relaxed);
+}
+
+/// Globally pause permission prompting across all sessions (maintenance
+/// mode). New prompts are queued rather than shown, and timeouts of prompts
+/// already pending are suspended until `resume_all`.
+pub fn pause_all(registry: &PermissionServerRegistry) {
+    if !registry.paused.send_replace(true) {
+        log::info!("Permission prompting paused globally");
+        registry.emit_app_event(
+            "permission-global-pause",
+            &serde_json::json!({ "paused": true }),
+        );
+    }
+}
+
+/// Resume prompting after `pause_all`. Queued prompts are emitted as their
+/// handlers wake up.
+pub fn resume_all(registry: &PermissionServerRegistry) {
+    if registry.paused.send_replace(false) {
+        log::info!("Permission prompting resumed globally");
+        registry.emit_app_event(
+            "permission-global-pause",
+            &serde_json::json!({ "paused": false }),
+        );
+    }
+}
+
+/// Install (or clear, with `None`) the hook applied to prompt events before
+/// they are emitted.
+pub fn set_event_transform(registry: &PermissionServerRegistry, transform: Option<EventTransform>) {
+    *registry
+        .event_transform
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = transform;
+}
+
+/// Response behavior for "allow, but sandboxed". See `QUARANTINE_FIELD`.
+pub const QUARANTINE_BEHAVIOR: &str = "quarantine";
+
+/// Key added to `updatedInput` when a prompt is quarantined.
+///
+/// The permission-prompt tool protocol has only `allow` and `deny`, and on
+/// allow Claude Code runs the tool with `updatedInput` as its input. There
+/// is no documented field asking a tool to sandbox itself, so Claude Code's
+/// own tools don't act on this key; it is namespaced so it can't clobber a
+/// real field. Enforcement is left to a wrapper, which can also watch the
+/// `permission-quarantined` event and the `quarantine` entries in the
+/// decision log.
+pub const QUARANTINE_FIELD: &str = "__opcode_quarantine";
+
+fn is_quarantined(resp: &PermissionResponse) -> bool {
+    resp.behavior == "allow"
+        && resp
+            .updated_input
+            .as_ref()
+            .and_then(|input| input.get(QUARANTINE_FIELD))
+            .is_some()
+}
+
+/// Turn a quarantine answer into an allow whose input carries the sandbox
+/// flag, and announce it.
+async fn quarantine_response(
+    session_id: &str,
+    prompt_id: &str,
+    response: PermissionResponse,
+    registry: &PermissionServerRegistry,
+) -> Result<PermissionResponse, PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    let event = entry
+        .pending
+        .lock()
+        .await
+        .get(prompt_id)
+        .and_then(|prompt| prompt.event.clone())
+        .ok_or_else(|| PermissionError::PromptNotFound(prompt_id.to_string()))?;
+
+    let mut input = response
+        .updated_input
+        .or(event.original_input)
+        .unwrap_or(event.input);
+    match input.as_object_mut() {
+        Some(fields) => {
+            fields.insert(
+                QUARANTINE_FIELD.to_string(),
+                serde_json::json!({ "sandbox": true }),
+            );
+        }
+        None => {
+            return Err(PermissionError::Invalid(
+                "Only object inputs can be quarantined".to_string(),
+            ))
+        }
+    }
+
+    let current_id = entry.session_id.lock().await.clone();
+    log_prompt_step(
+        log::Level::Info,
+        &current_id,
+        prompt_id,
+        "quarantined",
+        format_args!("tool={}", event.tool_name),
+    );
+    emit_session_event(
+        entry.emitter.as_ref(),
+        "permission-quarantined",
+        &current_id,
+        &QuarantinedEvent {
+            session_id: current_id.clone(),
+            prompt_id: prompt_id.to_string(),
+            tool_name: event.tool_name,
+        },
+        registry.emit_generic(),
+    );
+    Ok(PermissionResponse {
+        behavior: "allow".to_string(),
+        updated_input: Some(input),
+        message: response.message,
+    })
+}
+
+/// Resolve a pending permission prompt with a response from the frontend.
+///
+/// With deny confirmation enabled, denying a high-risk prompt only stages the
+/// deny: a `permission-deny-confirm` event is emitted and the prompt stays
+/// pending until `confirm_deny` (or another response) arrives. A
+/// `quarantine` response is sent to Claude Code as an allow flagged with
+/// `QUARANTINE_FIELD`.
+pub async fn resolve_prompt(
+    session_id: &str,
+    prompt_id: &str,
+    response: PermissionResponse,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    resolve_prompt_with(session_id, prompt_id, response, None, false, registry).await
+}
+
+/// Message Claude gets when the user denies without saying why.
+pub const USER_DENY_MESSAGE: &str = "Denied by user";
+
+/// Deny a prompt, telling Claude why so it can adapt. A missing or blank
+/// `message` falls back to `USER_DENY_MESSAGE`.
+pub async fn deny_prompt(
+    session_id: &str,
+    prompt_id: &str,
+    message: Option<&str>,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let message = message
+        .map(str::trim)
+        .filter(|message| !message.is_empty())
+        .unwrap_or(USER_DENY_MESSAGE);
+    resolve_prompt(session_id, prompt_id, deny_with(message), registry).await
+}
+
+/// Allow a prompt. Claude Code runs the tool with `updated_input`, which
+/// must be an object; `None` runs it with the input the user was shown.
+pub async fn allow_prompt(
+    session_id: &str,
+    prompt_id: &str,
+    updated_input: Option<serde_json::Value>,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let updated_input = match updated_input {
+        Some(input) => Some(input),
+        None => shown_prompt_input(session_id, prompt_id, registry).await,
+    };
+    let response = PermissionResponse {
+        behavior: "allow".to_string(),
+        updated_input,
+        message: None,
+    };
+    resolve_prompt(session_id, prompt_id, response, registry).await
+}
+
+/// The unredacted input a pending single prompt shows, if there is one.
+async fn shown_prompt_input(
+    session_id: &str,
+    prompt_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Option<serde_json::Value> {
+    let servers = registry.servers.lock().await;
+    let pending = servers.get(session_id)?.pending.lock().await;
+    pending
+        .get(prompt_id)
+        .and_then(|prompt| prompt.event.as_ref())
+        .map(|event| event.input.clone())
+}
+
+/// Answer an `ask_user` question; `None` dismisses it.
+pub async fn resolve_user_input(
+    session_id: &str,
+    request_id: &str,
+    answer: Option<String>,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let pending_inputs = {
+        let servers = registry.servers.lock().await;
+        let entry = servers
+            .get(session_id)
+            .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+        entry.pending_inputs.clone()
+    };
+    let tx = pending_inputs
+        .lock()
+        .await
+        .remove(request_id)
+        .ok_or_else(|| PermissionError::PromptNotFound(request_id.to_string()))?;
+
+    let response = match answer {
+        Some(answer) => UserInputResponse {
+            answer: Some(answer),
+            message: None,
+        },
+        None => UserInputResponse::unanswered("The user dismissed the question"),
+    };
+    tx.send(response)
+        .map_err(|_| PermissionError::ReceiverDropped)
+}
+
+/// `resolve_prompt`, optionally applying a named input transform (see
+/// `MODIFY_BEHAVIOR`) and remembering the answer so identical requests (same
+/// tool and input) later in the session get it without a prompt. A deny
+/// staged for confirmation is not remembered.
+pub async fn resolve_prompt_with(
+    session_id: &str,
+    prompt_id: &str,
+    response: PermissionResponse,
+    transform: Option<&str>,
+    remember: bool,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    // The same answer again is a no-op, whoever got there first; only a
+    // different one is an error
+    if let Some((source, behavior)) = resolved_outcome(session_id, prompt_id, registry).await {
+        let repeated = match response.behavior.as_str() {
+            MODIFY_BEHAVIOR => behavior == "allow",
+            answered => behavior == answered,
+        };
+        if !repeated {
+            return Err(PermissionError::AlreadyResolved(
+                prompt_id.to_string(),
+                source,
+            ));
+        }
+        log::debug!(
+            "Ignoring repeated answer to prompt '{}' in session '{}', already '{}' by {:?}",
+            prompt_id,
+            session_id,
+            behavior,
+            source
+        );
+        return Ok(());
+    }
+    let mut response = response;
+    check_response(&mut response)?;
+    let response = if response.behavior == QUARANTINE_BEHAVIOR {
+        quarantine_response(session_id, prompt_id, response, registry).await?
+    } else if transform.is_some() || response.behavior == MODIFY_BEHAVIOR {
+        let response =
+            transformed_response(session_id, prompt_id, response, transform, registry).await?;
+        check_updated_input(&response)?;
+        response
+    } else {
+        response
+    };
+
+    if response.behavior == "deny" {
+        let window = *registry
+            .deny_confirm_window
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(window) = window {
+            if stage_risky_deny(session_id, prompt_id, &response, window, registry).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    let prompt = take_pending(session_id, prompt_id, registry, |reply| {
+        matches!(reply, PendingReply::Single(_))
+    })
+    .await?;
+    prompt.remember.store(remember, Ordering::Relaxed);
+    let behavior = if is_quarantined(&response) {
+        QUARANTINE_BEHAVIOR.to_string()
+    } else {
+        response.behavior.clone()
+    };
+    let PendingReply::Single(tx) = prompt.reply else {
+        unreachable!("checked by take_pending");
+    };
+    tx.send((response, DecisionSource::User))
+        .map_err(|_| PermissionError::ReceiverDropped)?;
+
+    let servers = registry.servers.lock().await;
+    if let Some(entry) = servers.get(session_id) {
+        // The handler records it too, but a repeat may arrive before then
+        entry
+            .resolved
+            .insert(prompt_id, DecisionSource::User, &behavior);
+    }
+    Ok(())
+}
+
+/// How the session's prompt `prompt_id` was resolved and with which
+/// behavior, if it was lately and is no longer pending.
+async fn resolved_outcome(
+    session_id: &str,
+    prompt_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Option<(DecisionSource, String)> {
+    let servers = registry.servers.lock().await;
+    let entry = servers.get(session_id)?;
+    if entry.pending.lock().await.contains_key(prompt_id) {
+        return None;
+    }
+    entry.resolved.outcome(prompt_id)
+}
+
+/// Response behavior for "allow with this transform applied". The answer
+/// names one of the registry's `input_transforms`, which rewrites the input
+/// the user was shown (or the answer's own `updated_input`); Claude Code gets
+/// a plain allow carrying the result.
+pub const MODIFY_BEHAVIOR: &str = "modify";
+
+/// Turn a `modify` answer (or an allow naming a transform) into an allow
+/// whose input is the transform's output.
+async fn transformed_response(
+    session_id: &str,
+    prompt_id: &str,
+    response: PermissionResponse,
+    transform: Option<&str>,
+    registry: &PermissionServerRegistry,
+) -> Result<PermissionResponse, PermissionError> {
+    if response.behavior != "allow" && response.behavior != MODIFY_BEHAVIOR {
+        return Err(PermissionError::Invalid(format!(
+            "Transforms can't be applied to a '{}' answer",
+            response.behavior
+        )));
+    }
+    let name = transform.ok_or_else(|| {
+        PermissionError::Invalid("A modify answer must name a transform".to_string())
+    })?;
+    let apply = registry
+        .input_transforms
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+        .cloned()
+        .ok_or_else(|| PermissionError::Invalid(format!("Unknown input transform '{}'", name)))?;
+
+    let event = {
+        let servers = registry.servers.lock().await;
+        let entry = servers
+            .get(session_id)
+            .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+        let pending = entry.pending.lock().await;
+        pending
+            .get(prompt_id)
+            .and_then(|prompt| prompt.event.clone())
+            .ok_or_else(|| PermissionError::PromptNotFound(prompt_id.to_string()))?
+    };
+    let input = response.updated_input.unwrap_or(event.input);
+    let updated = apply(&event.tool_name, &input)
+        .map_err(|e| PermissionError::Invalid(format!("Transform '{}' failed: {}", name, e)))?;
+    log_prompt_step(
+        log::Level::Info,
+        session_id,
+        prompt_id,
+        "transformed",
+        format_args!("tool={} transform={}", event.tool_name, name),
+    );
+    Ok(PermissionResponse {
+        behavior: "allow".to_string(),
+        updated_input: Some(updated),
+        message: response.message,
+    })
+}
+
+/// Stage a deny for confirmation if the prompt is high-risk. Returns whether
+/// it was staged; ordinary prompts are left for the caller to resolve.
+async fn stage_risky_deny(
+    session_id: &str,
+    prompt_id: &str,
+    response: &PermissionResponse,
+    window: Duration,
+    registry: &PermissionServerRegistry,
+) -> Result<bool, PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    let current_id = entry.session_id.lock().await.clone();
+
+    let mut pending = entry.pending.lock().await;
+    let prompt = pending
+        .get_mut(prompt_id)
+        .ok_or_else(|| PermissionError::PromptNotFound(prompt_id.to_string()))?;
+    let reasons = match &prompt.event {
+        Some(event) => explain::risk_reasons(&event.tool_name, &event.input),
+        None => return Ok(false),
+    };
+    if reasons.is_empty() {
+        return Ok(false);
+    }
+
+    prompt.staged_deny = Some((response.clone(), Instant::now() + window));
+    let event = DenyConfirmEvent {
+        session_id: current_id,
+        prompt_id: prompt_id.to_string(),
+        reasons: reasons.iter().map(|r| r.to_string()).collect(),
+        window_ms: window.as_millis() as u64,
+    };
+    emit_session_event(
+        entry.emitter.as_ref(),
+        "permission-deny-confirm",
+        &event.session_id,
+        &event,
+        registry.emit_generic(),
+    );
+
+    // Revert to plain pending if nobody confirms in time
+    let pending = entry.pending.clone();
+    let session = entry.session_id.clone();
+    let emitter = entry.emitter.clone();
+    let emit_generic = registry.emit_generic();
+    let prompt_id = prompt_id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(window).await;
+        let session_id = session.lock().await.clone();
+        let mut pending = pending.lock().await;
+        let Some(prompt) = pending.get_mut(&prompt_id) else {
+            return;
+        };
+        let expired = matches!(&prompt.staged_deny, Some((_, until)) if Instant::now() >= *until);
+        if expired {
+            prompt.staged_deny = None;
+            let event = DenyRevertedEvent {
+                session_id,
+                prompt_id,
+            };
+            emit_session_event(
+                emitter.as_ref(),
+                "permission-deny-reverted",
+                &event.session_id,
+                &event,
+                emit_generic,
+            );
+        }
+    });
+    Ok(true)
+}
+
+/// Finalize a deny staged by `resolve_prompt`. Fails if no deny is staged or
+/// its confirmation window has passed, in which case the prompt is simply
+/// pending again.
+pub async fn confirm_deny(
+    session_id: &str,
+    prompt_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+
+    let current_id = entry.session_id.lock().await.clone();
+    let (prompt, response) = registry
+        .update_pending(&entry.pending, entry.emitter.as_ref(), &current_id, |p| {
+            let prompt = p
+                .get_mut(prompt_id)
+                .ok_or_else(|| PermissionError::PromptNotFound(prompt_id.to_string()))?;
+            match prompt.staged_deny.take() {
+                Some((response, until)) if Instant::now() < until => {
+                    Ok((p.remove(prompt_id), response))
+                }
+                _ => Err(PermissionError::Invalid(format!(
+                    "No deny awaiting confirmation for prompt '{}'",
+                    prompt_id
+                ))),
+            }
+        })
+        .await?;
+
+    match prompt.map(|prompt| prompt.reply) {
+        Some(PendingReply::Single(tx)) => tx
+            .send((response, DecisionSource::User))
+            .map_err(|_| PermissionError::ReceiverDropped),
+        _ => Err(PermissionError::PromptNotFound(prompt_id.to_string())),
+    }
+}
+
+/// Show prompt notifications as OS notifications too, while the main window
+/// isn't focused.
+pub fn set_os_notifications(registry: &PermissionServerRegistry, enabled: bool) {
+    registry.os_notifications.store(enabled, Ordering::Relaxed);
+}
+
+/// Set the window for coalescing prompt notifications. `Duration::ZERO`
+/// sends one notification per prompt.
+pub fn set_notify_window(registry: &PermissionServerRegistry, window: Duration) {
+    *registry
+        .notify_window
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = window;
+}
+
+/// Require confirmation for denying high-risk prompts, accepted within
+/// `window`. `None` (the default) turns confirmation off.
+pub fn set_deny_confirmation(registry: &PermissionServerRegistry, window: Option<Duration>) {
+    *registry
+        .deny_confirm_window
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = window;
+}
+
+/// Behaviors an answer may have. `modify` and `quarantine` are turned into
+/// allows before Claude Code sees them.
+const ANSWER_BEHAVIORS: &[&str] = &["allow", "deny", MODIFY_BEHAVIOR, QUARANTINE_BEHAVIOR];
+
+/// Check an answer's shape before it can reach Claude Code, which knows only
+/// `allow` with an `updatedInput` object and `deny` with a message. The
+/// behavior must match exactly and a deny can't carry input. A deny without
+/// a message gets `USER_DENY_MESSAGE`. On error the prompt stays pending.
+fn check_response(response: &mut PermissionResponse) -> Result<(), PermissionError> {
+    if !ANSWER_BEHAVIORS.contains(&response.behavior.as_str()) {
+        return Err(PermissionError::InvalidBehavior(response.behavior.clone()));
+    }
+    if response.behavior == "deny" {
+        if response.updated_input.is_some() {
+            return Err(PermissionError::Invalid(
+                "A deny can't carry updated input".to_string(),
+            ));
+        }
+        let has_message = response
+            .message
+            .as_deref()
+            .is_some_and(|message| !message.trim().is_empty());
+        if !has_message {
+            response.message = Some(USER_DENY_MESSAGE.to_string());
+        }
+    }
+    check_updated_input(response)
+}
+
+/// Reject an answer whose `updated_input` is present but not a JSON object,
+/// before it can reach Claude Code. The prompt stays pending.
+fn check_updated_input(response: &PermissionResponse) -> Result<(), PermissionError> {
+    let kind = match &response.updated_input {
+        None | Some(serde_json::Value::Object(_)) => return Ok(()),
+        Some(serde_json::Value::Null) => "null",
+        Some(serde_json::Value::Bool(_)) => "a boolean",
+        Some(serde_json::Value::Number(_)) => "a number",
+        Some(serde_json::Value::String(_)) => "a string",
+        Some(serde_json::Value::Array(_)) => "an array",
+    };
+    Err(PermissionError::InvalidUpdatedInput(kind))
+}
+
+/// Remove a pending prompt if `accepts` its reply slot; otherwise leave it
+/// pending so it can still be resolved the right way.
+async fn take_pending(
+    session_id: &str,
+    prompt_id: &str,
+    registry: &PermissionServerRegistry,
+    accepts: impl FnOnce(&PendingReply) -> bool,
+) -> Result<PendingPrompt, PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+
+    let current_id = entry.session_id.lock().await.clone();
+    registry
+        .update_pending(&entry.pending, entry.emitter.as_ref(), &current_id, |p| {
+            let prompt = p
+                .get(prompt_id)
+                .ok_or_else(|| PermissionError::PromptNotFound(prompt_id.to_string()))?;
+            if !accepts(&prompt.reply) {
+                return Err(PermissionError::Invalid(format!(
+                    "Response doesn't match the shape of prompt '{}'",
+                    prompt_id
+                )));
+            }
+            Ok(p.remove(prompt_id))
+        })
+        .await?
+        .ok_or_else(|| PermissionError::PromptNotFound(prompt_id.to_string()))
+}
+
+/// Tear down one pending prompt without answering it, e.g. when its tab was
+/// closed. Dropping the reply channel makes the waiting request deny as
+/// cancelled.
+pub async fn cancel_prompt(
+    session_id: &str,
+    prompt_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    drop(take_pending(session_id, prompt_id, registry, |_| true).await?);
+
+    let servers = registry.servers.lock().await;
+    if let Some(entry) = servers.get(session_id) {
+        let event = PromptCancelledEvent {
+            session_id: entry.session_id.lock().await.clone(),
+            prompt_id: prompt_id.to_string(),
+        };
+        emit_session_event(
+            entry.emitter.as_ref(),
+            "permission-cancelled",
+            &event.session_id,
+            &event,
+            registry.emit_generic(),
+        );
+    }
+    log_prompt_step(
+        log::Level::Info,
+        session_id,
+        prompt_id,
+        "cancelled",
+        format_args!("by the frontend"),
+    );
+    Ok(())
+}
+
+/// Put a single prompt aside for `delay` without answering it ("ask me
+/// later"). It leaves `pending` and its deadline moves back by `delay`; then
+/// it's registered again and its event re-emitted. The request that raised
+/// it keeps waiting throughout, since the reply channel travels with it.
+pub async fn defer_prompt(
+    session_id: &str,
+    prompt_id: &str,
+    delay: Duration,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let prompt = take_pending(session_id, prompt_id, registry, |reply| {
+        matches!(reply, PendingReply::Single(_))
+    })
+    .await?;
+    prompt.extend(delay);
+
+    let pending = {
+        let servers = registry.servers.lock().await;
+        let entry = servers
+            .get(session_id)
+            .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+        let event = PromptDeferredEvent {
+            session_id: entry.session_id.lock().await.clone(),
+            prompt_id: prompt_id.to_string(),
+            delay_ms: delay.as_millis() as u64,
+        };
+        emit_session_event(
+            entry.emitter.as_ref(),
+            "permission-deferred",
+            &event.session_id,
+            &event,
+            registry.emit_generic(),
+        );
+        entry.pending.clone()
+    };
+    log_prompt_step(
+        log::Level::Info,
+        session_id,
+        prompt_id,
+        "deferred",
+        format_args!("delay={:?}", delay),
+    );
+
+    let registry = registry.clone();
+    let prompt_id = prompt_id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        requeue_deferred(&registry, &pending, prompt_id, prompt).await;
+    });
+    Ok(())
+}
+
+/// Bring a deferred prompt back. It's dropped (denying as cancelled) if its
+/// server stopped meanwhile, and denied if the frontend can't be reached.
+async fn requeue_deferred(
+    registry: &PermissionServerRegistry,
+    pending: &PendingPrompts,
+    prompt_id: String,
+    mut prompt: PendingPrompt,
+) {
+    // Found by its pending map rather than ID, which may have been rekeyed
+    let servers = registry.servers.lock().await;
+    let Some(entry) = servers
+        .values()
+        .find(|entry| Arc::ptr_eq(&entry.pending, pending))
+    else {
+        log::info!("Dropping deferred prompt '{}': server stopped", prompt_id);
+        return;
+    };
+    if let PendingReply::Single(tx) = &prompt.reply {
+        if tx.is_closed() {
+            return;
+        }
+    }
+
+    let session_id = entry.session_id.lock().await.clone();
+    let event = prompt.event.as_mut().map(|event| {
+        event.session_id = session_id.clone();
+        event.clone()
+    });
+    registry
+        .update_pending(pending, entry.emitter.as_ref(), &session_id, |p| {
+            p.insert(prompt_id.clone(), prompt)
+        })
+        .await;
+    log_prompt_step(
+        log::Level::Info,
+        &session_id,
+        &prompt_id,
+        "requeued",
+        format_args!("after defer"),
+    );
+    let Some(event) = event else {
+        return;
+    };
+    if registry.emit_prompt_event(&entry.emitter, &event) {
+        return;
+    }
+
+    let unreachable = registry
+        .update_pending(pending, entry.emitter.as_ref(), &session_id, |p| {
+            p.remove(&prompt_id)
+        })
+        .await;
+    if let Some(PendingReply::Single(tx)) = unreachable.map(|prompt| prompt.reply) {
+        let _ = tx.send((
+            deny_with(FRONTEND_UNREACHABLE_MESSAGE),
+            DecisionSource::Cancelled,
+        ));
+    }
+}
+
+/// Deny every prompt the session is waiting on, e.g. when the user hits
+/// Stop, while keeping the server up for later requests. Runs under the
+/// pending lock, so a concurrent `resolve_prompt` either answers a prompt
+/// first or finds it gone. Returns how many prompts were denied.
+pub async fn deny_all_pending(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+    message: String,
+) -> usize {
+    let servers = registry.servers.lock().await;
+    let entry = match servers.get(session_id) {
+        Some(entry) => entry,
+        None => return 0,
+    };
+    let current_id = entry.session_id.lock().await.clone();
+    let denied = registry
+        .update_pending(
+            &entry.pending,
+            entry.emitter.as_ref(),
+            &current_id,
+            |pending| {
+                let mut denied = 0;
+                for (_, prompt) in pending.drain() {
+                    let sent = match prompt.reply {
+                        PendingReply::Single(tx) => {
+                            tx.send((deny_with(&message), DecisionSource::User)).is_ok()
+                        }
+                        PendingReply::Batch { tx, len } => {
+                            tx.send(vec![deny_with(&message); len]).is_ok()
+                        }
+                    };
+                    if sent {
+                        denied += 1;
+                    }
+                }
+                denied
+            },
+        )
+        .await;
+    if denied > 0 {
+        log::info!(
+            "Denied {} pending prompt(s) in session '{}'",
+            denied,
+            current_id
+        );
+    }
+    denied
+}
+
+/// Run the session's pending prompts through the current rules and resolve
+/// the ones a rule now answers, e.g. right after adding a rule. Prompts no
+/// rule matches stay pending. Returns how many were resolved.
+pub async fn reevaluate_pending(session_id: &str, registry: &PermissionServerRegistry) -> usize {
+    let servers = registry.servers.lock().await;
+    let entry = match servers.get(session_id) {
+        Some(entry) => entry,
+        None => return 0,
+    };
+    let current_id = entry.session_id.lock().await.clone();
+    let resolved = registry
+        .update_pending(
+            &entry.pending,
+            entry.emitter.as_ref(),
+            &current_id,
+            |pending| {
+                let answered: Vec<(String, PermissionResponse)> = pending
+                    .iter()
+                    .filter(|(_, prompt)| matches!(prompt.reply, PendingReply::Single(_)))
+                    .filter_map(|(prompt_id, prompt)| {
+                        let event = prompt.event.as_ref()?;
+                        let req = PermissionRequest {
+                            tool_use_id: String::new(),
+                            tool_name: event.tool_name.clone(),
+                            input: event
+                                .original_input
+                                .clone()
+                                .unwrap_or_else(|| event.input.clone()),
+                            context: None,
+                            agent_path: event.agent_path.clone(),
+                            cwd: event.cwd.clone(),
+                        };
+                        rule_decision(
+                            registry,
+                            entry.emitter.as_ref(),
+                            &current_id,
+                            &req,
+                            &request_cwd(&req, &entry.cwd),
+                        )
+                        .map(|resp| (prompt_id.clone(), resp))
+                    })
+                    .collect();
+
+                let mut resolved = 0;
+                for (prompt_id, resp) in answered {
+                    if let Some(PendingPrompt {
+                        reply: PendingReply::Single(tx),
+                        ..
+                    }) = pending.remove(&prompt_id)
+                    {
+                        if tx.send((resp, DecisionSource::Rule)).is_ok() {
+                            resolved += 1;
+                        }
+                    }
+                }
+                resolved
+            },
+        )
+        .await;
+    if resolved > 0 {
+        log::info!(
+            "Rules resolved {} pending prompt(s) in session '{}'",
+            resolved,
+            current_id
+        );
+    }
+    resolved
+}
+
+/// Write every decision to `path` as JSONL, or stop with `None`. Sequence
+/// numbers continue after the last entry already in the file.
+pub fn set_audit_log_path(registry: &PermissionServerRegistry, path: Option<PathBuf>) {
+    if let Some(path) = &path {
+        registry
+            .decisions
+            .seed(decisions::last_sequence_in_log(path));
+        log::info!("Permission audit log: {}", path.display());
+    }
+    registry.audit.set_path(path);
+}
+
+/// Replace the in-memory rules for one scope.
+pub fn set_scope_rules(
+    registry: &PermissionServerRegistry,
+    scope: RuleScope,
+    rules: Vec<PermissionRule>,
+) {
+    registry
+        .rules
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .set_scope(scope, rules);
+}
+
+/// Choose how conflicting rule matches are settled.
+pub fn set_conflict_policy(registry: &PermissionServerRegistry, policy: ConflictPolicy) {
+    registry
+        .rules
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .conflict_policy = policy;
+}
+
+/// Set what happens to requests no rule matches for one scope. `None`
+/// clears the scope's setting.
+pub fn set_default_outcome(
+    registry: &PermissionServerRegistry,
+    scope: RuleScope,
+    default: Option<ScopeDefault>,
+) {
+    registry
+        .rules
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .set_scope_default(scope, default);
+}
+
+/// The merged rule set currently in effect.
+pub fn current_rule_set(registry: &PermissionServerRegistry) -> RuleSet {
+    registry
+        .rules
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .merged()
+}
+
+/// Copy of the rule engine's in-memory state across all scopes.
+pub fn rule_engine_snapshot(registry: &PermissionServerRegistry) -> RuleEngineState {
+    registry
+        .rules
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Replace the rule engine's in-memory state wholesale, e.g. with an earlier
+/// `rule_engine_snapshot`.
+pub fn restore_rule_engine(state: RuleEngineState, registry: &PermissionServerRegistry) {
+    *registry.rules.write().unwrap_or_else(|e| e.into_inner()) = state;
+}
+
+/// Register (or with `None`, remove) a named input transform for `modify`
+/// answers. Built-ins can be replaced or removed the same way.
+pub fn set_input_transform(
+    registry: &PermissionServerRegistry,
+    name: &str,
+    transform: Option<InputTransform>,
+) {
+    let mut transforms = registry
+        .input_transforms
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+    match transform {
+        Some(transform) => {
+            transforms.insert(name.to_string(), transform);
+        }
+        None => {
+            transforms.remove(name);
+        }
+    }
+}
+
+/// Set (or with `None`, remove) the auto-edit applied to a tool's input
+/// before prompting.
+pub fn set_auto_edit(registry: &PermissionServerRegistry, tool_name: &str, edit: Option<AutoEdit>) {
+    let mut edits = registry
+        .auto_edits
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+    match edit {
+        Some(edit) => {
+            edits.insert(tool_name.to_string(), edit);
+        }
+        None => {
+            edits.remove(tool_name);
+        }
+    }
+}
+
+/// Add a pattern for secrets to mask in prompt events and the audit log, or
+/// replace the one with the same name. Patterns are regexes; when one has a
+/// `secret` capture group only that part is masked (see `redact`).
+pub fn add_secret_pattern(
+    registry: &PermissionServerRegistry,
+    name: &str,
+    pattern: &str,
+) -> Result<(), PermissionError> {
+    let pattern = redact::SecretPattern::new(name, pattern).map_err(PermissionError::Invalid)?;
+    let mut patterns = registry
+        .secret_patterns
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+    patterns.retain(|existing| existing.name != name);
+    patterns.push(pattern);
+    Ok(())
+}
+
+/// Stop masking secrets matched by the pattern called `name`, built-ins
+/// included. Returns whether there was one.
+pub fn remove_secret_pattern(registry: &PermissionServerRegistry, name: &str) -> bool {
+    let mut patterns = registry
+        .secret_patterns
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+    let before = patterns.len();
+    patterns.retain(|existing| existing.name != name);
+    patterns.len() != before
+}
+
+/// Set the cap on serialized prompt event size. `None` removes the limit.
+pub fn set_max_event_bytes(registry: &PermissionServerRegistry, max_bytes: Option<usize>) {
+    registry
+        .max_event_bytes
+        .store(max_bytes.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Set the cap on a prompt event's serialized input. `None` removes the
+/// limit.
+pub fn set_max_input_bytes(registry: &PermissionServerRegistry, max_bytes: Option<usize>) {
+    registry
+        .max_input_bytes
+        .store(max_bytes.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// The full input of a pending prompt whose emitted event only carried a
+/// preview (`input_truncated`), with secrets still masked.
+pub async fn get_full_input(
+    session_id: &str,
+    prompt_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<serde_json::Value, PermissionError> {
+    get_prompt_event(session_id, prompt_id, registry)
+        .await
+        .map(|event| event.input)
+}
+
+/// The full event of a pending prompt, including fields trimmed from the
+/// emitted copy to fit the event size limit.
+pub async fn get_prompt_event(
+    session_id: &str,
+    prompt_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<PermissionPromptEvent, PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    let pending = entry.pending.lock().await;
+    pending
+        .get(prompt_id)
+        .and_then(|prompt| prompt.event.clone())
+        .map(|event| registry.redact_event(event))
+        .ok_or_else(|| PermissionError::PromptNotFound(prompt_id.to_string()))
+}
+
+/// Prompts currently waiting for an answer in a session, soonest deadline
+/// first. Empty for an unknown session.
+pub async fn list_pending(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Vec<PendingPromptInfo> {
+    let servers = registry.servers.lock().await;
+    match servers.get(session_id) {
+        Some(entry) => pending_infos(&*entry.pending.lock().await, registry),
+        None => Vec::new(),
+    }
+}
+
+/// Emit `permission-prompt` again for every prompt still waiting in the
+/// session, oldest first, so a frontend that reloaded after they went out can
+/// show them. Prompts held back by a pause and batch prompts have no event
+/// yet and are skipped; replays don't raise a new notification. Returns how
+/// many were replayed.
+pub async fn replay_pending(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<usize, PermissionError> {
+    let (emitter, mut events) = {
+        let servers = registry.servers.lock().await;
+        let entry = servers
+            .get(session_id)
+            .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+        let events: Vec<(Instant, PermissionPromptEvent)> = entry
+            .pending
+            .lock()
+            .await
+            .values()
+            .filter_map(|prompt| Some((prompt.created_at, prompt.event.clone()?)))
+            .collect();
+        (entry.emitter.clone(), events)
+    };
+    events.sort_by_key(|(created_at, _)| *created_at);
+
+    for (_, event) in &events {
+        let _ = registry.send_prompt_event(emitter.as_ref(), event);
+    }
+    log::info!(
+        "Replayed {} pending prompt(s) for session '{}'",
+        events.len(),
+        session_id
+    );
+    Ok(events.len())
+}
+
+/// How many prompts each tool has raised in a session, e.g. for a "this
+/// session asked about: Bash (5), Write (2)" summary. Requests answered by
+/// rules aren't counted. Empty for unknown sessions.
+pub async fn tool_prompt_histogram(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+) -> HashMap<String, u64> {
+    registry
+        .servers
+        .lock()
+        .await
+        .get(session_id)
+        .map(|entry| {
+            entry
+                .tool_prompts
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()
+        })
+        .unwrap_or_default()
+}
+
+/// Decision counts for a session since its server started, splitting what
+/// the user answered from what rules, defaults and lists answered.
+pub async fn get_metrics(
+    session_id: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<PermissionMetrics, PermissionError> {
+    registry
+        .servers
+        .lock()
+        .await
+        .get(session_id)
+        .map(|entry| entry.metrics.snapshot())
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))
+}
+
+/// Whether a session's MCP script has sent at least one request since its
+/// server started. False for unknown sessions.
+pub async fn has_received_request(session_id: &str, registry: &PermissionServerRegistry) -> bool {
+    registry
+        .servers
+        .lock()
+        .await
+        .get(session_id)
+        .is_some_and(|entry| entry.handshake.received_request.load(Ordering::Relaxed))
+}
+
+/// One running permission server, as reported by `snapshot_registry`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerSnapshot {
+    pub session_id: String,
+    pub port: u16,
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abstract_socket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_path: Option<PathBuf>,
+    /// Prompts waiting for an answer, batches included.
+    pub pending: usize,
+    pub mcp_files: McpFiles,
+    /// Whether the MCP client has reached the server yet.
+    pub received_request: bool,
+    /// Milliseconds since the server last saw a request.
+    pub idle_ms: u64,
+}
+
+/// State of every running permission server, ordered by session ID, for
+/// diagnostics and bug reports. The registry is locked once, only long
+/// enough to copy each entry out.
+pub async fn snapshot_registry(registry: &PermissionServerRegistry) -> Vec<ServerSnapshot> {
+    let servers = registry.servers.lock().await;
+    let mut snapshots = Vec::with_capacity(servers.len());
+    for (session_id, entry) in servers.iter() {
+        snapshots.push(ServerSnapshot {
+            session_id: session_id.clone(),
+            port: entry.port,
+            host: entry.host.to_string(),
+            abstract_socket: entry.abstract_socket.clone(),
+            socket_path: entry.socket_path.clone(),
+            pending: entry.pending.lock().await.len(),
+            mcp_files: entry.mcp_files.clone(),
+            received_request: entry.handshake.received_request.load(Ordering::Relaxed),
+            idle_ms: entry.last_activity.lock().await.elapsed().as_millis() as u64,
+        });
+    }
+    drop(servers);
+    snapshots.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+    snapshots
+}
+
+/// Number of running permission servers. Unlike `snapshot_registry` it
+/// copies nothing, so it's cheap enough to poll for a status badge.
+pub async fn active_server_count(registry: &PermissionServerRegistry) -> usize {
+    registry.servers.lock().await.len()
+}
+
+/// Prompts waiting for an answer across every session, batches included.
+/// The registry is locked once and nothing is copied.
+pub async fn total_pending(registry: &PermissionServerRegistry) -> usize {
+    let servers = registry.servers.lock().await;
+    let mut total = 0;
+    for entry in servers.values() {
+        total += entry.pending.lock().await.len();
+    }
+    total
+}
+
+/// Sessions currently flagged as likely MCP handshake failures.
+pub async fn handshake_suspect_sessions(registry: &PermissionServerRegistry) -> Vec<String> {
+    let servers = registry.servers.lock().await;
+    let mut suspects = Vec::new();
+    for entry in servers.values() {
+        if entry.handshake.suspect.load(Ordering::Relaxed) {
+            suspects.push(entry.session_id.lock().await.clone());
+        }
+    }
+    suspects
+}
+
+/// Change a session's decision limit. `None` removes it. Lowering it below
+/// the decisions already made stops the session's next request.
+pub async fn set_max_decisions(
+    session_id: &str,
+    max_decisions: Option<u64>,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    let budget = &entry.decision_budget;
+    budget
+        .max
+        .store(max_decisions.unwrap_or(0), Ordering::Relaxed);
+    budget.reported.store(false, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Change a session's rate limit. `None` removes it. The new limit starts
+/// with a full bucket.
+pub async fn set_rate_limit(
+    session_id: &str,
+    rate_limit: Option<RateLimit>,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    entry.rate_limiter.set(rate_limit);
+    Ok(())
+}
+
+/// Milliseconds since the Unix epoch, for event timestamps.
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn read_timeout(timeout: &std::sync::RwLock<Option<Duration>>) -> Option<Duration> {
+    *timeout.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// How long a new prompt for `tool_name` waits: the tool's override if it
+/// has one, else the session's prompt timeout.
+fn tool_timeout(
+    default: &std::sync::RwLock<Option<Duration>>,
+    overrides: &ToolTimeouts,
+    tool_name: &str,
+) -> Option<Duration> {
+    let overrides = overrides.read().unwrap_or_else(|e| e.into_inner());
+    match overrides.get(tool_name) {
+        Some(timeout) => *timeout,
+        None => read_timeout(default),
+    }
+}
+
+/// The longest any new prompt of the session can wait, tool overrides
+/// included.
+fn longest_session_timeout(entry: &PermissionServerEntry) -> Option<Duration> {
+    let overrides = entry
+        .tool_timeouts
+        .read()
+        .unwrap_or_else(|e| e.into_inner());
+    longest_timeout(
+        std::iter::once(read_timeout(&entry.prompt_timeout)).chain(overrides.values().copied()),
+    )
+}
+
+/// Change how long a running session's new prompts wait for an answer.
+/// `Duration::ZERO` waits forever. Prompts already pending keep their
+/// deadline; use `extend_prompt` for those. The MCP client's own timeout is
+/// fixed when its config is written (see `CLIENT_TIMEOUT_MARGIN`), so a
+/// longer timeout only takes full effect for sessions started afterwards.
+pub async fn set_timeout(
+    session_id: &str,
+    timeout: Duration,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    *entry
+        .prompt_timeout
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = timeout_from(timeout);
+    Ok(())
+}
+
+/// Give a running session's new prompts for `tool_name` their own timeout,
+/// or go back to the session's with `None`. `Duration::ZERO` waits forever.
+/// Like `set_timeout`, this leaves pending prompts and the MCP client's
+/// timeout alone.
+pub async fn set_tool_timeout(
+    session_id: &str,
+    tool_name: &str,
+    timeout: Option<Duration>,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    if tool_name.is_empty() {
+        return Err(PermissionError::Invalid(
+            "Tool name must not be empty".to_string(),
+        ));
+    }
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    let mut overrides = entry
+        .tool_timeouts
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+    match timeout {
+        Some(timeout) => {
+            overrides.insert(tool_name.to_string(), timeout_from(timeout));
+        }
+        None => {
+            overrides.remove(tool_name);
+        }
+    }
+    Ok(())
+}
+
+/// Push back one pending prompt's deadline by `extra`.
+pub async fn extend_prompt(
+    session_id: &str,
+    prompt_id: &str,
+    extra: Duration,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    let pending = entry.pending.lock().await;
+    let prompt = pending
+        .get(prompt_id)
+        .ok_or_else(|| PermissionError::PromptNotFound(prompt_id.to_string()))?;
+    prompt.extend(extra);
+    Ok(())
+}
+
+/// Push back the deadline of every prompt currently pending in a session.
+/// Prompts created afterwards still get the session's configured timeout.
+/// Returns how many prompts were extended.
+pub async fn extend_all_pending(
+    session_id: &str,
+    extra: Duration,
+    registry: &PermissionServerRegistry,
+) -> usize {
+    let servers = registry.servers.lock().await;
+    let entry = match servers.get(session_id) {
+        Some(entry) => entry,
+        None => return 0,
+    };
+    let pending = entry.pending.lock().await;
+    for prompt in pending.values() {
+        prompt.extend(extra);
+    }
+    pending.len()
+}
+
+/// Recently resolved prompts, oldest first. Pass a session ID to see only
+/// that session's decisions.
+pub fn recent_decisions(
+    session_id: Option<&str>,
+    registry: &PermissionServerRegistry,
+) -> Vec<DecisionRecord> {
+    registry.decisions.recent(session_id)
+}
+
+/// Whether `inject_test_prompt` may be used. Always on in debug builds;
+/// release builds require `OPCODE_ENABLE_TEST_PROMPTS=1`.
+fn test_prompts_enabled() -> bool {
+    cfg!(debug_assertions)
+        || std::env::var("OPCODE_ENABLE_TEST_PROMPTS")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+}
+
+/// Simulate a permission prompt for UI development without running an agent.
+/// The prompt is registered and emitted exactly like a real request (but
+/// flagged with `test: true`) and can be resolved through `resolve_prompt`.
+/// Returns the prompt ID.
+pub async fn inject_test_prompt(
+    session_id: &str,
+    tool_name: &str,
+    input: serde_json::Value,
+    registry: &PermissionServerRegistry,
+) -> Result<String, PermissionError> {
+    if !test_prompts_enabled() {
+        return Err(PermissionError::Invalid(
+            "Test prompts are disabled; set OPCODE_ENABLE_TEST_PROMPTS=1 to enable them"
+                .to_string(),
+        ));
+    }
+
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+
+    let prompt_id = Uuid::new_v4().to_string();
+    let (tx, rx) = oneshot::channel::<(PermissionResponse, DecisionSource)>();
+    let current_id = entry.session_id.lock().await.clone();
+    let timeout = tool_timeout(&entry.prompt_timeout, &entry.tool_timeouts, tool_name);
+    let event = registry.transform_prompt_event(PermissionPromptEvent {
+        prompt_id: prompt_id.clone(),
+        session_id: current_id.clone(),
+        tool_use_id: String::new(),
+        tool_name: tool_name.to_string(),
+        created_at: unix_millis(),
+        summary: explain::target_summary(&input),
+        explanation: explain::explain_request(tool_name, &input),
+        risk_category: explain::classify_tool(tool_name, &input),
+        input,
+        suggested_input: None,
+        original_input: None,
+        context: None,
+        agent_path: Vec::new(),
+        test: true,
+        inspect: false,
+        truncated: false,
+        input_truncated: false,
+        input_bytes: None,
+        cwd: None,
+        timeout_ms: timeout.map(|t| t.as_millis() as u64),
+    });
+    let mut prompt = PendingPrompt::new(PendingReply::Single(tx), tool_name, timeout);
+    prompt.event = Some(event.clone());
+    let deadline = prompt.deadline.clone();
+    registry
+        .update_pending(&entry.pending, entry.emitter.as_ref(), &current_id, |p| {
+            p.insert(prompt_id.clone(), prompt)
+        })
+        .await;
+    registry.emit_prompt_event(&entry.emitter, &event);
+    log::info!(
+        "[test prompt] Injected '{}' for tool '{}' in session '{}'",
+        prompt_id,
+        tool_name,
+        session_id
+    );
+
+    // Nobody is waiting on a test prompt, but it times out like a real one;
+    // then just record how it ended. The session's metrics and decision
+    // budget only count real requests.
+    let state = HttpState::new(entry, registry);
+    let paused_rx = registry.paused.subscribe();
+    let log_id = prompt_id.clone();
+    tokio::spawn(async move {
+        let (resp, source) = match wait_for_response(rx, deadline, paused_rx).await {
+            Some(answer) => answer,
+            None => match expire_pending(&state, &log_id).await {
+                (DecisionSource::Timeout, message) => {
+                    let resp = timeout_response(
+                        state.timeout_behavior,
+                        message,
+                        &event.input,
+                        &event.input,
+                    );
+                    (resp, DecisionSource::Timeout)
+                }
+                _ => {
+                    log::info!("[test prompt] '{}' dropped without a response", log_id);
+                    return;
+                }
+            },
+        };
+        log::info!("[test prompt] '{}' resolved: {}", log_id, resp.behavior);
+        state.registry.log_decision(DecisionRecord {
+            message: resp.message.clone(),
+            test: event.test,
+            ..DecisionRecord::new(
+                &event.session_id,
+                &log_id,
+                &event.tool_name,
+                &event.input,
+                &resp.behavior,
+                source,
+            )
+        });
+    });
+
+    Ok(prompt_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+    use mcp_files::build_mcp_config;
+    use rules::RuleCondition;
+
+    fn sample_event(context: Option<PromptContext>) -> PermissionPromptEvent {
+        PermissionPromptEvent {
+            prompt_id: "prompt-1".to_string(),
+            session_id: "session-1".to_string(),
+            tool_use_id: "toolu_test".to_string(),
+            tool_name: "Bash".to_string(),
+            created_at: 0,
+            input: serde_json::json!({ "command": "ls" }),
+            context,
+            agent_path: Vec::new(),
+            suggested_input: None,
+            original_input: None,
+            summary: None,
+            explanation: None,
+            risk_category: RiskCategory::Execute,
+            test: false,
+            inspect: false,
+            truncated: false,
+            input_truncated: false,
+            input_bytes: None,
+            cwd: None,
+            timeout_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_prompt_context_round_trips_when_provided() {
+        let context = PromptContext {
+            message_id: Some("msg_123".to_string()),
+            turn: Some(4),
+        };
+        let json = serde_json::to_value(sample_event(Some(context.clone()))).unwrap();
+        assert_eq!(json["context"]["message_id"], "msg_123");
+        assert_eq!(json["context"]["turn"], 4);
+
+        let back: PermissionPromptEvent = serde_json::from_value(json).unwrap();
+        assert_eq!(back.context, Some(context));
+    }
+
+    #[test]
+    fn test_prompt_context_omitted_when_absent() {
+        let json = serde_json::to_value(sample_event(None)).unwrap();
+        assert!(json.get("context").is_none());
+
+        // Requests from older scripts carry no context at all
+        let req: PermissionRequest = serde_json::from_value(serde_json::json!({
+            "tool_use_id": "toolu_1",
+            "tool_name": "Read",
+            "input": { "file_path": "/tmp/a" }
+        }))
+        .unwrap();
+        assert!(req.context.is_none());
+    }
+
+    #[test]
+    fn test_generic_emit_suppressed_when_disabled() {
+        let event = sample_event(None);
+
+        let emitter = RecordingEmitter::default();
+        emit_session_event(&emitter, "permission-prompt", "session-1", &event, true);
+        assert_eq!(
+            emitter.names(),
+            vec!["permission-prompt:session-1", "permission-prompt"]
+        );
+
+        let emitter = RecordingEmitter::default();
+        emit_session_event(&emitter, "permission-prompt", "session-1", &event, false);
+        assert_eq!(emitter.names(), vec!["permission-prompt:session-1"]);
+    }
+
+    #[test]
+    fn test_registry_emits_generic_events_by_default() {
+        let registry = PermissionServerRegistry::default();
+        assert!(registry.emit_generic_events.load(Ordering::Relaxed));
+
+        set_emit_generic_events(&registry, false);
+        assert!(!registry.emit_generic_events.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_inject_test_prompt_emits_flagged_event_and_resolves() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+
+        let prompt_id = inject_test_prompt(
+            "session-1",
+            "Bash",
+            serde_json::json!({ "command": "echo hi" }),
+            &registry,
+        )
+        .await
+        .unwrap();
+
+        let prompts = emitter.prompts();
+        assert_eq!(prompts.len(), 1);
+        assert_eq!(prompts[0]["prompt_id"], prompt_id.as_str());
+        assert_eq!(prompts[0]["test"], true);
+
+        let allow = PermissionResponse {
+            behavior: "allow".to_string(),
+            updated_input: None,
+            message: None,
+        };
+        resolve_prompt("session-1", &prompt_id, allow, &registry)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inject_test_prompt_times_out_like_a_real_one() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                prompt_timeout: Some(Duration::from_millis(30)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let prompt_id = inject_test_prompt(
+            "session-1",
+            "Bash",
+            serde_json::json!({ "command": "echo hi" }),
+            &registry,
+        )
+        .await
+        .unwrap();
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || {
+            emitter_for_wait
+                .names()
+                .contains(&"permission-timeout:session-1".to_string())
+        })
+        .await;
+        assert!(list_pending("session-1", &registry).await.is_empty());
+
+        let registry_for_wait = registry.clone();
+        wait_until(move || !recent_decisions(Some("session-1"), &registry_for_wait).is_empty())
+            .await;
+        let decision = recent_decisions(Some("session-1"), &registry)
+            .pop()
+            .unwrap();
+        assert_eq!(decision.prompt_id, prompt_id);
+        assert_eq!(decision.source, DecisionSource::Timeout);
+        assert!(decision.test);
+    }
+
+    #[tokio::test]
+    async fn test_inject_test_prompt_audited_as_test_without_counting() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let registry = PermissionServerRegistry::default();
+        set_audit_log_path(&registry, Some(path.clone()));
+        insert_test_entry(&registry, "session-1").await;
+
+        let prompt_id = inject_test_prompt(
+            "session-1",
+            "Bash",
+            serde_json::json!({ "command": "echo hi" }),
+            &registry,
+        )
+        .await
+        .unwrap();
+        let allow = PermissionResponse {
+            behavior: "allow".to_string(),
+            updated_input: None,
+            message: None,
+        };
+        resolve_prompt("session-1", &prompt_id, allow, &registry)
+            .await
+            .unwrap();
+
+        let path_for_wait = path.clone();
+        wait_until(move || {
+            std::fs::read_to_string(&path_for_wait)
+                .unwrap_or_default()
+                .lines()
+                .count()
+                == 1
+        })
+        .await;
+        let line = std::fs::read_to_string(&path).unwrap();
+        let raw: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(raw["test"], true);
+        let record: DecisionRecord = serde_json::from_str(line.trim()).unwrap();
+        assert!(record.test);
+        assert_eq!(record.prompt_id, prompt_id);
+        assert_eq!(record.source, DecisionSource::User);
+        assert_eq!(record.behavior, "allow");
+
+        assert_eq!(
+            get_metrics("session-1", &registry).await.unwrap(),
+            PermissionMetrics::default()
+        );
+        let servers = registry.servers.lock().await;
+        let budget = &servers["session-1"].decision_budget;
+        assert_eq!(budget.used.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_relative_paths_resolve_against_configured_cwd() {
+        let registry = PermissionServerRegistry::default();
+        insert_test_entry(&registry, "session-1").await;
+        let cwd = registry.servers.lock().await["session-1"].cwd.clone();
+        assert_eq!(cwd.as_path(), Path::new("/work/project"));
+
+        let input = serde_json::json!({
+            "file_path": "src/main.rs",
+            "path": "/etc/hosts",
+            "content": "relative/but/not/a/path/field"
+        });
+        let resolved = resolve_input_paths(&input, &cwd);
+        assert_eq!(
+            resolved["file_path"],
+            cwd.join("src/main.rs").to_string_lossy().as_ref()
+        );
+        assert_eq!(resolved["path"], "/etc/hosts");
+        assert_eq!(resolved["content"], "relative/but/not/a/path/field");
+    }
+
+    #[tokio::test]
+    async fn test_event_transform_applied_before_emit() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_event_transform(
+            &registry,
+            Some(Arc::new(|mut event: PermissionPromptEvent| {
+                event.input["org"] = serde_json::json!("acme");
+                event.tool_name = event.tool_name.to_uppercase();
+                event
+            })),
+        );
+
+        inject_test_prompt(
+            "session-1",
+            "Bash",
+            serde_json::json!({ "command": "ls" }),
+            &registry,
+        )
+        .await
+        .unwrap();
+
+        let payload = &emitter.prompts()[0];
+        assert_eq!(payload["tool_name"], "BASH");
+        assert_eq!(payload["input"]["org"], "acme");
+        assert_eq!(payload["input"]["command"], "ls");
+    }
+
+    #[tokio::test]
+    async fn test_prompts_queue_under_global_pause() {
+        let app_emitter = Arc::new(RecordingEmitter::default());
+        let registry = PermissionServerRegistry::new(app_emitter.clone());
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+
+        pause_all(&registry);
+        assert_eq!(app_emitter.names(), vec!["permission-global-pause"]);
+
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+
+        // Queued: registered as pending but not shown while paused
+        let pending = registry.servers.lock().await["session-1"].pending.clone();
+        let pending_for_wait = pending.clone();
+        wait_until(move || {
+            pending_for_wait
+                .try_lock()
+                .map(|p| p.len() == 1)
+                .unwrap_or(false)
+        })
+        .await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(emitter.prompts().is_empty());
+
+        resume_all(&registry);
+        assert_eq!(app_emitter.names().len(), 2);
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_resolutions_recorded_with_increasing_sequence() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "a" }),
+            )),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+
+        // A prompt still pending when the server stops is recorded as cancelled
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 2).await;
+        stop_server("session-1", &registry).await;
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "deny");
+
+        let decisions = recent_decisions(Some("session-1"), &registry);
+        let summary: Vec<(u64, &str, DecisionSource)> = decisions
+            .iter()
+            .map(|d| (d.seq, d.tool_name.as_str(), d.source))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, "Read", DecisionSource::User),
+                (2, "Bash", DecisionSource::Cancelled)
+            ]
+        );
+        assert!(recent_decisions(Some("other"), &registry).is_empty());
+    }
+
+    #[test]
+    fn test_rule_engine_snapshot_restore_round_trips() {
+        let registry = PermissionServerRegistry::default();
+        let rule = |tool: &str, action| PermissionRule {
+            tool: tool.to_string(),
+            field: None,
+            pattern: None,
+            agent_path_prefix: None,
+            condition: None,
+            action,
+            message: None,
+            origin: None,
+        };
+        set_scope_rules(
+            &registry,
+            RuleScope::Project,
+            vec![rule("Read", RuleAction::Allow)],
+        );
+        set_scope_rules(
+            &registry,
+            RuleScope::Policy,
+            vec![rule("Bash", RuleAction::Deny)],
+        );
+        let snapshot = rule_engine_snapshot(&registry);
+
+        set_scope_rules(&registry, RuleScope::Policy, Vec::new());
+        set_scope_rules(
+            &registry,
+            RuleScope::Session,
+            vec![rule("*", RuleAction::Allow)],
+        );
+        assert_ne!(rule_engine_snapshot(&registry), snapshot);
+
+        restore_rule_engine(snapshot.clone(), &registry);
+        assert_eq!(rule_engine_snapshot(&registry), snapshot);
+
+        let input = serde_json::json!({ "command": "ls" });
+        let winner = current_rule_set(&registry)
+            .evaluate(&RuleTarget::new("Bash", &input))
+            .cloned()
+            .unwrap();
+        assert_eq!(winner.action, RuleAction::Deny);
+        assert_eq!(winner.origin.unwrap().scope, RuleScope::Policy);
+
+        // The state survives a serde round trip for export/import
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<RuleEngineState>(&json).unwrap(),
+            snapshot
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deny_all_default_applies_only_without_matching_rule() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_scope_rules(
+            &registry,
+            RuleScope::Project,
+            vec![PermissionRule {
+                tool: "Read".to_string(),
+                field: Some("file_path".to_string()),
+                pattern: Some("/work/project/*".to_string()),
+                agent_path_prefix: None,
+                condition: None,
+                action: RuleAction::Allow,
+                message: None,
+                origin: None,
+            }],
+        );
+        set_default_outcome(
+            &registry,
+            RuleScope::Policy,
+            Some(ScopeDefault {
+                outcome: DefaultOutcome::DenyAll,
+                message: Some("Ask an admin for a permission grant".to_string()),
+            }),
+        );
+
+        let state = test_http_state(&registry, "session-1").await;
+        let resp = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(
+            resp.message.as_deref(),
+            Some("Ask an admin for a permission grant")
+        );
+
+        // Relative path resolves into the project, so the allow rule matches
+        let input = serde_json::json!({ "file_path": "src/main.rs" });
+        let state = test_http_state(&registry, "session-1").await;
+        let resp =
+            handle_permission_prompt(AxumState(state), Json(test_request("Read", input.clone())))
+                .await
+                .unwrap()
+                .0;
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(resp.updated_input, Some(input));
+
+        // Neither request was shown to the user
+        assert!(emitter.names().is_empty());
+        let sources: Vec<DecisionSource> = recent_decisions(None, &registry)
+            .iter()
+            .map(|d| d.source)
+            .collect();
+        assert_eq!(sources, vec![DecisionSource::Default, DecisionSource::Rule]);
+    }
+
+    #[tokio::test]
+    async fn test_extend_all_pending_only_affects_current_prompts() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                cwd: Some(PathBuf::from("/work/project")),
+                prompt_timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let state = test_http_state(&registry, "session-1").await;
+        let extended = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        assert_eq!(
+            extend_all_pending("session-1", Duration::from_secs(5), &registry).await,
+            1
+        );
+
+        let state = test_http_state(&registry, "session-1").await;
+        let fresh = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Bash",
+                serde_json::json!({ "command": "pwd" }),
+            )),
+        ));
+
+        // The new prompt still times out on the session default...
+        let resp = fresh.await.unwrap().unwrap().0;
+        assert_eq!(resp.message.as_deref(), Some("Permission prompt timed out"));
+
+        // ...while the extended one has outlived its original deadline
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!extended.is_finished());
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(extended.await.unwrap().unwrap().0.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_pending_changed_fires_only_on_zero_transitions() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_emit_generic_events(&registry, false);
+        let pending_events = || -> Vec<serde_json::Value> {
+            emitter
+                .events
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(name, _)| name == "permission-pending-changed:session-1")
+                .map(|(_, payload)| payload.clone())
+                .collect()
+        };
+
+        let input = serde_json::json!({ "command": "ls" });
+        let first = inject_test_prompt("session-1", "Bash", input.clone(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(pending_events().len(), 1);
+        assert_eq!(pending_events()[0]["count"], 1);
+
+        // 1 → 2 and 2 → 1 don't cross zero
+        let second = inject_test_prompt("session-1", "Bash", input, &registry)
+            .await
+            .unwrap();
+        resolve_prompt("session-1", &first, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(pending_events().len(), 1);
+
+        resolve_prompt("session-1", &second, allow(), &registry)
+            .await
+            .unwrap();
+        let events = pending_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["count"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_event_trimmed_and_fetchable_in_full() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_max_event_bytes(&registry, Some(512));
+
+        let content = "x".repeat(4096);
+        let input = serde_json::json!({ "file_path": "notes.txt", "content": content });
+        let prompt_id = inject_test_prompt("session-1", "Write", input.clone(), &registry)
+            .await
+            .unwrap();
+
+        let emitted = &emitter.prompts()[0];
+        assert!(serde_json::to_vec(emitted).unwrap().len() <= 512);
+        assert_eq!(emitted["truncated"], true);
+        assert_eq!(emitted["prompt_id"], prompt_id.as_str());
+        assert_eq!(emitted["tool_name"], "Write");
+        assert_eq!(emitted["summary"], "notes.txt");
+        assert!(emitted["input"].is_null());
+        assert!(emitted.get("explanation").is_none());
+
+        let full = get_prompt_event("session-1", &prompt_id, &registry)
+            .await
+            .unwrap();
+        assert!(!full.truncated);
+        assert_eq!(full.input, input);
+        assert!(full.explanation.is_some());
+
+        // Events under the limit are emitted untouched
+        set_max_event_bytes(&registry, None);
+        inject_test_prompt("session-1", "Write", input.clone(), &registry)
+            .await
+            .unwrap();
+        let emitted = &emitter.prompts()[1];
+        assert!(emitted.get("truncated").is_none());
+        assert_eq!(emitted["input"], input);
+    }
+
+    #[tokio::test]
+    async fn test_auto_edit_shown_in_event_and_recorded_with_original() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_auto_edit(
+            &registry,
+            "Bash",
+            Some(AutoEdit {
+                append: BTreeMap::from([("command".to_string(), " --dry-run".to_string())]),
+                ..Default::default()
+            }),
+        );
+
+        let original = serde_json::json!({ "command": "make deploy" });
+        let edited = serde_json::json!({ "command": "make deploy --dry-run" });
+        let state = test_http_state(&registry, "session-1").await;
+        let before = unix_millis();
+        let request = test_request("Bash", original.clone());
+        let tool_use_id = request.tool_use_id.clone();
+        let handler = tokio::spawn(handle_permission_prompt(AxumState(state), Json(request)));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+
+        let event = &emitter.prompts()[0];
+        assert_eq!(event["tool_use_id"], tool_use_id.as_str());
+        let created_at = event["created_at"].as_u64().unwrap();
+        assert!((before..=unix_millis()).contains(&created_at));
+        assert_eq!(event["input"], edited);
+        assert_eq!(event["suggested_input"], edited);
+        assert_eq!(event["original_input"], original);
+
+        let prompt_id = event["prompt_id"].as_str().unwrap().to_string();
+        let response = PermissionResponse {
+            behavior: "allow".to_string(),
+            updated_input: Some(edited.clone()),
+            message: None,
+        };
+        resolve_prompt("session-1", &prompt_id, response, &registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+
+        let record = &recent_decisions(Some("session-1"), &registry)[0];
+        assert_eq!(record.input, original);
+        assert_eq!(record.auto_edited_input, Some(edited));
+
+        // Tools without an auto-edit are untouched
+        assert_eq!(registry.auto_edited_input("Read", &original), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_abstract_socket_binds_without_a_file() {
+        let (listener, port, name) =
+            bind_listener("session-1", ServerTransport::AbstractSocket, None)
+                .await
+                .unwrap();
+        assert!(matches!(listener, BoundListener::Abstract(_)));
+        assert_eq!(port, 0);
+        let name = name.unwrap();
+
+        // Reachable through the abstract address, with nothing on disk
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        std::os::unix::net::UnixStream::connect_addr(&addr).unwrap();
+        assert!(!Path::new(&name).exists());
+
+        let options = McpFileOptions {
+            abstract_socket: Some(name.clone()),
+            ..Default::default()
+        };
+        let config = build_mcp_config(
+            port,
+            "session-1",
+            "node",
+            Path::new("/tmp/opcode-mcp-server-session-1.js"),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            config.mcp_servers["opcode"].env["PERMISSION_SERVER_ABSTRACT_SOCKET"],
+            name
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_is_private_and_removed_on_stop() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let registry = PermissionServerRegistry::default();
+        let port = start_server_with(
+            Arc::new(RecordingEmitter::default()),
+            "session-1",
+            PermissionServerConfig {
+                transport: ServerTransport::UnixSocket,
+                ..Default::default()
+            },
+            &registry,
+        )
+        .await
+        .unwrap();
+        assert_eq!(port, 0);
+        let path = registry.servers.lock().await["session-1"]
+            .socket_path
+            .clone()
+            .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let dir_mode = std::fs::metadata(path.parent().unwrap())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(dir_mode & 0o777, 0o700);
+
+        // The server answers over the socket, still asking for the token
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /pending HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("HTTP/1.1 401"), "{}", reply);
+
+        let options = mcp_file_options("session-1", &registry).await.unwrap();
+        let config = build_mcp_config(
+            port,
+            "session-1",
+            "node",
+            Path::new("/tmp/opcode-mcp-server-session-1.js"),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            config.mcp_servers["opcode"].env["PERMISSION_SERVER_SOCKET_PATH"],
+            path.to_string_lossy()
+        );
+
+        stop_server("session-1", &registry).await;
+        assert!(!path.exists());
+        assert!(!path.parent().unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn test_tool_prompt_histogram_counts_prompts_per_tool() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+
+        let requests = [
+            ("Bash", serde_json::json!({ "command": "ls" })),
+            ("Write", serde_json::json!({ "file_path": "a" })),
+            ("Bash", serde_json::json!({ "command": "pwd" })),
+        ];
+        let mut handlers = Vec::new();
+        for (tool, input) in requests {
+            let state = test_http_state(&registry, "session-1").await;
+            handlers.push(tokio::spawn(handle_permission_prompt(
+                AxumState(state),
+                Json(test_request(tool, input)),
+            )));
+        }
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 3).await;
+
+        let histogram = tool_prompt_histogram("session-1", &registry).await;
+        assert_eq!(
+            histogram,
+            HashMap::from([("Bash".to_string(), 2), ("Write".to_string(), 1)])
+        );
+
+        // Counters go away with the session
+        stop_server("session-1", &registry).await;
+        for handler in handlers {
+            let resp = handler.await.unwrap().unwrap().0;
+            assert_eq!(resp.behavior, "deny");
+        }
+        assert!(tool_prompt_histogram("session-1", &registry)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_risky_deny_needs_confirmation_or_reverts() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_emit_generic_events(&registry, false);
+        set_deny_confirmation(&registry, Some(Duration::from_millis(100)));
+        let deny = || deny_with("Denied by user");
+        let risky = serde_json::json!({ "command": "rm -rf build" });
+        let pending = registry.servers.lock().await["session-1"].pending.clone();
+
+        // Ordinary prompts are denied straight away
+        let safe = inject_test_prompt(
+            "session-1",
+            "Bash",
+            serde_json::json!({ "command": "ls" }),
+            &registry,
+        )
+        .await
+        .unwrap();
+        resolve_prompt("session-1", &safe, deny(), &registry)
+            .await
+            .unwrap();
+        assert!(!pending.lock().await.contains_key(&safe));
+
+        // Confirm path: the deny is staged, then finalized
+        let first = inject_test_prompt("session-1", "Bash", risky.clone(), &registry)
+            .await
+            .unwrap();
+        resolve_prompt("session-1", &first, deny(), &registry)
+            .await
+            .unwrap();
+        assert!(pending.lock().await.contains_key(&first));
+        assert!(emitter
+            .names()
+            .contains(&"permission-deny-confirm:session-1".to_string()));
+        confirm_deny("session-1", &first, &registry).await.unwrap();
+        assert!(!pending.lock().await.contains_key(&first));
+
+        // Revert path: without confirmation the prompt is pending again
+        let second = inject_test_prompt("session-1", "Bash", risky, &registry)
+            .await
+            .unwrap();
+        resolve_prompt("session-1", &second, deny(), &registry)
+            .await
+            .unwrap();
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || {
+            emitter_for_wait
+                .names()
+                .contains(&"permission-deny-reverted:session-1".to_string())
+        })
+        .await;
+        assert!(confirm_deny("session-1", &second, &registry).await.is_err());
+        resolve_prompt("session-1", &second, allow(), &registry)
+            .await
+            .unwrap();
+        assert!(pending.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_request_clears_handshake_suspect() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        {
+            let servers = registry.servers.lock().await;
+            spawn_handshake_watchdog(&servers["session-1"], &registry, Duration::from_millis(20));
+        }
+
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || {
+            emitter_for_wait
+                .names()
+                .contains(&"permission-handshake-suspect:session-1".to_string())
+        })
+        .await;
+        assert!(!has_received_request("session-1", &registry).await);
+        assert_eq!(
+            handshake_suspect_sessions(&registry).await,
+            vec!["session-1"]
+        );
+
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "a" }),
+            )),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+
+        assert!(has_received_request("session-1", &registry).await);
+        assert!(handshake_suspect_sessions(&registry).await.is_empty());
+        assert!(emitter
+            .names()
+            .contains(&"permission-handshake-ok:session-1".to_string()));
+
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_burst_coalesces_notifications() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+
+        let mut handlers = Vec::new();
+        for path in ["a", "b", "c"] {
+            let state = test_http_state(&registry, "session-1").await;
+            handlers.push(tokio::spawn(handle_permission_prompt(
+                AxumState(state),
+                Json(test_request(
+                    "Read",
+                    serde_json::json!({ "file_path": path }),
+                )),
+            )));
+        }
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || {
+            emitter_for_wait
+                .names()
+                .contains(&"permission-prompt-notify:session-1".to_string())
+        })
+        .await;
+
+        let prompts = emitter.prompts();
+        assert_eq!(prompts.len(), 3);
+        let notifications: Vec<serde_json::Value> = emitter
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name == "permission-prompt-notify:session-1")
+            .map(|(_, payload)| payload.clone())
+            .collect();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["count"], 3);
+        assert_eq!(notifications[0]["message"], "3 permission requests pending");
+        assert_eq!(notifications[0]["tool_name"], "Read");
+        assert!(prompts
+            .iter()
+            .any(|prompt| prompt["prompt_id"] == notifications[0]["prompt_id"]));
+
+        for prompt in prompts {
+            let prompt_id = prompt["prompt_id"].as_str().unwrap();
+            resolve_prompt("session-1", prompt_id, allow(), &registry)
+                .await
+                .unwrap();
+        }
+        for handler in handlers {
+            let resp = handler.await.unwrap().unwrap().0;
+            assert_eq!(resp.behavior, "allow");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conditional_rule_applies_after_manual_allow() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_scope_rules(
+            &registry,
+            RuleScope::Session,
+            vec![PermissionRule {
+                tool: "Read".to_string(),
+                field: Some("file_path".to_string()),
+                pattern: Some("/work/project/src/*".to_string()),
+                agent_path_prefix: None,
+                condition: Some(RuleCondition::PriorUserAllow),
+                action: RuleAction::Allow,
+                message: None,
+                origin: None,
+            }],
+        );
+
+        // Nothing allowed yet, so the first read is shown to the user
+        let state = test_http_state(&registry, "session-1").await;
+        let first = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "src/main.rs" }),
+            )),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 1).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(first.await.unwrap().unwrap().0.behavior, "allow");
+
+        // The manual allow satisfies the condition for the rest of the directory
+        let state = test_http_state(&registry, "session-1").await;
+        let resp = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "src/lib.rs" }),
+            )),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(emitter.prompts().len(), 1);
+        let sources: Vec<DecisionSource> = recent_decisions(Some("session-1"), &registry)
+            .iter()
+            .map(|d| d.source)
+            .collect();
+        assert_eq!(sources, vec![DecisionSource::User, DecisionSource::Rule]);
+    }
+
+    #[tokio::test]
+    async fn test_requests_past_decision_limit_are_denied() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                cwd: Some(PathBuf::from("/work/project")),
+                max_decisions: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+        set_default_outcome(
+            &registry,
+            RuleScope::Session,
+            Some(ScopeDefault {
+                outcome: DefaultOutcome::AllowAll,
+                message: None,
+            }),
+        );
+
+        let mut behaviors = Vec::new();
+        for _ in 0..4 {
+            let state = test_http_state(&registry, "session-1").await;
+            let resp = handle_permission_prompt(
+                AxumState(state),
+                Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+            )
+            .await
+            .unwrap()
+            .0;
+            behaviors.push((resp.behavior, resp.message));
+        }
+        let limited = ("deny".to_string(), Some(DECISION_LIMIT_MESSAGE.to_string()));
+        assert_eq!(
+            behaviors,
+            vec![
+                ("allow".to_string(), None),
+                ("allow".to_string(), None),
+                limited.clone(),
+                limited,
+            ]
+        );
+
+        // Reported once, not on every blocked request
+        let reported = emitter
+            .names()
+            .iter()
+            .filter(|name| *name == "permission-limit-reached:session-1")
+            .count();
+        assert_eq!(reported, 1);
+        assert_eq!(
+            recent_decisions(Some("session-1"), &registry)
+                .last()
+                .unwrap()
+                .source,
+            DecisionSource::Limit
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reevaluate_resolves_prompts_matching_new_rule() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+
+        let mut handlers = Vec::new();
+        for input in [
+            serde_json::json!({ "file_path": "src/main.rs" }),
+            serde_json::json!({ "file_path": "/etc/passwd" }),
+        ] {
+            let state = test_http_state(&registry, "session-1").await;
+            handlers.push(tokio::spawn(handle_permission_prompt(
+                AxumState(state),
+                Json(test_request("Read", input)),
+            )));
+        }
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 2).await;
+        assert_eq!(reevaluate_pending("session-1", &registry).await, 0);
+
+        set_scope_rules(
+            &registry,
+            RuleScope::Session,
+            vec![PermissionRule {
+                tool: "Read".to_string(),
+                field: Some("file_path".to_string()),
+                pattern: Some("/work/project/*".to_string()),
+                agent_path_prefix: None,
+                condition: None,
+                action: RuleAction::Allow,
+                message: None,
+                origin: None,
+            }],
+        );
+        assert_eq!(reevaluate_pending("session-1", &registry).await, 1);
+
+        let allowed = handlers.remove(0).await.unwrap().unwrap().0;
+        assert_eq!(allowed.behavior, "allow");
+        assert_eq!(
+            allowed.updated_input,
+            Some(serde_json::json!({ "file_path": "src/main.rs" }))
+        );
+        let sources: Vec<DecisionSource> = recent_decisions(Some("session-1"), &registry)
+            .iter()
+            .map(|d| d.source)
+            .collect();
+        assert_eq!(sources, vec![DecisionSource::Rule]);
+
+        // The read outside the project still needs an answer
+        let outside = emitter
+            .prompts()
+            .into_iter()
+            .find(|p| p["input"]["file_path"] == "/etc/passwd")
+            .unwrap();
+        let pending: Vec<String> = {
+            let servers = registry.servers.lock().await;
+            let pending = servers["session-1"].pending.lock().await;
+            pending.keys().cloned().collect()
+        };
+        assert_eq!(
+            pending,
+            vec![outside["prompt_id"].as_str().unwrap().to_string()]
+        );
+        resolve_prompt(
+            "session-1",
+            outside["prompt_id"].as_str().unwrap(),
+            allow(),
+            &registry,
+        )
+        .await
+        .unwrap();
+        let resp = handlers.remove(0).await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_rule_conflict_is_emitted_with_winner() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let rule = |pattern: &str, action| PermissionRule {
+            tool: "Bash".to_string(),
+            field: Some("command".to_string()),
+            pattern: Some(pattern.to_string()),
+            agent_path_prefix: None,
+            condition: None,
+            action,
+            message: None,
+            origin: None,
+        };
+        set_scope_rules(
+            &registry,
+            RuleScope::Project,
+            vec![rule("rm *", RuleAction::Deny)],
+        );
+        set_scope_rules(
+            &registry,
+            RuleScope::Session,
+            vec![rule("rm build/*", RuleAction::Allow)],
+        );
+        set_conflict_policy(&registry, ConflictPolicy::DenyWins);
+
+        let state = test_http_state(&registry, "session-1").await;
+        let resp = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Bash",
+                serde_json::json!({ "command": "rm build/out" }),
+            )),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.behavior, "deny");
+
+        let conflicts: Vec<serde_json::Value> = emitter
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name == "permission-rule-conflict:session-1")
+            .map(|(_, payload)| payload.clone())
+            .collect();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0]["policy"], "deny_wins");
+        assert_eq!(conflicts[0]["winner"]["pattern"], "rm *");
+        assert_eq!(conflicts[0]["overridden"][0]["pattern"], "rm build/*");
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_allows_with_sandbox_flag() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Bash",
+                serde_json::json!({ "command": "npm install" }),
+            )),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let quarantine = PermissionResponse {
+            behavior: QUARANTINE_BEHAVIOR.to_string(),
+            updated_input: None,
+            message: None,
+        };
+        resolve_prompt("session-1", &prompt_id, quarantine, &registry)
+            .await
+            .unwrap();
+
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+        let input = resp.updated_input.unwrap();
+        assert_eq!(input["command"], "npm install");
+        assert_eq!(input[QUARANTINE_FIELD]["sandbox"], true);
+
+        assert!(emitter
+            .names()
+            .contains(&"permission-quarantined:session-1".to_string()));
+        assert_eq!(
+            recent_decisions(Some("session-1"), &registry)[0].behavior,
+            QUARANTINE_BEHAVIOR
+        );
+    }
+
+    #[tokio::test]
+    async fn test_live_timeout_change_and_zero_waits_forever() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                cwd: Some(PathBuf::from("/work/project")),
+                prompt_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = || test_request("Bash", serde_json::json!({ "command": "ls" }));
+
+        set_timeout("session-1", Duration::ZERO, &registry)
+            .await
+            .unwrap();
+        let state = test_http_state(&registry, "session-1").await;
+        let waiting = tokio::spawn(handle_permission_prompt(AxumState(state), Json(request())));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 1).await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!waiting.is_finished());
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(waiting.await.unwrap().unwrap().0.behavior, "allow");
+
+        set_timeout("session-1", Duration::from_millis(30), &registry)
+            .await
+            .unwrap();
+        let state = test_http_state(&registry, "session-1").await;
+        let resp = handle_permission_prompt(AxumState(state), Json(request()))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(resp.message.as_deref(), Some("Permission prompt timed out"));
+
+        assert!(set_timeout("missing", Duration::ZERO, &registry)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_behavior_allows_with_original_input() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                cwd: Some(PathBuf::from("/work/project")),
+                prompt_timeout: Some(Duration::from_millis(30)),
+                timeout_behavior: TimeoutBehavior::AllowWithOriginalInput,
+                ..Default::default()
+            },
+        )
+        .await;
+        set_auto_edit(
+            &registry,
+            "Bash",
+            Some(AutoEdit {
+                append: BTreeMap::from([("command".to_string(), " --dry-run".to_string())]),
+                ..Default::default()
+            }),
+        );
+
+        let input = serde_json::json!({ "command": "make deploy" });
+        let state = test_http_state(&registry, "session-1").await;
+        let resp =
+            handle_permission_prompt(AxumState(state), Json(test_request("Bash", input.clone())))
+                .await
+                .unwrap()
+                .0;
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(resp.updated_input, Some(input));
+        assert_eq!(
+            resp.message.as_deref(),
+            Some("Auto-allowed on timeout with the original input")
+        );
+        assert_eq!(
+            recent_decisions(Some("session-1"), &registry)[0].source,
+            DecisionSource::Timeout
+        );
+
+        let events = emitter.events.lock().unwrap().clone();
+        let timeouts: Vec<_> = events
+            .iter()
+            .filter(|(name, _)| name.starts_with("permission-timeout"))
+            .collect();
+        assert_eq!(timeouts.len(), 2);
+        assert_eq!(timeouts[0].0, "permission-timeout:session-1");
+        assert_eq!(timeouts[1].0, "permission-timeout");
+        assert_eq!(timeouts[0].1["tool_name"], "Bash");
+        assert_eq!(
+            timeouts[0].1["prompt_id"],
+            emitter.prompts()[0]["prompt_id"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_permission_route_requires_auth_token() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let payload = || {
+            Json(PermissionPayload::Single(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "a" }),
+            )))
+        };
+
+        for headers in [HeaderMap::new(), bearer_headers(&state.controller_token)] {
+            assert_eq!(
+                handle_permission_route(AxumState(state.clone()), headers, payload())
+                    .await
+                    .err(),
+                Some(StatusCode::UNAUTHORIZED)
+            );
+        }
+        assert!(emitter.prompts().is_empty());
+        assert!(!has_received_request("session-1", &registry).await);
+
+        let options = mcp_file_options("session-1", &registry).await.unwrap();
+        let config =
+            build_mcp_config(0, "session-1", "node", Path::new("/tmp/s.js"), &options).unwrap();
+        let token = &config.mcp_servers["opcode"].env["PERMISSION_AUTH_TOKEN"];
+        assert_eq!(token, &state.auth_token);
+        // Past the server's prompt timeout, so the server answers first
+        let client_timeout = &config.mcp_servers["opcode"].env["PERMISSION_CLIENT_TIMEOUT_MS"];
+        assert_eq!(
+            client_timeout,
+            &(DEFAULT_PROMPT_TIMEOUT + CLIENT_TIMEOUT_MARGIN)
+                .as_millis()
+                .to_string()
+        );
+
+        let handler = tokio::spawn(handle_permission_route(
+            AxumState(state),
+            bearer_headers(token),
+            payload(),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert!(handler.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_list_pending_tracks_prompts_until_resolved() {
+        let registry = PermissionServerRegistry::default();
+        insert_test_entry(&registry, "session-1").await;
+        assert!(list_pending("missing", &registry).await.is_empty());
+
+        let before_ms = chrono::Utc::now().timestamp_millis();
+        let prompt_id = inject_test_prompt("session-1", "Bash", serde_json::json!({}), &registry)
+            .await
+            .unwrap();
+        let listed = list_pending("session-1", &registry).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].prompt_id, prompt_id);
+        assert_eq!(listed[0].tool_name, "Bash");
+        assert!(listed[0].created_at_ms >= before_ms - 1);
+
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert!(list_pending("session-1", &registry).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_prompt_denies_waiting_request() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        cancel_prompt("session-1", &prompt_id, &registry)
+            .await
+            .unwrap();
+        let Json(resp) = handler.await.unwrap().unwrap();
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(
+            resp.message.as_deref(),
+            Some("Permission prompt was cancelled")
+        );
+        assert!(emitter
+            .names()
+            .contains(&"permission-cancelled:session-1".to_string()));
+
+        assert!(cancel_prompt("session-1", &prompt_id, &registry)
+            .await
+            .is_err());
+        assert!(cancel_prompt("missing", &prompt_id, &registry)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deny_all_pending_keeps_server_running() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let handlers: Vec<_> = ["ls", "pwd"]
+            .into_iter()
+            .map(|command| {
+                tokio::spawn(handle_permission_prompt(
+                    AxumState(state.clone()),
+                    Json(test_request(
+                        "Bash",
+                        serde_json::json!({ "command": command }),
+                    )),
+                ))
+            })
+            .collect();
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 2).await;
+
+        let denied = deny_all_pending("session-1", &registry, "Stopped by user".to_string()).await;
+        assert_eq!(denied, 2);
+        for handler in handlers {
+            let Json(resp) = handler.await.unwrap().unwrap();
+            assert_eq!(resp.behavior, "deny");
+            assert_eq!(resp.message.as_deref(), Some("Stopped by user"));
+        }
+        assert!(list_pending("session-1", &registry).await.is_empty());
+        assert_eq!(
+            deny_all_pending("missing", &registry, String::new()).await,
+            0
+        );
+
+        // The server still takes new prompts
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "a" }),
+            )),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 3).await;
+        let prompt_id = emitter.prompts()[2]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(handler.await.unwrap().unwrap().0.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_loopback_bind_falls_back_to_next_address() {
+        // 192.0.2.1 (TEST-NET-1) is never a local address, so binding fails
+        let listener = bind_loopback(&["192.0.2.1:0", "127.0.0.1:0"])
+            .await
+            .unwrap();
+        let host = BoundListener::Tcp(listener).host().unwrap();
+        assert_eq!(host, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(bind_loopback(&["192.0.2.1:0"]).await.is_err());
+
+        let options = McpFileOptions {
+            host: Some("::1".to_string()),
+            ..Default::default()
+        };
+        let config =
+            build_mcp_config(4321, "session-1", "node", Path::new("/tmp/s.js"), &options).unwrap();
+        assert_eq!(
+            config.mcp_servers["opcode"].env["PERMISSION_SERVER_HOST"],
+            "::1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_records_timeout_and_allowlist() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        std::fs::write(&path, "{\"seq\":41}\n").unwrap();
+        let registry = PermissionServerRegistry::default();
+        set_audit_log_path(&registry, Some(path.clone()));
+        insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                prompt_timeout: Some(Duration::from_millis(30)),
+                ..Default::default()
+            },
+        )
+        .await;
+        add_allowed_tool("session-1", "Read", &registry)
+            .await
+            .unwrap();
+
+        let state = test_http_state(&registry, "session-1").await;
+        let mut behaviors = Vec::new();
+        for tool in ["Bash", "Read"] {
+            let Json(resp) = handle_permission_prompt(
+                AxumState(state.clone()),
+                Json(test_request(tool, serde_json::json!({}))),
+            )
+            .await
+            .unwrap();
+            behaviors.push(resp.behavior);
+        }
+        assert_eq!(behaviors, ["deny", "allow"]);
+
+        let path_for_wait = path.clone();
+        wait_until(move || {
+            std::fs::read_to_string(&path_for_wait)
+                .unwrap_or_default()
+                .lines()
+                .count()
+                == 3
+        })
+        .await;
+        let records: Vec<DecisionRecord> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records[0].seq, 42);
+        assert_eq!(records[0].source, DecisionSource::Timeout);
+        assert_eq!(
+            records[0].message.as_deref(),
+            Some("Permission prompt timed out")
+        );
+        assert_eq!(records[1].source, DecisionSource::Allowlist);
+        assert_eq!(records[1].behavior, "allow");
+    }
+
+    #[test]
+    fn test_request_key_is_canonical_json() {
+        let key = request_key(
+            "Bash",
+            &serde_json::json!({ "timeout": 5, "command": "git status", "env": ["A", 1] }),
+        );
+        assert_eq!(
+            key,
+            r#""Bash":{"command":"git status","env":["A",1],"timeout":5}"#
+        );
+        assert_eq!(
+            key,
+            request_key(
+                "Bash",
+                &serde_json::json!({ "env": ["A", 1], "command": "git status", "timeout": 5 }),
+            )
+        );
+        // Nothing but an identical request shares the key
+        assert_ne!(
+            key,
+            request_key(
+                "Bash",
+                &serde_json::json!({ "command": "git status", "env": ["A", "1"], "timeout": 5 }),
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remembered_answer_reused_for_identical_request() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let request = |input: serde_json::Value| {
+            handle_permission_prompt(AxumState(state.clone()), Json(test_request("Bash", input)))
+        };
+
+        let handler = tokio::spawn(request(
+            serde_json::json!({ "command": "git status", "timeout": 5 }),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 1).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt_with("session-1", &prompt_id, allow(), None, true, &registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+
+        // Same tool and input (in any key order) is answered without a prompt
+        let Json(resp) = request(serde_json::json!({ "timeout": 5, "command": "git status" }))
+            .await
+            .unwrap();
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(emitter.prompts().len(), 1);
+        assert_eq!(
+            recent_decisions(Some("session-1"), &registry)
+                .last()
+                .unwrap()
+                .source,
+            DecisionSource::Remembered
+        );
+
+        // A different input still prompts
+        let handler = tokio::spawn(request(serde_json::json!({ "command": "git push" })));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 2).await;
+        handler.abort();
+    }
+
+    #[tokio::test]
+    async fn test_remembered_answer_replays_the_real_input() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let input = serde_json::json!({ "command": "API_KEY=abc123 make deploy" });
+        let request = || {
+            handle_permission_prompt(
+                AxumState(state.clone()),
+                Json(test_request("Bash", input.clone())),
+            )
+        };
+
+        let handler = tokio::spawn(request());
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 1).await;
+        let event = emitter.prompts()[0].clone();
+        assert_eq!(event["input"]["command"], "API_KEY=[REDACTED] make deploy");
+
+        // The dialog sends back the masked copy it was shown, unedited
+        let answer = PermissionResponse {
+            updated_input: Some(event["input"].clone()),
+            ..allow()
+        };
+        let prompt_id = event["prompt_id"].as_str().unwrap();
+        resolve_prompt_with("session-1", prompt_id, answer, None, true, &registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.updated_input, Some(input.clone()));
+
+        // The replay carries the real input too, not the masked one
+        let Json(resp) = request().await.unwrap();
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(resp.updated_input, Some(input.clone()));
+        assert_eq!(emitter.prompts().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_structured_and_keep_their_messages() {
+        let registry = PermissionServerRegistry::default();
+        insert_test_entry(&registry, "session-1").await;
+
+        let err = resolve_prompt("missing", "p", allow(), &registry)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PermissionError::SessionNotFound(_)));
+        assert_eq!(
+            err.to_string(),
+            "No permission server for session 'missing'"
+        );
+
+        let err = resolve_prompt("session-1", "p", allow(), &registry)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PermissionError::PromptNotFound(_)));
+        assert_eq!(String::from(err), "No pending prompt 'p'");
+
+        assert!(matches!(
+            rekey_server("missing", "session-2", &registry).await,
+            Err(PermissionError::SessionNotFound(_))
+        ));
+        rekey_server("session-1", "session-2", &registry)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sweeper_drops_prompts_past_their_deadline() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let pending = registry.servers.lock().await["session-1"].pending.clone();
+
+        let (stale_tx, stale_rx) = oneshot::channel::<(PermissionResponse, DecisionSource)>();
+        let (live_tx, _live_rx) = oneshot::channel::<(PermissionResponse, DecisionSource)>();
+        {
+            let mut pending = pending.lock().await;
+            pending.insert(
+                "stale".to_string(),
+                PendingPrompt::new(PendingReply::Single(stale_tx), "Bash", Some(Duration::ZERO)),
+            );
+            pending.insert(
+                "forever".to_string(),
+                PendingPrompt::new(PendingReply::Single(live_tx), "Read", None),
+            );
+        }
+
+        // Queued prompts are left alone while paused
+        pause_all(&registry);
+        assert!(sweep_stale_pending(
+            &registry,
+            &pending,
+            emitter.as_ref(),
+            "session-1",
+            Duration::ZERO
+        )
+        .await
+        .is_empty());
+        resume_all(&registry);
+
+        let removed = sweep_stale_pending(
+            &registry,
+            &pending,
+            emitter.as_ref(),
+            "session-1",
+            Duration::ZERO,
+        )
+        .await;
+        assert_eq!(removed, vec!["stale".to_string()]);
+        assert!(stale_rx.await.is_err());
+        assert!(pending.lock().await.contains_key("forever"));
+    }
+
+    #[tokio::test]
+    async fn test_sweeper_stops_on_shutdown() {
+        let registry = PermissionServerRegistry::default();
+        insert_test_entry(&registry, "session-1").await;
+        let weak = {
+            let servers = registry.servers.lock().await;
+            let entry = &servers["session-1"];
+            spawn_pending_sweeper(entry, &registry, Duration::from_millis(10));
+            let weak = Arc::downgrade(&entry.pending);
+            let _ = entry.shutdown_tx.send(true);
+            weak
+        };
+        registry.servers.lock().await.remove("session-1");
+        // Once the task exits nothing else holds the pending map
+        wait_until(move || weak.strong_count() == 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_overflow_denies_past_max_concurrent() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                max_concurrent: Some(1),
+                overflow_behavior: OverflowBehavior::Deny,
+                ..Default::default()
+            },
+        )
+        .await;
+        let state = test_http_state(&registry, "session-1").await;
+        let first = tokio::spawn(handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+
+        let resp = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Bash",
+                serde_json::json!({ "command": "pwd" }),
+            )),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(resp.message.as_deref(), Some(TOO_MANY_PENDING_MESSAGE));
+        assert_eq!(emitter.prompts().len(), 1);
+        assert_eq!(
+            recent_decisions(Some("session-1"), &registry)[0].source,
+            DecisionSource::Overflow
+        );
+
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(first.await.unwrap().unwrap().0.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_overflow_queues_until_a_slot_frees() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                max_concurrent: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+        let state = test_http_state(&registry, "session-1").await;
+        let handlers: Vec<_> = ["ls", "pwd"]
+            .into_iter()
+            .map(|command| {
+                tokio::spawn(handle_permission_prompt(
+                    AxumState(state.clone()),
+                    Json(test_request(
+                        "Bash",
+                        serde_json::json!({ "command": command }),
+                    )),
+                ))
+            })
+            .collect();
+
+        for shown in 1..=2 {
+            let emitter_for_wait = emitter.clone();
+            wait_until(move || emitter_for_wait.prompts().len() >= shown).await;
+            // The other request waits without showing a prompt
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            assert_eq!(emitter.prompts().len(), shown);
+            assert_eq!(list_pending("session-1", &registry).await.len(), 1);
+
+            let prompt_id = emitter.prompts()[shown - 1]["prompt_id"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            resolve_prompt("session-1", &prompt_id, allow(), &registry)
+                .await
+                .unwrap();
+        }
+        for handler in handlers {
+            assert_eq!(handler.await.unwrap().unwrap().0.behavior, "allow");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_policy_answers_before_prompting() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("policy.json");
+        std::fs::write(
+            &path,
+            serde_json::json!({ "rules": [
+                { "tool": "Read", "field": "file_path", "pattern": "/work/project/*", "action": "allow" },
+                { "tool": "Bash", "field": "command", "pattern": "*rm -rf*", "action": "deny",
+                  "message": "No recursive deletes" }
+            ]})
+            .to_string(),
+        )
+        .unwrap();
+
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                cwd: Some(PathBuf::from("/work/project")),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(reload_policy("session-1", &registry).await.is_err());
+        assert_eq!(
+            set_policy("session-1", Some(path.clone()), &registry)
+                .await
+                .unwrap(),
+            2
+        );
+
+        // Relative paths are resolved against the session cwd first
+        let state = test_http_state(&registry, "session-1").await;
+        let resp = handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "src/lib.rs" }),
+            )),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.behavior, "allow");
+        let resp = handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(test_request(
+                "Bash",
+                serde_json::json!({ "command": "rm -rf /" }),
+            )),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.message.as_deref(), Some("No recursive deletes"));
+        assert!(emitter.prompts().is_empty());
+        assert!(recent_decisions(Some("session-1"), &registry)
+            .iter()
+            .all(|d| d.source == DecisionSource::Rule));
+
+        // A broken edit keeps the old rules; a fixed one is picked up
+        std::fs::write(&path, "not json").unwrap();
+        assert!(reload_policy("session-1", &registry).await.is_err());
+        assert_eq!(
+            state.policy.read().unwrap().as_ref().unwrap().rules.len(),
+            2
+        );
+        std::fs::write(&path, "[]").unwrap();
+        assert_eq!(reload_policy("session-1", &registry).await.unwrap(), 0);
+
+        assert_eq!(set_policy("session-1", None, &registry).await.unwrap(), 0);
+        assert!(state.policy.read().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_non_object_updated_input_is_rejected() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let bad = PermissionResponse {
+            updated_input: Some(serde_json::json!(["ls"])),
+            ..allow()
+        };
+        let err = resolve_prompt("session-1", &prompt_id, bad, &registry)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PermissionError::InvalidUpdatedInput("an array")
+        ));
+        assert_eq!(list_pending("session-1", &registry).await.len(), 1);
+
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(handler.await.unwrap().unwrap().0.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_modify_applies_named_transform() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Bash",
+                serde_json::json!({ "command": "NPM_TOKEN=abc123 npm publish" }),
+            )),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let modify = || PermissionResponse {
+            behavior: MODIFY_BEHAVIOR.to_string(),
+            updated_input: None,
+            message: None,
+        };
+
+        // A missing or unknown transform is rejected and the prompt stays open
+        let err = resolve_prompt("session-1", &prompt_id, modify(), &registry)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PermissionError::Invalid(_)));
+        let err = resolve_prompt_with(
+            "session-1",
+            &prompt_id,
+            modify(),
+            Some("no_such_transform"),
+            false,
+            &registry,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("no_such_transform"));
+        assert_eq!(list_pending("session-1", &registry).await.len(), 1);
+
+        resolve_prompt_with(
+            "session-1",
+            &prompt_id,
+            modify(),
+            Some(transforms::STRIP_ENV_ASSIGNMENTS),
+            false,
+            &registry,
+        )
+        .await
+        .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(resp.updated_input.unwrap()["command"], "npm publish");
+    }
+
+    #[tokio::test]
+    async fn test_custom_transform_and_deny_rejection() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_input_transform(
+            &registry,
+            "dry_run",
+            Some(Arc::new(|_: &str, input: &serde_json::Value| {
+                let mut input = input.clone();
+                input["dry_run"] = serde_json::Value::Bool(true);
+                Ok(input)
+            })),
+        );
+
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Deploy", serde_json::json!({ "env": "prod" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let deny = PermissionResponse {
+            behavior: "deny".to_string(),
+            updated_input: None,
+            message: Some("no".to_string()),
+        };
+        let err = resolve_prompt_with(
+            "session-1",
+            &prompt_id,
+            deny,
+            Some("dry_run"),
+            false,
+            &registry,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, PermissionError::Invalid(_)));
+
+        resolve_prompt_with(
+            "session-1",
+            &prompt_id,
+            allow(),
+            Some("dry_run"),
+            false,
+            &registry,
+        )
+        .await
+        .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(
+            resp.updated_input.unwrap(),
+            serde_json::json!({ "env": "prod", "dry_run": true })
+        );
+
+        set_input_transform(&registry, "dry_run", None);
+        assert!(!registry
+            .input_transforms
+            .read()
+            .unwrap()
+            .contains_key("dry_run"));
+    }
+
+    #[tokio::test]
+    async fn test_replay_pending_reemits_waiting_prompts() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        assert!(matches!(
+            replay_pending("missing", &registry).await,
+            Err(PermissionError::SessionNotFound(_))
+        ));
+        assert_eq!(replay_pending("session-1", &registry).await.unwrap(), 0);
+
+        let state = test_http_state(&registry, "session-1").await;
+        let first = tokio::spawn(handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 1).await;
+        let second = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "a.txt" }),
+            )),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 2).await;
+        let shown = emitter.prompts();
+
+        assert_eq!(replay_pending("session-1", &registry).await.unwrap(), 2);
+        let prompts = emitter.prompts();
+        assert_eq!(prompts.len(), 4);
+        assert_eq!(prompts[2], shown[0]);
+        assert_eq!(prompts[3], shown[1]);
+        assert_ne!(prompts[2]["tool_use_id"], prompts[3]["tool_use_id"]);
+
+        for prompt in &shown {
+            let prompt_id = prompt["prompt_id"].as_str().unwrap();
+            resolve_prompt("session-1", prompt_id, allow(), &registry)
+                .await
+                .unwrap();
+        }
+        for handler in [first, second] {
+            let resp = handler.await.unwrap().unwrap().0;
+            assert_eq!(resp.behavior, "allow");
+        }
+        assert_eq!(replay_pending("session-1", &registry).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_rejected() {
+        use tower::ServiceExt;
+
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let token = state.auth_token.clone();
+        let post = |body: String| {
+            axum::http::Request::post("/permission-prompt")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body))
+                .unwrap()
+        };
+
+        let big = serde_json::json!({
+            "tool_use_id": "toolu_test",
+            "tool_name": "Write",
+            "input": { "file_path": "big.txt", "content": "x".repeat(4096) },
+        });
+        let router = build_router(state, 1024);
+        let resp = router.clone().oneshot(post(big.to_string())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Small bodies still reach the handler (which refuses a bad token)
+        let mut small = post(
+            serde_json::to_string(&test_request(
+                "Bash",
+                serde_json::json!({ "command": "ls" }),
+            ))
+            .unwrap(),
+        );
+        small
+            .headers_mut()
+            .insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        let resp = router.oneshot(small).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(emitter.prompts().is_empty());
+        assert!(list_pending("session-1", &registry).await.is_empty());
+    }
+
+    #[test]
+    fn test_input_preview_shortens_long_strings() {
+        let input = serde_json::json!({ "file_path": "a.txt", "content": "x".repeat(5000) });
+        assert!(input_preview(&input, 0).is_none());
+        assert!(input_preview(&input, 10_000).is_none());
+
+        let preview = input_preview(&input, 2048).unwrap();
+        assert_eq!(preview["file_path"], "a.txt");
+        let content = preview["content"].as_str().unwrap();
+        assert_eq!(content.chars().count(), INPUT_PREVIEW_CHARS + 1);
+        assert!(content.ends_with('…'));
+
+        // Too big even with every string cut short
+        let many = serde_json::json!({ "edits": vec!["y".repeat(2000); 50] });
+        assert_eq!(input_preview(&many, 2048), Some(serde_json::Value::Null));
+    }
+
+    #[tokio::test]
+    async fn test_large_input_previewed_and_sent_back_in_full() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        set_max_input_bytes(&registry, Some(2048));
+        let state = test_http_state(&registry, "session-1").await;
+        let input = serde_json::json!({ "file_path": "big.txt", "content": "x".repeat(8000) });
+        let input_len = serde_json::to_vec(&input).unwrap().len();
+
+        // The UI may answer without an input or echo the preview back
+        for (shown, echo_preview) in [(1, false), (2, true)] {
+            let handler = tokio::spawn(handle_permission_prompt(
+                AxumState(state.clone()),
+                Json(test_request("Write", input.clone())),
+            ));
+            let emitter_for_wait = emitter.clone();
+            wait_until(move || emitter_for_wait.prompts().len() == shown).await;
+            let event = emitter.prompts()[shown - 1].clone();
+            assert_eq!(event["input_truncated"], true);
+            assert_eq!(event["input_bytes"], input_len);
+            assert_eq!(event["input"]["file_path"], "big.txt");
+            assert!(event["input"]["content"].as_str().unwrap().len() < 2048);
+
+            let prompt_id = event["prompt_id"].as_str().unwrap();
+            assert_eq!(
+                get_full_input("session-1", prompt_id, &registry)
+                    .await
+                    .unwrap(),
+                input
+            );
+            let mut answer = allow();
+            if echo_preview {
+                answer.updated_input = Some(event["input"].clone());
+            }
+            resolve_prompt("session-1", prompt_id, answer, &registry)
+                .await
+                .unwrap();
+            let resp = handler.await.unwrap().unwrap().0;
+            assert_eq!(resp.updated_input, Some(input.clone()));
+        }
+
+        // Small inputs are emitted as they are
+        inject_test_prompt(
+            "session-1",
+            "Bash",
+            serde_json::json!({ "command": "ls" }),
+            &registry,
+        )
+        .await
+        .unwrap();
+        let event = emitter.prompts()[2].clone();
+        assert!(event.get("input_truncated").is_none());
+        assert_eq!(event["input"]["command"], "ls");
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_registry_lists_every_server() {
+        let registry = PermissionServerRegistry::default();
+        assert!(snapshot_registry(&registry).await.is_empty());
+
+        insert_test_entry(&registry, "session-b").await;
+        insert_test_entry(&registry, "session-a").await;
+        let files = McpFiles {
+            dir: PathBuf::from("/tmp/opcode-mcp-x"),
+            config_path: PathBuf::from("/tmp/opcode-mcp-x/opcode-mcp-session-a.json"),
+            script_path: PathBuf::new(),
+        };
+        set_mcp_files("session-a", files.clone(), &registry).await;
+        inject_test_prompt("session-a", "Bash", serde_json::json!({}), &registry)
+            .await
+            .unwrap();
+
+        let snapshots = snapshot_registry(&registry).await;
+        let ids: Vec<&str> = snapshots.iter().map(|s| s.session_id.as_str()).collect();
+        assert_eq!(ids, ["session-a", "session-b"]);
+        assert_eq!(snapshots[0].pending, 1);
+        assert_eq!(snapshots[0].mcp_files, files);
+        assert_eq!(snapshots[0].host, "127.0.0.1");
+        assert!(!snapshots[0].received_request);
+        assert_eq!(snapshots[1].pending, 0);
+        assert_eq!(snapshots[1].mcp_files, McpFiles::default());
+    }
+
+    #[tokio::test]
+    async fn test_preferred_port_used_when_free() {
+        // Find a free port, then release it for the server to take
+        let free = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (listener, port, _) = bind_listener("session-1", ServerTransport::Tcp, Some(free))
+            .await
+            .unwrap();
+        assert_eq!(port, free);
+
+        // Taken on 127.0.0.1 now, so the next server gets it on ::1 where
+        // available and a random port otherwise
+        let (second, fallback, _) = bind_listener("session-2", ServerTransport::Tcp, Some(free))
+            .await
+            .unwrap();
+        assert_ne!(fallback, 0);
+        if fallback == free {
+            assert_eq!(
+                second.host(),
+                Some(IpAddr::V6(std::net::Ipv6Addr::LOCALHOST))
+            );
+        }
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_user_and_allowlist_decisions() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        assert!(get_metrics("missing", &registry).await.is_err());
+        let state = test_http_state(&registry, "session-1").await;
+        add_allowed_tool("session-1", "Read", &registry)
+            .await
+            .unwrap();
+
+        let Json(resp) = handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "a" }),
+            )),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.behavior, "allow");
+
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+
+        let metrics = get_metrics("session-1", &registry).await.unwrap();
+        assert_eq!(metrics.user_allowed, 1);
+        assert_eq!(metrics.auto_allowed, 1);
+        assert_eq!(
+            metrics.user_denied + metrics.auto_denied + metrics.timed_out,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ask_user_waits_for_answer() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+
+        let question = UserInputRequest {
+            question: "Which branch?".to_string(),
+            context: None,
+            agent_path: Vec::new(),
+        };
+        let asked = tokio::spawn(handle_user_input(
+            AxumState(state.clone()),
+            bearer_headers(&state.auth_token),
+            Json(question.clone()),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || {
+            emitter_for_wait
+                .names()
+                .contains(&"user-input-request:session-1".to_string())
+        })
+        .await;
+        let event = emitter.events.lock().unwrap()[0].1.clone();
+        assert_eq!(event["question"], "Which branch?");
+        let request_id = event["request_id"].as_str().unwrap().to_string();
+
+        assert!(matches!(
+            resolve_user_input("session-1", "missing", None, &registry).await,
+            Err(PermissionError::PromptNotFound(_))
+        ));
+        resolve_user_input(
+            "session-1",
+            &request_id,
+            Some("main".to_string()),
+            &registry,
+        )
+        .await
+        .unwrap();
+        let Json(reply) = asked.await.unwrap().unwrap();
+        assert_eq!(reply.answer.as_deref(), Some("main"));
+
+        // Wrong token is refused; Claude's own call to the tool needs no prompt
+        assert_eq!(
+            handle_user_input(AxumState(state.clone()), HeaderMap::new(), Json(question))
+                .await
+                .unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        let Json(resp) = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "mcp__opcode__ask_user",
+                serde_json::json!({ "question": "Which branch?" }),
+            )),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.behavior, "allow");
+        assert!(emitter.prompts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retried_tool_use_id_shares_the_prompt() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let request = test_request("Bash", serde_json::json!({ "command": "ls" }));
+        let request = || request.clone();
+
+        let first = tokio::spawn(handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(request()),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let retry = tokio::spawn(handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(request()),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(emitter.prompts().len(), 1);
+        assert_eq!(list_pending("session-1", &registry).await.len(), 1);
+
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(first.await.unwrap().unwrap().0.behavior, "allow");
+        assert_eq!(retry.await.unwrap().unwrap().0.behavior, "allow");
+        assert!(state.in_flight.lock().unwrap().is_empty());
+
+        // Once answered, the same ID prompts again
+        let again = tokio::spawn(handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(request()),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 2).await;
+        again.abort();
+        let _ = again.await;
+        assert!(state.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retry_taking_over_drops_the_abandoned_prompt() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let request = test_request("Bash", serde_json::json!({ "command": "ls" }));
+        let request = || request.clone();
+
+        let first = tokio::spawn(handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(request()),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        let abandoned = emitter.nth_prompt_id(0).await;
+        let retry = tokio::spawn(handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(request()),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // The first caller hangs up; the retry shows its own prompt and the
+        // first one is taken down
+        first.abort();
+        let _ = first.await;
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 2).await;
+        let taken_over = emitter.nth_prompt_id(1).await;
+        let pending: Vec<String> = list_pending("session-1", &registry)
+            .await
+            .into_iter()
+            .map(|p| p.prompt_id)
+            .collect();
+        assert_eq!(pending, vec![taken_over.clone()]);
+        let cancelled = emitter
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(name, _)| name == "permission-cancelled:session-1")
+            .map(|(_, payload)| payload["prompt_id"].clone())
+            .unwrap();
+        assert_eq!(cancelled, abandoned.as_str());
+
+        resolve_prompt("session-1", &taken_over, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(retry.await.unwrap().unwrap().0.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_request_cwd_reaches_event_and_rules() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let options = mcp_file_options("session-1", &registry).await.unwrap();
+        assert_eq!(options.cwd.as_deref(), Some(Path::new("/work/project")));
+        let config = build_mcp_config(1, "session-1", "node", Path::new("s.js"), &options).unwrap();
+        assert_eq!(
+            config.mcp_servers["opcode"].env["OPCODE_CWD"],
+            "/work/project"
+        );
+
+        let mut req = test_request("Read", serde_json::json!({ "file_path": "a.txt" }));
+        assert_eq!(
+            request_cwd(&req, Path::new("/work/project")),
+            Path::new("/work/project")
+        );
+        req.cwd = Some("packages/app".to_string());
+        assert_eq!(
+            request_cwd(&req, Path::new("/work/project")),
+            Path::new("/work/project/packages/app")
+        );
+        req.cwd = Some("/elsewhere".to_string());
+        assert_eq!(
+            request_cwd(&req, Path::new("/work/project")),
+            Path::new("/elsewhere")
+        );
+
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(AxumState(state), Json(req)));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || !emitter_for_wait.prompts().is_empty()).await;
+        assert_eq!(emitter.prompts()[0]["cwd"], "/elsewhere");
+        assert_eq!(emitter.prompts()[0]["risk_category"], "read_only");
+        handler.abort();
+    }
+
+    #[test]
+    fn test_emit_reports_failure() {
+        let payload = serde_json::json!({ "prompt_id": "prompt-1" });
+        assert!(!emit_session_event(
+            &FailingEmitter,
+            "permission-prompt",
+            "session-1",
+            &payload,
+            true
+        ));
+        let emitter = RecordingEmitter::default();
+        assert!(emit_session_event(
+            &emitter,
+            "permission-prompt",
+            "session-1",
+            &payload,
+            false
+        ));
+        assert_eq!(emitter.names(), ["permission-prompt:session-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_frontend_denies_immediately() {
+        let registry = PermissionServerRegistry::default();
+        let (shutdown_tx, _) = watch::channel(false);
+        let config = PermissionServerConfig::default();
+        registry.servers.lock().await.insert(
+            "session-1".to_string(),
+            PermissionServerEntry::new(
+                0,
+                "session-1",
+                shutdown_tx,
+                Arc::new(FailingEmitter),
+                &config,
+            ),
+        );
+        let state = test_http_state(&registry, "session-1").await;
+
+        let request = test_request("Bash", serde_json::json!({ "command": "ls" }));
+        let Json(resp) = tokio::time::timeout(
+            Duration::from_secs(2),
+            handle_permission_prompt(AxumState(state.clone()), Json(request)),
+        )
+        .await
+        .expect("denied without waiting for the timeout")
+        .unwrap();
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(resp.message.as_deref(), Some(FRONTEND_UNREACHABLE_MESSAGE));
+        assert!(state.pending.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_deferred_prompt_comes_back_to_the_same_request() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let request = test_request("Bash", serde_json::json!({ "command": "make" }));
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(request),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 1).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        defer_prompt(
+            "session-1",
+            &prompt_id,
+            Duration::from_millis(50),
+            &registry,
+        )
+        .await
+        .unwrap();
+        assert!(state.pending.lock().await.is_empty());
+        assert!(emitter
+            .names()
+            .contains(&"permission-deferred:session-1".to_string()));
+        assert!(matches!(
+            resolve_prompt("session-1", &prompt_id, allow(), &registry).await,
+            Err(PermissionError::PromptNotFound(_))
+        ));
+
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 2).await;
+        assert_eq!(emitter.prompts()[1]["prompt_id"], prompt_id.as_str());
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        let Json(resp) = handler.await.unwrap().unwrap();
+        assert_eq!(resp.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_inspect_mode_allows_and_shows_every_request() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        set_scope_rules(
+            &registry,
+            RuleScope::Session,
+            vec![PermissionRule {
+                tool: "Bash".to_string(),
+                field: None,
+                pattern: None,
+                agent_path_prefix: None,
+                condition: None,
+                action: RuleAction::Deny,
+                message: None,
+                origin: None,
+            }],
+        );
+        set_inspect_mode("session-1", true, &registry)
+            .await
+            .unwrap();
+
+        let input = serde_json::json!({ "command": "rm -rf build" });
+        let Json(resp) = handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(test_request("Bash", input.clone())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(resp.updated_input, Some(input.clone()));
+        assert!(state.pending.lock().await.is_empty());
+
+        let event = &emitter.prompts()[0];
+        assert_eq!(event["inspect"], true);
+        assert_eq!(event["input"], input);
+        let record = &recent_decisions(Some("session-1"), &registry)[0];
+        assert_eq!(record.source, DecisionSource::Inspect);
+        assert_eq!(serde_json::to_value(record).unwrap()["source"], "inspect");
+
+        // Back to enforcement: the rules apply again
+        set_inspect_mode("session-1", false, &registry)
+            .await
+            .unwrap();
+        let Json(resp) =
+            handle_permission_prompt(AxumState(state), Json(test_request("Bash", input)))
+                .await
+                .unwrap();
+        assert_eq!(resp.behavior, "deny");
+    }
+
+    #[tokio::test]
+    async fn test_stop_server_emits_stopped_event() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "pending-1").await;
+        rekey_server("pending-1", "session-1", &registry)
+            .await
+            .unwrap();
+
+        stop_server("session-1", &registry).await;
+        let events = emitter.events.lock().unwrap().clone();
+        let (_, stopped) = events
+            .iter()
+            .find(|(name, _)| name == "permission-server-stopped:session-1")
+            .expect("stopped event emitted");
+        assert_eq!(stopped["session_id"], "session-1");
+        assert_eq!(stopped["port"], 0);
+
+        // Stopping again finds nothing and emits nothing more
+        stop_server("session-1", &registry).await;
+        assert_eq!(emitter.events.lock().unwrap().len(), events.len());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_resolve_is_a_no_op() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+
+        let shown = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let prompt_id = emitter.nth_prompt_id(0).await;
+
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        let Json(resp) = shown.await.unwrap().unwrap();
+        assert_eq!(resp.behavior, "allow");
+
+        // The same answer again changes nothing; a different one is refused
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        let deny = PermissionResponse {
+            behavior: "deny".to_string(),
+            updated_input: None,
+            message: Some("too late".to_string()),
+        };
+        let err = resolve_prompt("session-1", &prompt_id, deny, &registry)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PermissionError::AlreadyResolved(ref id, DecisionSource::User) if *id == prompt_id
+        ));
+        assert!(matches!(
+            resolve_prompt("session-1", "missing", allow(), &registry).await,
+            Err(PermissionError::PromptNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_answer_after_timeout_is_an_error() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                prompt_timeout: Some(Duration::from_millis(30)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let state = test_http_state(&registry, "session-1").await;
+
+        let Json(resp) = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.behavior, "deny");
+
+        // An answer matching the timeout's is a no-op; a different one
+        // can't take effect
+        let prompt_id = emitter.nth_prompt_id(0).await;
+        resolve_prompt("session-1", &prompt_id, deny_with("No"), &registry)
+            .await
+            .unwrap();
+        let err = resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PermissionError::AlreadyResolved(ref id, DecisionSource::Timeout) if *id == prompt_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tool_timeout_overrides_session_default() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                prompt_timeout: Some(Duration::ZERO),
+                tool_timeouts: HashMap::from([("Read".to_string(), Duration::from_millis(30))]),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let state = test_http_state(&registry, "session-1").await;
+        let resp = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "a" }),
+            )),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.message.as_deref(), Some("Permission prompt timed out"));
+        assert_eq!(emitter.prompts()[0]["timeout_ms"], 30);
+
+        // Other tools keep the session's timeout, here none at all
+        let state = test_http_state(&registry, "session-1").await;
+        let waiting = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 2).await;
+        assert!(emitter.prompts()[1].get("timeout_ms").is_none());
+        let prompt_id = emitter.prompts()[1]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        assert_eq!(waiting.await.unwrap().unwrap().0.behavior, "allow");
+
+        // The MCP client waits for the longest one
+        set_tool_timeout("session-1", "Read", None, &registry)
+            .await
+            .unwrap();
+        set_timeout("session-1", Duration::from_secs(60), &registry)
+            .await
+            .unwrap();
+        set_tool_timeout(
+            "session-1",
+            "Bash",
+            Some(Duration::from_secs(600)),
+            &registry,
+        )
+        .await
+        .unwrap();
+        let options = mcp_file_options("session-1", &registry).await.unwrap();
+        assert_eq!(
+            options.client_timeout,
+            Some(Duration::from_secs(600) + CLIENT_TIMEOUT_MARGIN)
+        );
+        let servers = registry.servers.lock().await;
+        let entry = &servers["session-1"];
+        assert_eq!(
+            tool_timeout(&entry.prompt_timeout, &entry.tool_timeouts, "Read"),
+            Some(Duration::from_secs(60))
+        );
+
+        drop(servers);
+        assert!(set_tool_timeout("missing", "Bash", None, &registry)
+            .await
+            .is_err());
+        assert!(set_tool_timeout("session-1", "", None, &registry)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_real_server_answers_post_with_resolution_or_timeout() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = Arc::new(RecordingEmitter::default());
+        let port = start_server_with(
+            emitter.clone(),
+            "session-1",
+            PermissionServerConfig {
+                cwd: Some(PathBuf::from("/work/project")),
+                prompt_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+            &registry,
+        )
+        .await
+        .unwrap();
+        let (token, addr) = {
+            let servers = registry.servers.lock().await;
+            let entry = &servers["session-1"];
+            (
+                entry.auth_token.clone(),
+                std::net::SocketAddr::new(entry.host, port),
+            )
+        };
+        let url = format!("http://{}/permission-prompt", addr);
+        // Loopback only, whatever proxy the environment sets
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let post = |request: PermissionRequest| {
+            let client = client.clone();
+            let token = token.clone();
+            let url = url.clone();
+            async move {
+                client
+                    .post(url)
+                    .bearer_auth(token)
+                    .json(&request)
+                    .send()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let request = test_request("Bash", serde_json::json!({ "command": "ls" }));
+        let shown = tokio::spawn(post(request));
+        let prompt_id = emitter.nth_prompt_id(0).await;
+        resolve_prompt(
+            "session-1",
+            &prompt_id,
+            PermissionResponse {
+                behavior: "allow".to_string(),
+                updated_input: Some(serde_json::json!({ "command": "ls -a" })),
+                message: None,
+            },
+            &registry,
+        )
+        .await
+        .unwrap();
+        let reply = shown.await.unwrap();
+        assert_eq!(reply.status(), reqwest::StatusCode::OK);
+        let resp: PermissionResponse = reply.json().await.unwrap();
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(
+            resp.updated_input,
+            Some(serde_json::json!({ "command": "ls -a" }))
+        );
+
+        // Nobody answers the second one
+        let resp: PermissionResponse = post(test_request(
+            "Bash",
+            serde_json::json!({ "command": "rm -rf build" }),
+        ))
+        .await
+        .json()
+        .await
+        .unwrap();
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(resp.message.as_deref(), Some("Permission prompt timed out"));
+
+        let unauthorized = client
+            .post(&url)
+            .json(&test_request("Bash", serde_json::json!({})))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(unauthorized.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        stop_server("session-1", &registry).await;
+    }
+
+    #[test]
+    fn test_rate_limiter_refills_over_the_window() {
+        let limiter = RateLimiter::new(Some(RateLimit::per_window(
+            2,
+            Some(Duration::from_secs(10)),
+        )));
+        let start = Instant::now();
+        assert!(limiter.try_acquire(start));
+        assert!(limiter.try_acquire(start));
+        assert!(!limiter.try_acquire(start));
+        // One token comes back every 5 seconds, and no more than the burst
+        assert!(limiter.try_acquire(start + Duration::from_secs(5)));
+        assert!(!limiter.try_acquire(start + Duration::from_secs(6)));
+        assert!(limiter.try_acquire(start + Duration::from_secs(60)));
+        assert!(limiter.try_acquire(start + Duration::from_secs(60)));
+        assert!(!limiter.try_acquire(start + Duration::from_secs(60)));
+
+        limiter.set(None);
+        assert!(limiter.try_acquire(start));
+        assert!(RateLimiter::new(Some(RateLimit::per_window(0, None))).try_acquire(start));
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_the_rate_limit_are_denied_unseen() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                rate_limit: Some(RateLimit::per_window(2, Some(Duration::from_secs(3600)))),
+                ..Default::default()
+            },
+        )
+        .await;
+        add_allowed_tool("session-1", "Read", &registry)
+            .await
+            .unwrap();
+
+        let mut behaviors = Vec::new();
+        for _ in 0..3 {
+            let state = test_http_state(&registry, "session-1").await;
+            let resp = handle_permission_prompt(
+                AxumState(state),
+                Json(test_request(
+                    "Read",
+                    serde_json::json!({ "file_path": "a" }),
+                )),
+            )
+            .await
+            .unwrap()
+            .0;
+            behaviors.push((resp.behavior, resp.message));
+        }
+        assert_eq!(behaviors[0].0, "allow");
+        assert_eq!(behaviors[1].0, "allow");
+        assert_eq!(
+            behaviors[2],
+            ("deny".to_string(), Some(RATE_LIMITED_MESSAGE.to_string()))
+        );
+
+        let metrics = get_metrics("session-1", &registry).await.unwrap();
+        assert_eq!(metrics.auto_allowed, 2);
+        assert_eq!(metrics.auto_denied, 0);
+        assert_eq!(metrics.rate_limited, 1);
+        assert!(emitter.prompts().is_empty());
+        assert_eq!(registry.decisions.recent(Some("session-1")).len(), 2);
+
+        // Lifting the limit lets requests through again
+        set_rate_limit("session-1", None, &registry).await.unwrap();
+        let state = test_http_state(&registry, "session-1").await;
+        let resp = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request(
+                "Read",
+                serde_json::json!({ "file_path": "a" }),
+            )),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_allow_and_deny_prompt_build_well_formed_responses() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let input = serde_json::json!({ "command": "rm -rf build" });
+        let ask = |input: serde_json::Value| {
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                let state = test_http_state(&registry, "session-1").await;
+                handle_permission_prompt(AxumState(state), Json(test_request("Bash", input))).await
+            })
+        };
+
+        let handler = ask(input.clone());
+        let prompt_id = emitter.nth_prompt_id(0).await;
+        deny_prompt(
+            "session-1",
+            &prompt_id,
+            Some("  Use `make clean` instead "),
+            &registry,
+        )
+        .await
+        .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(resp.message.as_deref(), Some("Use `make clean` instead"));
+        assert_eq!(resp.updated_input, None);
+
+        let handler = ask(input.clone());
+        let prompt_id = emitter.nth_prompt_id(1).await;
+        deny_prompt("session-1", &prompt_id, Some(" "), &registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.message.as_deref(), Some(USER_DENY_MESSAGE));
+
+        // Without an edit the tool runs with the input that was shown
+        let handler = ask(input.clone());
+        let prompt_id = emitter.nth_prompt_id(2).await;
+        allow_prompt("session-1", &prompt_id, None, &registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(resp.updated_input, Some(input.clone()));
+        assert_eq!(resp.message, None);
+
+        let handler = ask(input.clone());
+        let prompt_id = emitter.nth_prompt_id(3).await;
+        assert!(matches!(
+            allow_prompt(
+                "session-1",
+                &prompt_id,
+                Some(serde_json::json!("ls")),
+                &registry
+            )
+            .await,
+            Err(PermissionError::InvalidUpdatedInput(_))
+        ));
+        let edited = serde_json::json!({ "command": "rm -rf build/tmp" });
+        allow_prompt("session-1", &prompt_id, Some(edited.clone()), &registry)
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.await.unwrap().unwrap().0.updated_input,
+            Some(edited)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_answers_are_rejected_and_leave_the_prompt_pending() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        ));
+        let prompt_id = emitter.nth_prompt_id(0).await;
+
+        let answer = |behavior: &str, updated_input, message: Option<&str>| PermissionResponse {
+            behavior: behavior.to_string(),
+            updated_input,
+            message: message.map(str::to_string),
+        };
+        for behavior in ["Allow", "allow ", "approve", ""] {
+            assert!(matches!(
+                resolve_prompt(
+                    "session-1",
+                    &prompt_id,
+                    answer(behavior, Some(serde_json::json!({})), None),
+                    &registry,
+                )
+                .await,
+                Err(PermissionError::InvalidBehavior(b)) if b == behavior
+            ));
+        }
+        assert!(matches!(
+            resolve_prompt(
+                "session-1",
+                &prompt_id,
+                answer("deny", Some(serde_json::json!({ "command": "ls" })), None),
+                &registry,
+            )
+            .await,
+            Err(PermissionError::Invalid(_))
+        ));
+        assert!(matches!(
+            resolve_batch(
+                "session-1",
+                &prompt_id,
+                vec![answer("ALLOW", None, None)],
+                &registry
+            )
+            .await,
+            Err(PermissionError::InvalidBehavior(_))
+        ));
+        assert_eq!(list_pending("session-1", &registry).await.len(), 1);
+
+        // A deny always tells Claude something
+        resolve_prompt(
+            "session-1",
+            &prompt_id,
+            answer("deny", None, None),
+            &registry,
+        )
+        .await
+        .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(resp.message.as_deref(), Some(USER_DENY_MESSAGE));
+    }
+
+    #[tokio::test]
+    async fn test_os_notifications_only_when_enabled() {
+        let registry = PermissionServerRegistry::default();
+        set_notify_window(&registry, Duration::ZERO);
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let registry = &registry;
+        let ask = move || {
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                let state = test_http_state(&registry, "session-1").await;
+                let input = serde_json::json!({ "command": "ls" });
+                handle_permission_prompt(AxumState(state), Json(test_request("Bash", input))).await
+            })
+        };
+
+        let handler = ask();
+        let prompt_id = emitter.nth_prompt_id(0).await;
+        assert!(emitter.os_notifications.lock().unwrap().is_empty());
+        resolve_prompt("session-1", &prompt_id, allow(), registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+
+        set_os_notifications(registry, true);
+        let handler = ask();
+        let prompt_id = emitter.nth_prompt_id(1).await;
+        wait_until(|| !emitter.os_notifications.lock().unwrap().is_empty()).await;
+        let shown = emitter.os_notifications.lock().unwrap().clone();
+        assert_eq!(shown.len(), 1);
+        assert_eq!(shown[0].prompt_id, prompt_id);
+        assert_eq!(
+            shown[0].body(),
+            "1 permission request pending (Bash in session session-1)"
+        );
+        resolve_prompt("session-1", &prompt_id, allow(), registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+    }
+
+    #[tokio::test]
+    async fn test_active_server_count_and_total_pending() {
+        let registry = PermissionServerRegistry::default();
+        assert_eq!(active_server_count(&registry).await, 0);
+        assert_eq!(total_pending(&registry).await, 0);
+
+        let first = insert_test_entry(&registry, "session-1").await;
+        let second = insert_test_entry(&registry, "session-2").await;
+        let mut handlers = Vec::new();
+        for (session_id, command) in [
+            ("session-1", "ls"),
+            ("session-1", "pwd"),
+            ("session-2", "ls"),
+        ] {
+            let state = test_http_state(&registry, session_id).await;
+            handlers.push(tokio::spawn(handle_permission_prompt(
+                AxumState(state),
+                Json(test_request(
+                    "Bash",
+                    serde_json::json!({ "command": command }),
+                )),
+            )));
+        }
+        let first_ids = [first.nth_prompt_id(0).await, first.nth_prompt_id(1).await];
+        second.nth_prompt_id(0).await;
+        assert_eq!(active_server_count(&registry).await, 2);
+        assert_eq!(total_pending(&registry).await, 3);
+
+        for prompt_id in &first_ids {
+            resolve_prompt("session-1", prompt_id, allow(), &registry)
+                .await
+                .unwrap();
+        }
+        assert_eq!(total_pending(&registry).await, 1);
+        stop_server("session-2", &registry).await;
+        assert_eq!(active_server_count(&registry).await, 1);
+        assert_eq!(total_pending(&registry).await, 0);
+        let mut behaviors = Vec::new();
+        for handler in handlers {
+            behaviors.push(handler.await.unwrap().unwrap().0.behavior);
+        }
+        assert_eq!(behaviors, ["allow", "allow", "deny"]);
+    }
+}
@@ -0,0 +1,719 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Where a rule came from. Variants are declared in ascending precedence, so
+/// the derived `Ord` puts `Policy` above `Session` above `Project` above
+/// `Global`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleScope {
+    Global,
+    Project,
+    Session,
+    Policy,
+}
+
+/// What happens when a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+/// Which rule wins when rules with different actions match one request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// The first match in precedence order, as `RuleSet::evaluate` picks.
+    #[default]
+    ScopeOrder,
+    /// Any matching deny beats every allow.
+    DenyWins,
+}
+
+/// What to do with a request that no rule matches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DefaultOutcome {
+    /// Ask the user (the historical behavior).
+    #[default]
+    Prompt,
+    AllowAll,
+    /// Closed-world policy: anything not explicitly allowed is denied.
+    DenyAll,
+}
+
+/// A scope's fallback for unmatched requests.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScopeDefault {
+    pub outcome: DefaultOutcome,
+    /// Message returned to Claude when the outcome denies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// The scope and file a loaded rule originated from, kept for display and
+/// audit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleOrigin {
+    pub scope: RuleScope,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+}
+
+/// Session history a conditional rule must find before it applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleCondition {
+    /// The user manually allowed an earlier request in this session that
+    /// the rule also matches.
+    PriorUserAllow,
+}
+
+/// A request the user manually allowed earlier in the session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriorAllow {
+    pub tool_name: String,
+    pub input: serde_json::Value,
+}
+
+/// The parts of a permission request that rules match against.
+#[derive(Debug, Clone, Copy)]
+pub struct RuleTarget<'a> {
+    pub tool_name: &'a str,
+    pub input: &'a serde_json::Value,
+    /// Agent hierarchy the request came from, outermost first.
+    pub agent_path: &'a [String],
+    /// The session's manual allows, consulted by conditional rules only.
+    pub prior_allows: &'a [PriorAllow],
+}
+
+impl<'a> RuleTarget<'a> {
+    pub fn new(tool_name: &'a str, input: &'a serde_json::Value) -> Self {
+        Self {
+            tool_name,
+            input,
+            agent_path: &[],
+            prior_allows: &[],
+        }
+    }
+}
+
+/// A single auto-approval rule.
+///
+/// `tool` is a glob over the tool name. When `pattern` is set it is a glob
+/// matched against the string value of `field` in the tool input, or against
+/// any top-level string value when `field` is omitted. `agent_path_prefix`
+/// restricts the rule to requests from that branch of the agent hierarchy.
+///
+/// A rule with a `condition` is a conditional rule: besides matching the
+/// request it only applies once the session history satisfies the condition.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PermissionRule {
+    pub tool: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_path_prefix: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<RuleCondition>,
+    pub action: RuleAction,
+    /// Message returned to Claude when the rule denies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Filled in when the rule is loaded; rule files don't need to set it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<RuleOrigin>,
+}
+
+impl PermissionRule {
+    /// Whether this rule applies to the given tool invocation, including its
+    /// condition if it has one.
+    pub fn matches(&self, target: &RuleTarget) -> bool {
+        if !self.matches_request(target) {
+            return false;
+        }
+        match self.condition {
+            None => true,
+            Some(RuleCondition::PriorUserAllow) => target.prior_allows.iter().any(|prior| {
+                self.matches_request(&RuleTarget {
+                    tool_name: &prior.tool_name,
+                    input: &prior.input,
+                    ..*target
+                })
+            }),
+        }
+    }
+
+    pub fn is_conditional(&self) -> bool {
+        self.condition.is_some()
+    }
+
+    /// Whether the tool, agent path and input match, ignoring the condition.
+    fn matches_request(&self, target: &RuleTarget) -> bool {
+        if !glob_matches(&self.tool, target.tool_name) {
+            return false;
+        }
+
+        if let Some(prefix) = &self.agent_path_prefix {
+            if !target.agent_path.starts_with(prefix) {
+                return false;
+            }
+        }
+        let input = target.input;
+
+        let pattern = match &self.pattern {
+            Some(p) => p,
+            None => return true,
+        };
+
+        match &self.field {
+            Some(field) => input
+                .get(field)
+                .and_then(|v| v.as_str())
+                .map(|v| glob_matches(pattern, v))
+                .unwrap_or(false),
+            None => input
+                .as_object()
+                .map(|obj| {
+                    obj.values()
+                        .filter_map(|v| v.as_str())
+                        .any(|v| glob_matches(pattern, v))
+                })
+                .unwrap_or(false),
+        }
+    }
+
+    /// Rules with the same matcher target the same requests; when they come
+    /// from different scopes only the higher-precedence one survives a merge.
+    fn same_matcher(&self, other: &PermissionRule) -> bool {
+        self.tool == other.tool
+            && self.field == other.field
+            && self.pattern == other.pattern
+            && self.agent_path_prefix == other.agent_path_prefix
+            && self.condition == other.condition
+    }
+
+    fn scope(&self) -> RuleScope {
+        self.origin
+            .as_ref()
+            .map(|o| o.scope)
+            .unwrap_or(RuleScope::Global)
+    }
+}
+
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    glob::Pattern::new(pattern)
+        .map(|p| p.matches(value))
+        .unwrap_or(false)
+}
+
+/// Contents of a rules file: either `{ "rules": [...] }` or a bare array.
+/// TOML files can only use the first form, as a `[[rules]]` array.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RulesFile {
+    Wrapped { rules: Vec<PermissionRule> },
+    Bare(Vec<PermissionRule>),
+}
+
+/// An ordered, merged set of rules. Rules are sorted by scope precedence
+/// (highest first) and keep their file order within a scope, so the first
+/// matching rule is the one that wins.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuleSet {
+    pub rules: Vec<PermissionRule>,
+    /// Lower-precedence rules dropped because a higher scope defined the same
+    /// matcher. Kept so the UI can explain why a rule has no effect.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shadowed: Vec<PermissionRule>,
+}
+
+impl RuleSet {
+    /// Return the winning rule for a request, if any.
+    pub fn evaluate(&self, target: &RuleTarget) -> Option<&PermissionRule> {
+        self.rules.iter().find(|r| r.matches(target))
+    }
+
+    /// Like `evaluate`, but checks every rule so conflicts can be reported,
+    /// and picks the winner according to `policy`.
+    pub fn evaluate_with_conflicts(
+        &self,
+        target: &RuleTarget,
+        policy: ConflictPolicy,
+    ) -> Option<RuleEvaluation<'_>> {
+        let matching: Vec<&PermissionRule> =
+            self.rules.iter().filter(|r| r.matches(target)).collect();
+        let winner = match policy {
+            ConflictPolicy::ScopeOrder => matching.first(),
+            ConflictPolicy::DenyWins => matching
+                .iter()
+                .find(|r| r.action == RuleAction::Deny)
+                .or(matching.first()),
+        }
+        .copied()?;
+        let overridden = matching
+            .into_iter()
+            .filter(|r| r.action != winner.action)
+            .collect();
+        Some(RuleEvaluation { winner, overridden })
+    }
+
+    /// Whether any rule depends on session history.
+    pub fn has_conditional_rules(&self) -> bool {
+        self.rules.iter().any(PermissionRule::is_conditional)
+    }
+}
+
+/// Exact Bash commands that are allowed, stored as SHA-256 hashes of their
+/// canonical form. Unlike pattern rules a hash only ever matches the one
+/// command it was made from, and the stored list reveals nothing about it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CommandHashAllowlist {
+    pub hashes: BTreeSet<String>,
+}
+
+impl CommandHashAllowlist {
+    /// Only surrounding whitespace and CRLF line endings are normalized;
+    /// anything else (inner spacing, quoting) makes a different command.
+    pub fn canonicalize(command: &str) -> String {
+        command.trim().replace("\r\n", "\n")
+    }
+
+    /// Hex SHA-256 of the canonical command.
+    pub fn hash_command(command: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(Self::canonicalize(command).as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Allow a command, returning its hash.
+    pub fn add(&mut self, command: &str) -> String {
+        let hash = Self::hash_command(command);
+        self.hashes.insert(hash.clone());
+        hash
+    }
+
+    /// Stop allowing a command. Returns whether it was allowed.
+    pub fn remove(&mut self, command: &str) -> bool {
+        self.hashes.remove(&Self::hash_command(command))
+    }
+
+    pub fn contains(&self, command: &str) -> bool {
+        !self.hashes.is_empty() && self.hashes.contains(&Self::hash_command(command))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+}
+
+/// The rule that answered a request, plus any matching rules that wanted
+/// the opposite action.
+#[derive(Debug, Clone)]
+pub struct RuleEvaluation<'a> {
+    pub winner: &'a PermissionRule,
+    /// Empty unless the matching rules disagree.
+    pub overridden: Vec<&'a PermissionRule>,
+}
+
+/// The in-memory rules of every scope, before merging. Snapshotting and
+/// restoring this gives tests (and config export/import) a known rule state
+/// without touching the rule files on disk.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RuleEngineState {
+    #[serde(default)]
+    pub scopes: BTreeMap<RuleScope, Vec<PermissionRule>>,
+    /// Fallback per scope; the highest scope that sets one wins.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub defaults: BTreeMap<RuleScope, ScopeDefault>,
+    /// How conflicting matches are settled.
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+    /// Exact Bash commands allowed when no rule matches them.
+    #[serde(default, skip_serializing_if = "CommandHashAllowlist::is_empty")]
+    pub command_allowlist: CommandHashAllowlist,
+}
+
+impl RuleEngineState {
+    /// Replace one scope's rules, tagging each with that scope. Any source
+    /// path already recorded on a rule is kept.
+    pub fn set_scope(&mut self, scope: RuleScope, rules: Vec<PermissionRule>) {
+        let rules: Vec<PermissionRule> = rules
+            .into_iter()
+            .map(|mut rule| {
+                let source = rule.origin.take().and_then(|o| o.source);
+                rule.origin = Some(RuleOrigin { scope, source });
+                rule
+            })
+            .collect();
+        if rules.is_empty() {
+            self.scopes.remove(&scope);
+        } else {
+            self.scopes.insert(scope, rules);
+        }
+    }
+
+    /// Set (or with `None`, clear) one scope's fallback for unmatched
+    /// requests.
+    pub fn set_scope_default(&mut self, scope: RuleScope, default: Option<ScopeDefault>) {
+        match default {
+            Some(default) => {
+                self.defaults.insert(scope, default);
+            }
+            None => {
+                self.defaults.remove(&scope);
+            }
+        }
+    }
+
+    /// The fallback in effect: the highest-precedence scope's setting, or
+    /// `Prompt` when no scope sets one.
+    pub fn default_outcome(&self) -> ScopeDefault {
+        self.defaults
+            .values()
+            .next_back()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// All scopes merged into one ordered set.
+    pub fn merged(&self) -> RuleSet {
+        merge_rules(self.scopes.values().flatten().cloned().collect())
+    }
+}
+
+/// Load the rules in a single file, tagging each with its origin. Files
+/// ending in `.toml` are read as TOML, everything else as JSON.
+pub fn load_rules_file(path: &Path, scope: RuleScope) -> Result<Vec<PermissionRule>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read rules file {:?}: {}", path, e))?;
+    let is_toml = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+    let parsed: RulesFile = if is_toml {
+        toml::from_str(&content).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str(&content).map_err(|e| e.to_string())
+    }
+    .map_err(|e| format!("Failed to parse rules file {:?}: {}", path, e))?;
+
+    let rules = match parsed {
+        RulesFile::Wrapped { rules } | RulesFile::Bare(rules) => rules,
+    };
+
+    Ok(rules
+        .into_iter()
+        .map(|mut rule| {
+            rule.origin = Some(RuleOrigin {
+                scope,
+                source: Some(path.to_path_buf()),
+            });
+            rule
+        })
+        .collect())
+}
+
+/// Rules pinned to one session by a policy file. They are checked before
+/// the shared rule engine, first match in file order, and carry the
+/// `Policy` scope.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PermissionPolicy {
+    pub rules: Vec<PermissionRule>,
+    /// File the policy was loaded from, re-read on reload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+}
+
+impl PermissionPolicy {
+    pub fn evaluate(&self, target: &RuleTarget) -> Option<&PermissionRule> {
+        self.rules.iter().find(|r| r.matches(target))
+    }
+
+    pub fn has_conditional_rules(&self) -> bool {
+        self.rules.iter().any(PermissionRule::is_conditional)
+    }
+}
+
+/// Load a session policy file in any format `load_rules_file` reads.
+pub fn load_policy(path: &Path) -> Result<PermissionPolicy, String> {
+    Ok(PermissionPolicy {
+        rules: load_rules_file(path, RuleScope::Policy)?,
+        source: Some(path.to_path_buf()),
+    })
+}
+
+/// Merge already-tagged rules into a single ordered set, resolving matcher
+/// conflicts in favour of the higher scope.
+pub fn merge_rules(rules: Vec<PermissionRule>) -> RuleSet {
+    let mut ordered = rules;
+    // Stable sort keeps file order within a scope.
+    ordered.sort_by_key(|rule| std::cmp::Reverse(rule.scope()));
+
+    let mut merged = RuleSet::default();
+    for rule in ordered {
+        let shadowed = merged
+            .rules
+            .iter()
+            .any(|kept| kept.scope() > rule.scope() && kept.same_matcher(&rule));
+        if shadowed {
+            merged.shadowed.push(rule);
+        } else {
+            merged.rules.push(rule);
+        }
+    }
+    merged
+}
+
+/// Load every rules source and merge them with policy > session > project >
+/// global precedence. Missing files are skipped; unreadable or malformed
+/// ones are logged and skipped so one bad file doesn't disable the rest.
+pub fn load_and_merge_rules(sources: &[(PathBuf, RuleScope)]) -> RuleSet {
+    let mut all = Vec::new();
+    for (path, scope) in sources {
+        if !path.exists() {
+            continue;
+        }
+        match load_rules_file(path, *scope) {
+            Ok(rules) => all.extend(rules),
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+    merge_rules(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_rules(dir: &TempDir, name: &str, json: serde_json::Value) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, json.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_higher_scope_wins_conflicting_rule() {
+        let dir = TempDir::new().unwrap();
+        let global = write_rules(
+            &dir,
+            "global.json",
+            serde_json::json!({ "rules": [
+                { "tool": "Bash", "field": "command", "pattern": "git *", "action": "allow" },
+                { "tool": "Read", "action": "allow" }
+            ]}),
+        );
+        let policy = write_rules(
+            &dir,
+            "policy.json",
+            serde_json::json!([
+                { "tool": "Bash", "field": "command", "pattern": "git *", "action": "deny",
+                  "message": "git is managed by policy" }
+            ]),
+        );
+
+        let set = load_and_merge_rules(&[
+            (global.clone(), RuleScope::Global),
+            (policy.clone(), RuleScope::Policy),
+            (dir.path().join("missing.json"), RuleScope::Project),
+        ]);
+
+        let input = serde_json::json!({ "command": "git push" });
+        let winner = set.evaluate(&RuleTarget::new("Bash", &input)).unwrap();
+        assert_eq!(winner.action, RuleAction::Deny);
+        let origin = winner.origin.as_ref().unwrap();
+        assert_eq!(origin.scope, RuleScope::Policy);
+        assert_eq!(origin.source.as_deref(), Some(policy.as_path()));
+
+        // The global rule is shadowed, not silently lost
+        assert_eq!(set.shadowed.len(), 1);
+        assert_eq!(
+            set.shadowed[0].origin.as_ref().unwrap().scope,
+            RuleScope::Global
+        );
+
+        // Non-conflicting rules from lower scopes still apply
+        let input = serde_json::json!({ "file_path": "/tmp/a" });
+        let read = set.evaluate(&RuleTarget::new("Read", &input)).unwrap();
+        assert_eq!(read.action, RuleAction::Allow);
+    }
+
+    #[test]
+    fn test_merge_orders_by_precedence() {
+        let rule = |scope: RuleScope, action: RuleAction| PermissionRule {
+            tool: "*".to_string(),
+            field: None,
+            pattern: Some(format!("{:?}", scope)),
+            agent_path_prefix: None,
+            condition: None,
+            action,
+            message: None,
+            origin: Some(RuleOrigin {
+                scope,
+                source: None,
+            }),
+        };
+
+        let set = merge_rules(vec![
+            rule(RuleScope::Global, RuleAction::Allow),
+            rule(RuleScope::Session, RuleAction::Deny),
+            rule(RuleScope::Project, RuleAction::Allow),
+            rule(RuleScope::Policy, RuleAction::Deny),
+        ]);
+
+        let scopes: Vec<RuleScope> = set
+            .rules
+            .iter()
+            .map(|r| r.origin.as_ref().unwrap().scope)
+            .collect();
+        assert_eq!(
+            scopes,
+            vec![
+                RuleScope::Policy,
+                RuleScope::Session,
+                RuleScope::Project,
+                RuleScope::Global
+            ]
+        );
+        assert!(set.shadowed.is_empty());
+    }
+
+    #[test]
+    fn test_agent_path_prefix_matches_nested_branch_only() {
+        let rule = PermissionRule {
+            tool: "Read".to_string(),
+            field: None,
+            pattern: None,
+            agent_path_prefix: Some(vec!["main".to_string(), "research-subagent".to_string()]),
+            condition: None,
+            action: RuleAction::Allow,
+            message: None,
+            origin: None,
+        };
+        let input = serde_json::json!({ "file_path": "/tmp/a" });
+        fn path(p: &[&str]) -> Vec<String> {
+            p.iter().map(|s| s.to_string()).collect()
+        }
+
+        let nested = path(&["main", "research-subagent", "summarizer"]);
+        let target = RuleTarget {
+            agent_path: &nested,
+            ..RuleTarget::new("Read", &input)
+        };
+        assert!(rule.matches(&target));
+
+        let other_branch = path(&["main", "writer-subagent"]);
+        let target = RuleTarget {
+            agent_path: &other_branch,
+            ..RuleTarget::new("Read", &input)
+        };
+        assert!(!rule.matches(&target));
+
+        // Requests with no known hierarchy don't match scoped rules
+        assert!(!rule.matches(&RuleTarget::new("Read", &input)));
+    }
+
+    #[test]
+    fn test_conflicting_rules_are_reported_with_winner() {
+        let rule = |scope: RuleScope, pattern: &str, action: RuleAction| PermissionRule {
+            tool: "Bash".to_string(),
+            field: Some("command".to_string()),
+            pattern: Some(pattern.to_string()),
+            agent_path_prefix: None,
+            condition: None,
+            action,
+            message: None,
+            origin: Some(RuleOrigin {
+                scope,
+                source: None,
+            }),
+        };
+        let set = merge_rules(vec![
+            rule(RuleScope::Global, "git *", RuleAction::Deny),
+            rule(RuleScope::Project, "git status*", RuleAction::Allow),
+        ]);
+        let input = serde_json::json!({ "command": "git status" });
+        let target = RuleTarget::new("Bash", &input);
+
+        // Scope order: the project allow outranks the global deny
+        let eval = set
+            .evaluate_with_conflicts(&target, ConflictPolicy::ScopeOrder)
+            .unwrap();
+        assert_eq!(eval.winner.action, RuleAction::Allow);
+        assert_eq!(eval.overridden.len(), 1);
+        assert_eq!(eval.overridden[0].action, RuleAction::Deny);
+        assert_eq!(set.evaluate(&target), Some(eval.winner));
+
+        // Deny-wins: the global deny beats it regardless of scope
+        let eval = set
+            .evaluate_with_conflicts(&target, ConflictPolicy::DenyWins)
+            .unwrap();
+        assert_eq!(eval.winner.action, RuleAction::Deny);
+        assert_eq!(eval.overridden[0].action, RuleAction::Allow);
+
+        // Rules that agree aren't a conflict
+        let input = serde_json::json!({ "command": "git push" });
+        let eval = set
+            .evaluate_with_conflicts(&RuleTarget::new("Bash", &input), ConflictPolicy::DenyWins)
+            .unwrap();
+        assert!(eval.overridden.is_empty());
+    }
+
+    #[test]
+    fn test_policy_loads_from_toml() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("policy.toml");
+        std::fs::write(
+            &path,
+            r#"
+[[rules]]
+tool = "Bash"
+field = "command"
+pattern = "*rm -rf*"
+action = "deny"
+message = "No recursive deletes"
+
+[[rules]]
+tool = "Read"
+field = "file_path"
+pattern = "/home/me/project/*"
+action = "allow"
+"#,
+        )
+        .unwrap();
+
+        let policy = load_policy(&path).unwrap();
+        assert_eq!(policy.source.as_deref(), Some(path.as_path()));
+        assert_eq!(
+            policy.rules[0].origin.as_ref().unwrap().scope,
+            RuleScope::Policy
+        );
+
+        let command = serde_json::json!({ "command": "cd /tmp && rm -rf build" });
+        let rule = policy.evaluate(&RuleTarget::new("Bash", &command)).unwrap();
+        assert_eq!(rule.action, RuleAction::Deny);
+
+        let inside = serde_json::json!({ "file_path": "/home/me/project/src/main.rs" });
+        let outside = serde_json::json!({ "file_path": "/home/me/other/notes.md" });
+        assert_eq!(
+            policy
+                .evaluate(&RuleTarget::new("Read", &inside))
+                .unwrap()
+                .action,
+            RuleAction::Allow
+        );
+        assert!(policy
+            .evaluate(&RuleTarget::new("Read", &outside))
+            .is_none());
+
+        std::fs::write(&path, "[[rules]]\ntool = \"Read\"\n").unwrap();
+        assert!(load_policy(&path).unwrap_err().contains("Failed to parse"));
+    }
+}
@@ -0,0 +1,159 @@
+//! In-memory stand-ins for the app and Claude Code, shared by the tests of
+//! this module and its submodules. Sessions are registered without binding a
+//! server and driven by calling the handlers directly, with events recorded
+//! by `RecordingEmitter` instead of reaching a window.
+
+use super::*;
+
+/// Records every emitted event instead of sending it anywhere.
+#[derive(Default)]
+pub(super) struct RecordingEmitter {
+    pub(super) events: std::sync::Mutex<Vec<(String, serde_json::Value)>>,
+    /// OS notifications, as if the window were never focused.
+    pub(super) os_notifications: std::sync::Mutex<Vec<PromptNotifyEvent>>,
+}
+
+impl RecordingEmitter {
+    pub(super) fn names(&self) -> Vec<String> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Payloads of the session-scoped prompt events, in order.
+    pub(super) fn prompts(&self) -> Vec<serde_json::Value> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name.starts_with("permission-prompt:"))
+            .map(|(_, payload)| payload.clone())
+            .collect()
+    }
+
+    /// Wait for the session's `n`th prompt event (from 0) and return its
+    /// prompt ID.
+    pub(super) async fn nth_prompt_id(&self, n: usize) -> String {
+        wait_until(|| self.prompts().len() > n).await;
+        self.prompts()[n]["prompt_id"].as_str().unwrap().to_string()
+    }
+}
+
+impl PermissionEmitter for RecordingEmitter {
+    fn emit_json(&self, event: &str, payload: serde_json::Value) -> Result<(), String> {
+        self.events
+            .lock()
+            .unwrap()
+            .push((event.to_string(), payload));
+        Ok(())
+    }
+
+    fn notify_os(&self, notification: &PromptNotifyEvent) -> bool {
+        self.os_notifications
+            .lock()
+            .unwrap()
+            .push(notification.clone());
+        true
+    }
+}
+
+/// An event bus that's gone, as after the window closed.
+pub(super) struct FailingEmitter;
+
+impl PermissionEmitter for FailingEmitter {
+    fn emit_json(&self, _: &str, _: serde_json::Value) -> Result<(), String> {
+        Err("window closed".to_string())
+    }
+}
+
+/// Register a bare entry for `session_id` without binding a real server,
+/// returning the emitter that records its events.
+pub(super) async fn insert_test_entry(
+    registry: &PermissionServerRegistry,
+    session_id: &str,
+) -> Arc<RecordingEmitter> {
+    insert_test_entry_with(
+        registry,
+        session_id,
+        PermissionServerConfig {
+            cwd: Some(PathBuf::from("/work/project")),
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+pub(super) async fn insert_test_entry_with(
+    registry: &PermissionServerRegistry,
+    session_id: &str,
+    config: PermissionServerConfig,
+) -> Arc<RecordingEmitter> {
+    let emitter = Arc::new(RecordingEmitter::default());
+    let (shutdown_tx, _) = watch::channel(false);
+    registry.servers.lock().await.insert(
+        session_id.to_string(),
+        PermissionServerEntry::new(0, session_id, shutdown_tx, emitter.clone(), &config),
+    );
+    emitter
+}
+
+/// A request with its own `tool_use_id`, as Claude Code sends them.
+pub(super) fn test_request(tool_name: &str, input: serde_json::Value) -> PermissionRequest {
+    PermissionRequest {
+        tool_use_id: format!("toolu_{}", Uuid::new_v4().simple()),
+        tool_name: tool_name.to_string(),
+        input,
+        context: None,
+        agent_path: Vec::new(),
+        cwd: None,
+    }
+}
+
+pub(super) fn bearer_headers(token: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        format!("Bearer {}", token).parse().unwrap(),
+    );
+    headers
+}
+
+/// Handler state for a session registered with `insert_test_entry`.
+pub(super) async fn test_http_state(
+    registry: &PermissionServerRegistry,
+    session_id: &str,
+) -> HttpState {
+    let servers = registry.servers.lock().await;
+    HttpState::new(&servers[session_id], registry)
+}
+
+/// Poll until `cond` holds, failing the test after a couple of seconds.
+pub(super) async fn wait_until(mut cond: impl FnMut() -> bool) {
+    for _ in 0..200 {
+        if cond() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not met in time");
+}
+
+pub(super) fn allow() -> PermissionResponse {
+    PermissionResponse {
+        behavior: "allow".to_string(),
+        updated_input: None,
+        message: None,
+    }
+}
+
+/// An executable that's always there, standing in for Node.js where only
+/// its path ends up in the config.
+pub(super) fn test_node_path() -> String {
+    std::env::current_exe()
+        .unwrap()
+        .to_string_lossy()
+        .to_string()
+}
@@ -0,0 +1,87 @@
+//! Opt-in HTTPS for the permission server. Each server gets its own
+//! self-signed certificate for localhost; the MCP script trusts it by its
+//! SHA-256 fingerprint (`PERMISSION_SERVER_CERT_SHA256`) rather than any CA.
+
+use super::PermissionError;
+use axum_server::tls_rustls::RustlsConfig;
+use sha2::{Digest, Sha256};
+
+/// Names the certificate is valid for: every address the script may dial.
+const SUBJECT_ALT_NAMES: &[&str] = &["localhost", "127.0.0.1", "::1"];
+
+/// A server's certificate and private key. Lives on the registry entry, so
+/// the key is dropped together with the server.
+pub struct ServerTls {
+    pub cert_pem: String,
+    pub key_pem: String,
+    /// The certificate's fingerprint in the `AB:CD:...` form the script
+    /// compares against.
+    pub fingerprint: String,
+}
+
+impl std::fmt::Debug for ServerTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerTls")
+            .field("fingerprint", &self.fingerprint)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ServerTls {
+    /// Generate a fresh self-signed certificate and key.
+    pub fn generate() -> Result<Self, PermissionError> {
+        let names: Vec<String> = SUBJECT_ALT_NAMES.iter().map(|n| n.to_string()).collect();
+        let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names)
+            .map_err(|e| {
+                PermissionError::Invalid(format!("Failed to generate TLS certificate: {}", e))
+            })?;
+        Ok(Self {
+            fingerprint: fingerprint(cert.der()),
+            cert_pem: cert.pem(),
+            key_pem: key_pair.serialize_pem(),
+        })
+    }
+
+    /// The rustls server config serving this certificate.
+    pub async fn rustls_config(&self) -> Result<RustlsConfig, PermissionError> {
+        RustlsConfig::from_pem(
+            self.cert_pem.clone().into_bytes(),
+            self.key_pem.clone().into_bytes(),
+        )
+        .await
+        .map_err(PermissionError::Io)
+    }
+}
+
+/// SHA-256 of a DER certificate as uppercase, colon-separated hex, matching
+/// Node's `fingerprint256`.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_format() {
+        let print = fingerprint(b"");
+        assert_eq!(print.len(), 32 * 3 - 1);
+        assert!(print.starts_with("E3:B0:C4:42"));
+        assert!(print.ends_with("52:B8:55"));
+    }
+
+    #[tokio::test]
+    async fn test_generated_certificate_loads() {
+        let first = ServerTls::generate().unwrap();
+        let second = ServerTls::generate().unwrap();
+        assert_ne!(first.fingerprint, second.fingerprint);
+        assert!(first.cert_pem.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(!format!("{:?}", first).contains(&first.key_pem));
+        first.rustls_config().await.unwrap();
+    }
+}
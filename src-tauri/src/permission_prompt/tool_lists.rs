@@ -0,0 +1,417 @@
+//! Tools a session lets through or turns away without a prompt, and exact
+//! Bash commands allowed app-wide by their hash.
+
+use super::decisions::DecisionSource;
+use super::{
+    deny_with, HttpState, PermissionError, PermissionRequest, PermissionResponse,
+    PermissionServerRegistry,
+};
+
+/// Add an exact Bash command to the hash allowlist. Returns its hash.
+pub fn allow_command(registry: &PermissionServerRegistry, command: &str) -> String {
+    registry
+        .rules
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .command_allowlist
+        .add(command)
+}
+
+/// Remove a Bash command from the hash allowlist. Returns whether it was on
+/// it.
+pub fn disallow_command(registry: &PermissionServerRegistry, command: &str) -> bool {
+    registry
+        .rules
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .command_allowlist
+        .remove(command)
+}
+
+/// Let `tool_name` through without a prompt for the rest of the session.
+pub async fn add_allowed_tool(
+    session_id: &str,
+    tool_name: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    entry
+        .allowed_tools
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(tool_name.to_string());
+    Ok(())
+}
+
+/// Go back to prompting for `tool_name`. Returns whether it was allowlisted.
+pub async fn remove_allowed_tool(
+    session_id: &str,
+    tool_name: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<bool, PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    let removed = entry
+        .allowed_tools
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(tool_name);
+    Ok(removed)
+}
+
+/// Deny `tool_name` without a prompt for the rest of the session, even if
+/// it is also allowlisted.
+pub async fn block_tool(
+    session_id: &str,
+    tool_name: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<(), PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    entry
+        .blocked_tools
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(tool_name.to_string());
+    Ok(())
+}
+
+/// Lift a block set by `block_tool`. Returns whether the tool was blocked.
+pub async fn unblock_tool(
+    session_id: &str,
+    tool_name: &str,
+    registry: &PermissionServerRegistry,
+) -> Result<bool, PermissionError> {
+    let servers = registry.servers.lock().await;
+    let entry = servers
+        .get(session_id)
+        .ok_or_else(|| PermissionError::SessionNotFound(session_id.to_string()))?;
+    let removed = entry
+        .blocked_tools
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(tool_name);
+    Ok(removed)
+}
+
+/// The deny for a tool on the session's block list. Checked before
+/// anything else that could answer a request, so no other setting (inspect
+/// mode included) lets a blocked tool through.
+pub(super) fn blocked_decision(
+    state: &HttpState,
+    tool_name: &str,
+) -> Option<(PermissionResponse, DecisionSource)> {
+    let blocked = state
+        .blocked_tools
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .contains(tool_name);
+    blocked.then(|| {
+        let message = format!("Tool '{}' is blocked by policy", tool_name);
+        (deny_with(&message), DecisionSource::Blocklist)
+    })
+}
+
+/// The allow for a tool on the session's allowlist, without prompting.
+pub(super) fn allowed_decision(
+    state: &HttpState,
+    req: &PermissionRequest,
+) -> Option<(PermissionResponse, DecisionSource)> {
+    let allowed = state.mcp_server.asks_user(&req.tool_name)
+        || state
+            .allowed_tools
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(&req.tool_name);
+    allowed.then(|| {
+        let resp = PermissionResponse {
+            behavior: "allow".to_string(),
+            updated_input: Some(req.input.clone()),
+            message: None,
+        };
+        (resp, DecisionSource::Allowlist)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::batch::handle_permission_batch;
+    use super::super::rules::{PermissionRule, RuleAction, RuleScope};
+    use super::super::testing::*;
+    use super::super::{
+        handle_permission_prompt, recent_decisions, resolve_prompt, set_inspect_mode,
+        set_scope_rules, PermissionBatchRequest, PermissionServerConfig, ToolInvocation,
+        DECISION_LIMIT_MESSAGE,
+    };
+    use super::*;
+    use axum::{extract::State as AxumState, Json};
+    use std::collections::BTreeSet;
+
+    #[tokio::test]
+    async fn test_allowed_tool_skips_prompt() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        add_allowed_tool("session-1", "Read", &registry)
+            .await
+            .unwrap();
+
+        let input = serde_json::json!({ "file_path": "src/main.rs" });
+        let state = test_http_state(&registry, "session-1").await;
+        let Json(resp) =
+            handle_permission_prompt(AxumState(state), Json(test_request("Read", input.clone())))
+                .await
+                .unwrap();
+        assert_eq!(resp.behavior, "allow");
+        assert_eq!(resp.updated_input, Some(input));
+        assert!(emitter.prompts().is_empty());
+        assert!(registry.servers.lock().await["session-1"]
+            .pending
+            .lock()
+            .await
+            .is_empty());
+        let record = &recent_decisions(Some("session-1"), &registry)[0];
+        assert_eq!(record.source, DecisionSource::Allowlist);
+        assert!(!record.prompt_id.is_empty());
+
+        assert!(remove_allowed_tool("session-1", "Read", &registry)
+            .await
+            .unwrap());
+        assert!(!remove_allowed_tool("session-1", "Read", &registry)
+            .await
+            .unwrap());
+        assert!(add_allowed_tool("missing", "Read", &registry)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_tool_denied_even_when_allowlisted() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        add_allowed_tool("session-1", "mcp__shell__exec", &registry)
+            .await
+            .unwrap();
+        block_tool("session-1", "mcp__shell__exec", &registry)
+            .await
+            .unwrap();
+
+        let state = test_http_state(&registry, "session-1").await;
+        let Json(resp) = handle_permission_prompt(
+            AxumState(state.clone()),
+            Json(test_request("mcp__shell__exec", serde_json::json!({}))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(
+            resp.message.as_deref(),
+            Some("Tool 'mcp__shell__exec' is blocked by policy")
+        );
+        assert!(emitter.prompts().is_empty());
+
+        // Unblocking falls back to the allowlist
+        assert!(unblock_tool("session-1", "mcp__shell__exec", &registry)
+            .await
+            .unwrap());
+        let Json(resp) = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("mcp__shell__exec", serde_json::json!({}))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.behavior, "allow");
+
+        let ids: BTreeSet<_> = recent_decisions(Some("session-1"), &registry)
+            .into_iter()
+            .map(|record| record.prompt_id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert!(!ids.contains(""));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_tool_denied_in_inspect_mode() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        block_tool("session-1", "Bash", &registry).await.unwrap();
+        set_inspect_mode("session-1", true, &registry)
+            .await
+            .unwrap();
+
+        let state = test_http_state(&registry, "session-1").await;
+        let Json(resp) = handle_permission_prompt(
+            AxumState(state),
+            Json(test_request("Bash", serde_json::json!({ "command": "ls" }))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.behavior, "deny");
+        assert_eq!(
+            resp.message.as_deref(),
+            Some("Tool 'Bash' is blocked by policy")
+        );
+        assert!(emitter.prompts().is_empty());
+        assert_eq!(
+            recent_decisions(Some("session-1"), &registry)[0].source,
+            DecisionSource::Blocklist
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocked_tool_denied_in_batch() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        block_tool("session-1", "mcp__shell__exec", &registry)
+            .await
+            .unwrap();
+        add_allowed_tool("session-1", "Read", &registry)
+            .await
+            .unwrap();
+        // A rule allowing the blocked tool doesn't let it through either
+        set_scope_rules(
+            &registry,
+            RuleScope::Global,
+            vec![PermissionRule {
+                tool: "mcp__shell__exec".to_string(),
+                field: None,
+                pattern: None,
+                agent_path_prefix: None,
+                condition: None,
+                action: RuleAction::Allow,
+                message: None,
+                origin: None,
+            }],
+        );
+
+        let batch = PermissionBatchRequest {
+            batch: vec![
+                ToolInvocation {
+                    tool_use_id: "toolu_1".to_string(),
+                    tool_name: "mcp__shell__exec".to_string(),
+                    input: serde_json::json!({ "command": "rm -rf /" }),
+                },
+                ToolInvocation {
+                    tool_use_id: "toolu_2".to_string(),
+                    tool_name: "Read".to_string(),
+                    input: serde_json::json!({ "file_path": "a" }),
+                },
+            ],
+            context: None,
+            agent_path: Vec::new(),
+            cwd: None,
+        };
+        let state = test_http_state(&registry, "session-1").await;
+        let replies = handle_permission_batch(state, batch).await;
+        assert_eq!(replies[0].behavior, "deny");
+        assert_eq!(
+            replies[0].message.as_deref(),
+            Some("Tool 'mcp__shell__exec' is blocked by policy")
+        );
+        assert_eq!(replies[1].behavior, "allow");
+        assert!(emitter.names().is_empty());
+        let sources: Vec<_> = recent_decisions(Some("session-1"), &registry)
+            .into_iter()
+            .map(|record| record.source)
+            .collect();
+        assert_eq!(
+            sources,
+            [DecisionSource::Blocklist, DecisionSource::Allowlist]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_hash_allowlist_matches_exact_command_only() {
+        let registry = PermissionServerRegistry::default();
+        let emitter = insert_test_entry(&registry, "session-1").await;
+        let hash = allow_command(&registry, "cargo test --workspace");
+        assert_eq!(hash.len(), 64);
+
+        let request =
+            |command: &str| test_request("Bash", serde_json::json!({ "command": command }));
+
+        // Surrounding whitespace is canonicalized away
+        let state = test_http_state(&registry, "session-1").await;
+        let resp = handle_permission_prompt(
+            AxumState(state),
+            Json(request("  cargo test --workspace\n")),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(resp.behavior, "allow");
+        assert!(emitter.prompts().is_empty());
+
+        // A near-variant is not on the list and goes to the user
+        let state = test_http_state(&registry, "session-1").await;
+        let handler = tokio::spawn(handle_permission_prompt(
+            AxumState(state),
+            Json(request("cargo test --workspace; rm -rf ~")),
+        ));
+        let emitter_for_wait = emitter.clone();
+        wait_until(move || emitter_for_wait.prompts().len() == 1).await;
+        let prompt_id = emitter.prompts()[0]["prompt_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        resolve_prompt("session-1", &prompt_id, allow(), &registry)
+            .await
+            .unwrap();
+        let resp = handler.await.unwrap().unwrap().0;
+        assert_eq!(resp.behavior, "allow");
+
+        assert!(disallow_command(&registry, "cargo test --workspace"));
+        assert!(!disallow_command(&registry, "cargo test --workspace"));
+    }
+
+    #[tokio::test]
+    async fn test_allowlisted_tool_denied_past_decision_limit() {
+        let registry = PermissionServerRegistry::default();
+        insert_test_entry_with(
+            &registry,
+            "session-1",
+            PermissionServerConfig {
+                max_decisions: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+        add_allowed_tool("session-1", "Read", &registry)
+            .await
+            .unwrap();
+
+        let mut behaviors = Vec::new();
+        for _ in 0..2 {
+            let state = test_http_state(&registry, "session-1").await;
+            let resp = handle_permission_prompt(
+                AxumState(state),
+                Json(test_request(
+                    "Read",
+                    serde_json::json!({ "file_path": "a" }),
+                )),
+            )
+            .await
+            .unwrap()
+            .0;
+            behaviors.push((resp.behavior, resp.message));
+        }
+        assert_eq!(
+            behaviors,
+            vec![
+                ("allow".to_string(), None),
+                ("deny".to_string(), Some(DECISION_LIMIT_MESSAGE.to_string())),
+            ]
+        );
+        let sources: Vec<_> = recent_decisions(Some("session-1"), &registry)
+            .into_iter()
+            .map(|record| record.source)
+            .collect();
+        assert_eq!(sources, [DecisionSource::Allowlist, DecisionSource::Limit]);
+    }
+}
@@ -0,0 +1,290 @@
+//! Tools the generated MCP server offers Claude. The Node script has the list
+//! injected when it's written and the native bridge reads it directly; both
+//! post each call to the tool's route on the session's permission server.
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Name the MCP config registers the server under unless configured
+/// otherwise; Claude Code calls its tools as `mcp__opcode__<tool>`.
+pub const MCP_SERVER_NAME: &str = "opcode";
+
+pub const PERMISSION_PROMPT_TOOL: &str = "permission_prompt";
+pub const ASK_USER_TOOL: &str = "ask_user";
+
+/// Stands in for the tool list in the script template. Kept a valid (empty)
+/// array so the template itself still parses.
+pub const TOOLS_PLACEHOLDER: &str = "/*__OPCODE_MCP_TOOLS__*/[]";
+
+/// Stands in for the `serverInfo` object in the script template.
+pub const SERVER_INFO_PLACEHOLDER: &str = "/*__OPCODE_MCP_SERVER_INFO__*/{}";
+
+/// Env vars carrying a non-default identity to the native bridge.
+pub const SERVER_NAME_ENV: &str = "OPCODE_MCP_SERVER_NAME";
+pub const TOOL_DESCRIPTION_ENV: &str = "OPCODE_MCP_TOOL_DESCRIPTION";
+
+/// How the MCP server presents itself: the `mcpServers` key (and so the
+/// `mcp__<name>__` tool prefix) and the permission tool's description.
+/// Separate instances or white-labeled builds change these so their servers
+/// don't collide or read as OpCode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerIdentity {
+    pub name: String,
+    /// Replaces the `permission_prompt` tool's description when set.
+    pub description: Option<String>,
+}
+
+impl Default for ServerIdentity {
+    fn default() -> Self {
+        Self {
+            name: MCP_SERVER_NAME.to_string(),
+            description: None,
+        }
+    }
+}
+
+impl ServerIdentity {
+    /// Read the identity from `SERVER_NAME_ENV` and `TOOL_DESCRIPTION_ENV`,
+    /// defaulting what's unset.
+    pub fn from_env(env: impl Fn(&str) -> Option<String>) -> Self {
+        let non_empty = |key: &str| env(key).filter(|v| !v.is_empty());
+        Self {
+            name: non_empty(SERVER_NAME_ENV).unwrap_or_else(|| MCP_SERVER_NAME.to_string()),
+            description: non_empty(TOOL_DESCRIPTION_ENV),
+        }
+    }
+
+    /// The env vars that reproduce this identity in `from_env`; empty for the
+    /// default.
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = Vec::new();
+        if self.name != MCP_SERVER_NAME {
+            env.push((SERVER_NAME_ENV, self.name.clone()));
+        }
+        if let Some(description) = &self.description {
+            env.push((TOOL_DESCRIPTION_ENV, description.clone()));
+        }
+        env
+    }
+
+    /// Claude Code joins the name into tool names and config keys, so keep it
+    /// to ASCII letters, digits, `-` and `_`.
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("Invalid MCP server name '{}'", self.name))
+        }
+    }
+
+    /// The `serverInfo` sent in the `initialize` reply.
+    pub fn server_info(&self) -> Value {
+        json!({ "name": format!("{}-permission-prompt", self.name), "version": "1.0.0" })
+    }
+
+    /// The value for Claude Code's `--permission-prompt-tool`.
+    pub fn permission_prompt_tool(&self) -> String {
+        format!("mcp__{}__{}", self.name, PERMISSION_PROMPT_TOOL)
+    }
+
+    /// Every tool the server offers, with the configured description.
+    pub fn tools(&self) -> Vec<McpTool> {
+        let mut tools = mcp_tools();
+        if let Some(description) = &self.description {
+            for tool in &mut tools {
+                if tool.name == PERMISSION_PROMPT_TOOL {
+                    tool.description = description.clone();
+                }
+            }
+        }
+        tools
+    }
+
+    /// The tool called `name`, if the server offers one.
+    pub fn find_tool(&self, name: &str) -> Option<McpTool> {
+        self.tools().into_iter().find(|tool| tool.name == name)
+    }
+
+    /// Whether `tool_name` (as Claude Code names it) is one of our tools that
+    /// asks the user itself, so asking permission first would only ask twice.
+    pub fn asks_user(&self, tool_name: &str) -> bool {
+        tool_name
+            .strip_prefix("mcp__")
+            .and_then(|rest| rest.strip_prefix(self.name.as_str()))
+            .and_then(|rest| rest.strip_prefix("__"))
+            .is_some_and(|name| name == ASK_USER_TOOL)
+    }
+
+    /// The Node script with the tool list and server info filled in.
+    pub fn render_script(&self, template: &str) -> String {
+        let tools = serde_json::to_string(&self.tools()).expect("tool definitions serialize");
+        template.replacen(TOOLS_PLACEHOLDER, &tools, 1).replacen(
+            SERVER_INFO_PLACEHOLDER,
+            &self.server_info().to_string(),
+            1,
+        )
+    }
+}
+
+/// One MCP tool and how its calls reach the permission server.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpTool {
+    pub name: &'static str,
+    pub description: String,
+    pub input_schema: Value,
+    /// Route on the permission server each call is posted to.
+    pub path: &'static str,
+    /// Request fields taken from the call's arguments, each with the value
+    /// sent when the argument is missing or falsy. `context`, `agent_path`
+    /// and `cwd` are always added.
+    pub defaults: Value,
+    /// Answer handed to Claude when the server can't be reached.
+    pub fallback: Value,
+}
+
+impl McpTool {
+    /// The entry for `tools/list`.
+    pub fn definition(&self) -> Value {
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "inputSchema": self.input_schema,
+        })
+    }
+}
+
+/// Every tool the server offers, `permission_prompt` first, as the default
+/// identity describes them.
+pub fn mcp_tools() -> Vec<McpTool> {
+    vec![
+        McpTool {
+            name: PERMISSION_PROMPT_TOOL,
+            description: "Handle permission requests from Claude Code. Returns whether the user allowed or denied the action.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "tool_use_id": {
+                        "type": "string",
+                        "description": "Unique identifier for this tool invocation",
+                    },
+                    "tool_name": {
+                        "type": "string",
+                        "description": "The name of the tool requesting permission",
+                    },
+                    "input": {
+                        "description": "The input parameters for the tool",
+                    },
+                },
+                "required": ["tool_use_id", "tool_name", "input"],
+            }),
+            path: "/permission-prompt",
+            defaults: json!({ "tool_use_id": "", "tool_name": "unknown", "input": {} }),
+            fallback: json!({ "behavior": "deny", "message": "Permission server unavailable" }),
+        },
+        McpTool {
+            name: ASK_USER_TOOL,
+            description: "Ask the user a free-text question and wait for their answer. Returns the answer, or no answer if the user dismissed the question.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "question": {
+                        "type": "string",
+                        "description": "The question to show the user",
+                    },
+                },
+                "required": ["question"],
+            }),
+            path: "/user-input",
+            defaults: json!({ "question": "" }),
+            fallback: json!({ "message": "OpCode is unavailable; the user could not be asked" }),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_lookup_and_definitions() {
+        let identity = ServerIdentity::default();
+        let names: Vec<_> = identity.tools().iter().map(|tool| tool.name).collect();
+        assert_eq!(names, [PERMISSION_PROMPT_TOOL, ASK_USER_TOOL]);
+        assert_eq!(
+            identity.find_tool(ASK_USER_TOOL).unwrap().path,
+            "/user-input"
+        );
+        assert!(identity.find_tool("other").is_none());
+
+        let definition = identity.find_tool(ASK_USER_TOOL).unwrap().definition();
+        assert_eq!(definition["inputSchema"]["required"], json!(["question"]));
+        assert!(definition.get("path").is_none());
+
+        assert!(identity.asks_user("mcp__opcode__ask_user"));
+        assert!(!identity.asks_user("mcp__opcode__permission_prompt"));
+        assert!(!identity.asks_user("mcp__other__ask_user"));
+        assert!(!identity.asks_user("ask_user"));
+    }
+
+    #[test]
+    fn test_render_script_injects_tools() {
+        let script = ServerIdentity::default()
+            .render_script(&format!("const TOOLS = {};", TOOLS_PLACEHOLDER));
+        let tools: Value = serde_json::from_str(
+            script
+                .trim_start_matches("const TOOLS = ")
+                .trim_end_matches(';'),
+        )
+        .unwrap();
+        assert_eq!(tools[1]["name"], ASK_USER_TOOL);
+        assert_eq!(tools[1]["inputSchema"]["type"], "object");
+        assert_eq!(tools[0]["defaults"]["tool_name"], "unknown");
+    }
+
+    #[test]
+    fn test_custom_identity() {
+        let identity = ServerIdentity::from_env(|key| match key {
+            SERVER_NAME_ENV => Some("acme".to_string()),
+            TOOL_DESCRIPTION_ENV => Some("Ask Acme before acting.".to_string()),
+            _ => None,
+        });
+        assert_eq!(identity.name, "acme");
+        assert!(identity.validate().is_ok());
+        assert_eq!(
+            identity.permission_prompt_tool(),
+            "mcp__acme__permission_prompt"
+        );
+        assert!(identity.asks_user("mcp__acme__ask_user"));
+        assert!(!identity.asks_user("mcp__opcode__ask_user"));
+        assert_eq!(identity.tools()[0].description, "Ask Acme before acting.");
+        assert_eq!(identity.server_info()["name"], "acme-permission-prompt");
+        let env = identity.env();
+        let lookup = |key: &str| env.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone());
+        assert_eq!(ServerIdentity::from_env(lookup), identity);
+
+        let script = identity.render_script(&format!("const INFO = {};", SERVER_INFO_PLACEHOLDER));
+        assert_eq!(
+            script,
+            r#"const INFO = {"name":"acme-permission-prompt","version":"1.0.0"};"#
+        );
+
+        assert_eq!(
+            ServerIdentity::from_env(|_| None),
+            ServerIdentity::default()
+        );
+        assert!(ServerIdentity::default().env().is_empty());
+        for name in ["", "a b", "x/y", "ünï"] {
+            let identity = ServerIdentity {
+                name: name.to_string(),
+                description: None,
+            };
+            assert!(identity.validate().is_err(), "{:?}", name);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Rewrites a tool's input for a `modify` answer. Gets the tool name and the
+/// input the user was shown; an `Err` is reported back to whoever answered.
+pub type InputTransform = Arc<dyn Fn(&str, &Value) -> Result<Value, String> + Send + Sync>;
+
+/// Name of the built-in transform that drops `NAME=value` prefixes from Bash
+/// commands (e.g. to keep a pasted secret out of the run).
+pub const STRIP_ENV_ASSIGNMENTS: &str = "strip_env_assignments";
+
+/// Tokens that end one shell command and start the next, longest first.
+const SEPARATORS: &[&str] = &["&&", "||", ";", "|", "&", "\n"];
+
+/// Transforms every registry starts with.
+pub fn builtin_transforms() -> HashMap<String, InputTransform> {
+    let mut transforms: HashMap<String, InputTransform> = HashMap::new();
+    transforms.insert(
+        STRIP_ENV_ASSIGNMENTS.to_string(),
+        Arc::new(strip_env_assignments_transform),
+    );
+    transforms
+}
+
+fn strip_env_assignments_transform(tool_name: &str, input: &Value) -> Result<Value, String> {
+    if tool_name != "Bash" {
+        return Err(format!("only applies to Bash, not {}", tool_name));
+    }
+    let command = input
+        .get("command")
+        .and_then(Value::as_str)
+        .ok_or("input has no command")?;
+    let stripped = strip_env_assignments(command);
+    if stripped.trim().is_empty() {
+        return Err("nothing left to run without the assignments".to_string());
+    }
+
+    let mut input = input.clone();
+    input["command"] = Value::String(stripped);
+    Ok(input)
+}
+
+/// Drop the leading `NAME=value` words of every command in a shell line,
+/// leaving the rest as written. Quoted and escaped text is kept intact, so
+/// `FOO="a b" make` becomes `make`.
+pub fn strip_env_assignments(command: &str) -> String {
+    let mut out = String::new();
+    let mut rest = command;
+    let mut at_command_start = true;
+    // Spacing in front of a dropped word, reused for the word after it
+    let mut carried_space: Option<&str> = None;
+
+    loop {
+        let trimmed = rest.trim_start_matches([' ', '\t']);
+        let space = &rest[..rest.len() - trimmed.len()];
+        if trimmed.is_empty() {
+            break;
+        }
+
+        if let Some(sep) = SEPARATORS.iter().find(|sep| trimmed.starts_with(**sep)) {
+            out.push_str(carried_space.take().unwrap_or(space));
+            out.push_str(sep);
+            rest = &trimmed[sep.len()..];
+            at_command_start = true;
+            continue;
+        }
+
+        let word = &trimmed[..word_len(trimmed)];
+        rest = &trimmed[word.len()..];
+        if at_command_start && is_assignment(word) {
+            carried_space.get_or_insert(space);
+            continue;
+        }
+        out.push_str(carried_space.take().unwrap_or(space));
+        out.push_str(word);
+        at_command_start = false;
+    }
+    out
+}
+
+/// Length in bytes of the shell word at the start of `s`, honouring quotes
+/// and backslash escapes.
+fn word_len(s: &str) -> usize {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some('"') if c == '\\' => escaped = true,
+            Some(_) => {}
+            None => match c {
+                '\\' => escaped = true,
+                '\'' | '"' => quote = Some(c),
+                ' ' | '\t' | '\n' | ';' | '&' | '|' => return i,
+                _ => {}
+            },
+        }
+    }
+    s.len()
+}
+
+/// Whether a shell word is a `NAME=value` assignment.
+fn is_assignment(word: &str) -> bool {
+    let Some((name, _)) = word.split_once('=') else {
+        return false;
+    };
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_strips_leading_assignments() {
+        assert_eq!(strip_env_assignments("FOO=1 make"), "make");
+        assert_eq!(
+            strip_env_assignments("API_KEY='s3cr3t value' BAR=\"x y\" curl -H \"a=b\" example.com"),
+            "curl -H \"a=b\" example.com"
+        );
+        assert_eq!(
+            strip_env_assignments("cd app && TOKEN=abc npm publish; echo done"),
+            "cd app && npm publish; echo done"
+        );
+        assert_eq!(
+            strip_env_assignments("A=1 printenv | B=2 grep x=y"),
+            "printenv | grep x=y"
+        );
+    }
+
+    #[test]
+    fn test_leaves_non_assignments_alone() {
+        assert_eq!(strip_env_assignments("ls -la"), "ls -la");
+        assert_eq!(strip_env_assignments("echo FOO=1"), "echo FOO=1");
+        assert_eq!(strip_env_assignments("1X=2 run"), "1X=2 run");
+        assert_eq!(strip_env_assignments("=x run"), "=x run");
+        assert_eq!(
+            strip_env_assignments("git log --format='%H;%s'"),
+            "git log --format='%H;%s'"
+        );
+    }
+
+    #[test]
+    fn test_builtin_transform_rewrites_bash_command_only() {
+        let transforms = builtin_transforms();
+        let strip = &transforms[STRIP_ENV_ASSIGNMENTS];
+
+        let input = json!({ "command": "SECRET=hunter2 ./deploy.sh", "timeout": 5 });
+        assert_eq!(
+            strip("Bash", &input).unwrap(),
+            json!({ "command": "./deploy.sh", "timeout": 5 })
+        );
+        assert!(strip("Bash", &json!({ "command": "FOO=1" })).is_err());
+        assert!(strip("Bash", &json!({ "cmd": "ls" })).is_err());
+        assert!(strip("Write", &json!({ "command": "FOO=1 ls" })).is_err());
+    }
+}
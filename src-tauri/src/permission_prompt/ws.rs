@@ -0,0 +1,268 @@
+//! `GET /ws` on the permission server: a WebSocket for clients outside the
+//! app (e.g. a dashboard in another process) to watch a session's prompts
+//! and answer them. Every session event is pushed as it is emitted, and
+//! clients resolve prompts with the body `POST /resolve` takes. Like
+//! `/resolve`, it needs the session's controller token.
+
+use super::controller::{resolve_from_controller, ResolveRequest};
+use super::{bearer_matches, HttpState, PermissionEmitter, PromptNotifyEvent};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State as AxumState,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Events buffered per client. One that falls further behind skips the
+/// oldest and is told how many with `ServerMessage::Lagged`.
+pub const FEED_CAPACITY: usize = 256;
+
+/// Sending side of a session's event feed.
+pub type EventFeed = broadcast::Sender<FeedEvent>;
+
+/// A session event as WebSocket clients see it: its unscoped name (e.g.
+/// `permission-prompt`) and payload.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeedEvent {
+    pub event: String,
+    pub payload: serde_json::Value,
+}
+
+/// Emitter that also hands each session-scoped event (`{name}:{session_id}`)
+/// to the session's feed. Generic copies aren't forwarded, so clients see
+/// every event once. An event a client received counts as delivered even if
+/// the frontend is gone, since that client can answer it.
+pub struct FeedEmitter {
+    inner: Arc<dyn PermissionEmitter>,
+    feed: EventFeed,
+}
+
+impl FeedEmitter {
+    pub fn new(inner: Arc<dyn PermissionEmitter>, feed: EventFeed) -> Self {
+        Self { inner, feed }
+    }
+}
+
+impl PermissionEmitter for FeedEmitter {
+    fn emit_json(&self, event: &str, payload: serde_json::Value) -> Result<(), String> {
+        let forwarded = match event.split_once(':') {
+            Some((name, _)) => self
+                .feed
+                .send(FeedEvent {
+                    event: name.to_string(),
+                    payload: payload.clone(),
+                })
+                .is_ok(),
+            None => false,
+        };
+        match self.inner.emit_json(event, payload) {
+            Err(e) if forwarded => {
+                log::debug!(
+                    "Failed to emit '{}', but a WebSocket client has it: {}",
+                    event,
+                    e
+                );
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn notify_os(&self, notification: &PromptNotifyEvent) -> bool {
+        self.inner.notify_os(notification)
+    }
+}
+
+/// Messages a client sends, as JSON text frames.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Answer a prompt, exactly like `POST /resolve`.
+    Resolve(ResolveRequest),
+}
+
+/// Messages the server sends, as JSON text frames.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// A session event. Prompts already showing are sent this way on
+    /// connect, so one created meanwhile may arrive twice.
+    Event(FeedEvent),
+    /// A `resolve` went through.
+    Resolved { prompt_id: String },
+    /// A message couldn't be parsed or its resolve failed.
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prompt_id: Option<String>,
+        message: String,
+    },
+    /// The client fell behind and this many events were dropped.
+    Lagged { skipped: u64 },
+}
+
+/// Upgrade to a WebSocket for clients with the controller token.
+pub(super) async fn handle_ws(
+    AxumState(state): AxumState<HttpState>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if !bearer_matches(&headers, &state.controller_token) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    ws.on_upgrade(move |socket| serve_client(socket, state))
+}
+
+/// Push the session's events to one client and act on its messages until
+/// either side closes or the server stops.
+async fn serve_client(mut socket: WebSocket, state: HttpState) {
+    // Subscribe before listing, so a prompt created in between isn't missed
+    let mut feed = state.feed.subscribe();
+    let mut shutdown_rx = state.shutdown_rx.clone();
+    for message in showing_prompts(&state).await {
+        if send(&mut socket, &message).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        let message = tokio::select! {
+            event = feed.recv() => match event {
+                Ok(event) => ServerMessage::Event(event),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    ServerMessage::Lagged { skipped }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            received = socket.recv() => match received {
+                Some(Ok(Message::Text(text))) => client_reply(&state, text.as_str()).await,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // axum answers pings itself
+                Some(Ok(_)) => continue,
+            },
+            // Don't let the select output hold a (non-Send) `watch::Ref`
+            _ = async { shutdown_rx.wait_for(|stopped| *stopped).await.is_ok() } => break,
+        };
+        if send(&mut socket, &message).await.is_err() {
+            break;
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+/// The session's single prompts still showing, as the events that announced
+/// them. Batch prompts have no stored event and are left out.
+async fn showing_prompts(state: &HttpState) -> Vec<ServerMessage> {
+    let pending = state.pending.lock().await;
+    pending
+        .values()
+        .filter_map(|prompt| prompt.event.as_ref())
+        .filter_map(|event| serde_json::to_value(event).ok())
+        .map(|payload| {
+            ServerMessage::Event(FeedEvent {
+                event: "permission-prompt".to_string(),
+                payload,
+            })
+        })
+        .collect()
+}
+
+/// Act on one text frame from a client and say how it went.
+async fn client_reply(state: &HttpState, text: &str) -> ServerMessage {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(e) => {
+            return ServerMessage::Error {
+                prompt_id: None,
+                message: format!("Invalid message: {}", e),
+            }
+        }
+    };
+    match message {
+        ClientMessage::Resolve(req) => {
+            let prompt_id = req.prompt_id.clone();
+            match resolve_from_controller(state, req).await {
+                Ok(()) => ServerMessage::Resolved { prompt_id },
+                Err(e) => ServerMessage::Error {
+                    prompt_id: Some(prompt_id),
+                    message: e.to_string(),
+                },
+            }
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), axum::Error> {
+    match serde_json::to_string(message) {
+        Ok(text) => socket.send(Message::Text(text.into())).await,
+        Err(e) => {
+            log::warn!("Failed to serialize WebSocket message: {}", e);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::FailingEmitter;
+    use super::*;
+
+    #[test]
+    fn test_feed_emitter_forwards_scoped_events_once() {
+        let feed = broadcast::channel(FEED_CAPACITY).0;
+        let emitter = FeedEmitter::new(Arc::new(FailingEmitter), feed.clone());
+
+        // Nobody is listening yet, so the failure stands
+        let payload = serde_json::json!({ "prompt_id": "p1" });
+        assert!(emitter
+            .emit_json("permission-prompt:session-1", payload.clone())
+            .is_err());
+
+        let mut rx = feed.subscribe();
+        emitter
+            .emit_json("permission-prompt:session-1", payload.clone())
+            .unwrap();
+        assert!(emitter
+            .emit_json("permission-prompt", payload.clone())
+            .is_err());
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            FeedEvent {
+                event: "permission-prompt".to_string(),
+                payload,
+            }
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_message_format() {
+        let message: ClientMessage = serde_json::from_str(
+            r#"{"type":"resolve","prompt_id":"p1","behavior":"allow","remember":true}"#,
+        )
+        .unwrap();
+        let ClientMessage::Resolve(req) = message;
+        assert_eq!(req.prompt_id, "p1");
+        assert!(req.remember);
+
+        let reply = serde_json::to_value(ServerMessage::Resolved {
+            prompt_id: "p1".to_string(),
+        })
+        .unwrap();
+        assert_eq!(
+            reply,
+            serde_json::json!({ "type": "resolved", "prompt_id": "p1" })
+        );
+        let event = serde_json::to_value(ServerMessage::Event(FeedEvent {
+            event: "permission-timeout".to_string(),
+            payload: serde_json::json!({}),
+        }))
+        .unwrap();
+        assert_eq!(event["type"], "event");
+        assert_eq!(event["event"], "permission-timeout");
+    }
+}
@@ -444,6 +444,117 @@ export interface ImportServerResult {
   error?: string;
 }
 
+/**
+ * What kind of access a permission request asks for
+ */
+export type PermissionRiskCategory = 'read_only' | 'file_write' | 'execute' | 'network' | 'unknown';
+
+/**
+ * Permission prompt emitted by a session's permission server
+ */
+export interface PermissionPromptEvent {
+  prompt_id: string;
+  session_id: string;
+  /** Claude Code's ID for the tool call; empty for test prompts */
+  tool_use_id: string;
+  tool_name: string;
+  /** When the request arrived, in Unix milliseconds */
+  created_at: number;
+  input: Record<string, any>;
+  context?: { message_id?: string; turn?: number };
+  agent_path?: string[];
+  /** Auto-edited input the dialog should offer by default */
+  suggested_input?: Record<string, any>;
+  /** The input as Claude Code sent it, before any auto-edit */
+  original_input?: Record<string, any>;
+  /** The request's main target (file path, command, URL, ...) */
+  summary?: string;
+  explanation?: string;
+  risk_category: PermissionRiskCategory;
+  test?: boolean;
+  /** Already allowed; shown for inspect mode only */
+  inspect?: boolean;
+  /** Fields were dropped; see getPermissionPromptEvent */
+  truncated?: boolean;
+  /** `input` is a preview; see getFullPermissionInput */
+  input_truncated?: boolean;
+  input_bytes?: number;
+  cwd?: string;
+  /** Time until the prompt times out; unset when it waits forever */
+  timeout_ms?: number;
+}
+
+/**
+ * Answer to one permission request
+ */
+export interface PermissionResponse {
+  behavior: "allow" | "deny" | "modify";
+  updatedInput?: Record<string, any>;
+  message?: string;
+}
+
+/**
+ * A permission prompt still waiting for an answer
+ */
+export interface PendingPermissionPrompt {
+  prompt_id: string;
+  tool_name: string;
+  /** When the prompt was registered, in Unix milliseconds */
+  created_at_ms: number;
+  /** Missing for batch prompts */
+  event?: PermissionPromptEvent;
+}
+
+/**
+ * Permission decision counts for a session
+ */
+export interface PermissionMetrics {
+  user_allowed: number;
+  user_denied: number;
+  auto_allowed: number;
+  auto_denied: number;
+  timed_out: number;
+  cancelled: number;
+  rate_limited: number;
+}
+
+/**
+ * State of one running permission server
+ */
+export interface PermissionServerSnapshot {
+  session_id: string;
+  port: number;
+  host: string;
+  abstract_socket?: string;
+  socket_path?: string;
+  pending: number;
+  mcp_files: { dir: string; config_path: string; script_path: string };
+  received_request: boolean;
+  idle_ms: number;
+}
+
+/**
+ * Running permission servers and the prompts they are waiting on
+ */
+export interface PermissionActivity {
+  sessions: number;
+  waiting: number;
+}
+
+/**
+ * Outcome of stopping a permission server gracefully
+ */
+export type PermissionStopResult = 'stopped' | 'aborted' | 'not_found';
+
+/**
+ * The Node.js used for permission prompts
+ */
+export interface PermissionNodeStatus {
+  path: string;
+  version: string | null;
+  warning: string | null;
+}
+
 /**
  * API client for interacting with the Rust backend
  */
@@ -1058,15 +1169,267 @@ export const api = {
    * Responds to a permission prompt from Claude Code
    * @param sessionId - The session ID the prompt belongs to
    * @param promptId - The unique prompt ID
-   * @param behavior - "allow" or "deny"
+   * @param behavior - "allow", "deny" or "modify"
+   * @param remember - Reuse the answer for identical requests later in the session
+   * @param transform - Name of a transform to apply to the input
    */
   async respondPermissionPrompt(
     sessionId: string,
     promptId: string,
-    behavior: "allow" | "deny",
+    behavior: "allow" | "deny" | "modify",
     input?: Record<string, any>,
+    remember?: boolean,
+    transform?: string,
   ): Promise<void> {
-    return apiCall("respond_permission_prompt", { sessionId, promptId, behavior, input });
+    return apiCall("respond_permission_prompt", { sessionId, promptId, behavior, input, remember, transform });
+  },
+
+  /**
+   * Allows a permission prompt, with edited input or the input it showed
+   */
+  async allowPermissionPrompt(sessionId: string, promptId: string, input?: Record<string, any>): Promise<void> {
+    return apiCall("allow_permission_prompt", { sessionId, promptId, input });
+  },
+
+  /**
+   * Denies a permission prompt, telling Claude why with `message`
+   */
+  async denyPermissionPrompt(sessionId: string, promptId: string, message?: string): Promise<void> {
+    return apiCall("deny_permission_prompt", { sessionId, promptId, message });
+  },
+
+  /**
+   * Answers a batched permission prompt with one response per invocation, in order
+   */
+  async respondPermissionBatch(sessionId: string, promptId: string, responses: PermissionResponse[]): Promise<void> {
+    return apiCall("respond_permission_batch", { sessionId, promptId, responses });
+  },
+
+  /**
+   * Finalizes a high-risk deny staged by respondPermissionPrompt
+   */
+  async confirmPermissionDeny(sessionId: string, promptId: string): Promise<void> {
+    return apiCall("confirm_permission_deny", { sessionId, promptId });
+  },
+
+  /**
+   * Cancels a pending permission prompt, e.g. because its tab was closed
+   */
+  async cancelPermissionPrompt(sessionId: string, promptId: string): Promise<void> {
+    return apiCall("cancel_permission_prompt", { sessionId, promptId });
+  },
+
+  /**
+   * Postpones a permission prompt without answering it
+   * @param delayMs - How long until the prompt is shown again
+   */
+  async deferPermissionPrompt(sessionId: string, promptId: string, delayMs: number): Promise<void> {
+    return apiCall("defer_permission_prompt", { sessionId, promptId, delayMs });
+  },
+
+  /**
+   * Denies every permission prompt a session is waiting on
+   * @returns Promise resolving to the number of prompts denied
+   */
+  async denyAllPermissionPrompts(sessionId: string, message?: string): Promise<number> {
+    return apiCall<number>("deny_all_permission_prompts", { sessionId, message });
+  },
+
+  /**
+   * Gets the full event of a prompt whose emitted copy was truncated
+   */
+  async getPermissionPromptEvent(sessionId: string, promptId: string): Promise<PermissionPromptEvent> {
+    return apiCall<PermissionPromptEvent>("get_permission_prompt_event", { sessionId, promptId });
+  },
+
+  /**
+   * Gets the real input of a prompt whose event only carried a preview
+   */
+  async getFullPermissionInput(sessionId: string, promptId: string): Promise<Record<string, any>> {
+    return apiCall<Record<string, any>>("get_full_permission_input", { sessionId, promptId });
+  },
+
+  /**
+   * Lists a session's pending permission prompts, soonest deadline first
+   */
+  async listPendingPermissionPrompts(sessionId: string): Promise<PendingPermissionPrompt[]> {
+    return apiCall<PendingPermissionPrompt[]>("list_pending_permission_prompts", { sessionId });
+  },
+
+  /**
+   * Emits a session's pending permission prompts again, e.g. after a reload
+   * @returns Promise resolving to the number of prompts replayed
+   */
+  async replayPermissionPrompts(sessionId: string): Promise<number> {
+    return apiCall<number>("replay_permission_prompts", { sessionId });
+  },
+
+  /**
+   * Answers a question Claude asked through the ask_user tool; null dismisses it
+   */
+  async respondUserInput(sessionId: string, requestId: string, answer: string | null): Promise<void> {
+    return apiCall("respond_user_input", { sessionId, requestId, answer });
+  },
+
+  /**
+   * Gets a session's permission decision counts
+   */
+  async getPermissionMetrics(sessionId: string): Promise<PermissionMetrics> {
+    return apiCall<PermissionMetrics>("get_permission_metrics", { sessionId });
+  },
+
+  /**
+   * Gets every running permission server, for diagnostics
+   */
+  async getPermissionServersSnapshot(): Promise<PermissionServerSnapshot[]> {
+    return apiCall<PermissionServerSnapshot[]>("get_permission_servers_snapshot");
+  },
+
+  /**
+   * Gets running permission server and waiting prompt counts, for the status badge
+   */
+  async getPermissionActivity(): Promise<PermissionActivity> {
+    return apiCall<PermissionActivity>("get_permission_activity");
+  },
+
+  /**
+   * Stops a session's permission server, letting prompts already showing be answered first
+   * @param graceMs - How long to wait for those answers
+   */
+  async stopPermissionServerGraceful(sessionId: string, graceMs: number): Promise<PermissionStopResult> {
+    return apiCall<PermissionStopResult>("stop_permission_server_graceful", { sessionId, graceMs });
+  },
+
+  /**
+   * Gets the Node.js used for permission prompts and whether it is supported
+   */
+  async getPermissionNodeStatus(): Promise<PermissionNodeStatus> {
+    return apiCall<PermissionNodeStatus>("get_permission_node_status");
+  },
+
+  /**
+   * Gets the token for answering a session's prompts through its /resolve endpoint
+   */
+  async getPermissionControllerToken(sessionId: string): Promise<string> {
+    return apiCall<string>("get_permission_controller_token", { sessionId });
+  },
+
+  /**
+   * Lets a tool through without a prompt for the rest of the session
+   */
+  async addPermissionAllowedTool(sessionId: string, toolName: string): Promise<void> {
+    return apiCall("add_permission_allowed_tool", { sessionId, toolName });
+  },
+
+  /**
+   * Resumes prompting for an allowlisted tool
+   * @returns Promise resolving to whether the tool was allowlisted
+   */
+  async removePermissionAllowedTool(sessionId: string, toolName: string): Promise<boolean> {
+    return apiCall<boolean>("remove_permission_allowed_tool", { sessionId, toolName });
+  },
+
+  /**
+   * Denies a tool without a prompt for the rest of the session
+   */
+  async blockPermissionTool(sessionId: string, toolName: string): Promise<void> {
+    return apiCall("block_permission_tool", { sessionId, toolName });
+  },
+
+  /**
+   * Lifts a block set by blockPermissionTool
+   * @returns Promise resolving to whether the tool was blocked
+   */
+  async unblockPermissionTool(sessionId: string, toolName: string): Promise<boolean> {
+    return apiCall<boolean>("unblock_permission_tool", { sessionId, toolName });
+  },
+
+  /**
+   * Turns inspect mode on or off: requests are allowed as sent and only shown
+   */
+  async setPermissionInspectMode(sessionId: string, enabled: boolean): Promise<void> {
+    return apiCall("set_permission_inspect_mode", { sessionId, enabled });
+  },
+
+  /**
+   * Sets how long a session's new prompts wait for an answer; 0 waits forever
+   */
+  async setPermissionPromptTimeout(sessionId: string, timeoutMs: number): Promise<void> {
+    return apiCall("set_permission_prompt_timeout", { sessionId, timeoutMs });
+  },
+
+  /**
+   * Gives one tool its own prompt timeout, or clears it with null
+   */
+  async setPermissionToolTimeout(sessionId: string, toolName: string, timeoutMs: number | null): Promise<void> {
+    return apiCall("set_permission_tool_timeout", { sessionId, toolName, timeoutMs });
+  },
+
+  /**
+   * Limits how many permission requests a session may send per window, or lifts the limit with null
+   * @param windowMs - Window length; defaults to 10 seconds
+   */
+  async setPermissionRateLimit(sessionId: string, maxRequests: number | null, windowMs?: number): Promise<void> {
+    return apiCall("set_permission_rate_limit", { sessionId, maxRequests, windowMs });
+  },
+
+  /**
+   * Loads a policy file for a session, or drops it with null
+   * @returns Promise resolving to the number of policy rules in effect
+   */
+  async setPermissionPolicy(sessionId: string, path: string | null): Promise<number> {
+    return apiCall<number>("set_permission_policy", { sessionId, path });
+  },
+
+  /**
+   * Re-reads a session's policy file
+   * @returns Promise resolving to the number of policy rules in effect
+   */
+  async reloadPermissionPolicy(sessionId: string): Promise<number> {
+    return apiCall<number>("reload_permission_policy", { sessionId });
+  },
+
+  /**
+   * Moves the permission audit log, or turns it off with null
+   */
+  async setPermissionAuditLogPath(path: string | null): Promise<void> {
+    return apiCall("set_permission_audit_log_path", { path });
+  },
+
+  /**
+   * Masks secrets matching a regex in prompt events and the audit log
+   */
+  async addPermissionSecretPattern(name: string, pattern: string): Promise<void> {
+    return apiCall("add_permission_secret_pattern", { name, pattern });
+  },
+
+  /**
+   * Shows OS notifications for new permission prompts while the window is unfocused
+   */
+  async setPermissionOsNotifications(enabled: boolean): Promise<void> {
+    return apiCall("set_permission_os_notifications", { enabled });
+  },
+
+  /**
+   * Sets the minimum level of permission logs echoed as permission-log events
+   */
+  async setPermissionLogEcho(level: "off" | "error" | "warn" | "info" | "debug" | "trace"): Promise<void> {
+    return apiCall("set_permission_log_echo", { level });
+  },
+
+  /**
+   * Simulates a permission prompt for UI development (debug builds only)
+   * @param sessionId - The session whose permission server should emit the prompt
+   * @param toolName - Tool name shown in the prompt
+   * @param input - Tool input shown in the prompt
+   * @returns Promise resolving to the new prompt ID
+   */
+  async injectTestPermissionPrompt(
+    sessionId: string,
+    toolName: string,
+    input: Record<string, any>,
+  ): Promise<string> {
+    return apiCall<string>("inject_test_permission_prompt", { sessionId, toolName, input });
   },
 
   /**